@@ -1,3 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
 fn main() {
     embuild::espidf::sysenv::output();
+    defaults();
+}
+
+/// Compile the product defaults into `$OUT_DIR/defaults.rs`, see src/defaults.rs
+///
+/// Only the subset of TOML the file needs is understood: `[section]` headers
+/// and `key = value` lines with integer, float, boolean or string values.
+/// Values are passed through as Rust literals, so the types come from the
+/// structs in src/defaults.rs and a missing or unknown key fails the build
+/// there.
+fn defaults() {
+    println!("cargo:rerun-if-env-changed=DEFAULTS_TOML");
+    let path = env::var("DEFAULTS_TOML").unwrap_or_else(|_| "defaults.toml".to_string());
+    println!("cargo:rerun-if-changed={}", path);
+    let toml = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+
+    let mut code = String::from("pub const DEFAULTS: Defaults = Defaults {\n");
+    let mut in_section = false;
+    for (n, line) in toml.lines().enumerate() {
+        let fail = |what: &str| -> ! { panic!("{}:{}: {}", path, n + 1, what) };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let section = section.trim();
+            if !is_ident(section) {
+                fail("expected a [section] name of a-z, 0-9 and _");
+            }
+            if in_section {
+                code.push_str("    },\n");
+            }
+            code.push_str(&format!("    {}: {} {{\n", section, camel_case(section)));
+            in_section = true;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            fail("expected `key = value`");
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if !in_section {
+            fail("keys belong in a [section]");
+        }
+        if !is_ident(key) {
+            fail("expected a key of a-z, 0-9 and _");
+        }
+        if !is_literal(value) {
+            fail("expected an integer, float, boolean or \"string\"");
+        }
+        code.push_str(&format!("        {}: {},\n", key, value));
+    }
+    if in_section {
+        code.push_str("    },\n");
+    }
+    code.push_str("};\n");
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(Path::new(&out).join("defaults.rs"), code).unwrap();
+}
+
+/// `line` up to a `#` outside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_ident(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn is_literal(value: &str) -> bool {
+    if value == "true" || value == "false" {
+        return true;
+    }
+    if let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return !inner.contains('"') && !inner.contains('\\');
+    }
+    let digits = value.strip_prefix('-').unwrap_or(value).replace('_', "");
+    digits.parse::<u64>().is_ok() || (digits.contains('.') && digits.parse::<f64>().is_ok())
+}
+
+/// `away_mode` -> `AwayMode`, the struct holding a section
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_ascii_uppercase().to_string() + chars.as_str()
+            })
+        })
+        .collect()
 }
@@ -0,0 +1,84 @@
+//! Acknowledgments to senders
+//!
+//! Once the main loop has handled a sequenced data frame, the hub answers the
+//! sender with an `ACK` frame: the tag, the topic id of the frame's first
+//! reading as an i32 and the sequence number as a u16, little-endian, 9
+//! bytes. A sender that gets it can stop retransmitting and go back to sleep
+//! early. A retransmission of a frame already handled is acked again, its
+//! first ack evidently got lost. A frame dropped on a full receive queue is
+//! not acked, so the sender tries again.
+//!
+//! Frames without a sequence number get no ack, the sender would have
+//! nothing to match it against, and neither do injected frames. Acks go out
+//! fire-and-forget: a sender that gave up waiting is asleep, and a lost ack
+//! only costs a retransmission, which is acked in turn.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use log::debug;
+
+use crate::espnow_tx::{self, Qos};
+
+const TAG: &[u8; 3] = b"ACK";
+pub const LEN: usize = 9;
+// Acks waiting for the main loop, further ones are dropped
+const MAX_PENDING: usize = 16;
+
+// Queued on the receive path too, sent by flush once per loop iteration
+static PENDING: Mutex<VecDeque<([u8; 6], Ack)>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub topic_id: i32,
+    pub seq: u16,
+}
+
+impl Ack {
+    pub fn encode(&self) -> [u8; LEN] {
+        let mut frame = [0u8; LEN];
+        frame[..3].copy_from_slice(TAG);
+        frame[3..7].copy_from_slice(&self.topic_id.to_le_bytes());
+        frame[7..].copy_from_slice(&self.seq.to_le_bytes());
+        frame
+    }
+
+    /// Parse an ack frame, `None` for anything else
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let rest = frame.strip_prefix(TAG)?;
+        if rest.len() != LEN - TAG.len() {
+            return None;
+        }
+        Some(Self {
+            topic_id: i32::from_le_bytes(rest[..4].try_into().ok()?),
+            seq: u16::from_le_bytes(rest[4..].try_into().ok()?),
+        })
+    }
+}
+
+/// Queue `ack` for `src`, sent by the next [`flush`]
+pub fn queue(src: [u8; 6], ack: Ack) {
+    let mut pending = PENDING.lock().unwrap();
+    if pending.len() < MAX_PENDING {
+        pending.push_back((src, ack));
+    }
+}
+
+/// Acks waiting for the next [`flush`]
+pub fn pending() -> usize {
+    PENDING.lock().unwrap().len()
+}
+
+/// Send the queued acks, from the main loop
+pub fn flush() {
+    loop {
+        let Some((src, ack)) = PENDING.lock().unwrap().pop_front() else {
+            break;
+        };
+        let sent = espnow_tx::add_peer(src)
+            .and_then(|_| espnow_tx::send(src, &ack.encode(), Qos::FireAndForget));
+        if let Err(e) = sent {
+            debug!("Ack {} to {:02X?} not sent: {}", ack.seq, src, e);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Receiver ids and groups inside the payload
+//!
+//! ESP-NOW only knows a frame's MAC, one device or everyone on the channel.
+//! On top of that a message can be wrapped in a [`Message::Addressed`] for
+//! an [`Address`]: every receiver, one receiver by its id, or a group. Each
+//! hub has a receiver id, or none, and belongs to up to [`MAX_GROUPS`]
+//! groups; both start from `[addressing]` in defaults.toml and `address` on
+//! the console changes them, kept in NVS. They are the unit's own, like its
+//! identity, so the config backup leaves them out.
+//!
+//! The receive path unwraps an addressed message for this hub and takes it
+//! as if it came unwrapped, and drops one for others before it is acked or
+//! counted as a duplicate; the drops are counted for inspect. A message that
+//! is not wrapped is for whoever receives it, so senders and controlling
+//! nodes that know nothing of addresses work as before.
+//!
+//! The other way, [`command`] and [`alarm`] broadcast a command or a
+//! critical frame addressed to one receiver or a group, so a hub can
+//! silence, reboot or alarm other hubs from its console: `send <to>
+//! <command>`. They take it as from any controlling node, so this hub must
+//! be among their peers and, with an HMAC secret set, share it. Their
+//! replies are logged as they come in.
+
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::auth;
+use crate::espnow_tx::{self, Qos};
+use crate::keys;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::{esp, esp_wifi_get_mac, wifi_interface_t_WIFI_IF_STA, EspError};
+use crate::protocol::{self, Address, Command, Message};
+
+/// Groups a hub can be in
+pub const MAX_GROUPS: usize = 8;
+
+const NAMESPACE: &str = "addressing";
+const RECEIVER: &str = "receiver";
+const GROUPS: &str = "groups";
+
+static STATE: Mutex<State> = Mutex::new(State {
+    receiver: None,
+    groups: Vec::new(),
+    nvs: None,
+});
+// Addressed messages for other receivers, dropped
+static FOR_OTHERS: AtomicU32 = AtomicU32::new(0);
+// Id of the next command sent, and sequence number of the next alarm
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+struct State {
+    receiver: Option<u16>,
+    groups: Vec<u8>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl State {
+    fn save(&mut self) -> Result<(), &'static str> {
+        let (receiver, groups) = (self.receiver.unwrap_or(0), self.groups.clone());
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable, not stored")?;
+        nvs.set_u16(RECEIVER, receiver)
+            .and_then(|_| nvs.set_blob(GROUPS, &groups))
+            .map_err(|e| {
+                warn!("Failed to store the address: {}", e);
+                "write failed"
+            })
+    }
+}
+
+/// Take `receiver` and `groups` unless NVS holds others
+///
+/// With `partition` `None` they apply and changes are not kept.
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    receiver: Option<u16>,
+    groups: &[u8],
+) -> Result<(), EspError> {
+    let mut state = STATE.lock().unwrap();
+    state.receiver = receiver;
+    state.groups = groups.to_vec();
+    let Some(partition) = partition else {
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    if let Some(receiver) = nvs.get_u16(RECEIVER)? {
+        state.receiver = (receiver != 0).then_some(receiver);
+        let mut buf = [0u8; MAX_GROUPS];
+        state.groups = nvs.get_blob(GROUPS, &mut buf)?.unwrap_or_default().to_vec();
+    }
+    state.nvs = Some(nvs);
+    Ok(())
+}
+
+/// Whether a message addressed `to` is for this hub, counting it if not;
+/// called on the receive path
+pub fn accepts(to: Address) -> bool {
+    let state = STATE.lock().unwrap();
+    let accepted = match to {
+        Address::All => true,
+        Address::Receiver(id) => state.receiver == Some(id),
+        Address::Group(group) => state.groups.contains(&group),
+    };
+    if !accepted {
+        FOR_OTHERS.fetch_add(1, Ordering::Relaxed);
+    }
+    accepted
+}
+
+/// Set the receiver id, `None` for none, stored for the next boot too
+pub fn set_receiver(receiver: Option<u16>) -> Result<(), &'static str> {
+    if receiver == Some(0) {
+        return Err("receiver ids start at 1");
+    }
+    let mut state = STATE.lock().unwrap();
+    state.receiver = receiver;
+    state.save()
+}
+
+/// Join `group`, stored for the next boot too
+pub fn join(group: u8) -> Result<(), &'static str> {
+    if group == 0 {
+        return Err("groups start at 1");
+    }
+    let mut state = STATE.lock().unwrap();
+    if state.groups.contains(&group) {
+        return Ok(());
+    }
+    if state.groups.len() >= MAX_GROUPS {
+        return Err("in too many groups");
+    }
+    state.groups.push(group);
+    state.groups.sort_unstable();
+    state.save()
+}
+
+/// Leave `group`, stored for the next boot too
+pub fn leave(group: u8) -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    if !state.groups.contains(&group) {
+        return Err("not in that group");
+    }
+    state.groups.retain(|&g| g != group);
+    state.save()
+}
+
+/// Broadcast `command` for the receivers `to`; the id it went out with,
+/// which their replies echo
+pub fn command(to: Address, command: Command) -> Result<u16, &'static str> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    send(to, Message::Command { id, command })?;
+    Ok(id)
+}
+
+/// Broadcast a critical frame of `topic_id` at `measurement` for the
+/// receivers `to`, which raise the alarm on their fast path
+pub fn alarm(to: Address, topic_id: i32, measurement: i32) -> Result<(), &'static str> {
+    let seq = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    send(
+        to,
+        Message::Critical {
+            seq,
+            topic_id,
+            measurement,
+            battery: None,
+        },
+    )
+}
+
+/// Log the reply to a command sent with [`command`]
+pub fn replied(src: [u8; 6], id: u16, ok: bool) {
+    info!(
+        "Command {} {} on {:02X?}",
+        id,
+        if ok { "done" } else { "failed" },
+        src
+    );
+}
+
+fn send(to: Address, message: Message) -> Result<(), &'static str> {
+    let message = Message::Addressed {
+        to,
+        message: Box::new(message),
+    };
+    let frame = protocol::encode(&message).map_err(|_| "encoding failed")?;
+    let frame = match keys::hmac_secret() {
+        Some(secret) => {
+            let mut mac = [0u8; 6];
+            esp!(unsafe { esp_wifi_get_mac(wifi_interface_t_WIFI_IF_STA, mac.as_mut_ptr()) })
+                .map_err(|_| "own MAC unknown")?;
+            auth::seal(&secret, &mac, &frame).ok_or("authentication failed")?
+        }
+        None => frame,
+    };
+    espnow_tx::add_peer(espnow_tx::BROADCAST)
+        .and_then(|_| espnow_tx::send(espnow_tx::BROADCAST, &frame, Qos::Reliable))
+        .map_err(|e| {
+            warn!("Addressed frame for {} not sent: {}", to, e);
+            "send failed"
+        })
+}
+
+/// `all`, `r<id>` or `g<group>`, as [`Address`] displays
+pub fn parse(text: &str) -> Option<Address> {
+    if text == "all" {
+        return Some(Address::All);
+    }
+    if let Some(id) = text.strip_prefix('r') {
+        return id.parse().ok().filter(|&id| id != 0).map(Address::Receiver);
+    }
+    let group = text.strip_prefix('g')?;
+    group
+        .parse()
+        .ok()
+        .filter(|&group| group != 0)
+        .map(Address::Group)
+}
+
+/// Groups as `1,3,7`, as `[addressing] groups` has them
+pub fn parse_groups(text: &str) -> Result<Vec<u8>, &'static str> {
+    let mut groups = text
+        .split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(|group| match group.parse() {
+            Ok(0) | Err(_) => Err("expected groups 1 to 255"),
+            Ok(group) => Ok(group),
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    groups.sort_unstable();
+    groups.dedup();
+    if groups.len() > MAX_GROUPS {
+        return Err("too many groups");
+    }
+    Ok(groups)
+}
+
+/// Receiver id, groups and drops in one line, for the console and inspect
+pub fn summary() -> String {
+    let state = STATE.lock().unwrap();
+    let receiver = state.receiver.map_or("no receiver id".to_string(), |id| {
+        format!("receiver r{}", id)
+    });
+    let groups = if state.groups.is_empty() {
+        "no groups".to_string()
+    } else {
+        let groups: Vec<String> = state.groups.iter().map(|g| format!("g{}", g)).collect();
+        format!("groups {}", groups.join(" "))
+    };
+    format!(
+        "Address: {}, {}, {} frames for others dropped",
+        receiver,
+        groups,
+        FOR_OTHERS.load(Ordering::Relaxed)
+    )
+}
@@ -0,0 +1,654 @@
+//! Active alarm tracking and emergency escalation
+//!
+//! An alarm is raised when a topic goes out of range and stays active until
+//! the topic comes back in range or someone acknowledges it with the wake
+//! button. An alarm left unacknowledged for [`EMERGENCY_AFTER`] escalates to
+//! emergency mode: the alarm frame is broadcast on the ESP-NOW channel so
+//! every listening device in range hears it, not just the paired hub, and the
+//! uplinks get an emergency event (retained, QoS 2 over MQTT).
+//!
+//! Every alarm carries the [`Priority`] class of its topic, which decides who
+//! gets the shared buzzer when several alarms are active at once.
+//!
+//! A topic with a [`Cooldown`] is rate limited: its unacknowledged alarm
+//! sounds for [`BURST`], then rests, LED steady and buzzer free, until the
+//! cooldown has passed since the burst began, and sounds again. An alarm
+//! raised again within the cooldown of the last burst, e.g. a reading
+//! flapping around its limit, starts resting, and repeated readings out of
+//! range only go upstream once per cooldown. Moving up a priority band
+//! sounds right away. The emergency counts from the raise whatever the phase.
+//!
+//! A topic with an [`Escalation`] starts gently and builds up while its
+//! alarm persists, through the [`Level`]s: a short soft beep at first, its
+//! full pattern (custom sound or class pattern) after `full_after`, and a
+//! continuous tone after `continuous_after`, which no longer rests for the
+//! cooldown, until acknowledged. Topics without one sound their full
+//! pattern from the start.
+//!
+//! An alarm raised by a critical frame, see the fast_path module, skips both:
+//! it sounds at once, at full level or above, and never rests.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::datalog::Sample;
+use crate::espnow_tx::{self, Qos};
+use crate::fragment;
+use crate::metrics;
+use crate::names;
+use crate::strings::{self, Text};
+use crate::uplink::{Event, UplinkChain};
+
+/// How long an alarm may go unacknowledged before it is broadcast
+const EMERGENCY_AFTER: Duration = Duration::from_secs(5 * 60);
+// Broadcasts are unacknowledged, so repeat them while the emergency lasts
+const BROADCAST_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a rate-limited alarm sounds before it rests
+pub const BURST: Duration = Duration::from_secs(5);
+
+/// Alarm priority class, higher classes preempt lower ones on the buzzer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Priority {
+    /// Lowercase name, as in `sounds.cfg` and the uplink severity
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Info => "info",
+            Priority::Warning => "warning",
+            Priority::Critical => "critical",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [Priority::Info, Priority::Warning, Priority::Critical]
+            .into_iter()
+            .find(|p| p.name() == name)
+    }
+}
+
+/// Alarm state of a single topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmState {
+    Clear,
+    Sounding,
+    /// Unacknowledged, out of range, but quiet until its cooldown is over
+    Resting,
+    /// Still out of range, but someone has seen it
+    Acknowledged,
+}
+
+/// At most one burst per `every` on `topic_id`, see the module docs
+#[derive(Debug, Clone, Copy)]
+pub struct Cooldown {
+    pub topic_id: i32,
+    pub every: Duration,
+}
+
+/// Sound levels of an escalating alarm, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// A short soft beep
+    First,
+    /// The topic's sound or its class pattern
+    Full,
+    /// One unbroken tone
+    Continuous,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::First => "first",
+            Level::Full => "full",
+            Level::Continuous => "continuous",
+        }
+    }
+}
+
+/// How long the alarm of `topic_id` is out of range before it moves up a
+/// [`Level`]; zero `continuous_after` never goes continuous
+#[derive(Debug, Clone, Copy)]
+pub struct Escalation {
+    pub topic_id: i32,
+    pub full_after: Duration,
+    pub continuous_after: Duration,
+}
+
+impl Escalation {
+    fn level(&self, raised: Duration) -> Level {
+        if !self.continuous_after.is_zero() && raised >= self.continuous_after {
+            Level::Continuous
+        } else if raised >= self.full_after {
+            Level::Full
+        } else {
+            Level::First
+        }
+    }
+}
+
+/// Where an alarm is in its state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Wanting the buzzer, the burst began then
+    Sounding(Instant),
+    /// Until then
+    Resting(Instant),
+    Acknowledged,
+}
+
+struct Alarm {
+    sample: Sample,
+    priority: Priority,
+    raised_at: Instant,
+    phase: Phase,
+    /// When the alarm last went upstream, for the rate limit
+    published_at: Instant,
+    /// Set once the alarm has escalated
+    last_broadcast: Option<Instant>,
+    /// Raised or refreshed by a critical frame
+    urgent: bool,
+}
+
+impl Alarm {
+    fn acknowledged(&self) -> bool {
+        self.phase == Phase::Acknowledged
+    }
+
+    fn sounding(&self) -> bool {
+        matches!(self.phase, Phase::Sounding(_))
+    }
+
+    fn is_emergency(&self) -> bool {
+        self.last_broadcast.is_some()
+    }
+
+    /// Leave emergency mode, replacing the retained emergency upstream
+    fn stand_down(&mut self, uplinks: &mut UplinkChain) {
+        if self.last_broadcast.take().is_none() {
+            return;
+        }
+        info!(
+            "{}",
+            strings::text(
+                Text::EmergencyStoodDown,
+                &[&names::label(self.sample.topic_id)]
+            )
+        );
+
+        let event = Event::Emergency {
+            sample: self.sample,
+            active: false,
+        };
+        broadcast(&event);
+        if let Err(e) = uplinks.send(&event) {
+            warn!("Emergency stand-down delivery failed: {}", e);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Alerts {
+    active: Vec<Alarm>,
+    cooldowns: Vec<Cooldown>,
+    escalations: Vec<Escalation>,
+    /// When each rate-limited topic's last burst began, kept past its alarm
+    bursts: Vec<(i32, Instant)>,
+}
+
+impl Alerts {
+    /// Alarms rate limited as `cooldowns` say and building up as
+    /// `escalations` say; topics not listed sound on, at full level
+    pub fn new(cooldowns: &[Cooldown], escalations: &[Escalation]) -> Self {
+        Self {
+            cooldowns: cooldowns.to_vec(),
+            escalations: escalations.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    /// Rate limit and build up the alarms as `cooldowns` and `escalations`
+    /// say from now on, as for [`new`](Self::new)
+    pub fn set_timings(&mut self, cooldowns: &[Cooldown], escalations: &[Escalation]) {
+        self.cooldowns = cooldowns.to_vec();
+        self.escalations = escalations.to_vec();
+    }
+
+    /// Record an out-of-range sample, refreshing the alarm if already active;
+    /// whether to send it upstream, `false` while the rate limit holds it
+    ///
+    /// An active alarm moving to another priority band takes that priority;
+    /// moving up it sounds again even if it was acknowledged.
+    pub fn raise(&mut self, sample: Sample, priority: Priority) -> bool {
+        let now = clock::now();
+        let cooldown = self.cooldown(sample.topic_id);
+        let last_burst = self.last_burst(sample.topic_id);
+        match self
+            .active
+            .iter_mut()
+            .find(|a| a.sample.topic_id == sample.topic_id)
+        {
+            Some(alarm) => {
+                alarm.sample = sample;
+                if priority == alarm.priority {
+                    if now.duration_since(alarm.published_at) < cooldown {
+                        return false;
+                    }
+                    alarm.published_at = now;
+                    return true;
+                }
+                let text = if priority > alarm.priority {
+                    alarm.phase = Phase::Sounding(now);
+                    Text::AlarmBandUp
+                } else {
+                    Text::AlarmBandDown
+                };
+                warn!(
+                    "{}",
+                    strings::text(text, &[&names::label(sample.topic_id), &sample.measurement])
+                );
+                alarm.priority = priority;
+                alarm.published_at = now;
+            }
+            None => {
+                warn!(
+                    "{}",
+                    strings::text(
+                        Text::AlarmRaised,
+                        &[&names::label(sample.topic_id), &sample.measurement]
+                    )
+                );
+                metrics::alarm_raised();
+                let phase = match last_burst.map(|t| t + cooldown) {
+                    Some(until) if until > now => Phase::Resting(until),
+                    _ => Phase::Sounding(now),
+                };
+                self.active.push(Alarm {
+                    sample,
+                    priority,
+                    raised_at: now,
+                    phase,
+                    published_at: now,
+                    last_broadcast: None,
+                    urgent: false,
+                });
+            }
+        }
+        self.note_bursts(now);
+        true
+    }
+
+    /// Raise the alarm of a critical frame: at critical priority, sounding
+    /// now even within a cooldown, at full level and without rests from now
+    /// on; whether to send it upstream, as [`Alerts::raise`]
+    pub fn raise_critical(&mut self, sample: Sample) -> bool {
+        let publish = self.raise(sample, Priority::Critical);
+        let now = clock::now();
+        if let Some(alarm) = self
+            .active
+            .iter_mut()
+            .find(|a| a.sample.topic_id == sample.topic_id)
+        {
+            alarm.urgent = true;
+            if let Phase::Resting(_) = alarm.phase {
+                alarm.phase = Phase::Sounding(now);
+            }
+        }
+        self.note_bursts(now);
+        publish
+    }
+
+    /// The topic is back in range, telling the uplinks if it was in alarm
+    pub fn clear(&mut self, topic_id: i32, uplinks: &mut UplinkChain) {
+        if let Some(i) = self
+            .active
+            .iter()
+            .position(|a| a.sample.topic_id == topic_id)
+        {
+            let mut alarm = self.active.swap_remove(i);
+            info!(
+                "{}",
+                strings::text(Text::AlarmCleared, &[&names::label(topic_id)])
+            );
+            alarm.stand_down(uplinks);
+            if let Err(e) = uplinks.send(&Event::AlarmCleared(alarm.sample)) {
+                warn!("Alarm clear delivery failed: {}", e);
+            }
+        }
+    }
+
+    /// Acknowledge every active alarm, ending any emergency
+    pub fn acknowledge(&mut self, uplinks: &mut UplinkChain) {
+        for alarm in self.active.iter_mut().filter(|a| !a.acknowledged()) {
+            info!(
+                "{}",
+                strings::text(
+                    Text::AlarmAcknowledged,
+                    &[&names::label(alarm.sample.topic_id)]
+                )
+            );
+            alarm.phase = Phase::Acknowledged;
+            alarm.stand_down(uplinks);
+        }
+    }
+
+    pub fn state(&self, topic_id: i32) -> AlarmState {
+        match self.active.iter().find(|a| a.sample.topic_id == topic_id) {
+            None => AlarmState::Clear,
+            Some(a) => match a.phase {
+                Phase::Sounding(_) => AlarmState::Sounding,
+                Phase::Resting(_) => AlarmState::Resting,
+                Phase::Acknowledged => AlarmState::Acknowledged,
+            },
+        }
+    }
+
+    /// Whether any alarm is raised, acknowledged or not
+    pub fn is_active(&self) -> bool {
+        !self.active.is_empty()
+    }
+
+    /// Unacknowledged alarms not resting as `(topic_id, priority)`, these
+    /// want the buzzer
+    pub fn sounding(&self) -> impl Iterator<Item = (i32, Priority)> + '_ {
+        self.active
+            .iter()
+            .filter(|a| a.sounding())
+            .map(|a| (a.sample.topic_id, a.priority))
+    }
+
+    /// When the oldest unacknowledged alarm of at least `priority` was
+    /// raised, resting ones included
+    pub fn unacknowledged_since(&self, priority: Priority) -> Option<Instant> {
+        self.active
+            .iter()
+            .filter(|a| !a.acknowledged() && a.priority >= priority)
+            .map(|a| a.raised_at)
+            .min()
+    }
+
+    /// Every raised alarm as `(topic_id, priority, acknowledged)`
+    pub fn raised(&self) -> impl Iterator<Item = (i32, Priority, bool)> + '_ {
+        self.active
+            .iter()
+            .map(|a| (a.sample.topic_id, a.priority, a.acknowledged()))
+    }
+
+    /// How far the alarm of `topic_id` has escalated, [`Level::Full`] for
+    /// topics without an escalation or an alarm, and at least that for an
+    /// urgent one
+    pub fn level(&self, topic_id: i32) -> Level {
+        let escalation = self.escalations.iter().find(|e| e.topic_id == topic_id);
+        let alarm = self.active.iter().find(|a| a.sample.topic_id == topic_id);
+        match (escalation, alarm) {
+            (Some(escalation), Some(alarm)) if alarm.urgent => escalation
+                .level(clock::since(alarm.raised_at))
+                .max(Level::Full),
+            (Some(escalation), Some(alarm)) => escalation.level(clock::since(alarm.raised_at)),
+            _ => Level::Full,
+        }
+    }
+
+    /// Every raised alarm, one line each, for the inspect command
+    pub fn list(&self) -> String {
+        let mut list = String::new();
+        for alarm in &self.active {
+            let state = match (alarm.phase, alarm.is_emergency()) {
+                (Phase::Acknowledged, _) => "acknowledged",
+                (_, true) => "emergency",
+                (Phase::Resting(_), false) => "resting",
+                (Phase::Sounding(_), false) => "sounding",
+            };
+            list.push_str(&format!(
+                "{} = {} {} {} at {} level, raised {} s ago\n",
+                names::label(alarm.sample.topic_id),
+                alarm.sample.measurement,
+                alarm.priority.name(),
+                state,
+                self.level(alarm.sample.topic_id).name(),
+                clock::since(alarm.raised_at).as_secs()
+            ));
+        }
+        list
+    }
+
+    /// Step rate-limited alarms between bursts and rests, escalate overdue
+    /// alarms and repeat the broadcasts of ongoing ones
+    pub fn poll(&mut self, uplinks: &mut UplinkChain) {
+        let now = clock::now();
+
+        for i in 0..self.active.len() {
+            let topic_id = self.active[i].sample.topic_id;
+            let cooldown = self.cooldown(topic_id);
+            let continuous = self.level(topic_id) == Level::Continuous;
+            let alarm = &mut self.active[i];
+            let steady = continuous || alarm.urgent;
+            alarm.phase = match alarm.phase {
+                Phase::Sounding(began) if !steady && cooldown > BURST && now >= began + BURST => {
+                    Phase::Resting(began + cooldown)
+                }
+                Phase::Resting(until) if steady || now >= until => Phase::Sounding(now),
+                phase => phase,
+            };
+        }
+        self.note_bursts(now);
+
+        for alarm in self.active.iter_mut().filter(|a| !a.acknowledged()) {
+            if now.duration_since(alarm.raised_at) < EMERGENCY_AFTER {
+                continue;
+            }
+            if alarm
+                .last_broadcast
+                .is_some_and(|t| now.duration_since(t) < BROADCAST_INTERVAL)
+            {
+                continue;
+            }
+
+            let event = Event::Emergency {
+                sample: alarm.sample,
+                active: true,
+            };
+            if !alarm.is_emergency() {
+                warn!(
+                    "{}",
+                    strings::text(
+                        Text::EmergencyBroadcast,
+                        &[
+                            &names::label(alarm.sample.topic_id),
+                            &(EMERGENCY_AFTER.as_secs() / 60)
+                        ]
+                    )
+                );
+                if let Err(e) = uplinks.send(&event) {
+                    warn!("Emergency delivery failed: {}", e);
+                }
+            }
+            broadcast(&event);
+            alarm.last_broadcast = Some(now);
+        }
+    }
+
+    fn cooldown(&self, topic_id: i32) -> Duration {
+        self.cooldowns
+            .iter()
+            .find(|c| c.topic_id == topic_id)
+            .map_or(Duration::ZERO, |c| c.every)
+    }
+
+    fn last_burst(&self, topic_id: i32) -> Option<Instant> {
+        self.bursts
+            .iter()
+            .find(|(id, _)| *id == topic_id)
+            .map(|&(_, began)| began)
+    }
+
+    /// Keep when the bursts of rate-limited topics began, past their alarms
+    fn note_bursts(&mut self, now: Instant) {
+        for alarm in &self.active {
+            let Phase::Sounding(began) = alarm.phase else {
+                continue;
+            };
+            let topic_id = alarm.sample.topic_id;
+            match self.bursts.iter_mut().find(|(id, _)| *id == topic_id) {
+                Some(burst) => burst.1 = began,
+                None => self.bursts.push((topic_id, began)),
+            }
+        }
+        let cooldowns = &self.cooldowns;
+        self.bursts.retain(|&(topic_id, began)| {
+            cooldowns
+                .iter()
+                .any(|c| c.topic_id == topic_id && now.duration_since(began) < c.every)
+        });
+    }
+}
+
+/// Broadcast `event` as JSON, in fragments if it does not fit one frame
+fn broadcast(event: &Event) {
+    let result = espnow_tx::add_peer(espnow_tx::BROADCAST).and_then(|_| {
+        fragment::send(
+            espnow_tx::BROADCAST,
+            event.to_json().as_bytes(),
+            Qos::FireAndForget,
+        )
+    });
+    if let Err(e) = result {
+        warn!("Emergency broadcast failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const SINK: i32 = 1;
+    const KETTLE: i32 = 2;
+    const FREEZER: i32 = 3;
+
+    fn raise(alerts: &mut Alerts, topic_id: i32, priority: Priority) -> bool {
+        alerts.raise(Sample::now(topic_id, 100), priority)
+    }
+
+    #[test]
+    fn concurrent_alarms_are_tracked_apart() {
+        let _clock = clock::mock();
+        let mut alerts = Alerts::default();
+        let mut uplinks = UplinkChain::default();
+        assert!(raise(&mut alerts, SINK, Priority::Warning));
+        assert!(raise(&mut alerts, KETTLE, Priority::Warning));
+        assert_eq!(alerts.state(SINK), AlarmState::Sounding);
+        assert_eq!(alerts.state(KETTLE), AlarmState::Sounding);
+
+        alerts.clear(KETTLE, &mut uplinks);
+        assert_eq!(alerts.state(KETTLE), AlarmState::Clear);
+        assert_eq!(alerts.state(SINK), AlarmState::Sounding);
+        assert!(alerts.is_active());
+
+        alerts.clear(SINK, &mut uplinks);
+        assert!(!alerts.is_active());
+    }
+
+    #[test]
+    fn acknowledging_leaves_later_alarms_sounding() {
+        let _clock = clock::mock();
+        let mut alerts = Alerts::default();
+        let mut uplinks = UplinkChain::default();
+        raise(&mut alerts, SINK, Priority::Warning);
+        raise(&mut alerts, KETTLE, Priority::Info);
+        alerts.acknowledge(&mut uplinks);
+        raise(&mut alerts, FREEZER, Priority::Warning);
+
+        assert_eq!(alerts.state(SINK), AlarmState::Acknowledged);
+        assert_eq!(alerts.state(KETTLE), AlarmState::Acknowledged);
+        assert_eq!(alerts.state(FREEZER), AlarmState::Sounding);
+        let sounding: Vec<_> = alerts.sounding().collect();
+        assert_eq!(sounding, [(FREEZER, Priority::Warning)]);
+
+        // Refreshing an acknowledged alarm in its band keeps it quiet
+        raise(&mut alerts, SINK, Priority::Warning);
+        assert_eq!(alerts.state(SINK), AlarmState::Acknowledged);
+    }
+
+    #[test]
+    fn moving_up_a_band_sounds_one_alarm_again() {
+        let _clock = clock::mock();
+        let mut alerts = Alerts::default();
+        let mut uplinks = UplinkChain::default();
+        raise(&mut alerts, SINK, Priority::Info);
+        raise(&mut alerts, KETTLE, Priority::Info);
+        alerts.acknowledge(&mut uplinks);
+        assert!(raise(&mut alerts, KETTLE, Priority::Critical));
+
+        assert_eq!(alerts.state(SINK), AlarmState::Acknowledged);
+        assert_eq!(alerts.state(KETTLE), AlarmState::Sounding);
+        let mut raised: Vec<_> = alerts.raised().collect();
+        raised.sort();
+        assert_eq!(
+            raised,
+            [
+                (SINK, Priority::Info, true),
+                (KETTLE, Priority::Critical, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limits_hold_only_their_own_topic() {
+        static COOLDOWNS: [Cooldown; 1] = [Cooldown {
+            topic_id: SINK,
+            every: Duration::from_secs(60),
+        }];
+        let (_turn, clock) = clock::mock();
+        let mut alerts = Alerts::new(&COOLDOWNS, &[]);
+        let mut uplinks = UplinkChain::default();
+        raise(&mut alerts, SINK, Priority::Warning);
+        raise(&mut alerts, KETTLE, Priority::Warning);
+
+        clock.advance(BURST);
+        alerts.poll(&mut uplinks);
+        assert_eq!(alerts.state(SINK), AlarmState::Resting);
+        assert_eq!(alerts.state(KETTLE), AlarmState::Sounding);
+        assert!(!raise(&mut alerts, SINK, Priority::Warning));
+        assert!(raise(&mut alerts, KETTLE, Priority::Warning));
+
+        clock.advance(Duration::from_secs(60) - BURST);
+        alerts.poll(&mut uplinks);
+        assert_eq!(alerts.state(SINK), AlarmState::Sounding);
+    }
+
+    proptest! {
+        #[test]
+        fn rests_always_end_within_the_cooldown(
+            secs in 6u64..600,
+            steps in proptest::collection::vec(1u64..30, 1..40),
+        ) {
+            let every = Duration::from_secs(secs);
+            let cooldowns = Box::leak(Box::new([Cooldown { topic_id: SINK, every }]));
+            let (_turn, clock) = clock::mock();
+            let mut alerts = Alerts::new(cooldowns, &[]);
+            let mut uplinks = UplinkChain::default();
+            raise(&mut alerts, SINK, Priority::Warning);
+            let mut rested_since = None;
+            for step in steps {
+                clock.advance(Duration::from_secs(step));
+                alerts.poll(&mut uplinks);
+                match alerts.state(SINK) {
+                    AlarmState::Resting => {
+                        let since = *rested_since.get_or_insert(clock::now());
+                        prop_assert!(clock::since(since) < every);
+                    }
+                    AlarmState::Sounding => rested_since = None,
+                    state => prop_assert!(false, "{:?}", state),
+                }
+            }
+        }
+    }
+}
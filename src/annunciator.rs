@@ -0,0 +1,511 @@
+//! Alarm outputs: the shared buzzer and the per-topic LEDs
+//!
+//! Only one alarm owns the buzzer at a time. The highest [`Priority`] class
+//! among the sounding alarms wins it, and alarms of the same class take turns,
+//! one pattern cycle each. Each alarm plays its custom [`Sounds`] sound if one
+//! is assigned, its class's built-in pattern otherwise. An escalating alarm
+//! (see [`Level`]) plays a short soft beep every 3 s at first and one unbroken
+//! tone once continuous, its sound or pattern only in between.
+//!
+//! Every LED is driven from its own topic's alarm state, so one alarm never
+//! masks another: steady while waiting for the buzzer, blinking in step with
+//! the buzzer while holding it, and a short reminder blink once acknowledged
+//! while the topic is still out of range.
+//!
+//! While someone is known to be close by (see [`Annunciator::set_quiet`]) the
+//! buzzer plays softly where the hardware allows, and only critical alarms
+//! sound at all; the LEDs carry the rest. While silent (see
+//! [`Annunciator::set_silent`]) no alarm sounds, the LEDs carry them all.
+//!
+//! Outside alarms the buzzer also plays the short boot and sleep [`Cue`]s,
+//! and the sleep countdown blinks every LED with a soft chirp each second.
+//! The pairing cues light every LED while they play. Otherwise idle LEDs show
+//! the hub's [`readiness`](crate::readiness): a short flash every 10 s while
+//! armed, a double flash every 2 s while degraded, nothing before, and a
+//! fast error blink while ESP-NOW is down. An idle LED whose sender has a
+//! weak link, see the link module, gives a triple flash every 5 s instead.
+//! A topic gone silent, see the freshness module, blinks its LED slowly, one
+//! second in four, and until acknowledged the idle buzzer gives a double low
+//! chirp every 30 s.
+//! While pairing, the LEDs of topics without an alarm blink the pairing PIN
+//! instead, and while commissioning they follow the commissioning [`Guide`].
+
+use std::time::{Duration, Instant};
+
+use crate::alerts::{AlarmState, Alerts, Level, Priority};
+#[cfg(target_os = "espidf")]
+use crate::board::{self, Board};
+use crate::clock;
+use crate::commissioning::Guide;
+use crate::gpio_io::Output;
+use crate::output_guard::GuardedOutput;
+use crate::pairing::PIN_DIGITS;
+#[cfg(target_os = "espidf")]
+use crate::pins::PinError;
+use crate::platform::delay::FreeRtos;
+use crate::platform::sys::EspError;
+use crate::readiness::State as Readiness;
+use crate::sounds::{beeps, Cue, Sounds, Step, BEEP_HZ};
+
+// Acknowledged alarms that are still out of range keep a short LED blink
+const REMINDER_PERIOD_MS: u128 = 2000;
+const REMINDER_ON_MS: u128 = 100;
+// Sleep countdown: LEDs blink at 1 Hz with a short, low chirp at each blink
+const COUNTDOWN_PERIOD_MS: u128 = 1000;
+const COUNTDOWN_CHIRP_MS: u128 = 20;
+const COUNTDOWN_CHIRP_HZ: u32 = BEEP_HZ / 2;
+// Pairing PIN: one burst of blinks per digit, a pause before it repeats
+const PIN_ON_MS: u128 = 200;
+const PIN_OFF_MS: u128 = 300;
+const PIN_DIGIT_GAP_MS: u128 = 1000;
+const PIN_REPEAT_GAP_MS: u128 = 2500;
+// Readiness on idle LEDs
+const ARMED_PERIOD_MS: u128 = 10_000;
+const ARMED_ON_MS: u128 = 30;
+const DEGRADED_PERIOD_MS: u128 = 2000;
+const DEGRADED_ON_MS: u128 = 60;
+const RADIO_DOWN_PERIOD_MS: u128 = 250;
+const WEAK_LINK_PERIOD_MS: u128 = 5000;
+const WEAK_LINK_ON_MS: u128 = 60;
+// Silent sensors: a slow LED blink, and a double low chirp on the buzzer
+const OFFLINE_PERIOD_MS: u128 = 4000;
+const OFFLINE_ON_MS: u128 = 1000;
+const OFFLINE_CHIRP_PERIOD_MS: u128 = 30_000;
+const OFFLINE_CHIRP_MS: u128 = 80;
+const OFFLINE_CHIRP_HZ: u32 = BEEP_HZ / 2;
+
+const CRITICAL_PATTERN: [Step; 6] = beeps([150, 100, 150, 100, 150, 650]);
+const WARNING_PATTERN: [Step; 2] = beeps([500, 500]);
+const INFO_PATTERN: [Step; 2] = beeps([100, 1900]);
+// Escalation: a soft short beep to start with, one tone at the end, broken
+// for an instant each second so the buzzer's on-time limit does not cut it
+const FIRST_PATTERN: [Step; 2] = beeps([80, 2920]);
+const CONTINUOUS_PATTERN: [Step; 2] = beeps([980, 20]);
+
+// Rising for waking up, falling for going to sleep; an active buzzer plays
+// both as two short chirps
+const BOOT_CUE: [Step; 3] = [
+    Step::tone(BEEP_HZ * 2 / 3, 60),
+    Step::rest(40),
+    Step::tone(BEEP_HZ, 80),
+];
+const SLEEP_CUE: [Step; 3] = [
+    Step::tone(BEEP_HZ, 60),
+    Step::rest(40),
+    Step::tone(BEEP_HZ * 2 / 3, 120),
+];
+// One long beep as a window opens, three quick ones for a paired sender
+const PAIRING_CUE: [Step; 1] = [Step::tone(BEEP_HZ, 300)];
+const PAIRED_CUE: [Step; 5] = beeps([60, 60, 60, 60, 60]);
+
+/// Built-in buzzer pattern of a priority class
+fn builtin_pattern(priority: Priority) -> &'static [Step] {
+    match priority {
+        Priority::Critical => &CRITICAL_PATTERN,
+        Priority::Warning => &WARNING_PATTERN,
+        Priority::Info => &INFO_PATTERN,
+    }
+}
+
+/// The alarm currently holding the buzzer and where it is in its pattern
+struct Turn {
+    topic_id: i32,
+    priority: Priority,
+    step: usize,
+    step_started: Instant,
+}
+
+pub struct Annunciator {
+    buzzer: GuardedOutput,
+    leds: Vec<(i32, Box<dyn Output>)>,
+    turn: Option<Turn>,
+    sounds: Sounds,
+    sleep_warning: bool,
+    quiet: bool,
+    silent: bool,
+    /// Whether the buzzer is set to play softly, when quiet or at first level
+    soft: bool,
+    /// Pairing PIN being shown and since when
+    pin: Option<([u8; PIN_DIGITS], Instant)>,
+    guide: Option<Guide>,
+    readiness: Readiness,
+    radio_down: bool,
+    /// Topics whose sender has a weak link
+    weak_links: Vec<i32>,
+    /// Silent topics, and whether they chirp
+    offline: Vec<i32>,
+    offline_chirp: bool,
+    /// Phase reference for the acknowledged-alarm reminder blink
+    epoch: Instant,
+}
+
+impl Annunciator {
+    /// `leds` maps topic ids to their alarm LED, all outputs start low
+    pub fn new(buzzer: GuardedOutput, leds: Vec<(i32, Box<dyn Output>)>) -> Self {
+        let mut annunciator = Self {
+            buzzer,
+            leds,
+            turn: None,
+            sounds: Sounds::default(),
+            sleep_warning: false,
+            quiet: false,
+            silent: false,
+            soft: false,
+            pin: None,
+            guide: None,
+            readiness: Readiness::Booting,
+            radio_down: false,
+            weak_links: Vec::new(),
+            offline: Vec::new(),
+            offline_chirp: false,
+            epoch: clock::now(),
+        };
+        for (_, led) in annunciator.leds.iter_mut() {
+            led.set_low().ok();
+        }
+        annunciator
+    }
+
+    /// Configure outputs that were held back during boot (strapping pins)
+    pub fn arm(&mut self) -> Result<(), EspError> {
+        self.buzzer.arm()?;
+        for (_, led) in self.leds.iter_mut() {
+            led.arm()?;
+        }
+        Ok(())
+    }
+
+    /// Drive every output low and start the buzzer's turns over, e.g. after
+    /// a driver hung; the next [`poll`](Self::poll) drives them afresh
+    pub fn reset(&mut self) {
+        self.turn = None;
+        self.buzzer.set_tone(None);
+        for (_, led) in self.leds.iter_mut() {
+            led.set_low().ok();
+        }
+    }
+
+    /// Replace the custom sounds, takes effect from the next pattern step
+    pub fn set_sounds(&mut self, sounds: Sounds) {
+        self.sounds = sounds;
+    }
+
+    /// Play `cue` to the end before returning, unless it is turned off
+    ///
+    /// Meant for boot and shutdown and the pairing feedback, all short; a
+    /// sounding alarm takes the buzzer and LEDs back at the next poll.
+    pub fn play_cue(&mut self, cue: Cue) {
+        let steps = self.sounds.cue(cue).unwrap_or(match cue {
+            Cue::Boot => &BOOT_CUE,
+            Cue::Sleep => &SLEEP_CUE,
+            Cue::Pairing => &PAIRING_CUE,
+            Cue::Paired => &PAIRED_CUE,
+        });
+        let lit = matches!(cue, Cue::Pairing | Cue::Paired);
+        if lit {
+            for (_, led) in self.leds.iter_mut() {
+                led.set_level(true).ok();
+            }
+        }
+        for step in steps {
+            self.buzzer.set_tone(step.tone);
+            FreeRtos::delay_ms(step.ms as u32);
+        }
+        self.buzzer.set_tone(None);
+        if lit {
+            for (_, led) in self.leds.iter_mut() {
+                led.set_low().ok();
+            }
+        }
+    }
+
+    /// Show the sleep countdown, alarms still take precedence
+    pub fn set_sleep_warning(&mut self, on: bool) {
+        self.sleep_warning = on;
+    }
+
+    /// Keep the buzzer down while someone is right by the hub, for
+    /// critical alarms a soft tone, for the rest just the LEDs
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+        self.soften(quiet);
+    }
+
+    /// Keep the buzzer off for every alarm, e.g. in quiet hours
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+    }
+
+    fn soften(&mut self, soft: bool) {
+        if soft != self.soft {
+            self.soft = soft;
+            self.buzzer.set_quiet(soft);
+        }
+    }
+
+    /// Blink `pin` on the idle LEDs, or stop with `None`
+    pub fn set_pin(&mut self, pin: Option<[u8; PIN_DIGITS]>) {
+        if pin != self.pin.map(|(pin, _)| pin) {
+            self.pin = pin.map(|pin| (pin, clock::now()));
+        }
+    }
+
+    /// Show the commissioning steps, or stop with `None`
+    pub fn set_guide(&mut self, guide: Option<Guide>) {
+        self.guide = guide;
+    }
+
+    /// Show the hub's readiness on the idle LEDs
+    pub fn set_readiness(&mut self, readiness: Readiness) {
+        self.readiness = readiness;
+    }
+
+    /// Blink the error pattern on the idle LEDs while ESP-NOW is down
+    pub fn set_radio_down(&mut self, down: bool) {
+        self.radio_down = down;
+    }
+
+    /// Flag a weak link on the idle LEDs of `topics`
+    pub fn set_weak_links(&mut self, topics: Vec<i32>) {
+        self.weak_links = topics;
+    }
+
+    /// Show `topics` as offline, chirping the buzzer too with `chirp`
+    pub fn set_offline(&mut self, topics: Vec<i32>, chirp: bool) {
+        self.offline = topics;
+        self.offline_chirp = chirp;
+    }
+
+    /// Whether the buzzer is held off by its on-time limit
+    /// Force the buzzer off after `max_on` unbroken, see [`GuardedOutput`]
+    pub fn set_buzzer_max_on(&mut self, max_on: Duration) {
+        self.buzzer.set_max_on(max_on);
+    }
+
+    pub fn buzzer_tripped(&self) -> bool {
+        self.buzzer.tripped()
+    }
+
+    /// The current topic -> LED GPIO mapping
+    pub fn led_map(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        self.leds
+            .iter()
+            .map(|(topic_id, led)| (*topic_id, led.gpio()))
+    }
+
+    /// Move the alarm LED of `topic_id` to `gpio`, or detach it with `None`
+    ///
+    /// The new pin is claimed before the old one is released, so a bad pin
+    /// leaves the current mapping untouched. `reserved` lists GPIOs held by
+    /// anything other than the annunciator.
+    #[cfg(target_os = "espidf")]
+    pub fn remap_led<B: Board>(
+        &mut self,
+        topic_id: i32,
+        gpio: Option<i32>,
+        reserved: &[i32],
+    ) -> Result<(), PinError> {
+        let current = self.leds.iter().position(|(id, _)| *id == topic_id);
+        if gpio.is_some() && gpio == current.map(|i| self.leds[i].1.gpio()) {
+            return Ok(());
+        }
+
+        let new = match gpio {
+            Some(gpio) => {
+                let mut in_use = reserved.to_vec();
+                in_use.push(self.buzzer.gpio());
+                in_use.extend(self.leds.iter().map(|(_, led)| led.gpio()));
+                Some(board::claim_output::<B>(gpio, &in_use)?)
+            }
+            None => None,
+        };
+
+        if let Some(i) = current {
+            // Dropping the driver resets the pin
+            let (_, mut old) = self.leds.remove(i);
+            old.set_low().ok();
+        }
+        if let Some(led) = new {
+            self.leds.push((topic_id, led));
+        }
+        Ok(())
+    }
+
+    /// Advance the buzzer pattern and refresh every output
+    pub fn poll(&mut self, alerts: &Alerts) {
+        let now = clock::now();
+        let buzzing = self.advance(alerts, now);
+        let first = buzzing.is_some_and(|(owner, _)| alerts.level(owner) == Level::First);
+        self.soften(self.quiet || first);
+
+        let since_epoch = now.duration_since(self.epoch).as_millis();
+        let countdown = since_epoch % COUNTDOWN_PERIOD_MS;
+        let muted = self.silent
+            || self.quiet
+                && self
+                    .turn
+                    .as_ref()
+                    .is_some_and(|t| t.priority < Priority::Critical);
+        let tone = match buzzing {
+            Some(_) if muted => None,
+            Some((_, tone)) => tone,
+            None if self.guide.as_ref().is_some_and(|g| g.tone.is_some()) => {
+                self.guide.as_ref().and_then(|g| g.tone)
+            }
+            None if self.sleep_warning && countdown < COUNTDOWN_CHIRP_MS => {
+                Some(COUNTDOWN_CHIRP_HZ)
+            }
+            None if self.offline_chirp
+                && !self.quiet
+                && !self.silent
+                && offline_chirp(since_epoch) =>
+            {
+                Some(OFFLINE_CHIRP_HZ)
+            }
+            None => None,
+        };
+        self.buzzer.set_tone(tone);
+
+        // Each LED follows its own topic's alarm, whatever the others do
+        let reminder = since_epoch % REMINDER_PERIOD_MS < REMINDER_ON_MS;
+        let pin = self
+            .pin
+            .map(|(pin, since)| pin_blink(&pin, now.duration_since(since).as_millis()));
+        for (topic_id, led) in self.leds.iter_mut() {
+            let guided = self.guide.as_ref().and_then(|g| {
+                g.leds
+                    .iter()
+                    .find(|(id, _)| id == topic_id)
+                    .map(|&(_, on)| on)
+            });
+            let on = match alerts.state(*topic_id) {
+                AlarmState::Clear => match (guided, pin) {
+                    (Some(on), _) | (None, Some(on)) => on,
+                    (None, None) if self.sleep_warning => countdown < COUNTDOWN_PERIOD_MS / 2,
+                    (None, None) if self.radio_down => {
+                        since_epoch % RADIO_DOWN_PERIOD_MS < RADIO_DOWN_PERIOD_MS / 2
+                    }
+                    (None, None) if self.offline.contains(topic_id) => {
+                        since_epoch % OFFLINE_PERIOD_MS < OFFLINE_ON_MS
+                    }
+                    (None, None) if self.weak_links.contains(topic_id) => {
+                        weak_link_blink(since_epoch)
+                    }
+                    (None, None) => readiness_blink(self.readiness, since_epoch),
+                },
+                AlarmState::Sounding => match buzzing {
+                    Some((owner, tone)) if owner == *topic_id => tone.is_some(),
+                    _ => true,
+                },
+                AlarmState::Resting => true,
+                AlarmState::Acknowledged => reminder,
+            };
+            led.set_level(on).ok();
+        }
+    }
+
+    /// Step the current turn, handing the buzzer over at the end of each
+    /// cycle. Returns the owner and the tone the buzzer plays right now.
+    fn advance(&mut self, alerts: &Alerts, now: Instant) -> Option<(i32, Option<u32>)> {
+        let Some(top) = alerts.sounding().map(|(_, p)| p).max() else {
+            self.turn = None;
+            return None;
+        };
+
+        // A turn ends early when its alarm goes away or is outranked
+        let keep = self.turn.as_ref().is_some_and(|t| {
+            t.priority == top && alerts.sounding().any(|(id, _)| id == t.topic_id)
+        });
+        if !keep {
+            let after = self.turn.as_ref().map(|t| t.topic_id);
+            self.turn = Some(Turn {
+                topic_id: next_in_class(alerts, top, after),
+                priority: top,
+                step: 0,
+                step_started: now,
+            });
+        }
+
+        let turn = self.turn.as_mut().unwrap();
+        loop {
+            let steps: &[Step] = match alerts.level(turn.topic_id) {
+                Level::First => &FIRST_PATTERN,
+                Level::Full => self
+                    .sounds
+                    .sound(turn.topic_id, turn.priority)
+                    .unwrap_or(builtin_pattern(turn.priority)),
+                Level::Continuous => &CONTINUOUS_PATTERN,
+            };
+            // A sound swapped mid-cycle restarts from its first step
+            if turn.step >= steps.len() {
+                turn.step = 0;
+            }
+            let step = steps[turn.step];
+            let length = Duration::from_millis(step.ms);
+            if now.duration_since(turn.step_started) < length {
+                return Some((turn.topic_id, step.tone));
+            }
+            turn.step_started += length;
+            turn.step += 1;
+            if turn.step == steps.len() {
+                turn.step = 0;
+                turn.topic_id = next_in_class(alerts, top, Some(turn.topic_id));
+            }
+        }
+    }
+}
+
+/// Whether the PIN blink code has the LEDs on `ms` into it
+/// Whether the idle LEDs are lit `ms` into the readiness pattern
+fn readiness_blink(readiness: Readiness, ms: u128) -> bool {
+    match readiness {
+        Readiness::Armed => ms % ARMED_PERIOD_MS < ARMED_ON_MS,
+        Readiness::Degraded => {
+            let phase = ms % DEGRADED_PERIOD_MS;
+            phase < DEGRADED_ON_MS || (2 * DEGRADED_ON_MS..3 * DEGRADED_ON_MS).contains(&phase)
+        }
+        Readiness::Booting | Readiness::RadioReady => false,
+    }
+}
+
+/// Whether a weak link's triple flash has the LED on `ms` into it
+fn weak_link_blink(ms: u128) -> bool {
+    let phase = ms % WEAK_LINK_PERIOD_MS;
+    (0..3).any(|i| (2 * i * WEAK_LINK_ON_MS..(2 * i + 1) * WEAK_LINK_ON_MS).contains(&phase))
+}
+
+/// Whether the silent-sensor double chirp sounds `ms` into it
+fn offline_chirp(ms: u128) -> bool {
+    let phase = ms % OFFLINE_CHIRP_PERIOD_MS;
+    phase < OFFLINE_CHIRP_MS || (2 * OFFLINE_CHIRP_MS..3 * OFFLINE_CHIRP_MS).contains(&phase)
+}
+
+fn pin_blink(pin: &[u8], ms: u128) -> bool {
+    let blink = PIN_ON_MS + PIN_OFF_MS;
+    let digit_length = |digit: u8| digit as u128 * blink + PIN_DIGIT_GAP_MS;
+    let total: u128 = pin.iter().map(|&d| digit_length(d)).sum::<u128>() + PIN_REPEAT_GAP_MS;
+
+    let mut t = ms % total;
+    for &digit in pin {
+        if t < digit_length(digit) {
+            return t < digit as u128 * blink && t % blink < PIN_ON_MS;
+        }
+        t -= digit_length(digit);
+    }
+    false
+}
+
+/// Round-robin: the sounding alarm of `class` with the next topic id after
+/// `after`, wrapping around
+fn next_in_class(alerts: &Alerts, class: Priority, after: Option<i32>) -> i32 {
+    let mut ids: Vec<i32> = alerts
+        .sounding()
+        .filter(|&(_, p)| p == class)
+        .map(|(id, _)| id)
+        .collect();
+    ids.sort_unstable();
+
+    after
+        .and_then(|a| ids.iter().copied().find(|&id| id > a))
+        .unwrap_or(ids[0])
+}
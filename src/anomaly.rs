@@ -0,0 +1,92 @@
+//! Unusual readings on topics without a threshold alarm
+//!
+//! Built with `ANOMALY_Z_SCORE` set, every topic that has no [`Rule`] keeps a
+//! trailing window of its last readings, and a reading further than that
+//! many standard deviations from the window's mean is flagged as unusual.
+//! That is a soft notification, logged and sent upstream, which never sounds
+//! the buzzer: with no limit to go by the hub cannot tell a fault from a
+//! change nobody told it about. The topic is usual again once a reading is
+//! back within half the score, and an unusual reading joins the window like
+//! any other, so a lasting change of level stops being flagged on its own.
+//!
+//! [`Rule`]: crate::thresholds::Rule
+
+use std::collections::VecDeque;
+
+/// Readings in the trailing window
+const WINDOW: usize = 60;
+// Readings needed before anything is flagged
+const MIN_READINGS: usize = 20;
+// Floor for the standard deviation, so a topic that always reads the same
+// is not flagged for the smallest step
+const MIN_DEVIATION: f32 = 1.0;
+const MAX_TOPICS: usize = 16;
+
+struct Topic {
+    topic_id: i32,
+    readings: VecDeque<i32>,
+    unusual: bool,
+}
+
+pub struct Detector {
+    z_score: f32,
+    topics: Vec<Topic>,
+}
+
+impl Detector {
+    /// Flag readings at least `z_score` standard deviations off
+    pub fn new(z_score: f32) -> Self {
+        Self {
+            z_score,
+            topics: Vec::new(),
+        }
+    }
+
+    /// Check a reading against the window of `topic_id`, returns the new
+    /// unusual state when it changed
+    pub fn observe(&mut self, topic_id: i32, measurement: i32) -> Option<bool> {
+        let i = match self.topics.iter().position(|t| t.topic_id == topic_id) {
+            Some(i) => i,
+            None if self.topics.len() < MAX_TOPICS => {
+                self.topics.push(Topic {
+                    topic_id,
+                    readings: VecDeque::with_capacity(WINDOW),
+                    unusual: false,
+                });
+                self.topics.len() - 1
+            }
+            None => return None,
+        };
+        let topic = &mut self.topics[i];
+
+        let unusual = if topic.readings.len() >= MIN_READINGS {
+            let n = topic.readings.len() as f32;
+            let mean = topic.readings.iter().map(|&r| r as f32).sum::<f32>() / n;
+            let variance = topic
+                .readings
+                .iter()
+                .map(|&r| (r as f32 - mean).powi(2))
+                .sum::<f32>()
+                / n;
+            let z = (measurement as f32 - mean).abs() / variance.sqrt().max(MIN_DEVIATION);
+            if topic.unusual {
+                z >= self.z_score / 2.0
+            } else {
+                z >= self.z_score
+            }
+        } else {
+            false
+        };
+
+        if topic.readings.len() == WINDOW {
+            topic.readings.pop_front();
+        }
+        topic.readings.push_back(measurement);
+
+        if unusual == topic.unusual {
+            return None;
+        }
+        topic.unusual = unusual;
+        Some(unusual)
+    }
+}
@@ -0,0 +1,96 @@
+//! Application-level authentication of data frames
+//!
+//! Independent of ESP-NOW's own encryption, which needs a peer slot per
+//! sender, a data frame can carry an HMAC under a secret shared by the hub
+//! and its senders. An authenticated frame is `b"AUT"`, the data frame as it
+//! would otherwise be sent, and the first [`TAG_LEN`] bytes of
+//! HMAC-SHA256 over the sender's MAC followed by the data frame. The MAC
+//! ties the tag to its sender, so one sender's frames cannot be replayed
+//! as another's.
+//!
+//! Once the secret is set (`key hmac <32 hex digits>`) the hub only takes
+//! authenticated data frames, and drops the others together with frames
+//! whose tag does not match. Without a secret the envelope is unwrapped and
+//! the frame taken unchecked. Announcements, pairing frames and injected
+//! frames are never checked. A captured frame can still be replayed as is;
+//! sequenced messages are dropped as duplicates within the dedup window.
+
+use crate::keys::{self, KEY_LEN};
+use crate::platform::sys::{
+    mbedtls_md_hmac, mbedtls_md_info_from_type, mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+};
+use crate::protocol;
+
+/// Bytes of the HMAC kept in a frame
+pub const TAG_LEN: usize = 8;
+
+const MAGIC: &[u8; 3] = b"AUT";
+
+/// Why a data frame was not taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A secret is set and the frame carries no tag
+    Missing,
+    /// The tag does not match, a spoofed or corrupted frame
+    Mismatch,
+}
+
+/// The data frame inside `frame` from `src`, checked against the secret
+pub fn open<'a>(src: &[u8; 6], frame: &'a [u8]) -> Result<&'a [u8], Error> {
+    let secret = keys::hmac_secret();
+    let Some(sealed) = frame.strip_prefix(MAGIC) else {
+        return match secret {
+            Some(_) if protocol::is_data(frame) => Err(Error::Missing),
+            // Not for the data path, which ignores it
+            _ => Ok(frame),
+        };
+    };
+    let split = sealed.len().checked_sub(TAG_LEN).ok_or(Error::Mismatch)?;
+    let (inner, tag) = sealed.split_at(split);
+    let Some(secret) = secret else {
+        return Ok(inner);
+    };
+    let expected = tag_of(&secret, src, inner).ok_or(Error::Mismatch)?;
+    // In constant time, so the tag cannot be guessed byte by byte
+    let diff = expected
+        .iter()
+        .zip(tag)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return Err(Error::Mismatch);
+    }
+    Ok(inner)
+}
+
+/// Wrap data frame `frame` from `src` in an authenticated envelope, for
+/// senders
+pub fn seal(secret: &[u8; KEY_LEN], src: &[u8; 6], frame: &[u8]) -> Option<Vec<u8>> {
+    let mut sealed = MAGIC.to_vec();
+    sealed.extend_from_slice(frame);
+    sealed.extend_from_slice(&tag_of(secret, src, frame)?);
+    Some(sealed)
+}
+
+/// HMAC-SHA256 of `data` under `key`, `None` if mbedTLS fails
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Option<[u8; 32]> {
+    let mut hmac = [0u8; 32];
+    // Safe: the buffers outlive the call and the output holds a SHA-256
+    let ret = unsafe {
+        mbedtls_md_hmac(
+            mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256),
+            key.as_ptr(),
+            key.len(),
+            data.as_ptr(),
+            data.len(),
+            hmac.as_mut_ptr(),
+        )
+    };
+    (ret == 0).then_some(hmac)
+}
+
+fn tag_of(secret: &[u8; KEY_LEN], src: &[u8; 6], frame: &[u8]) -> Option<[u8; TAG_LEN]> {
+    let mut data = src.to_vec();
+    data.extend_from_slice(frame);
+    let hmac = hmac_sha256(secret, &data)?;
+    Some(hmac[..TAG_LEN].try_into().unwrap())
+}
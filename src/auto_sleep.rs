@@ -0,0 +1,72 @@
+//! Deep sleep after a stretch without activity
+//!
+//! Frames, alarms and button presses all count as activity. Once the hub has
+//! been idle for the configured time it does not sleep straight away: a
+//! warning countdown runs first, and any activity during it, a button press
+//! in particular, cancels the sleep.
+
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::clock;
+use crate::strings::{self, Text};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepState {
+    Awake,
+    /// Counting down to sleep
+    Warning,
+    /// The countdown ran out
+    Sleep,
+}
+
+pub struct AutoSleep {
+    idle_after: Duration,
+    warning: Duration,
+    last_activity: Instant,
+    warned: bool,
+}
+
+impl AutoSleep {
+    pub fn new(idle_after: Duration, warning: Duration) -> Self {
+        Self {
+            idle_after,
+            warning,
+            last_activity: clock::now(),
+            warned: false,
+        }
+    }
+
+    /// Idle time before the countdown, e.g. longer while nobody is home
+    pub fn set_idle_after(&mut self, idle_after: Duration) {
+        self.idle_after = idle_after;
+    }
+
+    /// Restart the idle timer, cancelling a running countdown
+    pub fn activity(&mut self) {
+        if self.warned {
+            info!("{}", strings::text(Text::SleepCancelled, &[]));
+            self.warned = false;
+        }
+        self.last_activity = clock::now();
+    }
+
+    pub fn poll(&mut self) -> SleepState {
+        let idle = clock::since(self.last_activity);
+        if idle >= self.idle_after + self.warning {
+            SleepState::Sleep
+        } else if idle >= self.idle_after {
+            if !self.warned {
+                info!(
+                    "{}",
+                    strings::text(Text::SleepWarning, &[&self.warning.as_secs()])
+                );
+                self.warned = true;
+            }
+            SleepState::Warning
+        } else {
+            SleepState::Awake
+        }
+    }
+}
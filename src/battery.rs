@@ -0,0 +1,70 @@
+//! Battery levels reported by the senders
+//!
+//! Senders may append their battery charge in percent to each frame. The
+//! latest level is kept per sender and topic, shown with the measurements
+//! upstream, and crossing the low threshold raises a battery notification of
+//! its own, separate from the measurement alarms. Recovering takes a margin
+//! above the threshold so a level hovering around it does not flap.
+
+use std::sync::Mutex;
+
+/// Low at or below this charge
+const LOW_PERCENT: u8 = 20;
+/// Back to normal at or above this charge, e.g. after a battery swap
+const OK_PERCENT: u8 = 30;
+
+static LEVELS: Mutex<Vec<Level>> = Mutex::new(Vec::new());
+
+struct Level {
+    /// Sender MAC, all zero for injected frames
+    mac: [u8; 6],
+    topic_id: i32,
+    percent: u8,
+    low: bool,
+}
+
+/// Note a reported level, returns the new low state when it changed
+pub fn record(mac: [u8; 6], topic_id: i32, percent: u8) -> Option<bool> {
+    let mut levels = LEVELS.lock().unwrap();
+    let level = match levels
+        .iter_mut()
+        .position(|l| l.mac == mac && l.topic_id == topic_id)
+    {
+        Some(i) => &mut levels[i],
+        None => {
+            levels.push(Level {
+                mac,
+                topic_id,
+                percent,
+                low: false,
+            });
+            levels.last_mut().unwrap()
+        }
+    };
+    level.percent = percent;
+
+    let low = if level.low {
+        percent < OK_PERCENT
+    } else {
+        percent <= LOW_PERCENT
+    };
+    if low == level.low {
+        return None;
+    }
+    level.low = low;
+    Some(low)
+}
+
+/// Level last reported on `topic_id`, by the first sender seen on it
+pub fn level(topic_id: i32) -> Option<u8> {
+    let levels = LEVELS.lock().unwrap();
+    levels
+        .iter()
+        .find(|l| l.topic_id == topic_id)
+        .map(|l| l.percent)
+}
+
+/// How many senders are low on battery
+pub fn low_count() -> u32 {
+    LEVELS.lock().unwrap().iter().filter(|l| l.low).count() as u32
+}
@@ -0,0 +1,96 @@
+//! Receive throughput benchmark
+//!
+//! The `bench` console command counts frames from a flooding test sender for
+//! a fixed time. Every second it logs how many frames the main loop processed
+//! and how many were dropped because the receive queue was full. The summary
+//! reports the average rate and the best second without losses, which is the
+//! rate the pipeline can sustain.
+
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::clock;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Receive callback counters, both running totals
+#[derive(Debug, Clone, Copy)]
+pub struct Counters {
+    pub received: u32,
+    pub dropped: u32,
+}
+
+pub struct Bench {
+    duration: Duration,
+    started: Instant,
+    start: Counters,
+    processed: u32,
+    window_started: Instant,
+    window_start: Counters,
+    window_processed: u32,
+    best_clean: u32,
+}
+
+impl Bench {
+    pub fn start(duration: Duration, counters: Counters) -> Self {
+        info!(
+            "Bench started for {} s, start the test sender",
+            duration.as_secs()
+        );
+        let now = clock::now();
+        Self {
+            duration,
+            started: now,
+            start: counters,
+            processed: 0,
+            window_started: now,
+            window_start: counters,
+            window_processed: 0,
+            best_clean: 0,
+        }
+    }
+
+    /// Count one frame taken by the main loop
+    pub fn record(&mut self) {
+        self.processed += 1;
+        self.window_processed += 1;
+    }
+
+    /// Log per-second and final figures, returns true once the bench is over
+    pub fn poll(&mut self, counters: Counters) -> bool {
+        if clock::since(self.window_started) >= WINDOW {
+            let lost = counters.dropped.wrapping_sub(self.window_start.dropped);
+            let rate = (self.window_processed as u128 * 1000
+                / clock::since(self.window_started).as_millis()) as u32;
+            info!("Bench: {} frames/s processed, {} lost", rate, lost);
+            if lost == 0 {
+                self.best_clean = self.best_clean.max(rate);
+            }
+            self.window_started = clock::now();
+            self.window_start = counters;
+            self.window_processed = 0;
+        }
+
+        let elapsed = clock::since(self.started);
+        if elapsed < self.duration {
+            return false;
+        }
+
+        let received = counters.received.wrapping_sub(self.start.received);
+        let lost = counters.dropped.wrapping_sub(self.start.dropped);
+        info!(
+            "Bench done: {} frames received, {} processed, {} lost in {} ms",
+            received,
+            self.processed,
+            lost,
+            elapsed.as_millis()
+        );
+        info!(
+            "Bench: {} frames/s average, {} frames/s best second without losses",
+            self.processed as u128 * 1000 / elapsed.as_millis(),
+            self.best_clean
+        );
+        true
+    }
+}
@@ -0,0 +1,423 @@
+//! Host-side management of a hub, over its serial console or HTTP
+//!
+//! Runs on the development machine, not the ESP32, and needs nothing beyond
+//! std, so it builds with the stable toolchain for the host:
+//!
+//! `cargo +stable run --features cli --bin hubctl --target <host triple> --
+//! <transport> <command>`
+//!
+//! Transports:
+//!
+//! - `--serial <port>` the USB serial console, e.g. `/dev/ttyUSB0`, which is
+//!   set to 115200 baud raw with `stty` first
+//! - `--http <host>[:<port>]` the hub's HTTP server, once it is on WiFi;
+//!   anything but reading needs the API token set with `key api` on the
+//!   hub's serial console, passed in `HUBCTL_TOKEN`
+//!
+//! Commands:
+//!
+//! - `status` prints the status event as JSON
+//! - `config pull` prints the signed config blob, `config push <file>`
+//!   imports one and confirms it once the hub answers again, so an import
+//!   that cuts the hub off reverts on its own; outside the hub's maintenance
+//!   window the import waits for it, confirmed, unless `--urgent`
+//! - `logs` tails the log, over serial only
+//! - `test selftest|bench [seconds]|commission` starts a test; over serial
+//!   its log lines are printed until it ends, and a failed self-test exits
+//!   with 1
+//! - `run <console line>` sends any console command, see src/console.rs;
+//!   key commands only over serial
+//!
+//! Over serial the tool types the console commands a person would, and picks
+//! the answers out of the log; over HTTP it uses the endpoints, sending
+//! console lines to `POST /console`, whose output stays in the hub's log.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::process::{self, Command};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: hubctl (--serial <port> | --http <host>[:<port>]) <command>
+
+commands:
+  status                            print the status event as JSON
+  config pull                       print the signed config blob
+  config push [--urgent] <file>     import a blob and confirm it
+  logs                              tail the log (serial only)
+  test selftest|bench [s]|commission  run a test
+  run <console line>                send any console command
+
+over HTTP, changes need the hub's API token in HUBCTL_TOKEN";
+
+const BAUD: &str = "115200";
+const HTTP_PORT: u16 = 80;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const TOKEN_VAR: &str = "HUBCTL_TOKEN";
+// How long the hub has to answer a command over serial
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a pushed config waits for the hub to answer again; the hub's own
+// revert timeout is longer
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+const STATUS_PREFIX: &str = r#"{"type":"status""#;
+const BLOB_PREFIX: &str = r#"{"version":"#;
+
+enum Transport {
+    Serial(Serial),
+    Http(String),
+}
+
+/// A test, how its log ends and how long it may take
+struct Test {
+    line: String,
+    ends: &'static [&'static str],
+    timeout: Duration,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["--serial", port, command @ ..] => {
+            Serial::open(port).and_then(|serial| run(Transport::Serial(serial), command))
+        }
+        ["--http", host, command @ ..] => run(Transport::Http(host.to_string()), command),
+        _ => Err(usage()),
+    };
+    if let Err(e) = result {
+        eprintln!("hubctl: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(mut transport: Transport, command: &[&str]) -> io::Result<()> {
+    match command {
+        ["status"] => println!("{}", status(&mut transport)?),
+        ["config", "pull"] => println!("{}", pull(&mut transport)?),
+        ["config", "push", file] => push(&mut transport, &fs::read_to_string(file)?, false)?,
+        ["config", "push", "--urgent", file] => {
+            push(&mut transport, &fs::read_to_string(file)?, true)?
+        }
+        ["logs"] => match &transport {
+            Transport::Serial(serial) => loop {
+                println!("{}", serial.next(None)?);
+            },
+            Transport::Http(_) => {
+                return Err(io::Error::other("logs are only on the serial console"))
+            }
+        },
+        ["test", test @ ..] => {
+            let test = parse_test(test)?;
+            match &mut transport {
+                Transport::Serial(serial) => return watch(serial, &test),
+                Transport::Http(host) => {
+                    console(host, &test.line)?;
+                    println!("{} started, the results are in the hub's log", test.line);
+                }
+            }
+        }
+        ["run", line @ ..] if !line.is_empty() => {
+            let line = line.join(" ");
+            match &mut transport {
+                Transport::Serial(serial) => serial.send(&line)?,
+                Transport::Http(host) => console(host, &line)?,
+            }
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn parse_test(words: &[&str]) -> io::Result<Test> {
+    let (line, ends, timeout) = match words {
+        ["selftest"] => (
+            "selftest".to_string(),
+            &["Self-test passed", "Self-test FAILED"][..],
+            30,
+        ),
+        ["bench"] => ("bench".to_string(), &["frames/s average"][..], 30),
+        ["bench", seconds] => {
+            let seconds: u64 = seconds
+                .parse()
+                .map_err(|_| io::Error::other("bench takes seconds"))?;
+            (
+                format!("bench {}", seconds),
+                &["frames/s average"][..],
+                seconds + 20,
+            )
+        }
+        // Waits on the installer at every step
+        ["commission"] => ("commission".to_string(), &["LEDs and buzzer:"][..], 1800),
+        _ => return Err(usage()),
+    };
+    Ok(Test {
+        line,
+        ends,
+        timeout: Duration::from_secs(timeout),
+    })
+}
+
+fn status(transport: &mut Transport) -> io::Result<String> {
+    match transport {
+        Transport::Serial(serial) => {
+            serial.send("status")?;
+            serial.expect(|line| line.starts_with(STATUS_PREFIX), REPLY_TIMEOUT)
+        }
+        Transport::Http(host) => http(host, "GET", "/status", None),
+    }
+}
+
+fn pull(transport: &mut Transport) -> io::Result<String> {
+    match transport {
+        Transport::Serial(serial) => {
+            serial.send("config export")?;
+            serial.expect(|line| line.starts_with(BLOB_PREFIX), REPLY_TIMEOUT)
+        }
+        Transport::Http(host) => http(host, "GET", "/config", None),
+    }
+}
+
+/// Import `blob`, then confirm it as soon as the hub answers again
+///
+/// A deferred import is confirmed the same way and applies as confirmed.
+fn push(transport: &mut Transport, blob: &str, urgent: bool) -> io::Result<()> {
+    let blob = blob.trim();
+    if !blob.starts_with(BLOB_PREFIX) {
+        return Err(io::Error::other("not a config blob from `config pull`"));
+    }
+    let deferred = match transport {
+        Transport::Serial(serial) => {
+            let urgent = if urgent { "urgent " } else { "" };
+            serial.send(&format!("config import {}{}", urgent, blob))?;
+            let line = serial.expect(
+                |line| {
+                    line.contains("Config: imported")
+                        || line.contains("Config: import deferred")
+                        || line.contains("Config import failed")
+                },
+                REPLY_TIMEOUT,
+            )?;
+            if line.contains("failed") {
+                return Err(io::Error::other(line));
+            }
+            line.contains("deferred")
+        }
+        Transport::Http(host) => {
+            let path = if urgent { "/config?urgent" } else { "/config" };
+            http(host, "PUT", path, Some(blob))?.trim() == "deferred"
+        }
+    };
+    if deferred {
+        println!("Deferred to the maintenance window, waiting for the hub to answer");
+    } else {
+        println!("Imported, waiting for the hub to answer");
+    }
+    let deadline = Instant::now() + CONFIRM_TIMEOUT;
+    while let Err(e) = status(transport) {
+        if Instant::now() >= deadline {
+            return Err(io::Error::other(format!(
+                "no answer after the import ({}), it will revert",
+                e
+            )));
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+    match transport {
+        Transport::Serial(serial) => {
+            serial.send("config confirm")?;
+            let line = serial.expect(
+                |line| {
+                    line.contains("Config: import confirmed")
+                        || line.contains("Config: deferred import confirmed")
+                        || line.contains("Config confirm failed")
+                },
+                REPLY_TIMEOUT,
+            )?;
+            if line.contains("failed") {
+                return Err(io::Error::other(line));
+            }
+        }
+        Transport::Http(host) => {
+            http(host, "POST", "/config/confirm", None)?;
+        }
+    }
+    println!("Confirmed");
+    Ok(())
+}
+
+/// Start `test` and print its log until it ends
+fn watch(serial: &mut Serial, test: &Test) -> io::Result<()> {
+    serial.send(&test.line)?;
+    let deadline = Instant::now() + test.timeout;
+    loop {
+        let line = serial.next(Some(deadline))?;
+        println!("{}", line);
+        if test.ends.iter().any(|end| line.contains(end)) {
+            if line.contains("FAILED") {
+                return Err(io::Error::other(line));
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Send a console line over HTTP
+fn console(host: &str, line: &str) -> io::Result<()> {
+    http(host, "POST", "/console", Some(line)).map(|_| ())
+}
+
+/// The USB serial console, read line by line in the background
+struct Serial {
+    port: File,
+    lines: Receiver<String>,
+}
+
+impl Serial {
+    fn open(path: &str) -> io::Result<Self> {
+        configure(path)?;
+        let port = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut reader = BufReader::new(port.try_clone()?);
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                }
+                let text = String::from_utf8_lossy(&line);
+                if tx.send(text.trim_end().to_string()).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Self { port, lines })
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.port.write_all(line.as_bytes())?;
+        self.port.write_all(b"\n")?;
+        self.port.flush()
+    }
+
+    /// The next line, waiting until `deadline` or for ever
+    fn next(&self, deadline: Option<Instant>) -> io::Result<String> {
+        let line = match deadline {
+            Some(deadline) => self
+                .lines
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|_| io::Error::new(ErrorKind::TimedOut, "no answer from the hub")),
+            None => self
+                .lines
+                .recv()
+                .map_err(|_| io::Error::other("serial port closed")),
+        }?;
+        Ok(line)
+    }
+
+    /// The first line within `timeout` that `wanted` accepts
+    fn expect(&self, wanted: impl Fn(&str) -> bool, timeout: Duration) -> io::Result<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = self.next(Some(deadline))?;
+            if wanted(&line) {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+/// Set the port to the console's baud rate, raw
+fn configure(path: &str) -> io::Result<()> {
+    if !cfg!(unix) {
+        // No stty, the port must be set up beforehand
+        return Ok(());
+    }
+    let device = if cfg!(target_os = "macos") {
+        "-f"
+    } else {
+        "-F"
+    };
+    let status = Command::new("stty")
+        .args([device, path, BAUD, "raw", "-echo"])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("stty could not set up {}", path)));
+    }
+    Ok(())
+}
+
+/// One HTTP/1.1 request, the body of a 2xx response
+fn http(host: &str, method: &str, path: &str, body: Option<&str>) -> io::Result<String> {
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, HTTP_PORT)
+    };
+    let mut stream = TcpStream::connect(&address)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let body = body.unwrap_or_default();
+    let authorization = env::var(TOKEN_VAR)
+        .map(|token| format!("Authorization: Bearer {}\r\n", token.trim()))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        host,
+        authorization,
+        body.len(),
+        body
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, content) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::other("malformed HTTP response"))?;
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::other("malformed HTTP status line"))?;
+    let chunked = head
+        .lines()
+        .any(|line| line.eq_ignore_ascii_case("transfer-encoding: chunked"));
+    let content = if chunked {
+        dechunk(content)?
+    } else {
+        content.to_string()
+    };
+    let content = content.trim_end().to_string();
+    match status {
+        200..=299 => Ok(content),
+        _ => Err(io::Error::other(format!("HTTP {}: {}", status, content))),
+    }
+}
+
+/// The body of a chunked response
+fn dechunk(mut content: &str) -> io::Result<String> {
+    let malformed = || io::Error::other("malformed chunked response");
+    let mut body = String::new();
+    loop {
+        let (size, rest) = content.split_once("\r\n").ok_or_else(malformed)?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16)
+            .map_err(|_| malformed())?;
+        if size == 0 {
+            return Ok(body);
+        }
+        let chunk = rest.get(..size).ok_or_else(malformed)?;
+        body.push_str(chunk);
+        content = rest[size..].strip_prefix("\r\n").ok_or_else(malformed)?;
+    }
+}
+
+fn usage() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, USAGE)
+}
@@ -0,0 +1,296 @@
+//! Synthetic sender for exercising the receiver on a second devkit
+//!
+//! Flash this onto any ESP32 next to the hub and it plays the kettle, sink
+//! and kettle sound sensors: the kettle heats up through every alarm band and
+//! cools down again, the sink swings around its limit and the battery runs
+//! down and is replaced. A room climate sensor sends its temperature and
+//! humidity together as a record, in floats. It announces itself at boot and sends its readings
+//! as sequenced messages, every few frames a retransmission to check the
+//! duplicate suppression, and now and then a fixed-layout frame or an
+//! unversioned message as older senders do. When the kettle reaches the boil
+//! it also sends a critical frame, for the hub's fast path.
+//!
+//! Set `SIM_RECEIVER_MAC` at build time to send to the hub as a peer, it
+//! broadcasts otherwise. `SIM_INTERVAL_MS` sets the period, 2 s by default.
+//! With `SIM_HMAC_SECRET`, the hub's `key hmac` in 32 hex digits, data frames
+//! are authenticated. Acks from the hub are logged. `SIM_LONG_RANGE=1`
+//! sends in 802.11 LR, for a hub with `longrange on`.
+//! The hub must be on the same channel, which it is when not on WiFi.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::espnow::{EspNow, PeerInfo, ReceiveInfo, BROADCAST};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::peripherals::Peripherals;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{esp, esp_wifi_get_mac, wifi_interface_t_WIFI_IF_STA};
+use esp_idf_svc::wifi::{BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use esp_now_receiver::ack::Ack;
+use esp_now_receiver::auth;
+use esp_now_receiver::keys::{self, KEY_LEN};
+use esp_now_receiver::long_range;
+use esp_now_receiver::protocol::{self, Field, Message, Quantity, Reading, Value};
+use esp_now_receiver::senders::{Announcement, PROTOCOL_REVISION};
+use esp_now_receiver::uplink::parse_mac;
+use log::{info, warn};
+
+const TOPIC_ID_KETTLE_THERMO: i32 = 1;
+const TOPIC_ID_SINK_THERMO: i32 = 2;
+const TOPIC_ID_KETTLE_SOUND: i32 = 3;
+const TOPIC_ID_ROOM_CLIMATE: i32 = 6;
+
+const SIM_RECEIVER_MAC: Option<&str> = option_env!("SIM_RECEIVER_MAC");
+const SIM_INTERVAL_MS: Option<&str> = option_env!("SIM_INTERVAL_MS");
+const SIM_HMAC_SECRET: Option<&str> = option_env!("SIM_HMAC_SECRET");
+const SIM_LONG_RANGE: Option<&str> = option_env!("SIM_LONG_RANGE");
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+
+// Kettle: boils from room temperature to 100 and cools back over a cycle
+const KETTLE_CYCLE_S: u64 = 6 * 60;
+const KETTLE_ROOM: i32 = 20;
+const KETTLE_BOIL: i32 = 100;
+// The sound sensor goes off near the boil
+const KETTLE_WHISTLE_ABOVE: i32 = 90;
+// A critical frame goes out once it is within a frame's rise of the boil
+const KETTLE_BOILING: i32 = KETTLE_BOIL - 1;
+// Sink: swings this far around its mean, across the alarm below 32
+const SINK_MEAN: i32 = 34;
+const SINK_SWING: i32 = 6;
+const SINK_CYCLE_S: u64 = 4 * 60;
+// Room: temperature in degrees and relative humidity in percent, drifting
+// over a cycle
+const ROOM_TEMPERATURE: f32 = 21.0;
+const ROOM_HUMIDITY: f32 = 45.0;
+const ROOM_CYCLE_S: u64 = 10 * 60;
+// Battery: one percent per minute down to this, then a fresh one
+const BATTERY_EMPTY: u64 = 5;
+// Every nth frame goes out twice, every mth in the fixed layout and every
+// kth sequenced one unversioned
+const RETRANSMIT_EVERY: u32 = 10;
+const FIXED_EVERY: u32 = 7;
+const UNVERSIONED_EVERY: u32 = 5;
+
+fn main() {
+    esp_idf_svc::sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+    info!("ESP-NOW sender simulator starting...");
+
+    let peripherals = Peripherals::take().unwrap();
+    let sys_loop = EspSystemEventLoop::take().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs)).unwrap(),
+        sys_loop,
+    )
+    .unwrap();
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .unwrap();
+    wifi.start().unwrap();
+    if SIM_LONG_RANGE == Some("1") {
+        long_range::set_protocol(true).unwrap();
+        info!("Sending in 802.11 LR mode");
+    }
+
+    let espnow = EspNow::take().unwrap();
+    let receiver = SIM_RECEIVER_MAC.map_or(BROADCAST, |mac| {
+        parse_mac(mac).unwrap_or_else(|| panic!("Invalid SIM_RECEIVER_MAC: {}", mac))
+    });
+    espnow
+        .add_peer(PeerInfo {
+            peer_addr: receiver,
+            ..Default::default()
+        })
+        .unwrap();
+    espnow
+        .register_recv_cb(|_: &ReceiveInfo, data: &[u8]| {
+            if let Some(ack) = Ack::parse(data) {
+                info!("Acked: seq {}, topic {}", ack.seq, ack.topic_id);
+            }
+        })
+        .unwrap();
+    let interval = SIM_INTERVAL_MS
+        .map(|ms| Duration::from_millis(ms.parse().expect("Invalid SIM_INTERVAL_MS")))
+        .unwrap_or(DEFAULT_INTERVAL);
+    info!(
+        "Sending to {:02X?} every {} ms",
+        receiver,
+        interval.as_millis()
+    );
+
+    let secret =
+        SIM_HMAC_SECRET.map(|secret| keys::parse_key(secret).expect("Invalid SIM_HMAC_SECRET"));
+    let mut mac = [0u8; 6];
+    esp!(unsafe { esp_wifi_get_mac(wifi_interface_t_WIFI_IF_STA, mac.as_mut_ptr()) }).unwrap();
+    let sender = Sender {
+        espnow: &espnow,
+        receiver,
+        mac,
+        secret,
+    };
+
+    let announcement = Announcement {
+        protocol: PROTOCOL_REVISION,
+        firmware: firmware_version(),
+    };
+    sender.send(&announcement.encode());
+
+    let started = Instant::now();
+    let mut seq: u16 = 0;
+    let mut boiling = false;
+    for frame in 1u32.. {
+        let secs = started.elapsed().as_secs();
+        let kettle = kettle(secs);
+        if kettle >= KETTLE_BOILING && !boiling {
+            seq = seq.wrapping_add(1);
+            let critical = Message::Critical {
+                seq,
+                topic_id: TOPIC_ID_KETTLE_THERMO,
+                measurement: kettle,
+                battery: None,
+            };
+            match protocol::encode(&critical) {
+                Ok(data) => sender.send_data(&data),
+                Err(e) => warn!("Encoding failed: {}", e),
+            }
+            info!("Kettle boiling, critical frame sent");
+        }
+        boiling = kettle >= KETTLE_BOILING;
+        let readings = vec![
+            Reading {
+                topic_id: TOPIC_ID_KETTLE_THERMO,
+                measurement: kettle,
+            },
+            Reading {
+                topic_id: TOPIC_ID_SINK_THERMO,
+                measurement: sink(secs),
+            },
+            Reading {
+                topic_id: TOPIC_ID_KETTLE_SOUND,
+                measurement: i32::from(kettle > KETTLE_WHISTLE_ABOVE),
+            },
+        ];
+        let battery = battery(secs);
+
+        if frame % FIXED_EVERY == 0 {
+            for &reading in &readings {
+                sender.send_data(&protocol::encode_fixed(reading, Some(battery)));
+            }
+        } else {
+            seq = seq.wrapping_add(1);
+            let message = Message::Sequenced {
+                seq,
+                readings,
+                battery: Some(battery),
+            };
+            let encoded = if frame % UNVERSIONED_EVERY == 0 {
+                protocol::encode_unversioned(&message)
+            } else {
+                protocol::encode(&message)
+            };
+            match encoded {
+                Ok(data) => {
+                    sender.send_data(&data);
+                    if frame % RETRANSMIT_EVERY == 0 {
+                        sender.send_data(&data);
+                    }
+                }
+                Err(e) => warn!("Encoding failed: {}", e),
+            }
+            seq = seq.wrapping_add(1);
+            match protocol::encode(&room(seq, secs, battery)) {
+                Ok(data) => sender.send_data(&data),
+                Err(e) => warn!("Encoding failed: {}", e),
+            }
+        }
+        info!(
+            "Frame {}: kettle {}, sink {}, battery {}%",
+            frame,
+            kettle,
+            sink(secs),
+            battery
+        );
+        thread::sleep(interval);
+    }
+}
+
+/// Kettle temperature `secs` into the run: up from room temperature in the
+/// first half of the cycle, down again in the second
+fn kettle(secs: u64) -> i32 {
+    let half = KETTLE_CYCLE_S / 2;
+    let t = secs % KETTLE_CYCLE_S;
+    let rise = if t < half { t } else { KETTLE_CYCLE_S - t };
+    KETTLE_ROOM + ((KETTLE_BOIL - KETTLE_ROOM) as u64 * rise / half) as i32
+}
+
+/// Sink temperature, a triangle around the mean
+fn sink(secs: u64) -> i32 {
+    let quarter = SINK_CYCLE_S / 4;
+    let t = (secs % SINK_CYCLE_S) as i64;
+    let q = quarter as i64;
+    let offset = if t < 2 * q { t - q } else { 3 * q - t };
+    SINK_MEAN + (i64::from(SINK_SWING) * offset / q) as i32
+}
+
+/// The room climate sensor's record, temperature and humidity
+fn room(seq: u16, secs: u64, battery: u8) -> Message {
+    let phase = (secs % ROOM_CYCLE_S) as f32 / ROOM_CYCLE_S as f32;
+    let drift = (phase * std::f32::consts::TAU).sin();
+    Message::Record {
+        seq,
+        topic_id: TOPIC_ID_ROOM_CLIMATE,
+        fields: vec![
+            Field {
+                quantity: Quantity::Temperature,
+                value: Value::Float(ROOM_TEMPERATURE + 1.5 * drift),
+            },
+            Field {
+                quantity: Quantity::Humidity,
+                value: Value::Float(ROOM_HUMIDITY - 10.0 * drift),
+            },
+        ],
+        battery: Some(battery),
+    }
+}
+
+fn battery(secs: u64) -> u8 {
+    let span = 100 - BATTERY_EMPTY;
+    (100 - (secs / 60) % (span + 1)) as u8
+}
+
+struct Sender<'a> {
+    espnow: &'a EspNow<'a>,
+    receiver: [u8; 6],
+    mac: [u8; 6],
+    secret: Option<[u8; KEY_LEN]>,
+}
+
+impl Sender<'_> {
+    fn send(&self, data: &[u8]) {
+        if let Err(e) = self.espnow.send(self.receiver, data) {
+            warn!("Send failed: {}", e);
+        }
+    }
+
+    /// Send a data frame, authenticated if there is a secret
+    fn send_data(&self, frame: &[u8]) {
+        match self.secret {
+            Some(secret) => match auth::seal(&secret, &self.mac, frame) {
+                Some(sealed) => self.send(&sealed),
+                None => warn!("Authenticating a frame failed"),
+            },
+            None => self.send(frame),
+        }
+    }
+}
+
+fn firmware_version() -> [u8; 3] {
+    let mut parts = env!("CARGO_PKG_VERSION")
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0));
+    [
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    ]
+}
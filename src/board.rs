@@ -0,0 +1,371 @@
+//! Board support
+//!
+//! A [`Board`] knows which pins drive what on one hardware profile and hands
+//! the application typed handles for them, so nothing outside this module
+//! deals in raw GPIO numbers. Supporting a new board means adding another
+//! impl and pointing `ActiveBoard` in `main.rs` at it.
+//!
+//! Outputs on strapping pins (GPIO12 selects the flash voltage on the
+//! classic ESP32) are claimed but left in their reset state until
+//! [`Output::arm`] is called at the end of boot.
+//!
+//! The application drives actuators through the [`Output`] trait of
+//! gpio_io only, so an output can be torn down and claimed again on another
+//! pin at runtime with [`claim_output`].
+//!
+//! Building with the `headless` feature selects the [`Headless`] board, whose
+//! actuators are virtual and only log what they would do.
+
+// The real-pin boards go unused in headless builds
+#![cfg_attr(feature = "headless", allow(dead_code))]
+
+mod devkit;
+#[cfg(feature = "headless")]
+mod headless;
+
+use esp_idf_svc::hal::gpio::{self, AnyInputPin, AnyOutputPin, Input, InterruptType, PinDriver};
+use esp_idf_svc::hal::ledc::config::TimerConfig;
+use esp_idf_svc::hal::ledc::{LedcDriver, LedcTimerDriver, Resolution, CHANNEL0, TIMER0};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::sys::{
+    esp, esp_deep_sleep_enable_gpio_wakeup,
+    gpio_deepsleep_wakeup_level_t_ESP_GPIO_WAKEUP_GPIO_HIGH, gpio_get_level,
+    gpio_int_type_t_GPIO_INTR_HIGH_LEVEL, gpio_pulldown_en, gpio_wakeup_enable,
+    ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_set_freq, ledc_timer_t_LEDC_TIMER_0, EspError,
+};
+use log::info;
+
+use crate::gpio_io::Output;
+use crate::i2c_bus::I2cBus;
+use crate::inputs::{self, Event};
+#[cfg(feature = "microphone")]
+use crate::microphone::Microphone;
+use crate::pins::{self, Assignment, PinError};
+use crate::sounds::BEEP_HZ;
+
+#[cfg(not(feature = "headless"))]
+pub use devkit::DevKit;
+#[cfg(feature = "headless")]
+pub use headless::Headless;
+
+type OutputDriver = PinDriver<'static, AnyOutputPin, gpio::Output>;
+
+pub trait Board {
+    const NAME: &'static str;
+
+    /// Every pin the board claims, validated before [`Board::take`]
+    const PIN_MAP: &'static [Assignment];
+
+    /// Claim the board's pins
+    ///
+    /// # Safety
+    ///
+    /// Must be called at most once, after `PIN_MAP` passed validation and
+    /// with none of its pins in use elsewhere.
+    unsafe fn take() -> Result<BoardIo, EspError>;
+
+    /// Claim one more output on `gpio` at runtime
+    ///
+    /// # Safety
+    ///
+    /// `gpio` must be valid for output and not claimed anywhere else.
+    unsafe fn output(gpio: i32) -> Result<Box<dyn Output>, EspError>;
+}
+
+pub struct BoardIo {
+    /// Alarm LEDs in board order, topics are mapped onto them by index
+    pub alarm_leds: Vec<Box<dyn Output>>,
+    pub buzzer: Box<dyn Output>,
+    /// External siren, e.g. through a relay, if the board has one
+    pub siren: Option<Box<dyn Output>>,
+    pub mute_switch: Option<Switch>,
+    pub wake_button: WakeButton,
+    // Handed to a display driver once one is supported
+    #[allow(dead_code)]
+    pub display_bus: Option<I2cBus>,
+    #[cfg(feature = "microphone")]
+    pub microphone: Option<Microphone>,
+}
+
+/// Output handle whose pin is configured late if it is a strapping pin
+pub struct BootSafeOutput {
+    gpio: i32,
+    driver: Option<OutputDriver>,
+}
+
+impl BootSafeOutput {
+    /// # Safety
+    ///
+    /// `gpio` must not be claimed anywhere else.
+    pub(crate) unsafe fn new(gpio: i32) -> Result<Self, EspError> {
+        let driver = if pins::is_strapping(gpio) {
+            None
+        } else {
+            Some(PinDriver::output(AnyOutputPin::new(gpio))?)
+        };
+        Ok(Self { gpio, driver })
+    }
+}
+
+impl Output for BootSafeOutput {
+    fn gpio(&self) -> i32 {
+        self.gpio
+    }
+
+    /// Drive the pin, ignored until the output is armed
+    fn set_level(&mut self, on: bool) -> Result<(), EspError> {
+        match self.driver.as_mut() {
+            Some(driver) => driver.set_level(on.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Configure a deferred pin as an output, driven low
+    ///
+    /// A no-op for pins that were configured straight away.
+    fn arm(&mut self) -> Result<(), EspError> {
+        if self.driver.is_none() {
+            // Safe: the pin was claimed for this handle when it was created
+            let mut driver = PinDriver::output(unsafe { AnyOutputPin::new(self.gpio) })?;
+            driver.set_low()?;
+            self.driver = Some(driver);
+        }
+        Ok(())
+    }
+}
+
+/// Passive buzzer driven by a square wave from LEDC timer 0, channel 0
+pub struct ToneOutput {
+    gpio: i32,
+    driver: LedcDriver<'static>,
+    hz: Option<u32>,
+    quiet: bool,
+}
+
+impl ToneOutput {
+    /// # Safety
+    ///
+    /// `gpio` must not be claimed anywhere else, nor LEDC timer 0 and
+    /// channel 0.
+    pub(crate) unsafe fn new(gpio: i32) -> Result<Self, EspError> {
+        let timer = LedcTimerDriver::new(
+            TIMER0::new(),
+            &TimerConfig::new()
+                .frequency(Hertz(BEEP_HZ))
+                .resolution(Resolution::Bits10),
+        )?;
+        let mut driver = LedcDriver::new(CHANNEL0::new(), timer, AnyOutputPin::new(gpio))?;
+        driver.set_duty(0)?;
+        Ok(Self {
+            gpio,
+            driver,
+            hz: None,
+            quiet: false,
+        })
+    }
+
+    /// Duty cycle of a sounding tone; a narrow pulse drives a piezo softly
+    fn duty(&self) -> u32 {
+        if self.quiet {
+            self.driver.get_max_duty() / 32
+        } else {
+            self.driver.get_max_duty() / 2
+        }
+    }
+}
+
+impl Output for ToneOutput {
+    fn gpio(&self) -> i32 {
+        self.gpio
+    }
+
+    fn set_level(&mut self, on: bool) -> Result<(), EspError> {
+        self.set_tone(on.then_some(BEEP_HZ))
+    }
+
+    fn set_tone(&mut self, hz: Option<u32>) -> Result<(), EspError> {
+        if hz == self.hz {
+            return Ok(());
+        }
+        match hz {
+            Some(hz) => {
+                // The driver owns the timer, retune it through the C API
+                esp!(unsafe {
+                    ledc_set_freq(
+                        ledc_mode_t_LEDC_LOW_SPEED_MODE,
+                        ledc_timer_t_LEDC_TIMER_0,
+                        hz,
+                    )
+                })?;
+                self.driver.set_duty(self.duty())?;
+            }
+            None => self.driver.set_duty(0)?,
+        }
+        self.hz = hz;
+        Ok(())
+    }
+
+    fn set_quiet(&mut self, quiet: bool) -> Result<(), EspError> {
+        if quiet != self.quiet {
+            self.quiet = quiet;
+            if self.hz.is_some() {
+                self.driver.set_duty(self.duty())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Claim `gpio` as an armed output after boot, e.g. to move an alarm LED
+///
+/// `in_use` lists every GPIO currently claimed elsewhere. The pin driver is
+/// released, and the pin reset, when the returned output is dropped.
+pub fn claim_output<B: Board>(gpio: i32, in_use: &[i32]) -> Result<Box<dyn Output>, PinError> {
+    pins::check_output("Output", gpio)?;
+    if in_use.contains(&gpio) {
+        return Err(PinError::InUse("Output", gpio));
+    }
+
+    // Safe: the pin is valid for output and not claimed by anything else
+    let mut output = unsafe { B::output(gpio) }.map_err(PinError::Driver)?;
+    output.arm().map_err(PinError::Driver)?;
+    Ok(output)
+}
+
+/// The touch sensor / button that wakes the board and acknowledges alarms
+pub struct WakeButton {
+    gpio: i32,
+    driver: PinDriver<'static, AnyInputPin, Input>,
+}
+
+impl WakeButton {
+    /// # Safety
+    ///
+    /// `gpio` must not be claimed anywhere else.
+    unsafe fn new(gpio: i32) -> Result<Self, EspError> {
+        Ok(Self {
+            gpio,
+            driver: PinDriver::input(AnyInputPin::new(gpio))?,
+        })
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.driver.is_high()
+    }
+
+    /// Queue an [`Event::Button`] on every edge, after [`inputs::init`]
+    pub fn listen(&mut self) -> Result<(), EspError> {
+        let gpio = self.gpio;
+        self.driver.set_interrupt_type(InterruptType::AnyEdge)?;
+        // Safe: the handler only reads the pin and queues without blocking
+        unsafe {
+            self.driver.subscribe(move || {
+                inputs::raise(Event::Button(gpio_get_level(gpio) != 0));
+            })?;
+        }
+        self.driver.enable_interrupt()
+    }
+
+    /// Re-enable the interrupt, which the GPIO driver disables each time it
+    /// fires; call after taking the queued events
+    pub fn rearm(&mut self) -> Result<(), EspError> {
+        self.driver.enable_interrupt()
+    }
+
+    /// Wake from deep sleep when the button is pressed
+    pub fn enable_wakeup(&self) {
+        unsafe {
+            gpio_wakeup_enable(self.gpio, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL);
+            esp_deep_sleep_enable_gpio_wakeup(
+                1u64 << self.gpio,
+                gpio_deepsleep_wakeup_level_t_ESP_GPIO_WAKEUP_GPIO_HIGH,
+            );
+        }
+    }
+}
+
+/// A latching switch read by its level, closed pulling the pin high
+pub struct Switch {
+    driver: PinDriver<'static, AnyInputPin, Input>,
+}
+
+impl Switch {
+    /// Pulled down, so a switch left off reads open
+    ///
+    /// # Safety
+    ///
+    /// `gpio` must not be claimed anywhere else.
+    pub(crate) unsafe fn new(gpio: i32) -> Result<Self, EspError> {
+        let driver = PinDriver::input(AnyInputPin::new(gpio))?;
+        esp!(gpio_pulldown_en(gpio))?;
+        Ok(Self { driver })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.driver.is_high()
+    }
+}
+
+/// An action button, closed pulling the pin high like the wake button
+pub struct Button {
+    gpio: i32,
+    driver: PinDriver<'static, AnyInputPin, Input>,
+}
+
+impl Button {
+    pub fn gpio(&self) -> i32 {
+        self.gpio
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.driver.is_high()
+    }
+
+    /// Queue an [`Event::Key`] on every edge, after [`inputs::init`]
+    pub fn listen(&mut self) -> Result<(), EspError> {
+        let gpio = self.gpio;
+        self.driver.set_interrupt_type(InterruptType::AnyEdge)?;
+        // Safe: the handler only reads the pin and queues without blocking
+        unsafe {
+            self.driver.subscribe(move || {
+                let pressed = gpio_get_level(gpio) != 0;
+                inputs::raise(Event::Key { gpio, pressed });
+            })?;
+        }
+        self.driver.enable_interrupt()
+    }
+
+    /// Re-enable the interrupt after taking the queued events, as for the
+    /// wake button
+    pub fn rearm(&mut self) -> Result<(), EspError> {
+        self.driver.enable_interrupt()
+    }
+}
+
+/// Claim `gpio` for an action button, pulled down, e.g. one set in the
+/// configuration rather than the board's pin map
+///
+/// `in_use` lists every GPIO claimed elsewhere.
+pub fn claim_button(gpio: i32, in_use: &[i32]) -> Result<Button, PinError> {
+    pins::check_input("Button", gpio)?;
+    if in_use.contains(&gpio) {
+        return Err(PinError::InUse("Button", gpio));
+    }
+
+    // Safe: the pin exists and is not claimed by anything else
+    let driver = PinDriver::input(unsafe { AnyInputPin::new(gpio) }).map_err(PinError::Driver)?;
+    // GPIO 34-39 have no pulls, a button there needs its own pull-down
+    unsafe { gpio_pulldown_en(gpio) };
+    Ok(Button { gpio, driver })
+}
+
+/// Validate the pin map of `B` and claim its pins, halting on a bad map
+pub fn init<B: Board>() -> BoardIo {
+    if let Err(e) = pins::validate(B::PIN_MAP) {
+        pins::halt(B::PIN_MAP, &e);
+    }
+    info!("Board: {}", B::NAME);
+
+    // Safe: called once from main, and the map has no duplicate pins
+    unsafe { B::take() }.unwrap()
+}
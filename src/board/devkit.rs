@@ -0,0 +1,79 @@
+use esp_idf_svc::sys::EspError;
+
+use super::{Board, BoardIo, BootSafeOutput, Output, Switch, ToneOutput, WakeButton};
+#[cfg(feature = "microphone")]
+use crate::microphone::Microphone;
+use crate::pins::{self, Assignment};
+
+// GPIO 34-39 are input only on ESP32
+const THERMO_1_LED_GPIO: i32 = 32; // Kettle Thermostat
+                                   // Sink Thermostat, GPIO12 is a strapping pin so it is only driven after boot.
+                                   // Set THERMO_2_LED_GPIO at build time to move it, e.g. to 33.
+const THERMO_2_LED_GPIO: i32 = pins::gpio_from_env(option_env!("THERMO_2_LED_GPIO"), 12);
+const BUZZER_GPIO: i32 = 13;
+// Set PASSIVE_BUZZER at build time for a passive buzzer, which plays melodies
+// through LEDC; the default active buzzer only switches on and off
+const PASSIVE_BUZZER: bool = option_env!("PASSIVE_BUZZER").is_some();
+const WAKEUP_GPIO: i32 = 4;
+// External siren through a relay or MOSFET, and the switch that mutes it;
+// set SIREN_GPIO or SIREN_MUTE_GPIO at build time to move them
+const SIREN_GPIO: i32 = pins::gpio_from_env(option_env!("SIREN_GPIO"), 17);
+const SIREN_MUTE_GPIO: i32 = pins::gpio_from_env(option_env!("SIREN_MUTE_GPIO"), 16);
+// I2S microphone, with the `microphone` feature
+#[cfg(feature = "microphone")]
+const MIC_BCLK_GPIO: i32 = 26;
+#[cfg(feature = "microphone")]
+const MIC_WS_GPIO: i32 = 25;
+#[cfg(feature = "microphone")]
+const MIC_DATA_GPIO: i32 = 27;
+
+/// ESP32 DevKit v1 with two alarm LEDs, a buzzer, a touch sensor on GPIO4
+/// and a siren output with its mute switch
+pub struct DevKit;
+
+impl Board for DevKit {
+    const NAME: &'static str = "ESP32 DevKit v1";
+
+    const PIN_MAP: &'static [Assignment] = &[
+        Assignment::output("THERMO_1_LED", THERMO_1_LED_GPIO),
+        Assignment::output("THERMO_2_LED", THERMO_2_LED_GPIO),
+        Assignment::output("BUZZER", BUZZER_GPIO),
+        Assignment::input("WAKEUP", WAKEUP_GPIO),
+        Assignment::output("SIREN", SIREN_GPIO),
+        Assignment::input("SIREN_MUTE", SIREN_MUTE_GPIO),
+        #[cfg(feature = "microphone")]
+        Assignment::output("MIC_BCLK", MIC_BCLK_GPIO),
+        #[cfg(feature = "microphone")]
+        Assignment::output("MIC_WS", MIC_WS_GPIO),
+        #[cfg(feature = "microphone")]
+        Assignment::input("MIC_DATA", MIC_DATA_GPIO),
+    ];
+
+    unsafe fn take() -> Result<BoardIo, EspError> {
+        let buzzer: Box<dyn Output> = if PASSIVE_BUZZER {
+            Box::new(ToneOutput::new(BUZZER_GPIO)?)
+        } else {
+            Box::new(BootSafeOutput::new(BUZZER_GPIO)?)
+        };
+
+        Ok(BoardIo {
+            alarm_leds: vec![
+                Box::new(BootSafeOutput::new(THERMO_1_LED_GPIO)?),
+                Box::new(BootSafeOutput::new(THERMO_2_LED_GPIO)?),
+            ],
+            buzzer,
+            siren: Some(Box::new(BootSafeOutput::new(SIREN_GPIO)?)),
+            mute_switch: Some(Switch::new(SIREN_MUTE_GPIO)?),
+            wake_button: WakeButton::new(WAKEUP_GPIO)?,
+            display_bus: None,
+            #[cfg(feature = "microphone")]
+            microphone: Microphone::new(MIC_BCLK_GPIO, MIC_WS_GPIO, MIC_DATA_GPIO)
+                .map_err(|e| log::warn!("Microphone unavailable: {}", e))
+                .ok(),
+        })
+    }
+
+    unsafe fn output(gpio: i32) -> Result<Box<dyn Output>, EspError> {
+        Ok(Box::new(BootSafeOutput::new(gpio)?))
+    }
+}
@@ -0,0 +1,50 @@
+use esp_idf_svc::sys::{esp, gpio_pulldown_en, EspError};
+
+use super::{Board, BoardIo, Output, WakeButton};
+use crate::gpio_io::VirtualOutput;
+use crate::pins::Assignment;
+
+const WAKEUP_GPIO: i32 = 4;
+
+// Pins the virtual outputs stand in for, same as on the DevKit
+const THERMO_1_LED_GPIO: i32 = 32;
+const THERMO_2_LED_GPIO: i32 = 12;
+const BUZZER_GPIO: i32 = 13;
+const SIREN_GPIO: i32 = 17;
+
+/// Any ESP32 devkit with nothing attached
+///
+/// Alarm LEDs, the buzzer and the siren are virtual and log their
+/// transitions, so the whole receive, alarm and uplink path can be exercised
+/// without hardware. Only the wake button is real, pulled down so a floating
+/// pin reads idle; there is no siren mute switch.
+pub struct Headless;
+
+impl Board for Headless {
+    const NAME: &'static str = "Headless (virtual outputs)";
+
+    const PIN_MAP: &'static [Assignment] = &[Assignment::input("WAKEUP", WAKEUP_GPIO)];
+
+    unsafe fn take() -> Result<BoardIo, EspError> {
+        let wake_button = WakeButton::new(WAKEUP_GPIO)?;
+        esp!(gpio_pulldown_en(WAKEUP_GPIO))?;
+
+        Ok(BoardIo {
+            alarm_leds: vec![
+                Box::new(VirtualOutput::new("THERMO_1_LED", THERMO_1_LED_GPIO)),
+                Box::new(VirtualOutput::new("THERMO_2_LED", THERMO_2_LED_GPIO)),
+            ],
+            buzzer: Box::new(VirtualOutput::new("BUZZER", BUZZER_GPIO)),
+            siren: Some(Box::new(VirtualOutput::new("SIREN", SIREN_GPIO))),
+            mute_switch: None,
+            wake_button,
+            display_bus: None,
+            #[cfg(feature = "microphone")]
+            microphone: None,
+        })
+    }
+
+    unsafe fn output(gpio: i32) -> Result<Box<dyn Output>, EspError> {
+        Ok(Box::new(VirtualOutput::new("LED", gpio)))
+    }
+}
@@ -0,0 +1,330 @@
+//! ESP-NOW frame capture for Wireshark
+//!
+//! The `capture` console command puts the radio into promiscuous mode and
+//! records every ESP-NOW action frame on the channel, also those addressed
+//! to other devices, in PCAP format with a radiotap header carrying the
+//! channel and RSSI. Wireshark's ESP-NOW dissector decodes them from there.
+//!
+//! - `capture file` writes `capture.pcap` to SPIFFS, stopping at
+//!   [`MAX_FILE_SIZE`], served on `GET /capture.pcap`
+//! - `capture tcp` streams to one client at a time on [`PORT`], e.g.
+//!   `nc <hub> 19000 | wireshark -k -i -`, which needs the WiFi uplink
+//! - `capture off` stops, `capture` shows what is being captured
+//!
+//! The promiscuous callback runs in the WiFi task like the ESP-NOW one, so
+//! it only copies the frame into a bounded queue; a writer thread does the
+//! I/O. Frames arriving while the queue is full are dropped and counted.
+//! The 4-byte FCS is stripped. Capture timestamps come from the system time,
+//! which starts at the epoch until the RTC is set.
+
+use std::ffi::c_void;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{slice, thread};
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write as _;
+use esp_idf_svc::sys::{
+    esp, esp_wifi_set_promiscuous, esp_wifi_set_promiscuous_filter, esp_wifi_set_promiscuous_rx_cb,
+    wifi_promiscuous_filter_t, wifi_promiscuous_pkt_t, wifi_promiscuous_pkt_type_t,
+    wifi_promiscuous_pkt_type_t_WIFI_PKT_MGMT, EspError, WIFI_PROMIS_FILTER_MASK_MGMT,
+};
+use log::{info, warn};
+
+use crate::storage;
+
+/// TCP port `capture tcp` listens on
+pub const PORT: u16 = 19000;
+/// Size at which `capture file` stops
+pub const MAX_FILE_SIZE: u64 = 256 * 1024;
+
+const FILE_NAME: &str = "capture.pcap";
+const STACK_SIZE: usize = 4096;
+// Frames between the WiFi task and the writer
+const QUEUE_LENGTH: usize = 32;
+// Longest frame kept, ESP-NOW frames are at most about 290 bytes
+const SNAPLEN: usize = 512;
+// How often the TCP writer looks for a client while frames are scarce
+const ACCEPT_POLL: Duration = Duration::from_millis(200);
+
+const LINKTYPE_IEEE802_11_RADIOTAP: u32 = 127;
+const FCS_LEN: usize = 4;
+// Radiotap fields present: channel (bit 3) and antenna signal in dBm (bit 5)
+const RADIOTAP_PRESENT: u32 = 1 << 3 | 1 << 5;
+const RADIOTAP_LEN: u16 = 13;
+const RADIOTAP_CHANNEL_2GHZ: u16 = 0x0080;
+// Action frame, vendor-specific category, Espressif OUI
+const FRAME_CONTROL_ACTION: u8 = 0xd0;
+const CATEGORY_VENDOR: u8 = 127;
+const ESPRESSIF_OUI: [u8; 3] = [0x18, 0xfe, 0x34];
+
+static WRITER: Mutex<Option<SyncSender<Packet>>> = Mutex::new(None);
+static TARGET: Mutex<Option<Target>> = Mutex::new(None);
+static CAPTURED: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Where captured frames go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    File,
+    Tcp,
+}
+
+struct Packet {
+    at: Duration,
+    channel: u8,
+    rssi: i8,
+    frame: Vec<u8>,
+}
+
+/// Start capturing to `target`
+pub fn start(target: Target) -> io::Result<()> {
+    let mut writer = WRITER.lock().unwrap();
+    if writer.is_some() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "capture already running",
+        ));
+    }
+    let (tx, packets) = mpsc::sync_channel(QUEUE_LENGTH);
+    thread::Builder::new()
+        .name("capture".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let result = match target {
+                Target::File => write_file(&packets),
+                Target::Tcp => stream(&packets),
+            };
+            if let Err(e) = result {
+                warn!("Capture failed: {}", e);
+            }
+            // Still connected: the writer gave up on its own, e.g. when the
+            // file is full, rather than being stopped
+            let stopped = loop {
+                match packets.try_recv() {
+                    Ok(_) => {}
+                    Err(e) => break e == TryRecvError::Disconnected,
+                }
+            };
+            if !stopped {
+                stop();
+            }
+        })?;
+    *writer = Some(tx);
+    *TARGET.lock().unwrap() = Some(target);
+    CAPTURED.store(0, Ordering::Relaxed);
+    DROPPED.store(0, Ordering::Relaxed);
+
+    let filter = wifi_promiscuous_filter_t {
+        filter_mask: WIFI_PROMIS_FILTER_MASK_MGMT,
+    };
+    let enabled = esp!(unsafe { esp_wifi_set_promiscuous_filter(&filter) })
+        .and_then(|_| esp!(unsafe { esp_wifi_set_promiscuous_rx_cb(Some(promiscuous_rx)) }))
+        .and_then(|_| esp!(unsafe { esp_wifi_set_promiscuous(true) }));
+    if let Err(e) = enabled {
+        // Dropping the sender ends the writer thread
+        *writer = None;
+        *TARGET.lock().unwrap() = None;
+        return Err(io::Error::other(e));
+    }
+    info!("Capturing ESP-NOW frames to {:?}", target);
+    Ok(())
+}
+
+/// Stop capturing, the writer closes its file or client
+pub fn stop() {
+    if WRITER.lock().unwrap().take().is_none() {
+        return;
+    }
+    *TARGET.lock().unwrap() = None;
+    if let Err(e) = esp!(unsafe { esp_wifi_set_promiscuous(false) }) {
+        warn!("Failed to leave promiscuous mode: {}", e);
+    }
+    info!(
+        "Capture stopped, {} frames, {} lost",
+        CAPTURED.load(Ordering::Relaxed),
+        DROPPED.load(Ordering::Relaxed)
+    );
+}
+
+/// What is being captured, for the console
+pub fn summary() -> String {
+    match *TARGET.lock().unwrap() {
+        Some(target) => format!(
+            "Capturing to {:?}, {} frames, {} lost",
+            target,
+            CAPTURED.load(Ordering::Relaxed),
+            DROPPED.load(Ordering::Relaxed)
+        ),
+        None => "Capture off".to_string(),
+    }
+}
+
+/// Serve the capture file on `server`
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/capture.pcap", Method::Get, |req| {
+        let Ok(mut file) = File::open(file_path()) else {
+            return req.into_status_response(404)?.write_all(b"no capture file");
+        };
+        let mut response = req.into_response(
+            200,
+            None,
+            &[("Content-Type", "application/vnd.tcpdump.pcap")],
+        )?;
+        let mut buf = [0u8; 1024];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(n) => response.write_all(&buf[..n])?,
+            }
+        }
+    })?;
+    info!("Capture file served on /capture.pcap");
+    Ok(())
+}
+
+/// Promiscuous receive callback, in the WiFi task
+unsafe extern "C" fn promiscuous_rx(buf: *mut c_void, kind: wifi_promiscuous_pkt_type_t) {
+    if kind != wifi_promiscuous_pkt_type_t_WIFI_PKT_MGMT || buf.is_null() {
+        return;
+    }
+    let packet = &*(buf as *const wifi_promiscuous_pkt_t);
+    let len = (packet.rx_ctrl.sig_len() as usize).saturating_sub(FCS_LEN);
+    let frame = slice::from_raw_parts(packet.payload.as_ptr(), len);
+    if !is_espnow(frame) {
+        return;
+    }
+    let writer = WRITER.lock().unwrap();
+    let Some(writer) = writer.as_ref() else {
+        return;
+    };
+    let packet = Packet {
+        at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+        channel: packet.rx_ctrl.channel() as u8,
+        rssi: packet.rx_ctrl.rssi() as i8,
+        frame: frame[..len.min(SNAPLEN)].to_vec(),
+    };
+    match writer.try_send(packet) {
+        Ok(()) => CAPTURED.fetch_add(1, Ordering::Relaxed),
+        Err(_) => DROPPED.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+fn is_espnow(frame: &[u8]) -> bool {
+    frame.len() >= 28
+        && frame[0] == FRAME_CONTROL_ACTION
+        && frame[24] == CATEGORY_VENDOR
+        && frame[25..28] == ESPRESSIF_OUI
+}
+
+fn write_file(packets: &Receiver<Packet>) -> io::Result<()> {
+    let path = file_path();
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = File::create(&path)?;
+    let mut size = write_header(&mut file)? as u64;
+    info!("Capture file {}", path.display());
+    for packet in packets {
+        let record = record(&packet);
+        if size + record.len() as u64 > MAX_FILE_SIZE {
+            info!("Capture file full at {} bytes", size);
+            break;
+        }
+        file.write_all(&record)?;
+        size += record.len() as u64;
+    }
+    file.flush()
+}
+
+fn file_path() -> PathBuf {
+    PathBuf::from(storage::BASE_PATH).join(FILE_NAME)
+}
+
+fn stream(packets: &Receiver<Packet>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", PORT))?;
+    listener.set_nonblocking(true)?;
+    info!("Capture listening on TCP port {}", PORT);
+    let mut client: Option<TcpStream> = None;
+    loop {
+        if client.is_none() {
+            match listener.accept() {
+                Ok((mut stream, peer)) => {
+                    stream.set_nonblocking(false)?;
+                    match write_header(&mut stream) {
+                        Ok(_) => {
+                            info!("Capture client {} connected", peer);
+                            client = Some(stream);
+                        }
+                        Err(e) => warn!("Capture client {} lost: {}", peer, e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Frames without a client are discarded
+        let packet = match packets.recv_timeout(ACCEPT_POLL) {
+            Ok(packet) => packet,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        if let Some(stream) = client.as_mut() {
+            if let Err(e) = stream.write_all(&record(&packet)) {
+                info!("Capture client gone: {}", e);
+                client = None;
+            }
+        }
+    }
+}
+
+/// The PCAP global header, returns its length
+fn write_header<W: Write>(out: &mut W) -> io::Result<usize> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Timezone offset and timestamp accuracy, both unused
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&((SNAPLEN + usize::from(RADIOTAP_LEN)) as u32).to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_IEEE802_11_RADIOTAP.to_le_bytes());
+    out.write_all(&header)?;
+    Ok(header.len())
+}
+
+/// A PCAP record: its header, the radiotap header and the frame
+fn record(packet: &Packet) -> Vec<u8> {
+    let len = (usize::from(RADIOTAP_LEN) + packet.frame.len()) as u32;
+    let mut record = Vec::with_capacity(16 + len as usize);
+    record.extend_from_slice(&(packet.at.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&packet.at.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&len.to_le_bytes());
+
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(&RADIOTAP_LEN.to_le_bytes());
+    record.extend_from_slice(&RADIOTAP_PRESENT.to_le_bytes());
+    record.extend_from_slice(&channel_mhz(packet.channel).to_le_bytes());
+    record.extend_from_slice(&RADIOTAP_CHANNEL_2GHZ.to_le_bytes());
+    record.push(packet.rssi as u8);
+
+    record.extend_from_slice(&packet.frame);
+    record
+}
+
+fn channel_mhz(channel: u8) -> u16 {
+    match channel {
+        14 => 2484,
+        n => 2407 + 5 * u16::from(n),
+    }
+}
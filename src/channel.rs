@@ -0,0 +1,270 @@
+//! The ESP-NOW channel, and moving it when the hub joins an AP
+//!
+//! Off an AP the hub listens on the channel stored in NVS, or the radio's
+//! default if none is. `channel <n>` on the console stores one, staged like
+//! a config import so a channel no sender is on reverts unless confirmed,
+//! and the main loop moves the radio once it changed. `channel
+//! scan` hops through channels 1 to 13 instead, dwelling on each for a few
+//! seconds, until a valid data frame from a known sender (paired or on the
+//! whitelist) comes in; the hub then locks to that channel and stores it. A
+//! scan needs the radio to itself and is refused while an AP is joined.
+//!
+//! ESP-NOW shares the radio with WiFi, so joining an AP moves the hub to the
+//! AP's channel and senders left on the old one go unheard. Before joining,
+//! the hub scans for the AP; when it sits on another channel the hub stays
+//! on the old one for a grace period and tells the senders where it is
+//! going, then joins. The notice is sent reliably to every paired sender and
+//! broadcast every few seconds for the others:
+//!
+//! `b"CHM"`, new channel, grace period in seconds (5 bytes)
+//!
+//! Senders asleep for the whole grace period have to find the hub again on
+//! their own.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{
+    esp, esp_wifi_get_channel, esp_wifi_set_channel, wifi_second_chan_t,
+    wifi_second_chan_t_WIFI_SECOND_CHAN_NONE, EspError,
+};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, warn};
+
+use crate::clock;
+use crate::espnow_tx::{self, Qos};
+use crate::pairing;
+
+const NOTICE: &[u8; 3] = b"CHM";
+const BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+
+pub const MAX_CHANNEL: u8 = 13;
+const NAMESPACE: &str = "channel";
+const STORED: &str = "espnow";
+
+static NVS: Mutex<Option<EspNvs<NvsDefault>>> = Mutex::new(None);
+// Set while a scan runs, and once it heard a known sender on its channel
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static HEARD: AtomicBool = AtomicBool::new(false);
+// Set when the stored channel changes, for the main loop to move to it
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Open the channel namespace in NVS
+pub fn load(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    *NVS.lock().unwrap() = Some(EspNvs::new(partition, NAMESPACE, true)?);
+    Ok(())
+}
+
+/// The channel stored for ESP-NOW, if any
+pub fn stored() -> Option<u8> {
+    let nvs = NVS.lock().unwrap();
+    let channel = nvs.as_ref()?.get_u8(STORED).ok().flatten()?;
+    (1..=MAX_CHANNEL).contains(&channel).then_some(channel)
+}
+
+/// Store `channel` for ESP-NOW, or with `None` go back to the default from
+/// the next boot
+pub fn store(channel: Option<u8>) -> Result<(), &'static str> {
+    if channel.is_some_and(|c| !(1..=MAX_CHANNEL).contains(&c)) {
+        return Err("channel must be 1 to 13");
+    }
+    let mut nvs = NVS.lock().unwrap();
+    let nvs = nvs.as_mut().ok_or("NVS unavailable")?;
+    let result = match channel {
+        Some(channel) => nvs.set_u8(STORED, channel),
+        None => nvs.remove(STORED).map(|_| ()),
+    };
+    result.map_err(|e| {
+        warn!("Failed to store the channel: {}", e);
+        "write failed"
+    })?;
+    CHANGED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether the stored channel changed since the last call
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::Relaxed)
+}
+
+/// The stored channel, or `default`, for the config backup
+pub fn list() -> String {
+    stored().map_or_else(|| "default".to_string(), |c| c.to_string())
+}
+
+/// Check a channel in the [`list`] format
+pub fn check(text: &str) -> Result<(), &'static str> {
+    parse(text).map(|_| ())
+}
+
+/// Store a channel in the [`list`] format
+pub fn replace(text: &str) -> Result<(), &'static str> {
+    store(parse(text)?)
+}
+
+fn parse(text: &str) -> Result<Option<u8>, &'static str> {
+    match text.trim() {
+        "default" => Ok(None),
+        text => {
+            let channel = text.parse().map_err(|_| "channel must be 1 to 13")?;
+            if !(1..=MAX_CHANNEL).contains(&channel) {
+                return Err("channel must be 1 to 13");
+            }
+            Ok(Some(channel))
+        }
+    }
+}
+
+/// Move the radio to `channel`, only while no AP is joined
+pub fn set(channel: u8) -> Result<(), EspError> {
+    esp!(unsafe { esp_wifi_set_channel(channel, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE) })
+}
+
+/// Whether a scan is waiting to hear a known sender
+pub fn scanning() -> bool {
+    SCANNING.load(Ordering::Relaxed)
+}
+
+/// A valid data frame from a known sender came in, from the receive path
+pub fn heard() {
+    HEARD.store(true, Ordering::Relaxed);
+}
+
+/// The channel, what is stored and whether a scan runs, for the console
+pub fn summary() -> String {
+    let current = current().map_or_else(|_| "unknown".to_string(), |c| c.to_string());
+    let stored = stored().map_or_else(|| "none".to_string(), |c| c.to_string());
+    format!(
+        "Channel {}, stored {}{}",
+        current,
+        stored,
+        if scanning() { ", scanning" } else { "" }
+    )
+}
+
+/// Primary channel the radio is on
+pub fn current() -> Result<u8, EspError> {
+    let mut primary = 0;
+    let mut second: wifi_second_chan_t = 0;
+    esp!(unsafe { esp_wifi_get_channel(&mut primary, &mut second) })?;
+    Ok(primary)
+}
+
+/// Channel of the strongest AP called `ssid`
+pub fn ap_channel(wifi: &mut BlockingWifi<EspWifi<'static>>, ssid: &str) -> Option<u8> {
+    let aps = wifi
+        .scan()
+        .map_err(|e| warn!("WiFi scan failed: {}", e))
+        .ok()?;
+    aps.iter()
+        .filter(|ap| ap.ssid.as_str() == ssid)
+        .max_by_key(|ap| ap.signal_strength)
+        .map(|ap| ap.channel)
+}
+
+/// Hopping through the channels until a known sender is heard
+pub struct Scan {
+    channel: u8,
+    dwell: Duration,
+    since: Instant,
+}
+
+impl Scan {
+    /// Start on channel 1, staying on each for `dwell`
+    pub fn start(dwell: Duration) -> Result<Self, EspError> {
+        set(1)?;
+        HEARD.store(false, Ordering::Relaxed);
+        SCANNING.store(true, Ordering::Relaxed);
+        info!(
+            "Scanning channels 1 to {}, {} s each",
+            MAX_CHANNEL,
+            dwell.as_secs()
+        );
+        Ok(Self {
+            channel: 1,
+            dwell,
+            since: clock::now(),
+        })
+    }
+
+    /// Hop on once the dwell is over, returns the channel once locked to it
+    pub fn poll(&mut self) -> Option<u8> {
+        if HEARD.swap(false, Ordering::Relaxed) {
+            info!("Known sender heard, locking to channel {}", self.channel);
+            if let Err(e) = store(Some(self.channel)) {
+                warn!("Channel {} not stored: {}", self.channel, e);
+            }
+            return Some(self.channel);
+        }
+        if clock::since(self.since) < self.dwell {
+            return None;
+        }
+
+        if self.channel == MAX_CHANNEL {
+            info!("No known sender heard on any channel, scanning on");
+        }
+        self.channel = self.channel % MAX_CHANNEL + 1;
+        self.since = clock::now();
+        if let Err(e) = set(self.channel) {
+            warn!("Failed to move to channel {}: {}", self.channel, e);
+        }
+        None
+    }
+}
+
+impl Drop for Scan {
+    fn drop(&mut self) {
+        SCANNING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Grace period on the old channel before joining the AP
+pub struct Migration {
+    notice: [u8; 5],
+    until: Instant,
+    next_broadcast: Instant,
+}
+
+impl Migration {
+    /// Announce the move from `from` to `to` in `grace`
+    pub fn start(from: u8, to: u8, grace: Duration) -> Self {
+        let grace_s = grace.as_secs().min(u8::MAX.into()) as u8;
+        let notice = [NOTICE[0], NOTICE[1], NOTICE[2], to, grace_s];
+        info!(
+            "ESP-NOW moving from channel {} to {} in {} s",
+            from, to, grace_s
+        );
+        for peer in pairing::paired() {
+            if let Err(e) = espnow_tx::send(peer, &notice, Qos::Reliable) {
+                warn!("Channel notice to {:02X?} failed: {}", peer, e);
+            }
+        }
+
+        let now = clock::now();
+        Self {
+            notice,
+            until: now + grace,
+            next_broadcast: now,
+        }
+    }
+
+    /// Repeat the broadcast notice, returns true once the grace period is
+    /// over
+    pub fn poll(&mut self) -> bool {
+        let now = clock::now();
+        if now >= self.until {
+            return true;
+        }
+        if now >= self.next_broadcast {
+            self.next_broadcast = now + BROADCAST_INTERVAL;
+            espnow_tx::add_peer(espnow_tx::BROADCAST)
+                .and_then(|_| {
+                    espnow_tx::send(espnow_tx::BROADCAST, &self.notice, Qos::FireAndForget)
+                })
+                .ok();
+        }
+        false
+    }
+}
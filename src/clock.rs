@@ -0,0 +1,121 @@
+//! Time source for timers, schedules and staleness checks
+//!
+//! Application timing reads the time through [`now`], [`since`] and
+//! [`unix_secs`] rather than `Instant` and `SystemTime` directly, so a
+//! simulation on the host can swap in a [`MockClock`] with [`set`] and step
+//! through snoozes, schedules and stale senders without waiting for them. On
+//! the target the [`SystemClock`] reads the esp_timer, which also drives
+//! the FreeRTOS tick, and the RTC.
+//!
+//! Hardware-facing timing stays on the real clock: radio retries, bus
+//! recovery, the buzzer's on-time cap and the loop watchdog. A simulated
+//! clock must never hold an output on or hide a hang.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, RwLock};
+#[cfg(test)]
+use std::sync::{MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Before this (2024-01-01) the RTC has not been set and the date means
+/// nothing
+pub const VALID_AFTER: u32 = 1_704_067_200;
+
+static CLOCK: RwLock<&'static dyn Clock> = RwLock::new(&SystemClock);
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time
+    fn now(&self) -> Instant;
+    /// Seconds since the Unix epoch, 0 until the RTC is set
+    fn unix_secs(&self) -> u32;
+}
+
+/// The chip's own clocks
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_secs(&self) -> u32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to
+pub struct MockClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+    unix_secs: AtomicU32,
+}
+
+impl MockClock {
+    /// A clock standing at `unix_secs`
+    pub fn new(unix_secs: u32) -> Self {
+        Self {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+            unix_secs: AtomicU32::new(unix_secs),
+        }
+    }
+
+    /// Move both clocks on by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+        self.unix_secs
+            .fetch_add(by.as_secs() as u32, Ordering::SeqCst);
+    }
+
+    /// Set the wall clock, e.g. to cross into another month
+    pub fn set_unix_secs(&self, unix_secs: u32) {
+        self.unix_secs.store(unix_secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    fn unix_secs(&self) -> u32 {
+        self.unix_secs.load(Ordering::SeqCst)
+    }
+}
+
+/// Use `clock` from now on
+///
+/// Set it before anything starts timing: an `Instant` taken from one clock
+/// means nothing to another.
+pub fn set(clock: &'static dyn Clock) {
+    *CLOCK.write().unwrap() = clock;
+}
+
+pub fn now() -> Instant {
+    CLOCK.read().unwrap().now()
+}
+
+/// Time passed since `earlier`, zero if the clock has not got there
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
+
+pub fn unix_secs() -> u32 {
+    CLOCK.read().unwrap().unix_secs()
+}
+
+/// Put tests on one shared [`MockClock`], taking turns while the guard lives
+/// so none moves the clock, or swaps module state such as the threshold
+/// rules, under another
+#[cfg(test)]
+pub(crate) fn mock() -> (MutexGuard<'static, ()>, &'static MockClock) {
+    static TURN: Mutex<()> = Mutex::new(());
+    static MOCK: OnceLock<MockClock> = OnceLock::new();
+    let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+    let mock = MOCK.get_or_init(|| MockClock::new(VALID_AFTER));
+    set(mock);
+    (turn, mock)
+}
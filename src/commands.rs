@@ -0,0 +1,138 @@
+//! Commands from a controlling node over ESP-NOW
+//!
+//! A paired sender, e.g. a wall panel, can control the hub with a
+//! [`Message::Command`]: move a threshold, silence the alarms, reboot or run
+//! the self-test. Commands from senders that are not paired are dropped, and
+//! with an HMAC secret set they must be authenticated like data frames.
+//!
+//! The receive path queues the commands and the main loop runs them
+//! through [`dispatch`], which answers each with a [`Message::Reply`] to its
+//! sender, `ok` false if it failed. A retransmission, the sender's last id
+//! again, is answered with the same outcome without running it twice. A
+//! self-test is answered once started, its result is only logged. A reboot
+//! is answered before the hub restarts.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::espnow_tx::{self, Qos, Receipt};
+use crate::metrics;
+use crate::pairing;
+use crate::platform::sys::esp_restart;
+use crate::protocol::{self, Command, Message};
+
+const MAX_INBOX: usize = 8;
+// Senders whose last command is remembered
+const MAX_SENDERS: usize = 16;
+// How long a reboot waits for its reply to go out
+const REBOOT_GRACE: Duration = Duration::from_millis(500);
+const REBOOT_POLL: Duration = Duration::from_millis(10);
+
+static INBOX: Mutex<VecDeque<Request>> = Mutex::new(VecDeque::new());
+// Each sender's last command id and whether it succeeded
+static LAST: Mutex<Vec<([u8; 6], u16, bool)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    src: [u8; 6],
+    id: u16,
+    command: Command,
+}
+
+/// Take a command from the receive path
+pub fn receive(src: [u8; 6], id: u16, command: Command) {
+    if !pairing::is_paired(&src) {
+        warn!("Command from unpaired {:02X?} dropped", src);
+        return;
+    }
+    let mut inbox = INBOX.lock().unwrap();
+    if inbox.len() < MAX_INBOX {
+        inbox.push_back(Request { src, id, command });
+    }
+}
+
+/// Commands waiting for the next [`dispatch`]
+pub fn queued() -> usize {
+    INBOX.lock().unwrap().len()
+}
+
+/// Run the queued commands through `handler` and answer each; once a reboot
+/// is accepted and answered the hub restarts
+pub fn dispatch(mut handler: impl FnMut(Command) -> Result<(), &'static str>) {
+    loop {
+        let Some(Request { src, id, command }) = INBOX.lock().unwrap().pop_front() else {
+            break;
+        };
+        let repeated = LAST
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(mac, last, _)| *mac == src && *last == id)
+            .map(|&(_, _, ok)| ok);
+        let ok = match repeated {
+            Some(ok) => ok,
+            None => {
+                let result = handler(command);
+                match result {
+                    Ok(()) => info!("Command {:?} from {:02X?} done", command, src),
+                    Err(e) => warn!("Command {:?} from {:02X?} failed: {}", command, src, e),
+                }
+                remember(src, id, result.is_ok());
+                result.is_ok()
+            }
+        };
+
+        let receipt = reply(src, id, ok);
+        if ok && repeated.is_none() && command == Command::Reboot {
+            reboot(receipt);
+        }
+    }
+}
+
+fn remember(src: [u8; 6], id: u16, ok: bool) {
+    let mut last = LAST.lock().unwrap();
+    match last.iter_mut().find(|(mac, _, _)| *mac == src) {
+        Some(entry) => *entry = (src, id, ok),
+        None => {
+            if last.len() >= MAX_SENDERS {
+                last.remove(0);
+            }
+            last.push((src, id, ok));
+        }
+    }
+}
+
+fn reply(src: [u8; 6], id: u16, ok: bool) -> Option<Receipt> {
+    let sent = protocol::encode(&Message::Reply { id, ok })
+        .map_err(|e| e.to_string())
+        .and_then(|frame| {
+            espnow_tx::add_peer(src)
+                .and_then(|_| espnow_tx::send_tracked(src, &frame, Qos::Reliable))
+                .map_err(|e| e.to_string())
+        });
+    match sent {
+        Ok(receipt) => Some(receipt),
+        Err(e) => {
+            warn!("Reply to {:02X?} not sent: {}", src, e);
+            None
+        }
+    }
+}
+
+/// Restart once `receipt` settled, or after the grace period
+fn reboot(receipt: Option<Receipt>) -> ! {
+    info!("Rebooting on command");
+    let started = Instant::now();
+    while receipt.as_ref().is_some_and(|r| r.outcome().is_none())
+        && started.elapsed() < REBOOT_GRACE
+    {
+        espnow_tx::poll();
+        thread::sleep(REBOOT_POLL);
+    }
+    metrics::save();
+    unsafe { esp_restart() }
+}
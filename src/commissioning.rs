@@ -0,0 +1,274 @@
+//! Guided commissioning of a new installation
+//!
+//! `commission` on the console walks the installer through three steps,
+//! shown on the topic LEDs so nobody needs to watch the console:
+//!
+//! 1. First frames: every expected topic's LED blinks fast until a frame
+//!    from that topic arrives, then stays on.
+//! 2. Signal check: [`SIGNAL_FRAMES`] more frames per topic are collected
+//!    and each LED shows the average RSSI: steady for good, a slow blink for
+//!    fair, a short flash for poor, where a sender should be moved.
+//! 3. Actuator test: the LEDs light in turn and the buzzer beeps; pressing
+//!    the button confirms all of them work.
+//!
+//! A step left waiting too long moves on regardless, and a report of what
+//! was seen is logged at the end so it can be kept with the installation.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::names;
+use crate::sounds::BEEP_HZ;
+
+/// Frames per topic the signal check averages
+const SIGNAL_FRAMES: u32 = 5;
+const FRAMES_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const ACTUATOR_TIMEOUT: Duration = Duration::from_secs(60);
+// RSSI in dBm from which a link counts as good, and as fair
+const GOOD_RSSI: i32 = -70;
+const FAIR_RSSI: i32 = -80;
+
+const WAITING_PERIOD_MS: u128 = 250;
+const FAIR_PERIOD_MS: u128 = 1000;
+const POOR_PERIOD_MS: u128 = 2000;
+const POOR_ON_MS: u128 = 100;
+// Actuator test: each LED in turn, with a beep at the start of each
+const CHASE_STEP_MS: u128 = 500;
+const BEEP_MS: u128 = 100;
+
+/// What the outputs should show for the current step
+pub struct Guide {
+    /// LED level per topic
+    pub leds: Vec<(i32, bool)>,
+    /// Buzzer tone, when no alarm needs the buzzer
+    pub tone: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Frames,
+    Signal,
+    Actuators,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Grade {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl Grade {
+    fn of(rssi: i32) -> Self {
+        if rssi >= GOOD_RSSI {
+            Grade::Good
+        } else if rssi >= FAIR_RSSI {
+            Grade::Fair
+        } else {
+            Grade::Poor
+        }
+    }
+}
+
+struct Topic {
+    topic_id: i32,
+    first_frame: Option<Duration>,
+    frames: u32,
+    rssi_sum: i32,
+    rssi_min: i32,
+}
+
+impl Topic {
+    fn rssi_average(&self) -> Option<i32> {
+        (self.frames > 0).then(|| self.rssi_sum / self.frames as i32)
+    }
+}
+
+pub struct Commissioning {
+    topics: Vec<Topic>,
+    step: Step,
+    started: Instant,
+    step_started: Instant,
+    confirmed: bool,
+}
+
+impl Commissioning {
+    /// Start with the first step, waiting for frames from `topics`
+    pub fn start(topics: impl IntoIterator<Item = i32>) -> Self {
+        let topics: Vec<Topic> = topics
+            .into_iter()
+            .map(|topic_id| Topic {
+                topic_id,
+                first_frame: None,
+                frames: 0,
+                rssi_sum: 0,
+                rssi_min: 0,
+            })
+            .collect();
+        info!(
+            "Commissioning: waiting for the first frame of {} topics, their LEDs blink until then",
+            topics.len()
+        );
+
+        let now = clock::now();
+        Self {
+            topics,
+            step: Step::Frames,
+            started: now,
+            step_started: now,
+            confirmed: false,
+        }
+    }
+
+    /// Note a frame on `topic_id`, with its RSSI when it came over the air
+    pub fn receive(&mut self, topic_id: i32, rssi: Option<i32>) {
+        let elapsed = clock::since(self.started);
+        let step = self.step;
+        let Some(topic) = self.topics.iter_mut().find(|t| t.topic_id == topic_id) else {
+            return;
+        };
+        match step {
+            Step::Frames if topic.first_frame.is_none() => {
+                topic.first_frame = Some(elapsed);
+                info!(
+                    "Commissioning: first frame from {} after {} s",
+                    names::label(topic_id),
+                    elapsed.as_secs()
+                );
+            }
+            Step::Signal if topic.frames < SIGNAL_FRAMES => {
+                let Some(rssi) = rssi else {
+                    return;
+                };
+                topic.rssi_min = if topic.frames == 0 {
+                    rssi
+                } else {
+                    topic.rssi_min.min(rssi)
+                };
+                topic.frames += 1;
+                topic.rssi_sum += rssi;
+            }
+            _ => {}
+        }
+    }
+
+    /// The button was pressed, confirming the actuator test
+    pub fn confirm(&mut self) {
+        if self.step == Step::Actuators {
+            self.confirmed = true;
+        }
+    }
+
+    /// Move through the steps, logs the report and returns true once done
+    pub fn poll(&mut self) -> bool {
+        let in_step = clock::since(self.step_started);
+        match self.step {
+            Step::Frames => {
+                let heard = self.topics.iter().all(|t| t.first_frame.is_some());
+                if heard || in_step >= FRAMES_TIMEOUT {
+                    if !heard {
+                        warn!("Commissioning: not every topic was heard, moving on");
+                    }
+                    info!("Commissioning: checking the signal of each sender");
+                    self.next(Step::Signal);
+                }
+            }
+            Step::Signal => {
+                let done = self
+                    .topics
+                    .iter()
+                    .filter(|t| t.first_frame.is_some())
+                    .all(|t| t.frames >= SIGNAL_FRAMES);
+                if done || in_step >= SIGNAL_TIMEOUT {
+                    info!(
+                        "Commissioning: testing the LEDs and buzzer, press the button if all work"
+                    );
+                    self.next(Step::Actuators);
+                }
+            }
+            Step::Actuators => {
+                if self.confirmed || in_step >= ACTUATOR_TIMEOUT {
+                    self.report();
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// What the LEDs and buzzer should show right now
+    pub fn guide(&self) -> Guide {
+        let ms = clock::since(self.step_started).as_millis();
+        let leds = self
+            .topics
+            .iter()
+            .enumerate()
+            .map(|(i, topic)| {
+                let on = match self.step {
+                    Step::Frames => topic.first_frame.is_some() || ms / WAITING_PERIOD_MS % 2 == 0,
+                    Step::Signal => match topic.rssi_average().map(Grade::of) {
+                        None => ms / WAITING_PERIOD_MS % 2 == 0,
+                        Some(Grade::Good) => true,
+                        Some(Grade::Fair) => ms % FAIR_PERIOD_MS < FAIR_PERIOD_MS / 2,
+                        Some(Grade::Poor) => ms % POOR_PERIOD_MS < POOR_ON_MS,
+                    },
+                    Step::Actuators => {
+                        ms / CHASE_STEP_MS % self.topics.len().max(1) as u128 == i as u128
+                    }
+                };
+                (topic.topic_id, on)
+            })
+            .collect();
+        let tone =
+            (self.step == Step::Actuators && ms % CHASE_STEP_MS < BEEP_MS).then_some(BEEP_HZ);
+        Guide { leds, tone }
+    }
+
+    fn next(&mut self, step: Step) {
+        self.step = step;
+        self.step_started = clock::now();
+    }
+
+    fn report(&self) {
+        info!(
+            "Commissioning report, {} s:",
+            clock::since(self.started).as_secs()
+        );
+        for topic in &self.topics {
+            let label = names::label(topic.topic_id);
+            match (topic.first_frame, topic.rssi_average()) {
+                (None, _) => warn!("  {}: never heard", label),
+                (Some(first), None) => warn!(
+                    "  {}: first frame after {} s, no signal reading",
+                    label,
+                    first.as_secs()
+                ),
+                (Some(first), Some(average)) => {
+                    let grade = Grade::of(average);
+                    let line = format!(
+                        "  {}: first frame after {} s, RSSI {} dBm average, {} dBm worst over {} frames, {:?}",
+                        label,
+                        first.as_secs(),
+                        average,
+                        topic.rssi_min,
+                        topic.frames,
+                        grade
+                    );
+                    if grade == Grade::Poor {
+                        warn!("{}, move the sender or the hub", line);
+                    } else {
+                        info!("{}", line);
+                    }
+                }
+            }
+        }
+        if self.confirmed {
+            info!("  LEDs and buzzer: confirmed");
+        } else {
+            warn!("  LEDs and buzzer: not confirmed");
+        }
+    }
+}
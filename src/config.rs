@@ -0,0 +1,766 @@
+//! Export and import of the stored configuration as one signed blob
+//!
+//! The hub's settings kept in NVS are gathered into a single JSON document,
+//! one text section per store in that store's own format, and signed with
+//! HMAC-SHA256 under the `CONFIG_KEY` set at build time:
+//!
+//! `{"version":1,"sections":{"names":"1 kettle\n"},"hmac":"<64 hex digits>"}`
+//!
+//! The sections are the names, peers, profile, settings, thresholds,
+//! topics, timezone, quiet hours, channel and long-range mode. Left out, as
+//! they belong to the unit rather than its setup or are no setting at all:
+//!
+//! - the keys, secrets that never leave the hub, see below
+//! - the identity, the device UUID and its household claim, which a clone
+//!   must not share
+//! - the receiver id and groups, see the addressing module
+//! - the metrics, this unit's counters, and the schema version, its data's
+//! - this module's own revert point and deferred import
+//!
+//! The sounds live on the storage partition, not in NVS, and are uploaded
+//! on their own, see the sounds module.
+//!
+//! A blob only imports on a hub built with the same key, which makes it
+//! usable for backups and for cloning one receiver's setup onto another. An
+//! import checks every section before applying any, so a bad blob leaves the
+//! configuration untouched. The signature covers the exact text, so blobs
+//! must be passed on unchanged.
+//!
+//! Imports are staged: the configuration they replace is kept in NVS, and
+//! unless `config confirm` follows within the revert timeout the hub goes
+//! back to it. A reset before the confirmation reverts too, at the next
+//! boot, so an import that locks the hub out of the network undoes itself.
+//! Console changes that can cut the hub off the same way, the channel, the
+//! long-range mode and the keys, are staged alike: the revert point keeps
+//! the keys too, though no export carries them and no import sets them.
+//! The WiFi credentials are build-time settings, not stored, so they cannot
+//! be changed at runtime at all.
+//!
+//! With a maintenance window configured, e.g. 03:00 to 04:00 local time, an
+//! import outside it is checked and deferred instead: kept in NVS, across
+//! resets too, and applied once the window opens and no alarm is raised, so
+//! a push never disturbs the hub while a kettle alarm could be pending. A
+//! deferred import confirmed beforehand applies as confirmed, one not
+//! confirmed is staged as usual. A later import replaces a deferred one, and
+//! an urgent import applies right away. While the clock is not set the hour
+//! is unknown and imports apply right away.
+//!
+//! Served as `GET /config`, `PUT /config` (`PUT /config?urgent` to skip the
+//! window) and `POST /config/confirm` once the hub is on WiFi.
+
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+use log::{info, warn};
+
+use crate::auth;
+#[cfg(target_os = "espidf")]
+use crate::channel;
+use crate::clock;
+#[cfg(target_os = "espidf")]
+use crate::http::{authorize, read_body, refuse, respond};
+use crate::keys;
+#[cfg(target_os = "espidf")]
+use crate::long_range;
+use crate::names;
+use crate::pairing;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+use crate::profiles;
+use crate::quiet_hours;
+use crate::settings;
+use crate::thresholds;
+use crate::timezone;
+use crate::topics;
+
+const CONFIG_KEY: Option<&str> = option_env!("CONFIG_KEY");
+const HEADER: &str = r#"{"version":1,"sections":{"#;
+const SIGNATURE: &str = r#","hmac":""#;
+const MAX_BLOB: usize = 8192;
+// The configuration an unconfirmed import replaced, as an unsigned body
+const NAMESPACE: &str = "config";
+const ROLLBACK: &str = "rollback";
+// A deferred import as signed, and whether it was confirmed
+const DEFERRED: &str = "deferred";
+const DEFERRED_CONFIRMED: &str = "deferred_ok";
+
+static STAGING: Mutex<Staging> = Mutex::new(Staging {
+    nvs: None,
+    revert_at: None,
+    timeout: Duration::ZERO,
+    window: None,
+    deferred: None,
+    held: false,
+});
+
+struct Staging {
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// Set while an import waits for confirmation
+    revert_at: Option<Instant>,
+    timeout: Duration,
+    /// Local hours the maintenance window opens and closes at
+    window: Option<(u32, u32)>,
+    /// Set while a deferred import waits for the window, whether confirmed
+    deferred: Option<bool>,
+    /// An alarm holds the deferred import back, logged once
+    held: bool,
+}
+
+/// What became of an accepted import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Import {
+    /// Applied, reverting unless confirmed in time
+    Staged,
+    /// Kept for the maintenance window
+    Deferred,
+}
+
+/// One NVS-backed store taking part in export and import
+struct Section {
+    name: &'static str,
+    /// Whether blobs carry it; the others are only kept for a revert
+    exported: bool,
+    export: fn() -> String,
+    check: fn(&str) -> Result<(), &'static str>,
+    import: fn(&str) -> Result<(), &'static str>,
+}
+
+const SECTIONS: &[Section] = &[
+    Section {
+        name: "names",
+        exported: true,
+        export: names::list,
+        check: |table| names::parse_table(table).map(|_| ()),
+        import: names::replace,
+    },
+    Section {
+        name: "peers",
+        exported: true,
+        export: pairing::list,
+        check: |table| pairing::parse_table(table).map(|_| ()),
+        import: pairing::replace,
+    },
+    Section {
+        name: "profile",
+        exported: true,
+        export: || profiles::active().to_string(),
+        check: profiles::check,
+        import: profiles::select,
+    },
+    Section {
+        name: "settings",
+        exported: true,
+        export: settings::list,
+        check: settings::check,
+        import: settings::replace,
+    },
+    Section {
+        name: "thresholds",
+        exported: true,
+        export: thresholds::list,
+        check: |table| thresholds::parse_table(table).map(|_| ()),
+        import: thresholds::replace,
+    },
+    Section {
+        name: "topics",
+        exported: true,
+        export: topics::list,
+        check: topics::check,
+        import: topics::replace,
+    },
+    Section {
+        name: "timezone",
+        exported: true,
+        export: timezone::list,
+        check: timezone::check,
+        import: timezone::replace,
+    },
+    Section {
+        name: "quiet_hours",
+        exported: true,
+        export: quiet_hours::list,
+        check: quiet_hours::check,
+        import: quiet_hours::replace,
+    },
+    #[cfg(target_os = "espidf")]
+    Section {
+        name: "channel",
+        exported: true,
+        export: channel::list,
+        check: channel::check,
+        import: channel::replace,
+    },
+    #[cfg(target_os = "espidf")]
+    Section {
+        name: "long_range",
+        exported: true,
+        export: long_range::list,
+        check: long_range::check,
+        import: long_range::replace,
+    },
+    // After the peers, whose LMKs it holds
+    Section {
+        name: "keys",
+        exported: false,
+        export: keys::list,
+        check: keys::check,
+        import: keys::replace,
+    },
+];
+
+/// Open the staging store, reverting an import left unconfirmed by a reset
+///
+/// Imports wait `timeout` for their confirmation. With a `window` of local
+/// hours, wrapping past midnight, imports outside it are deferred to it.
+pub fn init(
+    partition: EspDefaultNvsPartition,
+    timeout: Duration,
+    window: Option<(u32, u32)>,
+) -> Result<(), EspError> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_BLOB];
+    if let Some(rollback) = nvs.get_blob(ROLLBACK, &mut buf)? {
+        warn!("Config: reset before the last import was confirmed, reverting");
+        match apply(&String::from_utf8_lossy(rollback)) {
+            Ok(()) => info!("Config: reverted"),
+            Err(e) => warn!("Config revert failed: {}", e),
+        }
+        nvs.remove(ROLLBACK)?;
+    }
+    let deferred = nvs
+        .contains(DEFERRED)?
+        .then(|| nvs.get_u8(DEFERRED_CONFIRMED).ok().flatten() == Some(1));
+    if deferred.is_some() {
+        info!("Config: an import is deferred to the maintenance window");
+    }
+
+    let mut staging = STAGING.lock().unwrap();
+    staging.nvs = Some(nvs);
+    staging.timeout = timeout;
+    staging.window = window;
+    staging.deferred = deferred;
+    Ok(())
+}
+
+/// The signed blob of the current configuration
+pub fn export() -> Result<String, &'static str> {
+    let mut body = current_body(true);
+    let hmac = sign(&body)?;
+    body.push_str(SIGNATURE);
+    for byte in hmac {
+        body.push_str(&format!("{:02x}", byte));
+    }
+    body.push_str("\"}");
+    Ok(body)
+}
+
+/// The unsigned sections of the current configuration, only those blobs
+/// carry if `exported`
+fn current_body(exported: bool) -> String {
+    let mut body = String::from(HEADER);
+    let sections = SECTIONS.iter().filter(|s| s.exported || !exported);
+    for (i, section) in sections.enumerate() {
+        if i > 0 {
+            body.push(',');
+        }
+        push_string(&mut body, section.name);
+        body.push(':');
+        push_string(&mut body, &(section.export)());
+    }
+    body.push('}');
+    body
+}
+
+/// Check the signature and every section of `blob`, then stage them all,
+/// or defer them to the maintenance window unless `urgent`
+///
+/// Until [`confirm`] is called the previous configuration is kept for the
+/// revert. Further imports in that time keep the same revert point.
+pub fn import(blob: &str, urgent: bool) -> Result<Import, &'static str> {
+    let blob = blob.trim();
+    let sections = verify(blob)?;
+    let mut staging = STAGING.lock().unwrap();
+    if !urgent {
+        if let Some((from, to)) = staging.window.filter(|&w| !in_window(w)) {
+            staging.defer(blob)?;
+            info!(
+                "Config: import deferred to the maintenance window, {:02}:00 to {:02}:00",
+                from, to
+            );
+            return Ok(Import::Deferred);
+        }
+    }
+    if staging.deferred.is_some() {
+        staging.drop_deferred();
+        info!("Config: deferred import replaced");
+    }
+    stage(staging, "imported", || apply_sections(sections))?;
+    Ok(Import::Staged)
+}
+
+/// The checked sections of a signed `blob`
+fn verify(blob: &str) -> Result<Vec<(&'static Section, String)>, &'static str> {
+    let split = blob.rfind(SIGNATURE).ok_or("not a config blob")?;
+    let (body, signature) = (&blob[..split], &blob[split + SIGNATURE.len()..]);
+    let signature = signature.strip_suffix("\"}").ok_or("not a config blob")?;
+    if signature.len() != 64 || !signature.is_ascii() {
+        return Err("bad signature");
+    }
+    let expected = sign(body)?;
+    // Compare every byte so the time taken gives nothing away
+    let mut diff = 0;
+    for (i, byte) in expected.iter().enumerate() {
+        let given =
+            u8::from_str_radix(&signature[2 * i..2 * i + 2], 16).map_err(|_| "bad signature")?;
+        diff |= given ^ byte;
+    }
+    if diff != 0 {
+        return Err("signature does not match, wrong CONFIG_KEY or altered blob");
+    }
+    check(body, true)
+}
+
+/// Make a console change with `change`, staged like an import
+///
+/// The configuration it replaces is kept, and unless [`confirm`] follows in
+/// time the hub goes back to it.
+pub fn stage_change(change: impl FnOnce() -> Result<(), &'static str>) -> Result<(), &'static str> {
+    stage(STAGING.lock().unwrap(), "changed", change)
+}
+
+/// Make the change `what` with `change`, keeping the configuration it
+/// replaces for the revert
+fn stage(
+    mut staging: MutexGuard<Staging>,
+    what: &str,
+    change: impl FnOnce() -> Result<(), &'static str>,
+) -> Result<(), &'static str> {
+    let timeout = staging.timeout;
+    let nvs = staging.nvs.as_mut().ok_or("NVS unavailable")?;
+    if !nvs.contains(ROLLBACK).unwrap_or(false) {
+        nvs.set_blob(ROLLBACK, current_body(false).as_bytes())
+            .map_err(|_| "could not keep the current config for the revert")?;
+    }
+    if let Err(e) = change() {
+        drop(staging);
+        revert();
+        return Err(e);
+    }
+    staging.revert_at = Some(clock::now() + timeout);
+    info!(
+        "Config: {}, reverting in {} min unless confirmed",
+        what,
+        timeout.as_secs() / 60
+    );
+    Ok(())
+}
+
+/// Keep the imported configuration, or apply the deferred one as confirmed
+pub fn confirm() -> Result<(), &'static str> {
+    let mut staging = STAGING.lock().unwrap();
+    if staging.revert_at.take().is_none() {
+        if staging.deferred != Some(false) {
+            return Err("no import waiting for confirmation");
+        }
+        let nvs = staging.nvs.as_mut().ok_or("NVS unavailable")?;
+        nvs.set_u8(DEFERRED_CONFIRMED, 1)
+            .map_err(|_| "could not store the confirmation")?;
+        staging.deferred = Some(true);
+        info!("Config: deferred import confirmed, applying in the maintenance window");
+        return Ok(());
+    }
+    if let Some(nvs) = staging.nvs.as_mut() {
+        nvs.remove(ROLLBACK)
+            .map_err(|_| "could not drop the revert point")?;
+    }
+    info!("Config: import confirmed");
+    Ok(())
+}
+
+/// Time left to confirm an import before it reverts
+pub fn revert_in() -> Option<Duration> {
+    let revert_at = STAGING.lock().unwrap().revert_at?;
+    Some(revert_at.saturating_duration_since(clock::now()))
+}
+
+/// Whether an import is deferred to the maintenance window, and confirmed
+pub fn deferred() -> Option<bool> {
+    STAGING.lock().unwrap().deferred
+}
+
+/// Revert an import whose confirmation is overdue, and apply a deferred one
+/// once the window is open and no alarm `alarm_active`
+pub fn poll(alarm_active: bool) {
+    let overdue = STAGING
+        .lock()
+        .unwrap()
+        .revert_at
+        .is_some_and(|t| clock::now() >= t);
+    if overdue {
+        warn!("Config: import not confirmed in time, reverting");
+        revert();
+    }
+
+    let mut staging = STAGING.lock().unwrap();
+    // A staged import settles first, confirmed or reverted
+    let Some(confirmed) = staging.deferred.filter(|_| staging.revert_at.is_none()) else {
+        return;
+    };
+    if staging.window.is_some_and(|w| !in_window(w)) {
+        staging.held = false;
+        return;
+    }
+    if alarm_active {
+        if !staging.held {
+            info!("Config: deferred import held back while an alarm is raised");
+            staging.held = true;
+        }
+        return;
+    }
+    let Some(nvs) = staging.nvs.as_mut() else {
+        return;
+    };
+    let mut buf = vec![0u8; MAX_BLOB];
+    let blob = match nvs.get_blob(DEFERRED, &mut buf) {
+        Ok(Some(blob)) => String::from_utf8_lossy(blob).into_owned(),
+        Ok(None) => String::new(),
+        Err(e) => {
+            warn!("Deferred config import unreadable: {}", e);
+            String::new()
+        }
+    };
+    staging.drop_deferred();
+    let result = verify(&blob).and_then(|sections| {
+        if confirmed {
+            apply_sections(sections)
+        } else {
+            stage(staging, "imported", || apply_sections(sections))
+        }
+    });
+    match result {
+        Ok(()) if confirmed => info!("Config: deferred import applied"),
+        Ok(()) => {}
+        Err(e) => warn!("Deferred config import failed: {}", e),
+    }
+}
+
+impl Staging {
+    /// Keep `blob` for the maintenance window, replacing a deferred one
+    fn defer(&mut self, blob: &str) -> Result<(), &'static str> {
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable")?;
+        nvs.set_blob(DEFERRED, blob.as_bytes())
+            .and_then(|_| nvs.remove(DEFERRED_CONFIRMED))
+            .map_err(|_| "could not keep the import for the maintenance window")?;
+        self.deferred = Some(false);
+        self.held = false;
+        Ok(())
+    }
+
+    fn drop_deferred(&mut self) {
+        self.deferred = None;
+        self.held = false;
+        if let Some(nvs) = self.nvs.as_mut() {
+            nvs.remove(DEFERRED).ok();
+            nvs.remove(DEFERRED_CONFIRMED).ok();
+        }
+    }
+}
+
+/// Whether the local hour is in `window`, assumed with the clock not set
+fn in_window((from, to): (u32, u32)) -> bool {
+    let now = clock::unix_secs();
+    if now < clock::VALID_AFTER {
+        return true;
+    }
+    let hour = timezone::local(now).hour;
+    if from <= to {
+        (from..to).contains(&hour)
+    } else {
+        hour >= from || hour < to
+    }
+}
+
+/// Go back to the configuration before the first unconfirmed import
+fn revert() {
+    let mut staging = STAGING.lock().unwrap();
+    staging.revert_at = None;
+    let Some(nvs) = staging.nvs.as_mut() else {
+        return;
+    };
+    let mut buf = vec![0u8; MAX_BLOB];
+    let rollback = match nvs.get_blob(ROLLBACK, &mut buf) {
+        Ok(Some(rollback)) => String::from_utf8_lossy(rollback).into_owned(),
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Config revert failed: {}", e);
+            return;
+        }
+    };
+    match apply(&rollback) {
+        Ok(()) => info!("Config: reverted"),
+        Err(e) => warn!("Config revert failed: {}", e),
+    }
+    nvs.remove(ROLLBACK).ok();
+}
+
+/// Check every section of an unsigned revert point `body`, then apply them
+fn apply(body: &str) -> Result<(), &'static str> {
+    apply_sections(check(body, false)?)
+}
+
+/// The sections of an unsigned `body`, each checked by its store, only
+/// those blobs carry if `exported`
+fn check(body: &str, exported: bool) -> Result<Vec<(&'static Section, String)>, &'static str> {
+    let mut checked = Vec::new();
+    for (name, text) in parse_sections(body)? {
+        let section = SECTIONS
+            .iter()
+            .find(|s| s.name == name && (s.exported || !exported))
+            .ok_or("unknown section")?;
+        (section.check)(&text)?;
+        checked.push((section, text));
+    }
+    Ok(checked)
+}
+
+fn apply_sections(sections: Vec<(&'static Section, String)>) -> Result<(), &'static str> {
+    for (section, text) in sections {
+        (section.import)(&text)?;
+        info!("Config: {} applied", section.name);
+    }
+    Ok(())
+}
+
+/// Serve the export and import endpoints on `server`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/config", Method::Get, |req| match export() {
+        Ok(blob) => req.into_ok_response()?.write_all(blob.as_bytes()),
+        Err(e) => respond(req, Err(e)),
+    })?;
+
+    server.fn_handler("/config", Method::Put, |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let urgent = req
+            .uri()
+            .split_once('?')
+            .is_some_and(|(_, query)| query.split('&').any(|q| q == "urgent"));
+        match read_body(&mut req, MAX_BLOB)?.and_then(|blob| import(&blob, urgent)) {
+            Ok(Import::Deferred) => req.into_ok_response()?.write_all(b"deferred\n"),
+            result => respond(req, result.map(|_| ())),
+        }
+    })?;
+
+    server.fn_handler("/config/confirm", Method::Post, |req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        respond(req, confirm())
+    })?;
+
+    info!("Config export and import served on /config");
+    Ok(())
+}
+
+fn sign(body: &str) -> Result<[u8; 32], &'static str> {
+    let key = CONFIG_KEY.ok_or("CONFIG_KEY not set at build time")?;
+    auth::hmac_sha256(key.as_bytes(), body.as_bytes()).ok_or("signing failed")
+}
+
+/// The `"name":"text"` pairs of a signed body
+fn parse_sections(body: &str) -> Result<Vec<(String, String)>, &'static str> {
+    let mut rest = body
+        .strip_prefix(HEADER)
+        .and_then(|b| b.strip_suffix('}'))
+        .ok_or("unsupported config version")?;
+
+    let mut sections = Vec::new();
+    while !rest.is_empty() {
+        if !sections.is_empty() {
+            rest = rest.strip_prefix(',').ok_or("malformed sections")?;
+        }
+        let (name, after) = take_string(rest)?;
+        let after = after.strip_prefix(':').ok_or("malformed sections")?;
+        let (text, after) = take_string(after)?;
+        sections.push((name, text));
+        rest = after;
+    }
+    Ok(sections)
+}
+
+/// Append `text` as a JSON string
+fn push_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Split a leading JSON string off `s`, returning it unescaped
+fn take_string(s: &str) -> Result<(String, &str), &'static str> {
+    let mut chars = s
+        .strip_prefix('"')
+        .ok_or("expected a string")?
+        .char_indices();
+    let mut text = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, &s[1 + i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => text.push('"'),
+                Some('\\') => text.push('\\'),
+                Some('/') => text.push('/'),
+                Some('n') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some('r') => text.push('\r'),
+                Some('u') => {
+                    let hex: String = (0..4)
+                        .filter_map(|_| chars.next().map(|(_, c)| c))
+                        .collect();
+                    let code = u32::from_str_radix(&hex, 16).map_err(|_| "bad escape")?;
+                    text.push(char::from_u32(code).ok_or("bad escape")?);
+                }
+                _ => return Err("bad escape"),
+            },
+            c => text.push(c),
+        }
+    }
+    Err("unterminated string")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Priority;
+    use crate::names::Key;
+    use crate::profiles::Profile;
+    use crate::quiet_hours::{Mode, Window};
+    use crate::thresholds::{Limit, Rule};
+    use crate::topics::Topic;
+    use crate::transforms::{Pipeline, Stage};
+
+    // A topic of its own, as the stores are shared with other tests
+    const GARAGE: i32 = 41;
+    const REVERT: Duration = Duration::from_secs(600);
+    const SECRET: [u8; keys::KEY_LEN] = [7; keys::KEY_LEN];
+
+    static PROFILES: [Profile; 2] = [
+        Profile {
+            name: "home",
+            muted: &[],
+            away: false,
+        },
+        Profile {
+            name: "garage_off",
+            muted: &[GARAGE],
+            away: false,
+        },
+    ];
+    static RULES: [Rule; 1] = [Rule {
+        topic_id: GARAGE,
+        alarm: Limit::Above(30),
+        priority: Priority::Warning,
+        bands: &[],
+        requires: &[],
+    }];
+    static PIPELINES: [Pipeline; 1] = [Pipeline {
+        handler: "generic",
+        valid: i32::MIN..=i32::MAX,
+        stages: &[Stage::Threshold],
+    }];
+    const TOPICS: [Topic; 0] = [];
+
+    /// Every store and the staging on one fresh partition
+    fn stores() {
+        let nvs = EspDefaultNvsPartition::take().unwrap();
+        names::load(nvs.clone()).unwrap();
+        pairing::load(nvs.clone()).unwrap();
+        profiles::init(Some(nvs.clone()), &PROFILES).unwrap();
+        settings::init(Some(nvs.clone()), &[]).unwrap();
+        thresholds::init(Some(nvs.clone()), &RULES).unwrap();
+        topics::init(Some(nvs.clone()), &TOPICS, &PIPELINES).unwrap();
+        timezone::init(Some(nvs.clone()), "UTC").unwrap();
+        quiet_hours::init(Some(nvs.clone()), None).unwrap();
+        keys::load(nvs.clone()).unwrap();
+        init(nvs, REVERT, None).unwrap();
+    }
+
+    #[test]
+    fn exports_import_back_unchanged() {
+        let _turn = clock::mock();
+        stores();
+        let garage = Key::parse("41").unwrap();
+        names::set(garage, Some("garage")).unwrap();
+        topics::set("garage/temp", Some((GARAGE, "generic"))).unwrap();
+        thresholds::set(GARAGE, 35).unwrap();
+        profiles::select("garage_off").unwrap();
+        timezone::set(Some("Europe/Berlin")).unwrap();
+        quiet_hours::set(Some(Window {
+            from_hour: 22,
+            to_hour: 7,
+            mode: Mode::LedOnly,
+        }))
+        .unwrap();
+        let exported = current_body(true);
+
+        names::set(garage, None).unwrap();
+        topics::set("garage/temp", None).unwrap();
+        thresholds::set(GARAGE, 60).unwrap();
+        profiles::select("home").unwrap();
+        timezone::set(None).unwrap();
+        quiet_hours::set(None).unwrap();
+        assert_ne!(current_body(true), exported);
+
+        apply_sections(check(&exported, true).unwrap()).unwrap();
+        assert_eq!(current_body(true), exported);
+        timezone::set(None).unwrap();
+    }
+
+    #[test]
+    fn keys_only_go_into_the_revert_point() {
+        let _turn = clock::mock();
+        stores();
+        keys::set_hmac_secret(Some(SECRET)).unwrap();
+
+        assert!(!current_body(true).contains("\"keys\""));
+        let rollback = current_body(false);
+        assert!(rollback.contains("\"keys\""));
+        assert_eq!(check(&rollback, true).err(), Some("unknown section"));
+        assert!(check(&rollback, false).is_ok());
+        keys::set_hmac_secret(None).unwrap();
+    }
+
+    #[test]
+    fn console_changes_revert_unless_confirmed() {
+        let (_turn, clock) = clock::mock();
+        stores();
+
+        stage_change(|| keys::set_hmac_secret(Some(SECRET))).unwrap();
+        assert_eq!(keys::hmac_secret(), Some(SECRET));
+        clock.advance(REVERT);
+        poll(false);
+        assert_eq!(keys::hmac_secret(), None);
+
+        stage_change(|| keys::set_hmac_secret(Some(SECRET))).unwrap();
+        confirm().unwrap();
+        clock.advance(REVERT);
+        poll(false);
+        assert_eq!(keys::hmac_secret(), Some(SECRET));
+        keys::set_hmac_secret(None).unwrap();
+    }
+}
@@ -0,0 +1,789 @@
+//! Line commands over the serial console
+//!
+//! A background thread collects lines typed on UART0, the USB serial port of
+//! a devkit, and hands parsed commands to the main loop, which applies them
+//! between frames through the control module, so a unit in the field can be
+//! looked into and adjusted without reflashing:
+//!
+//! - `status` prints the status event as one JSON line
+//! - `reboot` restarts the hub, `sleep` puts it into deep sleep until the
+//!   wake button
+//! - `log level` shows the log level, `log level <level>` sets it until the
+//!   next boot, one of off, error, warn, info, debug and trace
+//! - `map` lists which GPIO drives each topic's alarm LED
+//! - `map <topic_id> <gpio>` moves a topic's alarm LED to another pin
+//! - `map <topic_id> off` detaches a topic's alarm LED
+//! - `selftest` checks the ESP-NOW transmit and receive path
+//! - `bench [seconds]` measures the receive throughput, 10 s by default
+//! - `inspect` dumps the runtime state for a bug report
+//! - `support on` turns on verbose logs and diagnostics streaming for an
+//!   hour, `support off` ends it early and `support` shows it
+//! - `channel` shows the ESP-NOW channel, `channel <1-13>` stores one,
+//!   `channel default` forgets it and `channel scan` locks on to a sender
+//! - `timezone` shows the timezone and local time, `timezone <zone>` sets
+//!   one, a POSIX TZ string or a listed name, and `timezone default` goes
+//!   back to the built-in one
+//! - `longrange` shows whether ESP-NOW uses 802.11 LR, `longrange on|off`
+//!   switches it
+//! - `quiet` shows the quiet hours, `quiet <from>-<to> [reduced|led_only]`
+//!   sets them by the hour, `quiet off` turns them off and `quiet default`
+//!   goes back to the built-in ones
+//! - `capture file|tcp` records the ESP-NOW frames on the channel as PCAP,
+//!   `capture off` stops and `capture` shows the capture
+//! - `commission` guides an installer through checking the senders and
+//!   outputs, on the LEDs
+//! - `names` lists the sensor names
+//! - `name <topic_id>[@<MAC>] <name>` names a sensor, `-` removes the name
+//! - `topics` lists the topic registry, `topic <name> <topic_id> <handler>`
+//!   registers a topic and `topic <name> -` removes it
+//! - `senders` lists the senders with the firmware versions they announced
+//!   and the layout of their last data frame
+//! - `pair` opens a pairing window, the PIN shows on the LEDs and console
+//! - `peers` lists the paired senders, `unpair <MAC>` forgets one
+//! - `legacy <MAC> on|off` takes or refuses the fixed frames without a CRC
+//!   of an older sender, pairing it to take them
+//! - `keys` shows which keys are set, `key pmk <hex>`, `key <MAC> <hex>`,
+//!   `key hmac <hex>` and `key api <hex>` set one, `-` for the key removes
+//!   it; on this console only
+//! - `thresholds` lists the alarm limits, `threshold <topic> <n>` or `set
+//!   threshold <topic> <n>` sets one, the topic by id or registered name,
+//!   e.g. `set threshold kettle 55`
+//! - `bands <topic> <n> <priority> ...` replaces the priority bands past a
+//!   topic's limit, e.g. `bands kettle 70 warning 95 critical`, `bands
+//!   <topic> off` drops them and `bands <topic> default` goes back to the
+//!   built-in ones
+//! - `settings` lists the runtime settings, `set <name> <seconds>` sets one
+//!   and `set <name> default` goes back to the built-in value; per-topic
+//!   timings are named `<name>.<topic_id>`, e.g. `set cooldown.2 120`
+//! - `address` shows the receiver id and groups, `address id <n|off>` sets
+//!   the id and `address join|leave <group>` the groups
+//! - `send <all|r<id>|g<group>> silence|reboot|selftest` sends a command to
+//!   other receivers, `send <to> threshold <topic_id> <n>` moves their limit
+//!   and `send <to> alarm <topic_id> <n>` raises a critical alarm on them
+//! - `learn <topic_id> [hours]` learns a topic's normal range and proposes a
+//!   limit, `learn` shows progress, `learn accept` or `learn cancel` follow
+//! - `identity` shows the device UUID and claim, `claim <household>` and
+//!   `unclaim` change the claim
+//! - `profile` shows the active alarm profile, `profile <name>` switches
+//! - `away <days>` switches to the away profile until then, `away off` ends
+//!   it early
+//! - `config export` prints the signed configuration blob
+//! - `config import [urgent] <blob>` applies a blob from `config export`,
+//!   reverting unless `config confirm` follows in time; outside the
+//!   maintenance window it waits for it unless `urgent`
+//!
+//! `channel`, `longrange` and `key` changes are staged like an import and
+//! revert unless `config confirm` follows in time.
+//!
+//! Once the hub is on WiFi the same lines are taken over HTTP too, one per
+//! `POST /console` carrying the API token, so hubctl (`src/bin/hubctl.rs`)
+//! can run them without a serial cable. Key commands are refused there. The
+//! answer only says whether the line parsed; the command's output goes to
+//! the log as usual.
+
+use std::io::{self, ErrorKind, Read};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::sys::EspError;
+use log::{info, warn, LevelFilter};
+
+use crate::addressing;
+use crate::alerts::Priority;
+use crate::capture::Target;
+use crate::channel::MAX_CHANNEL;
+use crate::http::{authorize, read_body, refuse, respond};
+use crate::keys::{self, KEY_LEN};
+use crate::names::Key;
+use crate::protocol::{Address, Command as RemoteCommand};
+use crate::quiet_hours::{Mode, Window};
+use crate::settings::Setting;
+use crate::thresholds::{self, DEFAULT_LEARN_HOURS, MAX_LEARN_HOURS};
+use crate::topics;
+use crate::uplink::parse_mac;
+
+const STACK_SIZE: usize = 4096;
+// stdin is non-blocking on ESP-IDF, poll it at this interval when idle
+const IDLE_POLL: Duration = Duration::from_millis(50);
+// Long enough for a pasted config blob
+const MAX_LINE: usize = 8192;
+const BENCH_DEFAULT_S: u32 = 10;
+const BENCH_MAX_S: u32 = 600;
+const AWAY_MAX_DAYS: u32 = 365;
+
+// The main loop's end of the console, for lines from HTTP
+static SENDER: Mutex<Option<Sender<Command>>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Status,
+    Reboot,
+    Sleep,
+    ShowLogLevel,
+    LogLevel {
+        level: LevelFilter,
+    },
+    ShowMap,
+    MapLed {
+        topic_id: i32,
+        gpio: Option<i32>,
+    },
+    SelfTest,
+    Inspect,
+    ShowSupport,
+    Support {
+        on: bool,
+    },
+    Bench {
+        seconds: u32,
+    },
+    ShowTimezone,
+    /// Set a timezone, or go back to the default with `None`
+    Timezone {
+        zone: Option<String>,
+    },
+    ShowLongRange,
+    LongRange {
+        on: bool,
+    },
+    ShowQuietHours,
+    /// Set quiet hours, or turn them off with `None`
+    QuietHours {
+        window: Option<Window>,
+    },
+    QuietHoursDefault,
+    ShowChannel,
+    /// Store an ESP-NOW channel, or forget it with `None`
+    Channel {
+        channel: Option<u8>,
+    },
+    ChannelScan,
+    ShowCapture,
+    /// Start capturing to `target`, or stop with `None`
+    Capture {
+        target: Option<Target>,
+    },
+    Commission,
+    ShowNames,
+    Name {
+        key: Key,
+        name: Option<String>,
+    },
+    ShowTopics,
+    /// Register `name` as a topic id and handler, or remove it with `None`
+    Topic {
+        name: String,
+        topic: Option<(i32, String)>,
+    },
+    ConfigExport,
+    ConfigImport {
+        blob: String,
+        /// Apply outside the maintenance window too
+        urgent: bool,
+    },
+    ConfigConfirm,
+    ShowSenders,
+    Pair,
+    ShowPeers,
+    Unpair {
+        mac: [u8; 6],
+    },
+    Legacy {
+        mac: [u8; 6],
+        on: bool,
+    },
+    ShowKeys,
+    /// Set or remove the PMK (`mac: None`) or a sender's LMK
+    Key {
+        mac: Option<[u8; 6]>,
+        key: Option<[u8; KEY_LEN]>,
+    },
+    /// Set or remove the HMAC secret
+    Secret {
+        key: Option<[u8; KEY_LEN]>,
+    },
+    /// Set or remove the HTTP API token
+    ApiToken {
+        key: Option<[u8; KEY_LEN]>,
+    },
+    ShowThresholds,
+    Threshold {
+        topic_id: i32,
+        value: i32,
+    },
+    /// Replace the bands of `topic_id` with `(limit, priority)` pairs, or go
+    /// back to the built-in ones with `None`
+    Bands {
+        topic_id: i32,
+        bands: Option<Vec<(i32, Priority)>>,
+    },
+    ShowSettings,
+    /// Set `setting` in seconds, or go back to its default with `None`
+    Set {
+        setting: Setting,
+        secs: Option<u32>,
+    },
+    ShowAddress,
+    /// Set the receiver id, `None` for none
+    AddressId {
+        receiver: Option<u16>,
+    },
+    /// Join `group`, or leave it
+    AddressGroup {
+        group: u8,
+        member: bool,
+    },
+    /// Send `command` to the receivers `to`
+    SendCommand {
+        to: Address,
+        command: RemoteCommand,
+    },
+    /// Raise a critical alarm on the receivers `to`
+    SendAlarm {
+        to: Address,
+        topic_id: i32,
+        measurement: i32,
+    },
+    Learn {
+        topic_id: i32,
+        hours: u32,
+    },
+    LearnStatus,
+    LearnAccept,
+    LearnCancel,
+    Away {
+        days: u32,
+    },
+    Back,
+    ShowIdentity,
+    Claim {
+        household: String,
+    },
+    Unclaim,
+    ShowProfile,
+    Profile {
+        name: String,
+    },
+}
+
+impl Command {
+    /// Whether the command sets a key, which is refused over HTTP
+    pub fn serial_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Key { .. } | Command::Secret { .. } | Command::ApiToken { .. }
+        )
+    }
+}
+
+pub struct Console {
+    commands: Receiver<Command>,
+}
+
+impl Console {
+    pub fn start() -> io::Result<Self> {
+        let (tx, commands) = mpsc::channel();
+        *SENDER.lock().unwrap() = Some(tx.clone());
+        thread::Builder::new()
+            .name("console".into())
+            .stack_size(STACK_SIZE)
+            .spawn(move || read_lines(tx))?;
+
+        Ok(Self { commands })
+    }
+
+    pub fn try_recv(&self) -> Option<Command> {
+        self.commands.try_recv().ok()
+    }
+}
+
+/// Serve `POST /console` on `server`, the body a console line
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/console", Method::Post, |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = read_body(&mut req, MAX_LINE)?.and_then(|line| {
+            let command = parse(line.trim())?;
+            if command.serial_only() {
+                return Err("keys are only set on the serial console");
+            }
+            let sender = SENDER.lock().unwrap();
+            let sender = sender.as_ref().ok_or("console not running")?;
+            sender.send(command).map_err(|_| "console not running")
+        });
+        respond(req, result)
+    })?;
+    info!("Console served on /console");
+    Ok(())
+}
+
+fn read_lines(tx: Sender<Command>) {
+    let mut stdin = io::stdin();
+    let mut line = Vec::with_capacity(MAX_LINE);
+    let mut byte = [0u8; 1];
+
+    loop {
+        match stdin.read(&mut byte) {
+            Ok(1) => {}
+            Ok(_) => {
+                thread::sleep(IDLE_POLL);
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(IDLE_POLL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Console read failed, console disabled: {}", e);
+                return;
+            }
+        }
+
+        match byte[0] {
+            b'\r' | b'\n' => {
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim();
+                if !text.is_empty() {
+                    match parse(text) {
+                        Ok(command) => {
+                            if tx.send(command).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => warn!("{}: {}", text, e),
+                    }
+                }
+                line.clear();
+            }
+            b if line.len() < MAX_LINE => line.push(b),
+            _ => {}
+        }
+    }
+}
+
+fn parse(line: &str) -> Result<Command, &'static str> {
+    // The blob holds spaces of its own, take the rest of the line as is
+    if let Some(blob) = line.strip_prefix("config import ") {
+        let blob = blob.trim_start();
+        let (blob, urgent) = match blob.strip_prefix("urgent ") {
+            Some(blob) => (blob, true),
+            None => (blob, false),
+        };
+        return Ok(Command::ConfigImport {
+            blob: blob.trim().to_string(),
+            urgent,
+        });
+    }
+    let mut words = line.split_whitespace();
+
+    match words.next() {
+        Some("status") if words.next().is_none() => return Ok(Command::Status),
+        Some("status") => return Err("usage: status"),
+        Some("reboot") if words.next().is_none() => return Ok(Command::Reboot),
+        Some("reboot") => return Err("usage: reboot"),
+        Some("sleep") if words.next().is_none() => return Ok(Command::Sleep),
+        Some("sleep") => return Err("usage: sleep"),
+        Some("log") => {
+            return match (words.next(), words.next(), words.next()) {
+                (Some("level"), None, _) => Ok(Command::ShowLogLevel),
+                (Some("level"), Some(level), None) => Ok(Command::LogLevel {
+                    level: level.parse().map_err(|_| "unknown log level")?,
+                }),
+                _ => Err("usage: log level [off | error | warn | info | debug | trace]"),
+            }
+        }
+        Some("map") => {}
+        Some("selftest") if words.next().is_none() => return Ok(Command::SelfTest),
+        Some("selftest") => return Err("usage: selftest"),
+        Some("inspect") if words.next().is_none() => return Ok(Command::Inspect),
+        Some("inspect") => return Err("usage: inspect"),
+        Some("support") => {
+            return match (words.next(), words.next()) {
+                (None, _) => Ok(Command::ShowSupport),
+                (Some("on"), None) => Ok(Command::Support { on: true }),
+                (Some("off"), None) => Ok(Command::Support { on: false }),
+                _ => Err("usage: support [on | off]"),
+            };
+        }
+        Some("bench") => return parse_bench(words.next(), words.next()),
+        Some("timezone") => {
+            return match (words.next(), words.next()) {
+                (None, _) => Ok(Command::ShowTimezone),
+                (Some("default"), None) => Ok(Command::Timezone { zone: None }),
+                (Some(zone), None) => Ok(Command::Timezone {
+                    zone: Some(zone.to_string()),
+                }),
+                _ => Err("usage: timezone [<zone> | default]"),
+            };
+        }
+        Some("longrange") => {
+            return match (words.next(), words.next()) {
+                (None, _) => Ok(Command::ShowLongRange),
+                (Some("on"), None) => Ok(Command::LongRange { on: true }),
+                (Some("off"), None) => Ok(Command::LongRange { on: false }),
+                _ => Err("usage: longrange [on | off]"),
+            };
+        }
+        Some("quiet") => return parse_quiet(words.next(), words.next(), words.next()),
+        Some("channel") => {
+            let channel = match (words.next(), words.next()) {
+                (None, _) => return Ok(Command::ShowChannel),
+                (Some("scan"), None) => return Ok(Command::ChannelScan),
+                (Some("default"), None) => None,
+                (Some(n), None) => match n.parse() {
+                    Ok(n) if (1..=MAX_CHANNEL).contains(&n) => Some(n),
+                    _ => return Err("channel must be 1 to 13"),
+                },
+                _ => return Err("usage: channel [<1-13> | default | scan]"),
+            };
+            return Ok(Command::Channel { channel });
+        }
+        Some("capture") => {
+            let target = match (words.next(), words.next()) {
+                (None, _) => return Ok(Command::ShowCapture),
+                (Some("file"), None) => Some(Target::File),
+                (Some("tcp"), None) => Some(Target::Tcp),
+                (Some("off"), None) => None,
+                _ => return Err("usage: capture [file | tcp | off]"),
+            };
+            return Ok(Command::Capture { target });
+        }
+        Some("commission") if words.next().is_none() => return Ok(Command::Commission),
+        Some("commission") => return Err("usage: commission"),
+        Some("names") if words.next().is_none() => return Ok(Command::ShowNames),
+        Some("names") => return Err("usage: names"),
+        Some("topics") if words.next().is_none() => return Ok(Command::ShowTopics),
+        Some("topics") => return Err("usage: topics"),
+        Some("topic") => {
+            let topic = match (words.next(), words.next(), words.next(), words.next()) {
+                (Some(name), Some("-"), None, None) => (name, None),
+                (Some(name), Some(topic_id), Some(handler), None) => {
+                    let topic_id = topic_id.parse().map_err(|_| "invalid topic id")?;
+                    (name, Some((topic_id, handler.to_string())))
+                }
+                _ => return Err("usage: topic <name> <topic_id> <handler> | topic <name> -"),
+            };
+            return Ok(Command::Topic {
+                name: topic.0.to_string(),
+                topic: topic.1,
+            });
+        }
+        Some("senders") if words.next().is_none() => return Ok(Command::ShowSenders),
+        Some("senders") => return Err("usage: senders"),
+        Some("pair") if words.next().is_none() => return Ok(Command::Pair),
+        Some("pair") => return Err("usage: pair"),
+        Some("peers") if words.next().is_none() => return Ok(Command::ShowPeers),
+        Some("peers") => return Err("usage: peers"),
+        Some("unpair") => {
+            return match (words.next().map(parse_mac), words.next()) {
+                (Some(Some(mac)), None) => Ok(Command::Unpair { mac }),
+                (Some(None), None) => Err("invalid MAC"),
+                _ => Err("usage: unpair <MAC>"),
+            }
+        }
+        Some("legacy") => {
+            let mac = words.next().map(parse_mac);
+            let on = match words.next() {
+                Some("on") => Some(true),
+                Some("off") => Some(false),
+                _ => None,
+            };
+            return match (mac, on, words.next()) {
+                (Some(Some(mac)), Some(on), None) => Ok(Command::Legacy { mac, on }),
+                (Some(None), Some(_), None) => Err("invalid MAC"),
+                _ => Err("usage: legacy <MAC> on|off"),
+            };
+        }
+        Some("keys") if words.next().is_none() => return Ok(Command::ShowKeys),
+        Some("keys") => return Err("usage: keys"),
+        Some("key") => return parse_key(words.next(), words.next(), words.next()),
+        Some("thresholds") if words.next().is_none() => return Ok(Command::ShowThresholds),
+        Some("thresholds") => return Err("usage: thresholds"),
+        Some("threshold") => {
+            return match (words.next(), words.next(), words.next()) {
+                (Some(topic), Some(value), None) => parse_threshold(topic, value),
+                _ => Err("usage: threshold <topic> <n>"),
+            }
+        }
+        Some("bands") => return parse_bands(words),
+        Some("settings") if words.next().is_none() => return Ok(Command::ShowSettings),
+        Some("settings") => return Err("usage: settings"),
+        Some("set") => {
+            return match (words.next(), words.next(), words.next(), words.next()) {
+                (Some("threshold"), Some(topic), Some(value), None) => {
+                    parse_threshold(topic, value)
+                }
+                (Some(name), Some(value), None, None) => Ok(Command::Set {
+                    setting: Setting::parse(name).ok_or("unknown setting, see `settings`")?,
+                    secs: match value {
+                        "default" => None,
+                        secs => Some(secs.parse().map_err(|_| "invalid seconds")?),
+                    },
+                }),
+                _ => Err("usage: set <name> <seconds | default> | set threshold <topic> <n>"),
+            }
+        }
+        Some("address") => {
+            return match (words.next(), words.next(), words.next()) {
+                (None, _, _) => Ok(Command::ShowAddress),
+                (Some("id"), Some("off"), None) => Ok(Command::AddressId { receiver: None }),
+                (Some("id"), Some(id), None) => match id.parse() {
+                    Ok(id) if id != 0 => Ok(Command::AddressId { receiver: Some(id) }),
+                    _ => Err("receiver id must be 1 to 65535"),
+                },
+                (Some(join @ ("join" | "leave")), Some(group), None) => match group.parse() {
+                    Ok(group) if group != 0 => Ok(Command::AddressGroup {
+                        group,
+                        member: join == "join",
+                    }),
+                    _ => Err("group must be 1 to 255"),
+                },
+                _ => Err("usage: address [id <n | off> | join <group> | leave <group>]"),
+            }
+        }
+        Some("send") => return parse_send(words),
+        Some("learn") => return parse_learn(words.next(), words.next(), words.next()),
+        Some("name") => return parse_name(words.next(), words.next(), words.next()),
+        Some("away") => {
+            return match (words.next(), words.next()) {
+                (Some("off"), None) => Ok(Command::Back),
+                (Some(days), None) => match days.parse() {
+                    Ok(days @ 1..=AWAY_MAX_DAYS) => Ok(Command::Away { days }),
+                    _ => Err("away takes 1 to 365 days"),
+                },
+                _ => Err("usage: away <days> | away off"),
+            }
+        }
+        Some("identity") if words.next().is_none() => return Ok(Command::ShowIdentity),
+        Some("identity") => return Err("usage: identity"),
+        Some("claim") => {
+            return match (words.next(), words.next()) {
+                (Some(household), None) => Ok(Command::Claim {
+                    household: household.to_string(),
+                }),
+                _ => Err("usage: claim <household>"),
+            }
+        }
+        Some("unclaim") if words.next().is_none() => return Ok(Command::Unclaim),
+        Some("unclaim") => return Err("usage: unclaim"),
+        Some("profile") => {
+            return match (words.next(), words.next()) {
+                (None, _) => Ok(Command::ShowProfile),
+                (Some(name), None) => Ok(Command::Profile {
+                    name: name.to_string(),
+                }),
+                _ => Err("usage: profile [<name>]"),
+            }
+        }
+        Some("config") => {
+            return match (words.next(), words.next()) {
+                (Some("export"), None) => Ok(Command::ConfigExport),
+                (Some("confirm"), None) => Ok(Command::ConfigConfirm),
+                _ => Err("usage: config export | config import [urgent] <blob> | config confirm"),
+            }
+        }
+        _ => return Err("unknown command"),
+    }
+
+    let Some(topic_id) = words.next() else {
+        return Ok(Command::ShowMap);
+    };
+    let topic_id = topic_id.parse().map_err(|_| "invalid topic id")?;
+    let gpio = match words.next() {
+        Some("off") => None,
+        Some(gpio) => Some(gpio.parse().map_err(|_| "invalid GPIO number")?),
+        None => return Err("usage: map [<topic_id> <gpio|off>]"),
+    };
+    if words.next().is_some() {
+        return Err("usage: map [<topic_id> <gpio|off>]");
+    }
+
+    Ok(Command::MapLed { topic_id, gpio })
+}
+
+fn parse_bench(seconds: Option<&str>, extra: Option<&str>) -> Result<Command, &'static str> {
+    let seconds = match seconds {
+        Some(seconds) => seconds.parse().map_err(|_| "invalid duration")?,
+        None => BENCH_DEFAULT_S,
+    };
+    if extra.is_some() {
+        return Err("usage: bench [seconds]");
+    }
+    if !(1..=BENCH_MAX_S).contains(&seconds) {
+        return Err("bench duration must be 1 to 600 s");
+    }
+
+    Ok(Command::Bench { seconds })
+}
+
+fn parse_quiet(
+    hours: Option<&str>,
+    mode: Option<&str>,
+    extra: Option<&str>,
+) -> Result<Command, &'static str> {
+    const USAGE: &str = "usage: quiet [<from>-<to> [reduced | led_only] | off | default]";
+    let hours = match (hours, mode) {
+        (None, _) => return Ok(Command::ShowQuietHours),
+        (Some("off"), None) => return Ok(Command::QuietHours { window: None }),
+        (Some("default"), None) => return Ok(Command::QuietHoursDefault),
+        (Some(hours), _) if extra.is_none() => hours,
+        _ => return Err(USAGE),
+    };
+    let (from_hour, to_hour) = hours
+        .split_once('-')
+        .and_then(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)))
+        .ok_or(USAGE)?;
+    if from_hour > 23 || to_hour > 23 {
+        return Err("quiet hours are 0 to 23");
+    }
+    let mode = match mode {
+        Some(mode) => Mode::parse(mode).ok_or(USAGE)?,
+        None => Mode::Reduced,
+    };
+
+    Ok(Command::QuietHours {
+        window: Some(Window {
+            from_hour,
+            to_hour,
+            mode,
+        }),
+    })
+}
+
+/// A threshold for `topic`, see [`threshold_topic`]
+fn parse_threshold(topic: &str, value: &str) -> Result<Command, &'static str> {
+    let value = value.parse().map_err(|_| "invalid limit")?;
+    let topic_id = threshold_topic(topic)?;
+    Ok(Command::Threshold { topic_id, value })
+}
+
+fn parse_bands<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Command, &'static str> {
+    const USAGE: &str = "usage: bands <topic> <<n> <priority> ... | off | default>";
+    let topic_id = threshold_topic(words.next().ok_or(USAGE)?)?;
+    let words: Vec<&str> = words.collect();
+    let bands = match words.as_slice() {
+        ["default"] => None,
+        ["off"] => Some(Vec::new()),
+        pairs if !pairs.is_empty() && pairs.len() % 2 == 0 => Some(
+            pairs
+                .chunks(2)
+                .map(|pair| {
+                    let value = pair[0].parse().map_err(|_| "invalid limit")?;
+                    let priority =
+                        Priority::parse(pair[1]).ok_or("expected info, warning or critical")?;
+                    Ok((value, priority))
+                })
+                .collect::<Result<_, &'static str>>()?,
+        ),
+        _ => return Err(USAGE),
+    };
+
+    Ok(Command::Bands { topic_id, bands })
+}
+
+/// `topic` as a topic id, or the registered name of a topic with a
+/// threshold alarm, see [`topics::find`]
+fn threshold_topic(topic: &str) -> Result<i32, &'static str> {
+    if let Ok(topic_id) = topic.parse() {
+        return Ok(topic_id);
+    }
+    let mut alarmed = topics::find(topic)
+        .into_iter()
+        .filter(|&topic_id| thresholds::has_rule(topic_id));
+    match (alarmed.next(), alarmed.next()) {
+        (Some(topic_id), None) => Ok(topic_id),
+        (None, _) => Err("no topic of that name has a threshold, see `topics`"),
+        (Some(_), Some(_)) => Err("several topics of that name have thresholds, name one in full"),
+    }
+}
+
+fn parse_send<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Command, &'static str> {
+    const USAGE: &str = "usage: send <to> <silence | reboot | selftest | threshold <topic_id> <n> | alarm <topic_id> <n>>";
+    let to = words.next().ok_or(USAGE)?;
+    let to = addressing::parse(to).ok_or("expected all, r<id> or g<group>")?;
+    let command = match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("silence"), None, _, _) => RemoteCommand::Silence,
+        (Some("reboot"), None, _, _) => RemoteCommand::Reboot,
+        (Some("selftest"), None, _, _) => RemoteCommand::SelfTest,
+        (Some(what @ ("threshold" | "alarm")), Some(topic_id), Some(value), None) => {
+            let topic_id = topic_id.parse().map_err(|_| "invalid topic id")?;
+            let value = value.parse().map_err(|_| "invalid value")?;
+            if what == "alarm" {
+                return Ok(Command::SendAlarm {
+                    to,
+                    topic_id,
+                    measurement: value,
+                });
+            }
+            RemoteCommand::SetThreshold { topic_id, value }
+        }
+        _ => return Err(USAGE),
+    };
+    Ok(Command::SendCommand { to, command })
+}
+
+fn parse_learn(
+    first: Option<&str>,
+    hours: Option<&str>,
+    extra: Option<&str>,
+) -> Result<Command, &'static str> {
+    let topic_id = match (first, hours) {
+        (None, _) => return Ok(Command::LearnStatus),
+        (Some("accept"), None) => return Ok(Command::LearnAccept),
+        (Some("cancel"), None) => return Ok(Command::LearnCancel),
+        (Some(topic_id), _) => topic_id.parse().map_err(|_| "invalid topic id")?,
+    };
+    let hours = match hours {
+        Some(hours) => hours.parse().map_err(|_| "invalid duration")?,
+        None => DEFAULT_LEARN_HOURS,
+    };
+    if extra.is_some() {
+        return Err("usage: learn [<topic_id> [hours] | accept | cancel]");
+    }
+    if !(1..=MAX_LEARN_HOURS).contains(&hours) {
+        return Err("learning takes 1 to 48 h");
+    }
+
+    Ok(Command::Learn { topic_id, hours })
+}
+
+fn parse_key(
+    target: Option<&str>,
+    key: Option<&str>,
+    extra: Option<&str>,
+) -> Result<Command, &'static str> {
+    let (Some(target), Some(key), None) = (target, key, extra) else {
+        return Err("usage: key <pmk|hmac|api|MAC> <32 hex digits|->");
+    };
+    let key = match key {
+        "-" => None,
+        key => Some(keys::parse_key(key).ok_or("keys are 32 hex digits")?),
+    };
+    let mac = match target {
+        "pmk" => None,
+        "hmac" => return Ok(Command::Secret { key }),
+        "api" => return Ok(Command::ApiToken { key }),
+        mac => Some(parse_mac(mac).ok_or("invalid MAC")?),
+    };
+
+    Ok(Command::Key { mac, key })
+}
+
+fn parse_name(
+    key: Option<&str>,
+    name: Option<&str>,
+    extra: Option<&str>,
+) -> Result<Command, &'static str> {
+    let (Some(key), Some(name), None) = (key, name, extra) else {
+        return Err("usage: name <topic_id>[@<MAC>] <name|->");
+    };
+    let key = Key::parse(key)?;
+    let name = (name != "-").then(|| name.to_string());
+
+    Ok(Command::Name { key, name })
+}
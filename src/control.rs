@@ -0,0 +1,486 @@
+//! What the operator's commands and buttons do
+//!
+//! Lines typed on the console arrive parsed as [`Command`]s, see the console
+//! module, button presses as [`Gesture`]s, see the inputs module, and
+//! commands from paired senders as [`RemoteCommand`]s, see the commands
+//! module. [`Context`] carries them out on the main loop's state, which it
+//! only borrows like the frame path's context in the dispatch module. The
+//! jobs they start, such as a pairing window or a self-test, run in [`Jobs`]
+//! until they end.
+
+use std::time::Duration;
+
+use esp_idf_svc::sys::{esp_restart, EspError};
+use log::{info, warn};
+
+use crate::alerts::{Alerts, Priority};
+use crate::annunciator::Annunciator;
+use crate::auto_sleep::AutoSleep;
+use crate::bench::Bench;
+use crate::board::Board;
+use crate::capture::{self, Target};
+use crate::channel::{self, Scan};
+use crate::commissioning::Commissioning;
+use crate::console::Command;
+use crate::datalog::{DataLog, Sample};
+use crate::espnow;
+use crate::inputs::{Action, Gesture};
+use crate::inspect;
+use crate::pairing::{self, Pairing};
+use crate::protocol::Command as RemoteCommand;
+use crate::selftest::SelfTest;
+use crate::settings::{self, Setting};
+use crate::siren::Siren;
+use crate::snooze::Snooze;
+use crate::sounds::Cue;
+use crate::transforms::Pipelines;
+use crate::uplink::{Event, UplinkChain};
+use crate::{
+    addressing, config, freshness, identity, keys, long_range, metrics, names, power, profiles,
+    quiet_hours, senders, sntp, support, thresholds, timezone, topics,
+};
+
+/// Jobs the operator started, each `None` while not running
+#[derive(Default)]
+pub struct Jobs {
+    pub self_test: Option<SelfTest>,
+    pub bench: Option<Bench>,
+    pub pairing: Option<Pairing>,
+    pub commissioning: Option<Commissioning>,
+    pub scan: Option<Scan>,
+}
+
+impl Jobs {
+    /// Drop the jobs that have ended; the scan ends in [`Scan::poll`], which
+    /// the main loop calls with the radio
+    pub fn poll(&mut self, annunciator: &mut Annunciator) {
+        if self.self_test.as_mut().is_some_and(SelfTest::poll) {
+            self.self_test = None;
+        }
+        if let Some(closed) = self.pairing.as_mut().and_then(Pairing::poll) {
+            if let pairing::Closed::Paired(_) = closed {
+                annunciator.play_cue(Cue::Paired);
+            }
+            self.pairing = None;
+        }
+        if self.commissioning.as_mut().is_some_and(Commissioning::poll) {
+            self.commissioning = None;
+        }
+        if self
+            .bench
+            .as_mut()
+            .is_some_and(|b| b.poll(espnow::counters()))
+        {
+            self.bench = None;
+            espnow::set_logging(true);
+        }
+    }
+}
+
+/// The main loop's state the operator reaches
+pub struct Context<'a> {
+    pub alerts: &'a mut Alerts,
+    pub annunciator: &'a mut Annunciator,
+    pub uplinks: &'a mut UplinkChain,
+    pub snooze: &'a mut Snooze,
+    pub jobs: &'a mut Jobs,
+    pub datalog: Option<&'a mut DataLog>,
+    pub auto_sleep: Option<&'a mut AutoSleep>,
+    pub pipelines: &'a Pipelines,
+    pub siren: Option<&'a Siren>,
+    /// Pins a moved LED must not take, as for [`Annunciator::remap_led`]
+    pub reserved_pins: &'a [i32],
+    /// Topic of the page button's alarm
+    pub page_topic: i32,
+    /// How long a channel scan listens on each channel
+    pub scan_dwell: Duration,
+    pub storage_ok: bool,
+    /// Whether the AP is joined
+    pub wifi_up: bool,
+    /// Whether the AP is still being joined in the background
+    pub joining: bool,
+    /// Whether the senders are being moved to the AP's channel
+    pub migrating: bool,
+    /// Whether the storage partition is still being mounted
+    pub mounting: bool,
+    /// Time to the next attempt at starting ESP-NOW, while it is down
+    pub radio_retry: Option<Duration>,
+}
+
+impl Context<'_> {
+    /// Whether the AP sets the channel, rather than the console
+    fn on_ap(&self) -> bool {
+        self.wifi_up || self.joining || self.migrating
+    }
+
+    fn start_pairing(&mut self, cue: bool) {
+        if self.jobs.pairing.is_none() {
+            self.jobs.pairing = Some(Pairing::start(settings::get(Setting::PairingWindow)));
+            if cue {
+                self.annunciator.play_cue(Cue::Pairing);
+            }
+        }
+    }
+
+    /// Start a self-test, the caller checking none is running
+    fn start_self_test(&mut self) -> Result<(), EspError> {
+        // Same entry point the ESP-NOW driver uses, without receive info
+        self.jobs.self_test = Some(SelfTest::start(espnow::inject)?);
+        Ok(())
+    }
+
+    fn acknowledge(&mut self) {
+        self.alerts.acknowledge(self.uplinks);
+        freshness::acknowledge();
+    }
+
+    fn flush(&mut self) {
+        if let Some(log) = self.datalog.as_mut() {
+            log.flush().ok();
+        }
+    }
+
+    /// Act on a button gesture
+    pub fn gesture(&mut self, gesture: Gesture) {
+        if let Some(auto_sleep) = self.auto_sleep.as_mut() {
+            auto_sleep.activity();
+        }
+        match gesture {
+            Gesture::Press => {
+                self.acknowledge();
+                if let Some(commissioning) = self.jobs.commissioning.as_mut() {
+                    commissioning.confirm();
+                }
+            }
+            Gesture::Hold => {
+                profiles::toggle().ok();
+            }
+            Gesture::LongHold | Gesture::Action(Action::Pair) => self.start_pairing(true),
+            Gesture::Action(Action::Mute) => self.acknowledge(),
+            Gesture::Action(Action::Snooze) => self.snooze.start(settings::get(Setting::Snooze)),
+            Gesture::Action(Action::Page) => {
+                let sample = Sample::now(self.page_topic, 1);
+                let priority = Priority::Critical;
+                self.uplinks.publish(Event::Alarm { sample, priority });
+            }
+        }
+    }
+
+    /// Carry out a command from a paired sender, see the commands module
+    pub fn remote(&mut self, command: RemoteCommand) -> Result<(), &'static str> {
+        match command {
+            RemoteCommand::SetThreshold { topic_id, value } => {
+                thresholds::set(topic_id, value).map(|_| ())
+            }
+            RemoteCommand::Silence => {
+                self.acknowledge();
+                Ok(())
+            }
+            RemoteCommand::SelfTest if self.jobs.self_test.is_some() => {
+                Err("self-test already running")
+            }
+            RemoteCommand::SelfTest => self
+                .start_self_test()
+                .map_err(|_| "could not send test frame"),
+            RemoteCommand::Reboot => {
+                self.flush();
+                Ok(())
+            }
+        }
+    }
+
+    /// Carry out a console command, logging the outcome; LEDs move within
+    /// the pin map of `B`
+    pub fn run<B: Board>(&mut self, command: Command) {
+        match command {
+            Command::ShowMap => {
+                for (topic_id, gpio) in self.annunciator.led_map() {
+                    info!("Topic {} -> GPIO{}", topic_id, gpio);
+                }
+            }
+            Command::MapLed { topic_id, gpio } => {
+                match self
+                    .annunciator
+                    .remap_led::<B>(topic_id, gpio, self.reserved_pins)
+                {
+                    Ok(()) => {
+                        match gpio {
+                            Some(gpio) => info!("Topic {} LED moved to GPIO{}", topic_id, gpio),
+                            None => info!("Topic {} LED detached", topic_id),
+                        }
+                        if let Err(e) = settings::set_led_pin(topic_id, gpio) {
+                            warn!("Topic {} LED pin: {}", topic_id, e);
+                        }
+                    }
+                    Err(e) => warn!("Remap of topic {} failed: {}", topic_id, e),
+                }
+            }
+            Command::SelfTest if self.jobs.self_test.is_some() => {
+                warn!("Self-test already running")
+            }
+            Command::SelfTest => {
+                if let Err(e) = self.start_self_test() {
+                    warn!("Self-test FAILED: could not send test frame: {}", e);
+                }
+            }
+            Command::Inspect => inspect::report(self).log(),
+            // Printed bare, one line, for hubctl
+            Command::Status => println!("{}", Event::Status(inspect::status()).to_json()),
+            Command::Reboot => {
+                info!("Rebooting from the console");
+                self.flush();
+                metrics::save();
+                unsafe { esp_restart() }
+            }
+            Command::Sleep => {
+                self.annunciator.play_cue(Cue::Sleep);
+                self.flush();
+                power::go_to_sleep();
+            }
+            Command::ShowLogLevel => info!("Log level {}", log::max_level()),
+            Command::LogLevel { level } => {
+                support::set_log_level(level);
+                info!("Log level {}", level);
+            }
+            Command::ShowSupport => info!("{}", support::summary()),
+            Command::Support { on: true } => {
+                if let Err(e) = support::start() {
+                    warn!("Support mode failed to start: {}", e);
+                }
+            }
+            Command::Support { on: false } => {
+                if let Err(e) = support::stop() {
+                    warn!("Support mode: {}", e);
+                }
+            }
+            Command::Bench { .. } if self.jobs.bench.is_some() => warn!("Bench already running"),
+            Command::Bench { seconds } => {
+                espnow::set_logging(false);
+                self.jobs.bench = Some(Bench::start(
+                    Duration::from_secs(seconds.into()),
+                    espnow::counters(),
+                ));
+            }
+            Command::ShowTimezone => {
+                info!("{}", timezone::summary());
+                info!("{}", sntp::summary());
+                info!("Zone names: {}", timezone::names());
+            }
+            Command::Timezone { zone } => match timezone::set(zone.as_deref()) {
+                Ok(()) => info!("{}", timezone::summary()),
+                Err(e) => warn!("Timezone: {}", e),
+            },
+            Command::ShowLongRange => info!("{}", long_range::summary()),
+            Command::LongRange { on } => {
+                if let Err(e) = config::stage_change(|| long_range::store(on)) {
+                    warn!("Long range: {}", e)
+                }
+            }
+            Command::ShowQuietHours => info!("{}", quiet_hours::summary()),
+            Command::QuietHours { window } => match quiet_hours::set(window) {
+                Ok(()) => info!("{}", quiet_hours::summary()),
+                Err(e) => warn!("Quiet hours: {}", e),
+            },
+            Command::QuietHoursDefault => match quiet_hours::reset() {
+                Ok(()) => info!("{}", quiet_hours::summary()),
+                Err(e) => warn!("Quiet hours: {}", e),
+            },
+            Command::ShowChannel => info!("{}", channel::summary()),
+            Command::Channel { channel } => {
+                self.jobs.scan = None;
+                if let Err(e) = config::stage_change(|| channel::store(channel)) {
+                    warn!("Channel not stored: {}", e)
+                }
+            }
+            Command::ChannelScan if self.on_ap() => {
+                warn!("Channel scan refused, the AP sets the channel")
+            }
+            Command::ChannelScan => match Scan::start(self.scan_dwell) {
+                Ok(started) => self.jobs.scan = Some(started),
+                Err(e) => warn!("Channel scan failed to start: {}", e),
+            },
+            Command::ShowCapture => info!("{}", capture::summary()),
+            Command::Capture {
+                target: Some(Target::File),
+            } if !self.storage_ok => warn!("Capture to file needs the storage partition"),
+            Command::Capture {
+                target: Some(target),
+            } => {
+                if let Err(e) = capture::start(target) {
+                    warn!("Capture not started: {}", e);
+                }
+            }
+            Command::Capture { target: None } => capture::stop(),
+            Command::ShowNames => log_lines(&names::list()),
+            Command::Name { key, name } => match names::set(key, name.as_deref()) {
+                Ok(()) => match name {
+                    Some(name) => info!("{} named {}", key, name),
+                    None => info!("{} unnamed", key),
+                },
+                Err(e) => warn!("Naming {} failed: {}", key, e),
+            },
+            Command::ShowTopics => log_lines(&topics::list()),
+            Command::Topic { name, topic } => {
+                let set = topic.as_ref().map(|(id, handler)| (*id, handler.as_str()));
+                match topics::set(&name, set) {
+                    Ok(()) => match topic {
+                        Some((topic_id, handler)) => {
+                            info!("{} registered as {}, {}", name, topic_id, handler)
+                        }
+                        None => info!("{} removed", name),
+                    },
+                    Err(e) => warn!("Registering {} failed: {}", name, e),
+                }
+            }
+            Command::ConfigExport => match config::export() {
+                // Printed bare so it can be copied off the terminal
+                Ok(blob) => println!("{}", blob),
+                Err(e) => warn!("Config export failed: {}", e),
+            },
+            Command::ConfigImport { blob, urgent } => {
+                if let Err(e) = config::import(&blob, urgent) {
+                    warn!("Config import failed: {}", e);
+                }
+            }
+            Command::ConfigConfirm => {
+                if let Err(e) = config::confirm() {
+                    warn!("Config confirm failed: {}", e);
+                }
+            }
+            Command::ShowSenders => log_lines(&senders::list()),
+            Command::Pair if self.jobs.pairing.is_some() => warn!("Pairing already open"),
+            Command::Pair => self.start_pairing(false),
+            Command::ShowPeers => log_lines(&pairing::list()),
+            Command::Unpair { mac } => match pairing::unpair(mac) {
+                Ok(()) => info!("Unpaired {:02X?}", mac),
+                Err(e) => warn!("Unpairing {:02X?} failed: {}", mac, e),
+            },
+            Command::Legacy { mac, on } => match pairing::set_legacy(mac, on) {
+                Ok(()) if on => info!("Taking frames without a CRC from {:02X?}", mac),
+                Ok(()) => info!("Refusing frames without a CRC from {:02X?}", mac),
+                Err(e) => warn!("Legacy frames from {:02X?} not changed: {}", mac, e),
+            },
+            Command::ShowKeys => log_lines(&keys::summary()),
+            Command::Key { mac, key } => {
+                let result = config::stage_change(|| match mac {
+                    Some(mac) => keys::set_lmk(mac, key),
+                    None => keys::set_pmk(key),
+                });
+                match result {
+                    Ok(()) => info!("Key saved, takes effect at the next boot"),
+                    Err(e) => warn!("Saving the key failed: {}", e),
+                }
+            }
+            Command::Secret { key } => match config::stage_change(|| keys::set_hmac_secret(key)) {
+                Ok(()) if key.is_some() => info!("HMAC secret saved, data frames must carry it"),
+                Ok(()) => info!("HMAC secret removed, data frames are taken unchecked"),
+                Err(e) => warn!("Saving the HMAC secret failed: {}", e),
+            },
+            Command::ApiToken { key } => match config::stage_change(|| keys::set_api_token(key)) {
+                Ok(()) if key.is_some() => info!("API token saved, HTTP changes must carry it"),
+                Ok(()) => info!("API token removed, nothing can be changed over HTTP"),
+                Err(e) => warn!("Saving the API token failed: {}", e),
+            },
+            Command::ShowThresholds => log_lines(&thresholds::summary()),
+            Command::ShowSettings => log_lines(&settings::summary()),
+            Command::Set { setting, secs } => match settings::set(setting, secs) {
+                Ok(()) => info!("{} is {} s", setting, settings::get(setting).as_secs()),
+                Err(e) => warn!("{}: {}", setting, e),
+            },
+            Command::Threshold { topic_id, value } => match thresholds::set(topic_id, value) {
+                Ok(limit) => info!("Topic {} alarms {}", topic_id, limit),
+                Err(e) => warn!("Threshold for topic {} not set: {}", topic_id, e),
+            },
+            Command::Bands { topic_id, bands } => {
+                match thresholds::set_bands(topic_id, bands.as_deref()) {
+                    Ok(()) => info!("Topic {} bands set, see `thresholds`", topic_id),
+                    Err(e) => warn!("Bands for topic {} not set: {}", topic_id, e),
+                }
+            }
+            Command::Learn { topic_id, hours } => {
+                if let Err(e) = thresholds::learn(topic_id, hours) {
+                    warn!("Learning topic {} not started: {}", topic_id, e);
+                }
+            }
+            Command::LearnStatus => info!("{}", thresholds::status()),
+            Command::LearnAccept => match thresholds::accept() {
+                Ok((topic_id, limit)) => info!("Topic {} alarms {}", topic_id, limit),
+                Err(e) => warn!("Nothing applied: {}", e),
+            },
+            Command::LearnCancel => match thresholds::cancel() {
+                Ok(()) => info!("Learning cancelled"),
+                Err(e) => warn!("Nothing cancelled: {}", e),
+            },
+            Command::Away { days } => {
+                if let Err(e) = profiles::away_for(days) {
+                    warn!("Away not set: {}", e);
+                }
+            }
+            Command::Back => {
+                if let Err(e) = profiles::come_back() {
+                    warn!("Nothing to end: {}", e);
+                }
+            }
+            Command::Commission if self.jobs.commissioning.is_some() => {
+                warn!("Commissioning already running")
+            }
+            Command::Commission => {
+                self.jobs.commissioning = Some(Commissioning::start(
+                    self.annunciator.led_map().map(|(topic_id, _)| topic_id),
+                ))
+            }
+            Command::ShowAddress => info!("{}", addressing::summary()),
+            Command::AddressId { receiver } => match addressing::set_receiver(receiver) {
+                Ok(()) => info!("{}", addressing::summary()),
+                Err(e) => warn!("Receiver id not set: {}", e),
+            },
+            Command::AddressGroup { group, member } => {
+                let result = if member {
+                    addressing::join(group)
+                } else {
+                    addressing::leave(group)
+                };
+                match result {
+                    Ok(()) => info!("{}", addressing::summary()),
+                    Err(e) => warn!("Group g{}: {}", group, e),
+                }
+            }
+            Command::SendCommand { to, command } => match addressing::command(to, command) {
+                Ok(id) => info!("Command {} {:?} sent to {}", id, command, to),
+                Err(e) => warn!("Command for {} not sent: {}", to, e),
+            },
+            Command::SendAlarm {
+                to,
+                topic_id,
+                measurement,
+            } => match addressing::alarm(to, topic_id, measurement) {
+                Ok(()) => info!("Alarm of topic {} sent to {}", topic_id, to),
+                Err(e) => warn!("Alarm for {} not sent: {}", to, e),
+            },
+            Command::ShowIdentity => info!("{}", identity::describe()),
+            Command::Claim { household } => {
+                if let Err(e) = identity::claim(&household) {
+                    warn!("Claim failed: {}", e);
+                }
+            }
+            Command::Unclaim => {
+                if let Err(e) = identity::unclaim(None) {
+                    warn!("Unclaim failed: {}", e);
+                }
+            }
+            Command::ShowProfile => info!("Profile: {}", profiles::active()),
+            Command::Profile { name } => {
+                if let Err(e) = profiles::select(&name) {
+                    warn!("Profile {} not selected: {}", name, e);
+                }
+            }
+        }
+    }
+}
+
+/// Log every line of a module's listing
+fn log_lines(text: &str) {
+    for line in text.lines() {
+        info!("{}", line);
+    }
+}
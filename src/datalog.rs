@@ -0,0 +1,302 @@
+//! Flash data logger
+//!
+//! Records are buffered in RAM and appended to the SPIFFS partition as
+//! LZ4-compressed blocks. Each block is stored column by column with every
+//! column delta-encoded, which turns slowly changing readings into long runs
+//! of near-identical bytes that LZ4 folds away.
+//!
+//! On flash a block is a little-endian `u16` length followed by the
+//! compressed bytes. When the active file grows past its size limit it is
+//! rotated to `<name>.old`, so retention is bounded to roughly two files.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+const RECORDS_PER_BLOCK: usize = 64;
+const MAX_LOG_SIZE: u64 = 64 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A fixed-size record made of 32-bit words
+pub trait Record: Copy {
+    const WORDS: usize;
+    const CSV_HEADER: &'static str;
+
+    fn to_words(&self, words: &mut [u32]);
+    fn from_words(words: &[u32]) -> Self;
+    fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// Seconds on the RTC clock, which keeps running through deep sleep
+    pub timestamp: u32,
+    pub topic_id: i32,
+    pub measurement: i32,
+}
+
+impl Sample {
+    pub fn now(topic_id: i32, measurement: i32) -> Self {
+        Self {
+            timestamp: clock::unix_secs(),
+            topic_id,
+            measurement,
+        }
+    }
+}
+
+impl Record for Sample {
+    const WORDS: usize = 3;
+    const CSV_HEADER: &'static str = "timestamp,topic_id,measurement";
+
+    fn to_words(&self, words: &mut [u32]) {
+        words[0] = self.timestamp;
+        words[1] = self.topic_id as u32;
+        words[2] = self.measurement as u32;
+    }
+
+    fn from_words(words: &[u32]) -> Self {
+        Self {
+            timestamp: words[0],
+            topic_id: words[1] as i32,
+            measurement: words[2] as i32,
+        }
+    }
+
+    fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{},{},{}",
+            self.timestamp, self.topic_id, self.measurement
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogStats {
+    pub blocks: usize,
+    pub records: usize,
+    /// Uncompressed size of all records
+    pub raw_bytes: usize,
+    /// Bytes used on flash, including block headers
+    pub stored_bytes: usize,
+}
+
+impl LogStats {
+    /// Uncompressed size divided by stored size
+    pub fn ratio(&self) -> f32 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f32 / self.stored_bytes as f32
+    }
+}
+
+/// The log of raw received samples
+pub type DataLog = BlockLog<Sample>;
+
+impl DataLog {
+    pub fn new(dir: &str) -> Self {
+        BlockLog::open(dir, "log", MAX_LOG_SIZE)
+    }
+}
+
+pub struct BlockLog<R: Record> {
+    dir: String,
+    name: &'static str,
+    max_size: u64,
+    pending: Vec<R>,
+    last_flush: Instant,
+}
+
+impl<R: Record> BlockLog<R> {
+    /// Open the log `name` inside `dir` (the mounted SPIFFS base path on target)
+    pub fn open(dir: &str, name: &'static str, max_size: u64) -> Self {
+        Self {
+            dir: dir.to_string(),
+            name,
+            max_size,
+            pending: Vec::with_capacity(RECORDS_PER_BLOCK),
+            last_flush: clock::now(),
+        }
+    }
+
+    pub fn append(&mut self, record: R) -> io::Result<()> {
+        self.pending.push(record);
+        if self.pending.len() >= RECORDS_PER_BLOCK {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush a partial block once it has been sitting in RAM for too long
+    pub fn poll(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() && clock::since(self.last_flush) >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compress the pending records and append them to flash as one block
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = clock::now();
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let compressed = lz4_flex::compress_prepend_size(&encode_block(&self.pending));
+        let len = u16::try_from(compressed.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "block too large"))?;
+
+        let path = self.path("bin");
+        let current_size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if current_size + 2 + len as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(&len.to_le_bytes())?;
+        file.write_all(&compressed)?;
+
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Visit every stored record, oldest first, decompressing block by block
+    pub fn for_each(&self, mut f: impl FnMut(R)) -> io::Result<()> {
+        for ext in ["old", "bin"] {
+            self.read_blocks(ext, |block| block.iter().copied().for_each(&mut f))?;
+        }
+        self.pending.iter().copied().for_each(f);
+        Ok(())
+    }
+
+    /// Write the whole log as CSV
+    pub fn export<W: Write>(&self, out: &mut W) -> io::Result<usize> {
+        writeln!(out, "{}", R::CSV_HEADER)?;
+
+        let mut count = 0;
+        let mut result = Ok(());
+        self.for_each(|r| {
+            if result.is_ok() {
+                result = r.write_csv(out);
+                count += 1;
+            }
+        })?;
+
+        result.map(|_| count)
+    }
+
+    pub fn stats(&self) -> io::Result<LogStats> {
+        let mut stats = LogStats::default();
+        for ext in ["old", "bin"] {
+            stats.stored_bytes +=
+                fs::metadata(self.path(ext)).map(|m| m.len()).unwrap_or(0) as usize;
+            self.read_blocks(ext, |block| {
+                stats.blocks += 1;
+                stats.records += block.len();
+            })?;
+        }
+        stats.raw_bytes = stats.records * R::WORDS * 4;
+        Ok(stats)
+    }
+
+    fn read_blocks(&self, ext: &str, mut f: impl FnMut(&[R])) -> io::Result<()> {
+        let file = match File::open(self.path(ext)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(file);
+
+        let mut compressed = Vec::new();
+        loop {
+            let mut len = [0u8; 2];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+
+            compressed.resize(u16::from_le_bytes(len) as usize, 0);
+            if reader.read_exact(&mut compressed).is_err() {
+                // Power was lost mid-write, the tail of the file is unusable
+                return Ok(());
+            }
+
+            let raw = lz4_flex::decompress_size_prepended(&compressed)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            f(&decode_block(&raw)?);
+        }
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let old = self.path("old");
+        match fs::remove_file(&old) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::rename(self.path("bin"), old)
+    }
+
+    fn path(&self, ext: &str) -> String {
+        format!("{}/{}.{}", self.dir, self.name, ext)
+    }
+}
+
+fn encode_block<R: Record>(records: &[R]) -> Vec<u8> {
+    let n = records.len();
+    let mut columns = vec![0u32; n * R::WORDS];
+
+    let mut words = vec![0u32; R::WORDS];
+    for (i, r) in records.iter().enumerate() {
+        r.to_words(&mut words);
+        for (col, word) in words.iter().enumerate() {
+            columns[col * n + i] = *word;
+        }
+    }
+
+    let mut raw = Vec::with_capacity(columns.len() * 4);
+    for column in columns.chunks(n) {
+        let mut prev = 0u32;
+        for word in column {
+            raw.extend_from_slice(&word.wrapping_sub(prev).to_le_bytes());
+            prev = *word;
+        }
+    }
+
+    raw
+}
+
+fn decode_block<R: Record>(raw: &[u8]) -> io::Result<Vec<R>> {
+    let record_size = R::WORDS * 4;
+    if raw.len() % record_size != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "truncated block"));
+    }
+
+    let n = raw.len() / record_size;
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut columns = vec![0u32; n * R::WORDS];
+    for (column, bytes) in columns.chunks_mut(n).zip(raw.chunks(n * 4)) {
+        let mut prev = 0u32;
+        for (word, delta) in column.iter_mut().zip(bytes.chunks_exact(4)) {
+            prev = prev.wrapping_add(u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]));
+            *word = prev;
+        }
+    }
+
+    let mut words = vec![0u32; R::WORDS];
+    Ok((0..n)
+        .map(|i| {
+            for (col, word) in words.iter_mut().enumerate() {
+                *word = columns[col * n + i];
+            }
+            R::from_words(&words)
+        })
+        .collect())
+}
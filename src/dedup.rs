@@ -0,0 +1,113 @@
+//! Duplicate suppression by sender sequence number
+//!
+//! Senders retransmit a frame when they miss its ACK, although the hub may
+//! well have received it. A [`Message::Sequenced`] carries a per-sender
+//! sequence number that repeats unchanged on a retransmission, so the hub
+//! remembers the last [`WINDOW`] numbers of each sender and drops a frame
+//! whose number it has already seen. Frames may arrive out of order within
+//! the window.
+//!
+//! A sender that restarts counts from zero again. Its announcement on boot
+//! resets its window, and so does a number far behind the window, or a
+//! silence longer than [`EXPIRY`]. Frames without a sequence number, and
+//! injected ones, are always taken.
+//!
+//! [`Message::Sequenced`]: crate::protocol::Message::Sequenced
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+/// Sequence numbers remembered per sender
+const WINDOW: u16 = 32;
+/// Silence after which a sender's window is forgotten
+const EXPIRY: Duration = Duration::from_secs(60);
+// Senders tracked at once; frames of further senders are always taken
+const MAX_SENDERS: usize = 16;
+
+static SENDERS: Mutex<Vec<Sender>> = Mutex::new(Vec::new());
+
+struct Sender {
+    mac: [u8; 6],
+    latest: u16,
+    /// Bit `n` is set when `latest - n` was seen
+    seen: u32,
+    at: Instant,
+}
+
+/// Whether frame `seq` from `mac` is new, remembering it
+///
+/// Called for every sequenced frame, so it only takes a short lock.
+pub fn accept(mac: [u8; 6], seq: u16) -> bool {
+    let mut senders = SENDERS.lock().unwrap();
+    let now = clock::now();
+    let full = senders.len() >= MAX_SENDERS;
+    let Some(sender) = senders.iter_mut().find(|s| s.mac == mac) else {
+        if !full {
+            senders.push(Sender {
+                mac,
+                latest: seq,
+                seen: 1,
+                at: now,
+            });
+        }
+        return true;
+    };
+
+    let expired = clock::since(sender.at) >= EXPIRY;
+    sender.at = now;
+    let ahead = seq.wrapping_sub(sender.latest);
+    let behind = sender.latest.wrapping_sub(seq);
+    if expired || (ahead > u16::MAX / 2 && behind >= WINDOW) {
+        // Restarted, or gone for long enough that old numbers mean nothing
+        sender.latest = seq;
+        sender.seen = 1;
+        true
+    } else if ahead <= u16::MAX / 2 && ahead > 0 {
+        sender.seen = sender.seen.checked_shl(u32::from(ahead)).unwrap_or(0) | 1;
+        sender.latest = seq;
+        true
+    } else {
+        let bit = 1 << behind;
+        let new = sender.seen & bit == 0;
+        sender.seen |= bit;
+        new
+    }
+}
+
+/// Forget the window of `mac`, e.g. when it announces a restart
+pub fn forget(mac: [u8; 6]) {
+    SENDERS.lock().unwrap().retain(|s| s.mac != mac);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_dropped_until_the_sender_goes_quiet() {
+        let (_turn, clock) = clock::mock();
+        let mac = [0xde, 0xd0, 0, 0, 0, 1];
+        assert!(accept(mac, 5));
+        assert!(!accept(mac, 5));
+        clock.advance(EXPIRY - Duration::from_secs(1));
+        assert!(!accept(mac, 5));
+
+        // Taken as a restart once silent for the expiry
+        clock.advance(EXPIRY);
+        assert!(accept(mac, 5));
+        assert!(!accept(mac, 5));
+    }
+
+    #[test]
+    fn window_takes_numbers_out_of_order() {
+        let _turn = clock::mock();
+        let mac = [0xde, 0xd0, 0, 0, 0, 2];
+        assert!(accept(mac, 10));
+        assert!(accept(mac, 12));
+        assert!(accept(mac, 11));
+        assert!(!accept(mac, 11));
+        assert!(!accept(mac, 10));
+    }
+}
@@ -0,0 +1,164 @@
+//! Product defaults, compiled in from `defaults.toml` at build time
+//!
+//! build.rs turns every `[section]` of the file into the field of the same
+//! name in [`DEFAULTS`], holding the struct of the CamelCase name below, so a
+//! SKU only differs in its TOML file (`DEFAULTS_TOML=<path>`). The structs
+//! fix the types; a key missing from the file or not declared here is a
+//! compile error. Consts in main.rs are derived from these, and what the hub
+//! keeps in NVS overrides them at runtime.
+
+include!(concat!(env!("OUT_DIR"), "/defaults.rs"));
+
+pub struct Defaults {
+    pub thresholds: Thresholds,
+    pub alerts: Alerts,
+    pub escalation: Escalation,
+    pub profiles: Profiles,
+    pub buttons: Buttons,
+    pub buzzer: Buzzer,
+    pub quiet_hours: QuietHours,
+    pub siren: Siren,
+    pub sleep: Sleep,
+    pub presence: Presence,
+    pub link: Link,
+    pub freshness: Freshness,
+    pub pairing: Pairing,
+    pub channel: Channel,
+    pub clock: Clock,
+    pub radio: Radio,
+    pub addressing: Addressing,
+    pub config: Config,
+    pub support: Support,
+    pub watchdog: Watchdog,
+    pub uplinks: Uplinks,
+    pub forecast: Forecast,
+    pub startup: Startup,
+}
+
+pub struct Thresholds {
+    pub kettle_above: i32,
+    pub kettle_warning_above: i32,
+    pub kettle_critical_above: i32,
+    pub sink_below: i32,
+}
+
+pub struct Alerts {
+    pub kettle_cooldown_secs: u64,
+    pub sink_cooldown_secs: u64,
+}
+
+pub struct Escalation {
+    pub kettle_full_after_secs: u64,
+    pub kettle_continuous_after_secs: u64,
+    pub sink_full_after_secs: u64,
+    pub sink_continuous_after_secs: u64,
+}
+
+pub struct Profiles {
+    pub hold_secs: u64,
+}
+
+pub struct Buttons {
+    pub mute_gpio: i32,
+    pub snooze_gpio: i32,
+    pub page_gpio: i32,
+    pub pair_gpio: i32,
+    pub snooze_mins: u64,
+}
+
+pub struct Buzzer {
+    pub max_on_secs: u64,
+}
+
+pub struct QuietHours {
+    pub from_hour: u32,
+    pub to_hour: u32,
+    pub led_only: bool,
+}
+
+pub struct Siren {
+    pub full_after_secs: u64,
+    pub max_on_secs: u64,
+}
+
+pub struct Sleep {
+    pub warning_secs: u64,
+    pub away_factor: u32,
+}
+
+pub struct Presence {
+    /// dBm
+    pub near_rssi: i32,
+}
+
+pub struct Link {
+    /// dBm
+    pub weak_rssi: i32,
+    pub weak_led: bool,
+}
+
+pub struct Freshness {
+    pub kettle_mins: u64,
+    pub sink_mins: u64,
+}
+
+pub struct Pairing {
+    pub window_secs: u64,
+    pub hold_secs: u64,
+}
+
+pub struct Channel {
+    pub grace_secs: u64,
+    pub scan_dwell_secs: u64,
+}
+
+pub struct Clock {
+    pub timezone: &'static str,
+    pub sntp_server: &'static str,
+}
+
+pub struct Radio {
+    pub long_range: bool,
+}
+
+pub struct Addressing {
+    pub receiver_id: u16,
+    pub groups: &'static str,
+}
+
+pub struct Config {
+    pub revert_timeout_secs: u64,
+    pub maintenance_from_hour: u32,
+    pub maintenance_to_hour: u32,
+}
+
+pub struct Support {
+    pub duration_mins: u64,
+    pub snapshot_secs: u64,
+}
+
+pub struct Watchdog {
+    pub stall_secs: u64,
+    pub restart_secs: u64,
+}
+
+pub struct Uplinks {
+    pub status_interval_secs: u64,
+    pub away_status_interval_secs: u64,
+}
+
+pub struct Forecast {
+    pub freezing: i32,
+    pub tighten_by: i32,
+    pub night_from_hour: u32,
+    pub night_to_hour: u32,
+    pub refresh_mins: u64,
+}
+
+pub struct Startup {
+    pub delay_ms: u64,
+    pub radio_timeout_secs: u64,
+    pub storage_timeout_secs: u64,
+    pub uplinks_timeout_secs: u64,
+    pub radio_retry_secs: u64,
+}
@@ -0,0 +1,77 @@
+//! Degraded modes the hub keeps running in
+//!
+//! Losing a peripheral never stops the hub, it falls back to a [`Mode`]:
+//!
+//! - `local_only` without a WiFi AP: alarms, LEDs and the buzzer work, the
+//!   IP uplinks and the HTTP endpoints do not
+//! - `led_codes` without a display: status shows as LED patterns only, e.g.
+//!   the pairing PIN and the readiness flashes
+//! - `volatile_config` without NVS: the built-in defaults apply and changes
+//!   made at runtime are lost on reboot
+//! - `radio_down` when ESP-NOW fails to start: the idle LEDs blink an error
+//!   pattern and the radio is retried periodically
+//!
+//! Entering and leaving a mode is logged, and the active modes are reported
+//! as `degraded` in the status uplink, e.g. `["local_only"]`.
+
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+static ACTIVE: Mutex<Vec<Mode>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    LocalOnly,
+    LedCodes,
+    VolatileConfig,
+    RadioDown,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::LocalOnly => "local_only",
+            Mode::LedCodes => "led_codes",
+            Mode::VolatileConfig => "volatile_config",
+            Mode::RadioDown => "radio_down",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Mode::LocalOnly => "no WiFi AP, alarms stay local",
+            Mode::LedCodes => "no display, status on the LEDs only",
+            Mode::VolatileConfig => "no NVS, running on defaults that are not kept",
+            Mode::RadioDown => "ESP-NOW down, retrying",
+        }
+    }
+}
+
+/// Enter or leave `mode`
+pub fn set(mode: Mode, on: bool) {
+    let mut active = ACTIVE.lock().unwrap();
+    let known = active.contains(&mode);
+    if on && !known {
+        warn!("Degraded: {} ({})", mode.describe(), mode.name());
+        active.push(mode);
+    } else if !on && known {
+        info!("No longer degraded: {}", mode.name());
+        active.retain(|m| *m != mode);
+    }
+}
+
+pub fn is_active(mode: Mode) -> bool {
+    ACTIVE.lock().unwrap().contains(&mode)
+}
+
+/// The active modes as a JSON array, for the status uplink
+pub fn json() -> String {
+    let modes: Vec<String> = ACTIVE
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|m| format!(r#""{}""#, m.name()))
+        .collect();
+    format!("[{}]", modes.join(","))
+}
@@ -0,0 +1,500 @@
+//! What the main loop does with each reading it takes
+//!
+//! The receive path, see the espnow module, hands the main loop one
+//! [`Frame`] per reading. [`Context::handle`] takes it from there: a
+//! critical frame goes down the fast path, see the fast_path module; any
+//! other is marked heard and through its pipeline stored, handed to its
+//! topic's handler, whose verdict raises or clears the alarm, and then
+//! published. A sequenced frame is acked once handled either way.
+//!
+//! The context only borrows what the main loop owns, so a test can feed a
+//! recorded trace of frames through the same code and watch the outputs.
+
+use log::{info, warn};
+
+use crate::ack::{self, Ack};
+use crate::alerts::{AlarmState, Alerts, Priority};
+use crate::annunciator::Annunciator;
+use crate::auto_sleep::AutoSleep;
+use crate::battery;
+use crate::bench::Bench;
+use crate::commissioning::Commissioning;
+use crate::datalog::{DataLog, Sample};
+use crate::fast_path;
+use crate::freshness;
+use crate::handlers::{AlertAction, Dispatcher, Measurement};
+use crate::history::History;
+use crate::link;
+use crate::names;
+use crate::profiles;
+use crate::readiness;
+use crate::strings::{self, Text};
+#[cfg(feature = "tracing")]
+use crate::trace;
+use crate::transforms::Pipelines;
+use crate::uplink::{Event, UplinkChain};
+use crate::watchdog::{Stage, Watchdog};
+
+/// A reading taken from the receive queue
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub topic_id: i32,
+    pub measurement: i32,
+    /// Sender MAC, `None` for injected frames
+    pub src: Option<[u8; 6]>,
+    /// Battery charge in percent, if the sender reports it
+    pub battery: Option<u8>,
+    /// RSSI in dBm, `None` for injected frames
+    pub rssi: Option<i32>,
+    /// Whether the log governor lets the frame log its lines
+    pub logged: bool,
+    /// On the last reading of a sequenced frame, to send once it is handled
+    pub ack: Option<Ack>,
+    /// From a [`protocol::Message::Critical`], for the fast path
+    ///
+    /// [`protocol::Message::Critical`]: crate::protocol::Message::Critical
+    pub critical: bool,
+    /// When the receive callback copied its frame, in microseconds since boot
+    pub received_us: i64,
+    /// Start of its wait in the queue
+    #[cfg(feature = "tracing")]
+    pub trace: trace::Frame,
+}
+
+/// The main loop's state a frame's handling touches
+pub struct Context<'a> {
+    pub alerts: &'a mut Alerts,
+    pub annunciator: &'a mut Annunciator,
+    pub uplinks: &'a mut UplinkChain,
+    pub pipelines: &'a mut Pipelines,
+    pub dispatcher: &'a mut Dispatcher,
+    pub watchdog: &'a Watchdog,
+    pub datalog: Option<&'a mut DataLog>,
+    pub history: Option<&'a mut History>,
+    pub commissioning: Option<&'a mut Commissioning>,
+    pub bench: Option<&'a mut Bench>,
+    pub auto_sleep: Option<&'a mut AutoSleep>,
+    /// Arrival of the frames that raised an alarm, timed up to the output
+    /// refresh that follows
+    pub actuating: &'a mut Vec<i64>,
+    /// Trace id of the frame whose dispatch ended last, traced through the
+    /// outputs
+    #[cfg(feature = "tracing")]
+    pub traced: &'a mut Option<u32>,
+}
+
+impl Context<'_> {
+    /// Carry out everything `frame` calls for, up to its ack
+    pub fn handle(&mut self, frame: Frame) {
+        // The fast path, see src/fast_path.rs: no pipeline, storage or rate
+        // limit, and the buzzer refreshed before anything else
+        if frame.critical && profiles::alarms_enabled(frame.topic_id) {
+            let sample = Sample::now(frame.topic_id, frame.measurement);
+            let new = self.alerts.state(frame.topic_id) == AlarmState::Clear;
+            let upstream = self.alerts.raise_critical(sample);
+            self.annunciator.poll(self.alerts);
+            let latency = fast_path::since(frame.received_us);
+            fast_path::actuated(frame.topic_id, latency);
+            if new {
+                readiness::actuated(latency);
+            }
+            if upstream {
+                let priority = Priority::Critical;
+                self.uplinks.publish(Event::Alarm { sample, priority });
+            }
+            acknowledge(&frame);
+            return;
+        }
+        #[cfg(feature = "tracing")]
+        let dispatch = trace::pickup(frame.trace);
+        let topic_id = frame.topic_id;
+        if let Some(sample) = freshness::heard(topic_id, frame.measurement) {
+            self.uplinks.publish(Event::Offline {
+                sample,
+                offline: false,
+            });
+        }
+        if let Some(src) = frame.src {
+            names::seen(topic_id, src);
+            if let Some(rssi) = frame.rssi {
+                link::record(src, topic_id, rssi);
+            }
+        }
+        if let Some(commissioning) = self.commissioning.as_mut() {
+            commissioning.receive(topic_id, frame.rssi);
+        }
+        if let Some(battery) = frame.battery {
+            let src = frame.src.unwrap_or([0; 6]);
+            if let Some(low) = battery::record(src, topic_id, battery) {
+                let text = if low {
+                    Text::BatteryLow
+                } else {
+                    Text::BatteryOk
+                };
+                warn!(
+                    "{}",
+                    strings::text(text, &[&names::label(topic_id), &battery])
+                );
+                let sample = Sample::now(topic_id, battery.into());
+                self.uplinks.publish(Event::Battery { sample, low });
+            }
+        }
+
+        let reading = match self.pipelines.process(topic_id, frame.measurement) {
+            Ok(reading) => reading,
+            Err(stage) => {
+                if frame.logged {
+                    info!(
+                        "Dropped by {} - Topic: {} | Measurement: {}",
+                        stage,
+                        names::label(topic_id),
+                        frame.measurement
+                    );
+                }
+                acknowledge(&frame);
+                return;
+            }
+        };
+        let measurement = reading.measurement;
+        if frame.logged {
+            info!(
+                "Processing - Topic: {} | Measurement: {}",
+                names::label(topic_id),
+                measurement
+            );
+        }
+        if let Some(bench) = self.bench.as_mut() {
+            bench.record();
+        }
+        if let Some(auto_sleep) = self.auto_sleep.as_mut() {
+            auto_sleep.activity();
+        }
+
+        let sample = Sample::now(topic_id, measurement);
+        self.watchdog.enter(Stage::Storage);
+        if let Some(log) = self.datalog.as_mut() {
+            if let Err(e) = log.append(sample) {
+                warn!("Data log write failed: {}", e);
+            }
+        }
+        if let Some(history) = self.history.as_mut() {
+            if let Err(e) = history.record(&sample) {
+                warn!("History write failed: {}", e);
+            }
+        }
+
+        self.watchdog.enter(Stage::Uplinks);
+        let measured = Measurement {
+            sample,
+            alarm: reading.alarm,
+        };
+        match self.dispatcher.dispatch(measured) {
+            AlertAction::Raise(priority) => {
+                let new = self.alerts.state(topic_id) == AlarmState::Clear;
+                if self.alerts.raise(sample, priority) {
+                    self.uplinks.publish(Event::Alarm { sample, priority });
+                }
+                if new {
+                    self.actuating.push(frame.received_us);
+                }
+            }
+            AlertAction::Clear => self.alerts.clear(topic_id, self.uplinks),
+            AlertAction::Anomaly(unusual) => {
+                let text = if unusual {
+                    Text::Unusual
+                } else {
+                    Text::UsualAgain
+                };
+                warn!(
+                    "{}",
+                    strings::text(text, &[&names::label(topic_id), &measurement])
+                );
+                self.uplinks.publish(Event::Anomaly { sample, unusual });
+            }
+            AlertAction::None => {}
+        }
+
+        self.uplinks.publish(Event::Measurement(sample));
+        acknowledge(&frame);
+        #[cfg(feature = "tracing")]
+        {
+            *self.traced = Some(dispatch.end(trace::Stage::Dispatch));
+        }
+    }
+}
+
+/// Queue the ack of a sequenced frame, sent at the next [`ack::flush`]
+fn acknowledge(frame: &Frame) {
+    if let (Some(src), Some(done)) = (frame.src, frame.ack) {
+        ack::queue(src, done);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Golden traces: each tests/golden/<name>.pcap, captured with `capture
+    // file`, is replayed through the context on the mock clock, and the
+    // changes of the LEDs and the buzzer have to match <name>.events line
+    // for line. UPDATE_GOLDEN=1 rewrites the events of a trace whose outputs
+    // changed on purpose.
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::clock;
+    use crate::gpio_io::Output;
+    use crate::handlers::{Rules, TopicHandler};
+    use crate::output_guard::GuardedOutput;
+    use crate::platform::sys::{esp_timer_get_time, EspError};
+    use crate::protocol;
+    use crate::thresholds::{self, Band, Limit, Rule};
+
+    const KETTLE: i32 = 1;
+    const SINK: i32 = 2;
+    // The main loop's tick, between output refreshes
+    const TICK: Duration = Duration::from_millis(10);
+    // Replayed past the last frame, for the outputs to settle
+    const TAIL: Duration = Duration::from_secs(2);
+
+    static KETTLE_BANDS: [Band; 2] = [
+        Band {
+            limit: Limit::Above(70),
+            priority: Priority::Warning,
+        },
+        Band {
+            limit: Limit::Above(95),
+            priority: Priority::Critical,
+        },
+    ];
+    static RULES: [Rule; 2] = [
+        Rule {
+            topic_id: KETTLE,
+            alarm: Limit::Above(50),
+            priority: Priority::Info,
+            bands: &KETTLE_BANDS,
+            requires: &[],
+        },
+        Rule {
+            topic_id: SINK,
+            alarm: Limit::Below(5),
+            priority: Priority::Warning,
+            bands: &[],
+            requires: &[],
+        },
+    ];
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// An output noting its changes in `log`, timed from `start`
+    struct Recorder {
+        name: &'static str,
+        gpio: i32,
+        state: Option<String>,
+        start: Instant,
+        log: Log,
+    }
+
+    impl Recorder {
+        fn boxed(name: &'static str, gpio: i32, start: Instant, log: &Log) -> Box<dyn Output> {
+            Box::new(Self {
+                name,
+                gpio,
+                state: None,
+                start,
+                log: log.clone(),
+            })
+        }
+
+        fn record(&mut self, state: String) {
+            if self.state.as_ref() == Some(&state) {
+                return;
+            }
+            let ms = clock::since(self.start).as_millis();
+            let line = format!("{:>6} ms {} {}", ms, self.name, state);
+            self.log.lock().unwrap().push(line);
+            self.state = Some(state);
+        }
+    }
+
+    impl Output for Recorder {
+        fn gpio(&self) -> i32 {
+            self.gpio
+        }
+
+        fn set_level(&mut self, on: bool) -> Result<(), EspError> {
+            self.record(if on { "on" } else { "off" }.to_string());
+            Ok(())
+        }
+
+        fn set_tone(&mut self, hz: Option<u32>) -> Result<(), EspError> {
+            self.record(hz.map_or("off".to_string(), |hz| format!("{} Hz", hz)));
+            Ok(())
+        }
+    }
+
+    /// A frame as `capture` records it: when, from whom, at what RSSI
+    struct Packet {
+        at: Duration,
+        src: [u8; 6],
+        rssi: i32,
+        payload: Vec<u8>,
+    }
+
+    /// The ESP-NOW frames of a capture, in the layout the capture module
+    /// writes: PCAP records of a radiotap header ending in the RSSI and an
+    /// action frame carrying the vendor element
+    fn packets(pcap: &[u8]) -> Vec<Packet> {
+        let u16_at = |at: usize| u16::from_le_bytes([pcap[at], pcap[at + 1]]) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(pcap[at..at + 4].try_into().unwrap());
+        let mut packets = Vec::new();
+        let mut at = 24;
+        while at < pcap.len() {
+            let secs = u64::from(u32_at(at));
+            let micros = u64::from(u32_at(at + 4));
+            let len = u32_at(at + 8) as usize;
+            let record = at + 16;
+            at = record + len;
+            let radiotap = u16_at(record + 2);
+            let rssi = i32::from(pcap[record + radiotap - 1] as i8);
+            let frame = &pcap[record + radiotap..at];
+            assert_eq!((frame[0], frame[24], frame[32]), (0xd0, 127, 221));
+            let element = usize::from(frame[33]);
+            packets.push(Packet {
+                at: Duration::from_secs(secs) + Duration::from_micros(micros),
+                src: frame[10..16].try_into().unwrap(),
+                rssi,
+                payload: frame[39..34 + element].to_vec(),
+            });
+        }
+        packets
+    }
+
+    /// The readings of `packet` as the receive path queues them, its sender
+    /// a legacy peer as the captured ones send frames without a CRC
+    fn frames(packet: &Packet) -> Vec<Frame> {
+        let message = protocol::decode(&packet.payload, true).unwrap().unwrap();
+        let ack = message
+            .seq()
+            .zip(message.ack_topic())
+            .map(|(seq, topic_id)| Ack { topic_id, seq });
+        let received_us = unsafe { esp_timer_get_time() };
+        let readings = message.readings();
+        let count = readings.len();
+        readings
+            .into_iter()
+            .enumerate()
+            .map(|(i, reading)| Frame {
+                topic_id: reading.topic_id,
+                measurement: reading.measurement,
+                src: Some(packet.src),
+                battery: message.battery(),
+                rssi: Some(packet.rssi),
+                logged: false,
+                ack: ack.filter(|_| i + 1 == count),
+                critical: message.is_critical(),
+                received_us,
+                #[cfg(feature = "tracing")]
+                trace: trace::received(received_us),
+            })
+            .collect()
+    }
+
+    /// Replay the capture `name`, the outputs' changes one per line
+    fn replay(name: &str) -> String {
+        let path = golden(name, "pcap");
+        let pcap = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let (_turn, mock) = clock::mock();
+        thresholds::init(None, &RULES).unwrap();
+
+        let start = clock::now();
+        let log = Log::default();
+        let buzzer = Recorder::boxed("buzzer", 27, start, &log);
+        let leds = vec![
+            (KETTLE, Recorder::boxed("kettle LED", 25, start, &log)),
+            (SINK, Recorder::boxed("sink LED", 26, start, &log)),
+        ];
+        let buzzer = GuardedOutput::new("Buzzer", buzzer, Duration::from_secs(3600));
+        let mut annunciator = Annunciator::new(buzzer, leds);
+        let mut alerts = Alerts::default();
+        let mut uplinks = UplinkChain::default();
+        let mut pipelines = Pipelines::new(&[]);
+        let mut dispatcher = Dispatcher::new(Box::new(|_| -> Box<dyn TopicHandler> {
+            Box::new(Rules::new(None))
+        }));
+        let hour = Duration::from_secs(3600);
+        let watchdog = Watchdog::start(hour, hour * 2);
+        let mut actuating = Vec::new();
+        #[cfg(feature = "tracing")]
+        let mut traced = None;
+
+        let packets = packets(&pcap);
+        let first = packets.first().map_or(Duration::ZERO, |p| p.at);
+        let end = packets.last().map_or(Duration::ZERO, |p| p.at - first) + TAIL;
+        let mut packets = packets.into_iter().peekable();
+        let mut elapsed = Duration::ZERO;
+        while elapsed <= end {
+            let mut context = Context {
+                alerts: &mut alerts,
+                annunciator: &mut annunciator,
+                uplinks: &mut uplinks,
+                pipelines: &mut pipelines,
+                dispatcher: &mut dispatcher,
+                watchdog: &watchdog,
+                datalog: None,
+                history: None,
+                commissioning: None,
+                bench: None,
+                auto_sleep: None,
+                actuating: &mut actuating,
+                #[cfg(feature = "tracing")]
+                traced: &mut traced,
+            };
+            while let Some(packet) = packets.next_if(|p| p.at - first <= elapsed) {
+                for frame in frames(&packet) {
+                    context.handle(frame);
+                }
+            }
+            alerts.poll(&mut uplinks);
+            annunciator.poll(&alerts);
+            actuating.clear();
+            mock.advance(TICK);
+            elapsed += TICK;
+        }
+
+        let mut events = log.lock().unwrap().join("\n");
+        events.push('\n');
+        events
+    }
+
+    fn golden(name: &str, extension: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(name)
+            .with_extension(extension)
+    }
+
+    fn check(name: &str) {
+        let events = replay(name);
+        let path = golden(name, "events");
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &events).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            events == expected,
+            "{} replayed as\n{}\nexpected\n{}",
+            name,
+            events,
+            expected
+        );
+    }
+
+    #[test]
+    fn kettle_and_sink() {
+        check("kettle_and_sink");
+    }
+}
@@ -0,0 +1,458 @@
+//! ESP-NOW receive path
+//!
+//! The receive callback runs in the WiFi task and does no more than copy
+//! the frame, its sender and RSSI into a FreeRTOS queue of
+//! [`RAW_QUEUE_LENGTH`], waking the main loop. A frame arriving while the
+//! queue is full is dropped and counted, see the bench command; the callback
+//! never blocks the WiFi task.
+//!
+//! The main loop works the queue off with [`poll`]. Announcements and
+//! pairing requests are handed on there. Frames from senders the whitelist
+//! does not trust are dropped, fragmented payloads are reassembled, see the
+//! fragment module, and data frames failing authentication are dropped, see
+//! the auth module. Retransmissions of a frame already received are dropped
+//! once decoded, see the dedup module, and so is a message addressed to
+//! other receivers, see the addressing module. Commands go to the commands
+//! module. The readings of the rest wait, [`QUEUE_LENGTH`] at most, for the
+//! main loop to take them in order with [`take`]; the main loop acks the
+//! sequenced frames it handled, see the ack module. The last
+//! [`RECENT_FRAMES`] readings are kept for the inspect command, taken or
+//! not.
+//!
+//! Critical frames wait apart, [`CRITICAL_QUEUE_LENGTH`] at most, so a flood
+//! of ordinary ones cannot hold them up; the main loop takes them with
+//! [`take_critical`] first, see the fast_path module.
+//!
+//! `EspNow` brings ESP-NOW up and takes the send callback, which reports to
+//! the espnow_tx module. Its receive callback only passes the addresses, so
+//! that one is registered here directly: the RSSI the presence and link
+//! modules go by is only in the receive info's `rx_ctrl`.
+
+use core::ffi::c_int;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use esp_idf_svc::espnow::{EspNow, SendStatus};
+use esp_idf_svc::hal::delay::NON_BLOCK;
+use esp_idf_svc::hal::task::queue::Queue;
+use esp_idf_svc::sys::{
+    esp, esp_now_recv_info_t, esp_now_register_recv_cb, esp_timer_get_time, uxQueueMessagesWaiting,
+    EspError, ESP_ERR_INVALID_STATE, ESP_NOW_MAX_DATA_LEN,
+};
+use log::{info, warn};
+
+use crate::ack::{self, Ack};
+use crate::addressing;
+use crate::auth;
+use crate::bench::Counters;
+use crate::channel;
+use crate::clock;
+use crate::commands;
+use crate::dedup;
+use crate::dispatch::Frame;
+use crate::espnow_tx;
+use crate::fragment::{self, Received};
+use crate::inputs;
+use crate::log_governor;
+use crate::pairing;
+use crate::presence;
+use crate::protocol::{self, Format, Reading};
+use crate::senders::{self, Announcement};
+#[cfg(feature = "tracing")]
+use crate::trace;
+use crate::transforms;
+use crate::whitelist;
+
+/// Frames the callback's queue holds, the most [`poll`] decodes at a time
+pub const RAW_QUEUE_LENGTH: usize = QUEUE_LENGTH + CRITICAL_QUEUE_LENGTH;
+/// Readings waiting for the main loop, the most it handles per iteration
+pub const QUEUE_LENGTH: usize = 16;
+/// Critical readings waiting for the fast path
+pub const CRITICAL_QUEUE_LENGTH: usize = 4;
+// Longest frame the radio delivers
+const MAX_FRAME_LEN: usize = ESP_NOW_MAX_DATA_LEN as usize;
+/// Readings kept for the inspect command
+pub const RECENT_FRAMES: usize = 8;
+
+static ESPNOW: Mutex<Option<EspNow<'static>>> = Mutex::new(None);
+static RAW: OnceLock<Queue<Raw>> = OnceLock::new();
+static QUEUE: Mutex<VecDeque<Frame>> = Mutex::new(VecDeque::new());
+static CRITICAL: Mutex<VecDeque<Frame>> = Mutex::new(VecDeque::new());
+// Throughput counters for the bench command
+static FRAMES_RECEIVED: AtomicU32 = AtomicU32::new(0);
+static FRAMES_DROPPED: AtomicU32 = AtomicU32::new(0);
+// Frames that failed their CRC, and retransmissions already seen
+static RX_CRC_ERRORS: AtomicU32 = AtomicU32::new(0);
+static RX_DUPLICATES: AtomicU32 = AtomicU32::new(0);
+// Frames from senders the whitelist does not trust
+static RX_UNKNOWN_SENDERS: AtomicU32 = AtomicU32::new(0);
+// Frames without a CRC, taken from legacy peers and refused from others
+static RX_LEGACY: AtomicU32 = AtomicU32::new(0);
+static RX_LEGACY_REFUSED: AtomicU32 = AtomicU32::new(0);
+// Data frames without a valid HMAC while a secret is set
+static RX_AUTH_FAILURES: AtomicU32 = AtomicU32::new(0);
+// Per-frame log lines, muted while benchmarking so the UART is not measured
+static FRAME_LOGGING: AtomicBool = AtomicBool::new(true);
+// The last readings received, as (received at, frame, queued)
+static RECENT: Mutex<VecDeque<(Instant, Frame, bool)>> = Mutex::new(VecDeque::new());
+
+/// A frame as the receive callback copied it
+#[derive(Clone, Copy)]
+struct Raw {
+    src: Option<[u8; 6]>,
+    rssi: Option<i32>,
+    len: usize,
+    data: [u8; MAX_FRAME_LEN],
+    received_us: i64,
+    #[cfg(feature = "tracing")]
+    trace: trace::Frame,
+}
+
+/// Bring up ESP-NOW with the send callback, and the receive queue, before
+/// peers are added
+pub fn init() -> Result<(), EspError> {
+    RAW.get_or_init(|| Queue::new(RAW_QUEUE_LENGTH));
+    let espnow = EspNow::take()?;
+    info!("ESP-NOW Initialized");
+    if let Err(e) = espnow.register_send_cb(|_, status| {
+        espnow_tx::sent(matches!(status, SendStatus::SUCCESS));
+    }) {
+        warn!("ESP-NOW send callback registration failed: {}", e);
+    }
+    *ESPNOW.lock().unwrap() = Some(espnow);
+    Ok(())
+}
+
+/// Take ESP-NOW down with its peers and callbacks, so [`init`] starts it
+/// afresh
+pub fn deinit() {
+    if ESPNOW.lock().unwrap().take().is_some() {
+        info!("ESP-NOW deinitialized");
+    }
+}
+
+/// Start receiving frames, after [`init`]
+pub fn listen() -> Result<(), EspError> {
+    if ESPNOW.lock().unwrap().is_none() {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+    }
+    esp!(unsafe { esp_now_register_recv_cb(Some(on_receive)) })?;
+    info!("ESP-NOW receive callback registered");
+    Ok(())
+}
+
+/// The receive callback, with the RSSI `ReceiveInfo` leaves out
+unsafe extern "C" fn on_receive(info: *const esp_now_recv_info_t, data: *const u8, len: c_int) {
+    let Some(info) = info.as_ref() else {
+        return;
+    };
+    if info.src_addr.is_null() || data.is_null() || len < 0 {
+        return;
+    }
+    let src = *info.src_addr.cast::<[u8; 6]>();
+    let rssi = info.rx_ctrl.as_ref().map(|rx_ctrl| rx_ctrl.rssi());
+    copy(
+        Some(src),
+        rssi,
+        core::slice::from_raw_parts(data, len as usize),
+    );
+}
+
+/// Feed `frame` to the receive path as if it had come over the air, without
+/// sender or RSSI
+pub fn inject(frame: &[u8]) {
+    copy(None, None, frame);
+}
+
+/// Queue a frame for [`poll`], in the receive callback
+fn copy(src: Option<[u8; 6]>, rssi: Option<i32>, frame: &[u8]) {
+    #[cfg(feature = "tracing")]
+    let entered = trace::now_us();
+    let Some(queue) = RAW.get() else {
+        return;
+    };
+    let mut raw = Raw {
+        src,
+        rssi,
+        len: 0,
+        data: [0; MAX_FRAME_LEN],
+        received_us: unsafe { esp_timer_get_time() },
+        #[cfg(feature = "tracing")]
+        trace: trace::received(entered),
+    };
+    let Some(data) = raw.data.get_mut(..frame.len()) else {
+        return;
+    };
+    data.copy_from_slice(frame);
+    raw.len = frame.len();
+    if !queue.send_back(raw, NON_BLOCK).unwrap_or(false) {
+        FRAMES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        FRAMES_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // Once per batch, the main loop drains the queue when woken
+    if unsafe { uxQueueMessagesWaiting(queue.as_raw()) } == 1 {
+        inputs::raise(inputs::Event::Frame);
+    }
+}
+
+/// Handle the frames the receive callback queued, in order, in the main loop
+pub fn poll() {
+    let Some(queue) = RAW.get() else {
+        return;
+    };
+    for _ in 0..RAW_QUEUE_LENGTH {
+        let Some((raw, _)) = queue.recv_front(NON_BLOCK) else {
+            break;
+        };
+        receive(&raw);
+    }
+}
+
+/// The oldest reading in the receive queue
+pub fn take() -> Option<Frame> {
+    QUEUE.lock().unwrap().pop_front()
+}
+
+/// The oldest critical reading waiting for the fast path
+pub fn take_critical() -> Option<Frame> {
+    CRITICAL.lock().unwrap().pop_front()
+}
+
+/// Frames waiting in the callback's queue
+pub fn queued() -> usize {
+    RAW.get()
+        .map_or(0, |queue| unsafe { uxQueueMessagesWaiting(queue.as_raw()) }
+            as usize)
+}
+
+/// The last [`RECENT_FRAMES`] readings, oldest first, as one line each
+pub fn recent() -> String {
+    let mut list = String::new();
+    for (at, frame, queued) in RECENT.lock().unwrap().iter() {
+        list.push_str(&format!(
+            "{} s ago from {} topic {} = {}{}{}\n",
+            clock::since(*at).as_secs(),
+            frame
+                .src
+                .map_or_else(|| "injected".to_string(), |src| format!("{:02X?}", src)),
+            frame.topic_id,
+            frame.measurement,
+            frame
+                .rssi
+                .map_or_else(String::new, |rssi| format!(", {} dBm", rssi)),
+            if *queued { "" } else { ", dropped" }
+        ));
+    }
+    list
+}
+
+/// Turn the per-frame log lines on or off
+pub fn set_logging(on: bool) {
+    FRAME_LOGGING.store(on, Ordering::Relaxed);
+}
+
+/// Data frames rejected for a bad CRC since boot
+pub fn crc_errors() -> u32 {
+    RX_CRC_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Frames without a CRC taken since boot from peers allowed them, see the
+/// pairing module
+pub fn legacy_frames() -> u32 {
+    RX_LEGACY.load(Ordering::Relaxed)
+}
+
+/// Frames without a CRC dropped since boot for coming from a sender not
+/// allowed them
+pub fn legacy_refused() -> u32 {
+    RX_LEGACY_REFUSED.load(Ordering::Relaxed)
+}
+
+/// Retransmitted data frames dropped since boot, see the dedup module
+pub fn duplicates() -> u32 {
+    RX_DUPLICATES.load(Ordering::Relaxed)
+}
+
+/// Frames dropped since boot for coming from an untrusted sender, see the
+/// whitelist module
+pub fn unknown_senders() -> u32 {
+    RX_UNKNOWN_SENDERS.load(Ordering::Relaxed)
+}
+
+/// Data frames dropped since boot for failing authentication, see the auth
+/// module
+pub fn auth_failures() -> u32 {
+    RX_AUTH_FAILURES.load(Ordering::Relaxed)
+}
+
+/// Receive callback counters, for the bench command
+pub fn counters() -> Counters {
+    Counters {
+        received: FRAMES_RECEIVED.load(Ordering::Relaxed),
+        dropped: FRAMES_DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+/// Handle a frame the receive callback copied
+fn receive(raw: &Raw) {
+    let src = raw.src.as_ref();
+    let frame = &raw.data[..raw.len];
+    if let (Some(src), Some(rssi)) = (src, raw.rssi) {
+        presence::record(src, rssi);
+    }
+    // Announcements and pairing frames are told apart by their tag
+    if src.is_some_and(|src| pairing::receive(*src, frame)) {
+        return;
+    }
+    if src.is_some_and(|src| !whitelist::allows(src)) {
+        RX_UNKNOWN_SENDERS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // A payload over one frame is handled once its last fragment is in
+    match src.map(|src| fragment::receive(*src, frame)) {
+        Some(Received::Pending) => {}
+        Some(Received::Complete(payload)) => handle(raw, &payload),
+        Some(Received::Whole) | None => handle(raw, frame),
+    }
+}
+
+/// Handle a whole payload, of `raw` or reassembled up to it
+fn handle(raw: &Raw, frame: &[u8]) {
+    let src = raw.src.as_ref();
+    if let Some(announcement) = Announcement::parse(frame) {
+        if let Some(src) = src {
+            dedup::forget(*src);
+            senders::record(*src, announcement);
+        }
+        return;
+    }
+    // Injected frames are the hub's own
+    let frame = match src.map(|src| auth::open(src, frame)) {
+        Some(Ok(frame)) => frame,
+        Some(Err(_)) => {
+            RX_AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        None => frame,
+    };
+    // Frames without a CRC only from peers marked legacy, or injected
+    let legacy = src.map_or(true, pairing::takes_legacy);
+    let mut message = match protocol::decode(frame, legacy) {
+        Some(Ok(message)) => {
+            let format = protocol::format(frame);
+            if format == Some(Format::Unchecked) {
+                RX_LEGACY.fetch_add(1, Ordering::Relaxed);
+            }
+            if let (Some(src), Some(format)) = (src, format) {
+                senders::heard(*src, format);
+            }
+            message
+        }
+        Some(Err(protocol::Error::Crc)) => {
+            RX_CRC_ERRORS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // Still listed, so `senders` shows who needs `legacy <MAC> on`
+        Some(Err(protocol::Error::Unchecked)) => {
+            RX_LEGACY_REFUSED.fetch_add(1, Ordering::Relaxed);
+            if let Some(src) = src {
+                senders::heard(*src, Format::Unchecked);
+            }
+            return;
+        }
+        Some(Err(e)) => {
+            warn!("Dropping a data frame: {}", e);
+            return;
+        }
+        None => return,
+    };
+    // A channel scan locks on to the first valid frame of a known sender
+    if channel::scanning() && src.is_some_and(whitelist::known) {
+        channel::heard();
+    }
+    // Decoding leaves at most one envelope
+    if let protocol::Message::Addressed { to, message: inner } = message {
+        if !addressing::accepts(to) {
+            return;
+        }
+        message = *inner;
+    }
+    if let protocol::Message::Command { id, command } = message {
+        if let Some(src) = src {
+            commands::receive(*src, id, command);
+        }
+        return;
+    }
+    if let (protocol::Message::Reply { id, ok }, Some(src)) = (&message, src) {
+        addressing::replied(*src, *id, *ok);
+        return;
+    }
+    let readings = message.readings();
+    transforms::count_outliers(message.rejected() as u32);
+    let ack = message
+        .seq()
+        .zip(message.ack_topic())
+        .map(|(seq, topic_id)| Ack { topic_id, seq });
+    if let (Some(src), Some(seq)) = (src, message.seq()) {
+        if !dedup::accept(*src, seq) {
+            RX_DUPLICATES.fetch_add(1, Ordering::Relaxed);
+            if let Some(ack) = ack {
+                ack::queue(*src, ack);
+            }
+            return;
+        }
+    }
+    let battery = message.battery();
+    let critical = message.is_critical();
+    let (mut queue, length) = if critical {
+        (CRITICAL.lock().unwrap(), CRITICAL_QUEUE_LENGTH)
+    } else {
+        (QUEUE.lock().unwrap(), QUEUE_LENGTH)
+    };
+    let count = readings.len();
+    // Only a frame whose readings were all queued is acked
+    let mut all_queued = true;
+    for (i, reading) in readings.into_iter().enumerate() {
+        let Reading {
+            topic_id,
+            measurement,
+        } = reading;
+        let logged = FRAME_LOGGING.load(Ordering::Relaxed) && log_governor::frame(topic_id);
+        if logged {
+            info!(
+                "Received - Topic ID: {} | Measurement: {}",
+                topic_id, measurement
+            );
+        }
+
+        let frame = Frame {
+            topic_id,
+            measurement,
+            src: src.copied(),
+            battery,
+            rssi: raw.rssi,
+            logged,
+            ack: ack.filter(|_| all_queued && i + 1 == count),
+            critical,
+            received_us: raw.received_us,
+            #[cfg(feature = "tracing")]
+            trace: raw.trace,
+        };
+        FRAMES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        let queued = queue.len() < length;
+        if queued {
+            queue.push_back(frame);
+        } else {
+            FRAMES_DROPPED.fetch_add(1, Ordering::Relaxed);
+            all_queued = false;
+        }
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() >= RECENT_FRAMES {
+            recent.pop_front();
+        }
+        recent.push_back((clock::now(), frame, queued));
+    }
+}
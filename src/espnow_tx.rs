@@ -0,0 +1,306 @@
+//! Outgoing ESP-NOW frames with per-message QoS
+//!
+//! Frames originated by the receiver go through one queue and are sent one
+//! at a time, so every send callback can be matched to the frame it reports
+//! on. `Qos::FireAndForget` frames (heartbeats, periodic reports) are dropped
+//! whatever the outcome. `Qos::Reliable` frames (alarm notifications, acks)
+//! are retried with exponential backoff until the peer's MAC layer acks them.
+//!
+//! Under congestion, with the queue filling up or several sends in a row
+//! failing, the queue keeps to the reliable frames: queued fire-and-forget
+//! frames are shed and new ones refused until sends go through again. The
+//! driver running out of buffers is not held against a frame, it is sent
+//! again once the driver had time to drain.
+//!
+//! A frame over [`MAX_FRAME`] bytes is refused as it is queued; longer
+//! payloads go through the fragment module.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::fragment::MAX_FRAME;
+use crate::keys;
+use crate::platform::sys::{
+    esp, esp_now_add_peer, esp_now_is_peer_exist, esp_now_peer_info_t, esp_now_send,
+    wifi_interface_t_WIFI_IF_STA, EspError, ESP_ERR_ESPNOW_NO_MEM, ESP_ERR_INVALID_SIZE,
+    ESP_ERR_NO_MEM,
+};
+
+/// Destination address that reaches every ESP-NOW device on the channel
+pub const BROADCAST: [u8; 6] = [0xFF; 6];
+
+const MAX_QUEUED: usize = 16;
+const MAX_ATTEMPTS: u32 = 10;
+const BASE_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// The send callback always fires, this only guards against a wedged driver
+const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+// Congested from this queue depth or this many failed sends in a row
+const CONGESTION_DEPTH: usize = MAX_QUEUED * 3 / 4;
+const CONGESTION_FAILURES: u32 = 3;
+// Pause after the driver reported it is out of buffers
+const DRIVER_FULL_BACKOFF: Duration = Duration::from_millis(20);
+
+const STATUS_PENDING: u8 = 0;
+const STATUS_SUCCESS: u8 = 1;
+const STATUS_FAIL: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Qos {
+    FireAndForget,
+    Reliable,
+}
+
+/// Outcome of one tracked frame, see [`send_tracked`]
+#[derive(Clone)]
+pub struct Receipt(Arc<AtomicU8>);
+
+impl Receipt {
+    /// `None` while the frame is queued or in flight
+    pub fn outcome(&self) -> Option<bool> {
+        match self.0.load(Ordering::SeqCst) {
+            STATUS_PENDING => None,
+            status => Some(status == STATUS_SUCCESS),
+        }
+    }
+
+    fn settle(&self, status: u8) {
+        self.0
+            .compare_exchange(STATUS_PENDING, status, Ordering::SeqCst, Ordering::SeqCst)
+            .ok();
+    }
+}
+
+struct Frame {
+    peer: [u8; 6],
+    data: Vec<u8>,
+    qos: Qos,
+    attempts: u32,
+    not_before: Instant,
+    receipt: Option<Receipt>,
+}
+
+impl Drop for Frame {
+    // However a frame leaves the queue without being acked, it failed
+    fn drop(&mut self) {
+        if let Some(receipt) = self.receipt.as_ref() {
+            receipt.settle(STATUS_FAIL);
+        }
+    }
+}
+
+struct TxQueue {
+    queue: VecDeque<Frame>,
+    in_flight: Option<(Frame, Instant)>,
+    /// Failed sends since the last success
+    failures: u32,
+    /// Fire-and-forget frames shed during the current congestion
+    shed: u32,
+}
+
+impl TxQueue {
+    fn congested(&self) -> bool {
+        self.queue.len() >= CONGESTION_DEPTH || self.failures >= CONGESTION_FAILURES
+    }
+
+    /// Drop the queued fire-and-forget frames
+    fn shed(&mut self) {
+        let before = self.queue.len();
+        self.queue.retain(|f| f.qos == Qos::Reliable);
+        self.count_shed((before - self.queue.len()) as u32);
+    }
+
+    fn count_shed(&mut self, frames: u32) {
+        if frames > 0 && self.shed == 0 {
+            warn!("ESP-NOW send queue congested, shedding fire-and-forget frames");
+        }
+        self.shed += frames;
+    }
+
+    /// A send went through, or there is nothing left to send
+    fn relieved(&mut self) {
+        self.failures = 0;
+        if self.shed > 0 && !self.congested() {
+            info!("ESP-NOW send queue recovered, {} frames shed", self.shed);
+            self.shed = 0;
+        }
+    }
+}
+
+static TX: Mutex<TxQueue> = Mutex::new(TxQueue {
+    queue: VecDeque::new(),
+    in_flight: None,
+    failures: 0,
+    shed: 0,
+});
+static SEND_STATUS: AtomicU8 = AtomicU8::new(STATUS_PENDING);
+
+/// Report the outcome of the frame in flight, from the ESP-NOW send
+/// callback the espnow module registers
+pub fn sent(success: bool) {
+    let status = if success { STATUS_SUCCESS } else { STATUS_FAIL };
+    SEND_STATUS.store(status, Ordering::SeqCst);
+}
+
+/// Register `peer` on the current channel unless it already is, encrypted
+/// if it has an LMK
+pub fn add_peer(peer: [u8; 6]) -> Result<(), EspError> {
+    unsafe {
+        if esp_now_is_peer_exist(peer.as_ptr()) {
+            return Ok(());
+        }
+        let lmk = keys::lmk(&peer);
+        let info = esp_now_peer_info_t {
+            peer_addr: peer,
+            channel: 0,
+            ifidx: wifi_interface_t_WIFI_IF_STA,
+            encrypt: lmk.is_some(),
+            lmk: lmk.unwrap_or_default(),
+            ..Default::default()
+        };
+        esp!(esp_now_add_peer(&info))
+    }
+}
+
+/// Queue a frame for `peer`
+///
+/// When the queue is full a fire-and-forget frame is shed to make room for a
+/// reliable one; a fire-and-forget frame is refused instead, as it is while
+/// the queue is congested.
+pub fn send(peer: [u8; 6], data: &[u8], qos: Qos) -> Result<(), EspError> {
+    enqueue(peer, data, qos, None)
+}
+
+/// Like [`send`], with a receipt reporting whether the frame went out
+pub fn send_tracked(peer: [u8; 6], data: &[u8], qos: Qos) -> Result<Receipt, EspError> {
+    let receipt = Receipt(Arc::new(AtomicU8::new(STATUS_PENDING)));
+    enqueue(peer, data, qos, Some(receipt.clone()))?;
+    Ok(receipt)
+}
+
+fn enqueue(peer: [u8; 6], data: &[u8], qos: Qos, receipt: Option<Receipt>) -> Result<(), EspError> {
+    if data.len() > MAX_FRAME {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+    }
+    let mut tx = TX.lock().unwrap();
+
+    if qos == Qos::FireAndForget && tx.congested() {
+        tx.count_shed(1);
+        return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+    }
+    if tx.queue.len() >= MAX_QUEUED {
+        let victim = tx.queue.iter().position(|f| f.qos == Qos::FireAndForget);
+        match (qos, victim) {
+            (Qos::Reliable, Some(i)) => {
+                tx.queue.remove(i);
+            }
+            _ => return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>()),
+        }
+    }
+
+    tx.queue.push_back(Frame {
+        peer,
+        data: data.to_vec(),
+        qos,
+        attempts: 0,
+        not_before: Instant::now(),
+        receipt,
+    });
+    Ok(())
+}
+
+/// Queue depth and state, for the inspect command
+pub fn summary() -> String {
+    let tx = TX.lock().unwrap();
+    let reliable = tx.queue.iter().filter(|f| f.qos == Qos::Reliable).count();
+    format!(
+        "Send queue {}/{}, {} reliable{}{}, {} failed in a row, {} shed",
+        tx.queue.len(),
+        MAX_QUEUED,
+        reliable,
+        if tx.in_flight.is_some() {
+            ", one in flight"
+        } else {
+            ""
+        },
+        if tx.congested() { ", congested" } else { "" },
+        tx.failures,
+        tx.shed
+    )
+}
+
+/// Drive the queue: settle the frame in flight and start the next one
+pub fn poll() {
+    let mut tx = TX.lock().unwrap();
+    let now = Instant::now();
+
+    if let Some((frame, sent_at)) = tx.in_flight.take() {
+        match SEND_STATUS.load(Ordering::SeqCst) {
+            STATUS_SUCCESS => {
+                if let Some(receipt) = frame.receipt.as_ref() {
+                    receipt.settle(STATUS_SUCCESS);
+                }
+                tx.relieved();
+            }
+            STATUS_PENDING if now.duration_since(sent_at) < SEND_TIMEOUT => {
+                tx.in_flight = Some((frame, sent_at));
+                return;
+            }
+            _ => {
+                tx.failures += 1;
+                retry(&mut tx, frame, now);
+            }
+        }
+    }
+    if tx.queue.is_empty() {
+        tx.relieved();
+        return;
+    }
+    if tx.congested() {
+        tx.shed();
+    }
+
+    let Some(i) = tx.queue.iter().position(|f| f.not_before <= now) else {
+        return;
+    };
+    let mut frame = tx.queue.remove(i).unwrap();
+    frame.attempts += 1;
+
+    SEND_STATUS.store(STATUS_PENDING, Ordering::SeqCst);
+    let result =
+        esp!(unsafe { esp_now_send(frame.peer.as_ptr(), frame.data.as_ptr(), frame.data.len()) });
+    match result {
+        Ok(()) => tx.in_flight = Some((frame, now)),
+        Err(e) if e.code() == ESP_ERR_ESPNOW_NO_MEM => {
+            frame.attempts -= 1;
+            frame.not_before = now + DRIVER_FULL_BACKOFF;
+            tx.queue.push_front(frame);
+        }
+        Err(e) => {
+            tx.failures += 1;
+            warn!("ESP-NOW send failed: {}", e);
+            retry(&mut tx, frame, now);
+        }
+    }
+}
+
+fn retry(tx: &mut TxQueue, mut frame: Frame, now: Instant) {
+    if frame.qos == Qos::FireAndForget {
+        return;
+    }
+    if frame.attempts >= MAX_ATTEMPTS {
+        warn!(
+            "Giving up on frame to {:02X?} after {} attempts",
+            frame.peer, frame.attempts
+        );
+        return;
+    }
+
+    let backoff = BASE_BACKOFF * 2u32.pow(frame.attempts - 1);
+    frame.not_before = now + backoff.min(MAX_BACKOFF);
+    tx.queue.push_front(frame);
+}
@@ -0,0 +1,213 @@
+//! Fast path for critical frames
+//!
+//! A reading that is an emergency by itself, a kettle boiling now, should
+//! not wait behind a queue of ordinary frames, a filter's averaging window or
+//! a flash write. A sender marks it as a [`Message::Critical`] and the
+//! receive path puts it on a queue of its own, which the main loop takes
+//! before and between the ordinary frames; the receive callback wakes the
+//! main loop at once.
+//! The frame then bypasses the topic's pipeline, the data log and history
+//! and the alarm's rate limit and gentle start: the alarm is raised at
+//! critical priority, sounding at full level, and the buzzer refreshed right
+//! away, before the uplinks hear of it.
+//!
+//! The time from the receive callback to the buzzer refresh must stay within
+//! [`DEADLINE`]. Every critical frame is timed, a late one is logged, and the
+//! count, misses and worst time go to inspect and the status event. The
+//! self-test times an injected critical frame against the same budget.
+//!
+//! The sender's regular readings still go the ordinary way, so the pipeline
+//! clears the alarm once the topic is back in range, and the history and
+//! freshness follow those.
+//!
+//! [`Message::Critical`]: crate::protocol::Message::Critical
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::names;
+use crate::platform::sys::esp_timer_get_time;
+
+/// Longest a critical frame may take from the receive callback to the buzzer
+pub const DEADLINE: Duration = Duration::from_millis(50);
+
+static STATS: Mutex<Stats> = Mutex::new(Stats {
+    frames: 0,
+    missed: 0,
+    worst: Duration::ZERO,
+});
+
+struct Stats {
+    frames: u32,
+    missed: u32,
+    worst: Duration,
+}
+
+/// Time since the receive callback took a frame at `received_us`, in
+/// microseconds since boot
+pub fn since(received_us: i64) -> Duration {
+    let now = unsafe { esp_timer_get_time() };
+    Duration::from_micros(now.saturating_sub(received_us).max(0) as u64)
+}
+
+/// Count a critical frame of `topic_id` that reached the buzzer in
+/// `latency`, warning if it took longer than [`DEADLINE`]
+pub fn actuated(topic_id: i32, latency: Duration) {
+    let mut stats = STATS.lock().unwrap();
+    stats.frames = stats.frames.saturating_add(1);
+    stats.worst = stats.worst.max(latency);
+    if latency > DEADLINE {
+        stats.missed = stats.missed.saturating_add(1);
+        warn!(
+            "Critical frame of {} took {} ms to the buzzer, over {} ms",
+            names::label(topic_id),
+            latency.as_millis(),
+            DEADLINE.as_millis()
+        );
+    }
+}
+
+/// `{"frames":..,"missed":..,"worst_ms":..}`, for the status event
+pub fn json() -> String {
+    let stats = STATS.lock().unwrap();
+    format!(
+        r#"{{"frames":{},"missed":{},"worst_ms":{}}}"#,
+        stats.frames,
+        stats.missed,
+        stats.worst.as_millis()
+    )
+}
+
+/// The figures in one line, for inspect
+pub fn summary() -> String {
+    let stats = STATS.lock().unwrap();
+    format!(
+        "Fast path: {} critical frames, {} over {} ms, worst {} ms",
+        stats.frames,
+        stats.missed,
+        DEADLINE.as_millis(),
+        stats.worst.as_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::alerts::{AlarmState, Alerts};
+    use crate::annunciator::Annunciator;
+    use crate::clock;
+    use crate::dispatch::{Context, Frame};
+    use crate::gpio_io::Output;
+    use crate::handlers::{Dispatcher, Rules, TopicHandler};
+    use crate::output_guard::GuardedOutput;
+    use crate::platform::sys::EspError;
+    use crate::transforms::Pipelines;
+    use crate::uplink::UplinkChain;
+    use crate::watchdog::Watchdog;
+
+    const SMOKE: i32 = 5;
+
+    fn stats() -> (u32, u32, Duration) {
+        let stats = STATS.lock().unwrap();
+        (stats.frames, stats.missed, stats.worst)
+    }
+
+    /// A buzzer noting when it first sounded, in microseconds since boot
+    struct Buzzer(Arc<Mutex<Option<i64>>>);
+
+    impl Output for Buzzer {
+        fn gpio(&self) -> i32 {
+            27
+        }
+
+        fn set_level(&mut self, on: bool) -> Result<(), EspError> {
+            let mut sounded = self.0.lock().unwrap();
+            if on && sounded.is_none() {
+                *sounded = Some(unsafe { esp_timer_get_time() });
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn since_counts_from_the_callback() {
+        let now = unsafe { esp_timer_get_time() };
+        assert!(since(now - 30_000) >= Duration::from_millis(30));
+        assert_eq!(since(now + 1_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn only_frames_over_the_deadline_are_missed() {
+        // Other tests count critical frames too
+        let _turn = clock::mock();
+        let (frames, missed, _) = stats();
+        actuated(SMOKE, DEADLINE);
+        actuated(SMOKE, DEADLINE + Duration::from_millis(1));
+        actuated(SMOKE, Duration::from_millis(3));
+        let (now_frames, now_missed, worst) = stats();
+        assert_eq!((now_frames - frames, now_missed - missed), (3, 1));
+        assert!(worst >= DEADLINE + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn critical_frames_sound_within_the_deadline() {
+        let _turn = clock::mock();
+        let sounded = Arc::new(Mutex::new(None));
+        let buzzer = Box::new(Buzzer(sounded.clone()));
+        let buzzer = GuardedOutput::new("Buzzer", buzzer, Duration::from_secs(60));
+        let mut annunciator = Annunciator::new(buzzer, Vec::new());
+        let mut alerts = Alerts::default();
+        let mut uplinks = UplinkChain::default();
+        let mut pipelines = Pipelines::new(&[]);
+        let mut dispatcher = Dispatcher::new(Box::new(|_| -> Box<dyn TopicHandler> {
+            Box::new(Rules::new(None))
+        }));
+        let hour = Duration::from_secs(3600);
+        let watchdog = Watchdog::start(hour, hour * 2);
+        let mut actuating = Vec::new();
+        #[cfg(feature = "tracing")]
+        let mut traced = None;
+        let mut context = Context {
+            alerts: &mut alerts,
+            annunciator: &mut annunciator,
+            uplinks: &mut uplinks,
+            pipelines: &mut pipelines,
+            dispatcher: &mut dispatcher,
+            watchdog: &watchdog,
+            datalog: None,
+            history: None,
+            commissioning: None,
+            bench: None,
+            auto_sleep: None,
+            actuating: &mut actuating,
+            #[cfg(feature = "tracing")]
+            traced: &mut traced,
+        };
+
+        let (frames, missed, _) = stats();
+        let received_us = unsafe { esp_timer_get_time() };
+        context.handle(Frame {
+            topic_id: SMOKE,
+            measurement: 1,
+            src: None,
+            battery: None,
+            rssi: None,
+            logged: false,
+            ack: None,
+            critical: true,
+            received_us,
+            #[cfg(feature = "tracing")]
+            trace: crate::trace::received(received_us),
+        });
+
+        let sounded = sounded.lock().unwrap().expect("buzzer did not sound");
+        assert!(Duration::from_micros((sounded - received_us) as u64) <= DEADLINE);
+        assert_eq!(stats().0 - frames, 1);
+        assert_eq!(stats().1, missed);
+        assert_eq!(alerts.state(SMOKE), AlarmState::Sounding);
+    }
+}
@@ -0,0 +1,220 @@
+//! Pre-arming the frost alarm on a freezing night forecast
+//!
+//! With a forecast URL set at build time (`FORECAST_URL`), a background
+//! thread GETs it every `[forecast] refresh_mins` while the AP is joined.
+//! The body gives tonight's low in the sink alarm's units, either as a plain
+//! number or as a JSON object with a `"min"` field, so any service or a
+//! small proxy in front of one will do.
+//!
+//! While the low is below `freezing` and it is night on the local clock,
+//! see the timezone module, the sink alarm's limit is tightened by
+//! `tighten_by`: the alarm goes off earlier on the way down, while there is
+//! still time to open a tap. With the clock not set yet any hour counts as
+//! night. A forecast not refreshed for three intervals is dropped and the
+//! limit goes back to normal; nothing is stored, so a reset starts over.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use log::{info, warn};
+
+use crate::clock;
+use crate::degraded::{self, Mode};
+use crate::http::read_body;
+use crate::thresholds;
+use crate::timezone;
+
+const STACK_SIZE: usize = 8192;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BODY: usize = 512;
+// How soon a failed fetch is tried again
+const RETRY: Duration = Duration::from_secs(300);
+// Refresh intervals a forecast is used for without being refreshed
+const STALE_AFTER: u32 = 3;
+
+static STATE: Mutex<State> = Mutex::new(State {
+    config: None,
+    low: None,
+    error: None,
+    armed: false,
+});
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub url: &'static str,
+    /// The frost alarm's topic
+    pub topic_id: i32,
+    /// Lows below this pre-arm the alarm
+    pub freezing: i32,
+    /// How far the limit moves while pre-armed
+    pub tighten_by: i32,
+    pub refresh: Duration,
+    /// Local hours the night starts and ends at, wrapping past midnight
+    pub night: (u32, u32),
+}
+
+struct State {
+    config: Option<Config>,
+    /// Tonight's low and when it was fetched
+    low: Option<(Instant, i32)>,
+    /// Why the last fetch failed, cleared by the next good one
+    error: Option<String>,
+    armed: bool,
+}
+
+impl State {
+    fn fresh_low(&self, config: &Config) -> Option<i32> {
+        let (fetched, low) = self.low?;
+        (clock::since(fetched) < config.refresh * STALE_AFTER).then_some(low)
+    }
+}
+
+/// Start fetching forecasts as `config` says
+pub fn start(config: Config) -> std::io::Result<()> {
+    STATE.lock().unwrap().config = Some(config);
+    thread::Builder::new()
+        .name("forecast".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            let wait = if degraded::is_active(Mode::LocalOnly) {
+                RETRY
+            } else {
+                refresh(&config)
+            };
+            thread::sleep(wait);
+        })?;
+    info!(
+        "Forecast from {} every {} min",
+        config.url,
+        config.refresh.as_secs() / 60
+    );
+    Ok(())
+}
+
+/// Pre-arm the frost alarm or stand it down as the forecast and the hour
+/// say, in the main loop
+pub fn poll() {
+    let mut state = STATE.lock().unwrap();
+    let Some(config) = state.config else {
+        return;
+    };
+    let low = state.fresh_low(&config);
+    let wanted = low.is_some_and(|low| low < config.freezing) && is_night(&config);
+    if wanted == state.armed {
+        return;
+    }
+    state.armed = wanted;
+    match thresholds::tighten(config.topic_id, wanted.then_some(config.tighten_by)) {
+        Ok(limit) if wanted => info!(
+            "Forecast low {} tonight, frost alarm pre-armed at {}",
+            low.unwrap_or_default(),
+            limit
+        ),
+        Ok(limit) => info!("Frost alarm back at {}", limit),
+        Err(e) => warn!("Frost alarm not pre-armed: {}", e),
+    }
+}
+
+/// The last forecast and whether the frost alarm is pre-armed, for inspect
+pub fn summary() -> String {
+    let state = STATE.lock().unwrap();
+    let Some(config) = state.config else {
+        return "No forecast URL".to_string();
+    };
+    let mut summary = match state.low {
+        Some((fetched, low)) => format!(
+            "Low {} tonight, fetched {} min ago",
+            low,
+            clock::since(fetched).as_secs() / 60
+        ),
+        None => "No forecast yet".to_string(),
+    };
+    if state.low.is_some() && state.fresh_low(&config).is_none() {
+        summary.push_str(", stale");
+    }
+    if let Some(e) = &state.error {
+        summary.push_str(&format!(", last fetch failed: {}", e));
+    }
+    summary.push_str(if state.armed {
+        ", frost alarm pre-armed"
+    } else {
+        ", frost alarm normal"
+    });
+    summary
+}
+
+/// Fetch the forecast once, the wait until the next fetch
+fn refresh(config: &Config) -> Duration {
+    let result = fetch(config.url);
+    let mut state = STATE.lock().unwrap();
+    match result {
+        Ok(low) => {
+            state.low = Some((clock::now(), low));
+            state.error = None;
+            config.refresh
+        }
+        Err(e) => {
+            if state.error.is_none() {
+                warn!("Forecast fetch failed: {}", e);
+            }
+            state.error = Some(e);
+            RETRY.min(config.refresh)
+        }
+    }
+}
+
+fn fetch(url: &str) -> Result<i32, String> {
+    let mut conn = EspHttpConnection::new(&Configuration {
+        timeout: Some(TIMEOUT),
+        ..Default::default()
+    })
+    .map_err(|e| e.to_string())?;
+    conn.initiate_request(Method::Get, url, &[])
+        .map_err(|e| e.to_string())?;
+    conn.initiate_response().map_err(|e| e.to_string())?;
+    match conn.status() {
+        200..=299 => {}
+        status => return Err(format!("HTTP {}", status)),
+    }
+    let body = read_body(&mut conn, MAX_BODY)
+        .map_err(|e| e.to_string())?
+        .map_err(str::to_string)?;
+    parse(&body).ok_or_else(|| "no low in the forecast".to_string())
+}
+
+/// The low in `body`, a number alone or the `"min"` field of JSON, rounded
+fn parse(body: &str) -> Option<i32> {
+    let body = body.trim();
+    let number = match body.parse::<f32>() {
+        Ok(number) => number,
+        Err(_) => {
+            let (_, rest) = body.split_once("\"min\"")?;
+            let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+            let end = rest
+                .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                .unwrap_or(rest.len());
+            rest[..end].parse().ok()?
+        }
+    };
+    let rounded = number.round();
+    (rounded.is_finite() && rounded.abs() < 1e6).then_some(rounded as i32)
+}
+
+/// Whether the local hour is in the night window, assumed with the clock
+/// not set
+fn is_night(config: &Config) -> bool {
+    let now = clock::unix_secs();
+    if now < clock::VALID_AFTER {
+        return true;
+    }
+    let hour = timezone::local(now).hour;
+    let (from, to) = config.night;
+    if from <= to {
+        (from..to).contains(&hour)
+    } else {
+        hour >= from || hour < to
+    }
+}
@@ -0,0 +1,219 @@
+//! Payloads larger than one ESP-NOW frame
+//!
+//! ESP-NOW carries at most [`MAX_FRAME`] bytes. A longer payload, e.g. a
+//! config blob or a batch of log lines, is split into fragments, each one
+//! frame:
+//!
+//! `b"FRG"`, message id (u16 LE), fragment index, fragment count, then the
+//! next slice of the payload
+//!
+//! The message id is the sender's choice and only has to differ from its
+//! previous message's. Fragments may arrive in any order; once a message is
+//! complete, the payload goes through the receive path as if it had come in
+//! one frame, so it is authenticated and decoded as a whole. A message whose
+//! fragments do not all arrive within [`REASSEMBLY_TIMEOUT`] is dropped. At
+//! most [`MAX_PARTIAL`] messages are reassembled at once, a further one
+//! evicts the oldest, and none may exceed [`MAX_MESSAGE`] bytes.
+
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::espnow_tx::{self, Qos};
+use crate::platform::sys::{EspError, ESP_ERR_INVALID_SIZE};
+
+const TAG: &[u8; 3] = b"FRG";
+const HEADER_LEN: usize = TAG.len() + 4;
+/// Largest ESP-NOW frame
+pub const MAX_FRAME: usize = 250;
+/// Payload bytes per fragment
+pub const MAX_CHUNK: usize = MAX_FRAME - HEADER_LEN;
+/// Largest payload that is reassembled
+pub const MAX_MESSAGE: usize = 4096;
+/// Messages reassembled at once
+pub const MAX_PARTIAL: usize = 4;
+/// How long the fragments of one message may take to arrive
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+static PARTIAL: Mutex<Vec<Partial>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+// Messages dropped incomplete, timed out or evicted, and fragments dropped
+// as malformed or for making a message too big
+static INCOMPLETE: AtomicU32 = AtomicU32::new(0);
+static REJECTED: AtomicU32 = AtomicU32::new(0);
+
+struct Partial {
+    src: [u8; 6],
+    id: u16,
+    chunks: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+impl Partial {
+    fn len(&self) -> usize {
+        self.chunks.iter().flatten().map(Vec::len).sum()
+    }
+}
+
+/// What a frame was to the reassembly
+pub enum Received {
+    /// Not a fragment, handle the frame as it is
+    Whole,
+    /// A fragment of a message still incomplete, or dropped
+    Pending,
+    /// The last missing fragment, with the complete payload
+    Complete(Vec<u8>),
+}
+
+/// Split `payload` into fragment frames under message `id`
+pub fn split(id: u16, payload: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+    if payload.len() > MAX_MESSAGE {
+        return Err("payload too large");
+    }
+    let chunks: Vec<&[u8]> = payload.chunks(MAX_CHUNK).collect();
+    let total = chunks.len() as u8;
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+            frame.extend_from_slice(TAG);
+            frame.extend_from_slice(&id.to_le_bytes());
+            frame.push(index as u8);
+            frame.push(total);
+            frame.extend_from_slice(chunk);
+            frame
+        })
+        .collect())
+}
+
+/// Queue `payload` for `peer`, fragmented if it does not fit one frame
+pub fn send(peer: [u8; 6], payload: &[u8], qos: Qos) -> Result<(), EspError> {
+    if payload.len() <= MAX_FRAME {
+        return espnow_tx::send(peer, payload, qos);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let frames =
+        split(id, payload).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+    for frame in frames {
+        espnow_tx::send(peer, &frame, qos)?;
+    }
+    Ok(())
+}
+
+/// Take a frame from `src` into the reassembly, on the receive path
+pub fn receive(src: [u8; 6], frame: &[u8]) -> Received {
+    let Some(rest) = frame.strip_prefix(TAG) else {
+        return Received::Whole;
+    };
+    let (id, index, total, chunk) = match rest {
+        [lo, hi, index, total, chunk @ ..]
+            if index < total && !chunk.is_empty() && chunk.len() <= MAX_CHUNK =>
+        {
+            (u16::from_le_bytes([*lo, *hi]), *index, *total, chunk)
+        }
+        _ => {
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+            return Received::Pending;
+        }
+    };
+
+    let mut partial = PARTIAL.lock().unwrap();
+    let before = partial.len();
+    partial.retain(|p| clock::since(p.started) < REASSEMBLY_TIMEOUT);
+    INCOMPLETE.fetch_add((before - partial.len()) as u32, Ordering::Relaxed);
+
+    let i = match partial.iter().position(|p| p.src == src && p.id == id) {
+        Some(i) if partial[i].chunks.len() == total.into() => i,
+        found => {
+            // A new message, or the id reused with another fragment count
+            if let Some(i) = found {
+                partial.remove(i);
+            }
+            if partial.len() >= MAX_PARTIAL {
+                partial.remove(0);
+                INCOMPLETE.fetch_add(1, Ordering::Relaxed);
+            }
+            partial.push(Partial {
+                src,
+                id,
+                chunks: vec![None; total.into()],
+                started: clock::now(),
+            });
+            partial.len() - 1
+        }
+    };
+    let message = &mut partial[i];
+    if message.chunks[usize::from(index)].is_some() {
+        // A retransmitted fragment
+        return Received::Pending;
+    }
+    if message.len() + chunk.len() > MAX_MESSAGE {
+        partial.remove(i);
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        return Received::Pending;
+    }
+    message.chunks[usize::from(index)] = Some(chunk.to_vec());
+    if message.chunks.iter().any(Option::is_none) {
+        return Received::Pending;
+    }
+    let message = partial.remove(i);
+    Received::Complete(message.chunks.into_iter().flatten().flatten().collect())
+}
+
+/// Reassembly state, for the inspect command
+pub fn summary() -> String {
+    format!(
+        "{} messages reassembling, {} dropped incomplete, {} fragments rejected",
+        PARTIAL.lock().unwrap().len(),
+        INCOMPLETE.load(Ordering::Relaxed),
+        REJECTED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        (0..MAX_CHUNK + 10).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn reassembles_within_the_timeout() {
+        let (_turn, clock) = clock::mock();
+        let src = [0xf7, 0, 0, 0, 0, 1];
+        let frames = split(1, &payload()).unwrap();
+        assert!(matches!(receive(src, &frames[1]), Received::Pending));
+        clock.advance(REASSEMBLY_TIMEOUT - Duration::from_millis(1));
+        match receive(src, &frames[0]) {
+            Received::Complete(whole) => assert_eq!(whole, payload()),
+            _ => panic!("not reassembled"),
+        }
+    }
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let (_turn, clock) = clock::mock();
+        let src = [0xf7, 0, 0, 0, 0, 2];
+        let frames = split(2, &payload()).unwrap();
+        let incomplete = INCOMPLETE.load(Ordering::Relaxed);
+        assert!(matches!(receive(src, &frames[0]), Received::Pending));
+        clock.advance(REASSEMBLY_TIMEOUT);
+
+        // The first fragment is gone, the last starts the message over
+        assert!(matches!(receive(src, &frames[1]), Received::Pending));
+        assert!(INCOMPLETE.load(Ordering::Relaxed) > incomplete);
+        assert!(matches!(receive(src, &frames[0]), Received::Complete(_)));
+    }
+
+    #[test]
+    fn frames_over_the_radio_limit_are_refused() {
+        let whole = vec![b'x'; MAX_FRAME + 1];
+        assert!(espnow_tx::send(espnow_tx::BROADCAST, &whole, Qos::FireAndForget).is_err());
+        let frames = split(3, &whole).unwrap();
+        assert!(frames.iter().all(|frame| frame.len() <= MAX_FRAME));
+        assert!(split(3, &vec![0; MAX_MESSAGE + 1]).is_err());
+    }
+}
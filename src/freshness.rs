@@ -0,0 +1,341 @@
+//! Sensors gone silent
+//!
+//! A sensor that dies sends nothing, and without this the hub would stay
+//! quiet about it for good. Each topic with a [`Timeout`] is expected to
+//! report within it; one that does not is offline, counting from boot for a
+//! topic not heard at all. Going offline is logged, sent upstream as an
+//! offline event and shown on the annunciator with a pattern of its own: a
+//! long slow blink on the topic's LED and a double low chirp every 30 s
+//! until acknowledged with the button. The next frame on the topic brings it
+//! back, any frame, readings the pipeline drops included.
+//!
+//! While ESP-NOW is down nothing can be heard, so the timeouts start over
+//! once it is back.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::datalog::Sample;
+use crate::names;
+use crate::strings::{self, Text};
+
+static STATE: Mutex<State> = Mutex::new(State {
+    topics: Vec::new(),
+    unwatched: Vec::new(),
+});
+
+/// How long `topic_id` may go without a frame, [`Duration::ZERO`] for ever
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    pub topic_id: i32,
+    pub after: Duration,
+}
+
+struct State {
+    topics: Vec<Topic>,
+    /// Topics with a zero timeout, for the summary
+    unwatched: Vec<i32>,
+}
+
+impl State {
+    /// Watch the topics of `timeouts` and no others, those watched already
+    /// keeping their state
+    fn watch(&mut self, timeouts: &[Timeout]) {
+        let watched = |t: &Timeout| !t.after.is_zero();
+        self.topics.retain(|topic| {
+            timeouts
+                .iter()
+                .any(|t| watched(t) && t.topic_id == topic.topic_id)
+        });
+        for timeout in timeouts.iter().filter(|t| watched(t)) {
+            match self
+                .topics
+                .iter_mut()
+                .find(|t| t.topic_id == timeout.topic_id)
+            {
+                Some(topic) => topic.after = timeout.after,
+                None => self.topics.push(Topic {
+                    topic_id: timeout.topic_id,
+                    after: timeout.after,
+                    heard: clock::now(),
+                    measurement: None,
+                    offline: false,
+                    acknowledged: false,
+                }),
+            }
+        }
+        self.unwatched = timeouts
+            .iter()
+            .filter(|t| !watched(t))
+            .map(|t| t.topic_id)
+            .collect();
+    }
+}
+
+struct Topic {
+    topic_id: i32,
+    after: Duration,
+    heard: Instant,
+    /// The last reading, for the offline event
+    measurement: Option<i32>,
+    offline: bool,
+    acknowledged: bool,
+}
+
+/// Watch the topics of `timeouts` and no others, counting from now
+pub fn init(timeouts: &[Timeout]) {
+    let mut state = STATE.lock().unwrap();
+    state.topics.clear();
+    state.watch(timeouts);
+}
+
+/// Watch the topics of `timeouts` and no others from now on; a topic
+/// watched already keeps counting from its last frame, and stays offline
+/// until it is heard
+pub fn set_timeouts(timeouts: &[Timeout]) {
+    STATE.lock().unwrap().watch(timeouts);
+}
+
+/// Note a frame on `topic_id`, the sample to report it back online with if
+/// it was offline
+pub fn heard(topic_id: i32, measurement: i32) -> Option<Sample> {
+    let mut state = STATE.lock().unwrap();
+    let topic = state.topics.iter_mut().find(|t| t.topic_id == topic_id)?;
+    topic.heard = clock::now();
+    topic.measurement = Some(measurement);
+    if !topic.offline {
+        return None;
+    }
+    topic.offline = false;
+    topic.acknowledged = false;
+    info!(
+        "{}",
+        strings::text(Text::SensorBack, &[&names::label(topic_id)])
+    );
+    Some(Sample::now(topic_id, measurement))
+}
+
+/// Take topics silent past their timeout offline, the samples to report
+/// them with; with `radio_down` the timeouts are restarted instead
+pub fn poll(radio_down: bool) -> Vec<Sample> {
+    let mut state = STATE.lock().unwrap();
+    let now = clock::now();
+    let mut offline = Vec::new();
+    for topic in state.topics.iter_mut() {
+        if radio_down {
+            topic.heard = now;
+            continue;
+        }
+        if topic.offline || now.duration_since(topic.heard) < topic.after {
+            continue;
+        }
+        topic.offline = true;
+        warn!(
+            "{}",
+            strings::text(
+                Text::SensorOffline,
+                &[
+                    &names::label(topic.topic_id),
+                    &(now.duration_since(topic.heard).as_secs() / 60)
+                ]
+            )
+        );
+        offline.push(Sample::now(
+            topic.topic_id,
+            topic.measurement.unwrap_or_default(),
+        ));
+    }
+    offline
+}
+
+/// Stop the chirp of every offline topic, their LEDs keep blinking
+pub fn acknowledge() {
+    let mut state = STATE.lock().unwrap();
+    for topic in state.topics.iter_mut().filter(|t| t.offline) {
+        topic.acknowledged = true;
+    }
+}
+
+/// Offline topics, and whether any is unacknowledged
+pub fn offline() -> (Vec<i32>, bool) {
+    let state = STATE.lock().unwrap();
+    let offline: Vec<&Topic> = state.topics.iter().filter(|t| t.offline).collect();
+    (
+        offline.iter().map(|t| t.topic_id).collect(),
+        offline.iter().any(|t| !t.acknowledged),
+    )
+}
+
+/// `[<topic_id>,...]` of the offline topics, for the status event
+pub fn json() -> String {
+    let (offline, _) = offline();
+    let ids: Vec<String> = offline.iter().map(i32::to_string).collect();
+    format!("[{}]", ids.join(","))
+}
+
+/// Every watched topic with its timeout and when it was last heard, for
+/// inspect
+pub fn summary() -> String {
+    let state = STATE.lock().unwrap();
+    let mut summary = String::new();
+    for topic in &state.topics {
+        summary.push_str(&format!(
+            "{}: heard {} s ago, offline after {} min{}\n",
+            names::label(topic.topic_id),
+            clock::since(topic.heard).as_secs(),
+            topic.after.as_secs() / 60,
+            match (topic.offline, topic.acknowledged) {
+                (false, _) => "",
+                (true, false) => ", offline",
+                (true, true) => ", offline, acknowledged",
+            }
+        ));
+    }
+    for &topic_id in &state.unwatched {
+        summary.push_str(&format!("{}: not watched\n", names::label(topic_id)));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AFTER: Duration = Duration::from_secs(30 * 60);
+    const TIMEOUTS: &[Timeout] = &[
+        Timeout {
+            topic_id: 61,
+            after: AFTER,
+        },
+        Timeout {
+            topic_id: 62,
+            after: Duration::ZERO,
+        },
+    ];
+
+    fn offline_ids(samples: &[Sample]) -> Vec<i32> {
+        samples.iter().map(|s| s.topic_id).collect()
+    }
+
+    #[test]
+    fn silent_topics_go_offline_once_past_their_timeout() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        assert_eq!(heard(61, 5), None);
+        clock.advance(AFTER - Duration::from_secs(1));
+        assert!(poll(false).is_empty());
+        assert_eq!(offline(), (vec![], false));
+
+        clock.advance(Duration::from_secs(1));
+        let gone = poll(false);
+        assert_eq!(offline_ids(&gone), [61]);
+        assert_eq!(gone[0].measurement, 5);
+        assert_eq!(offline(), (vec![61], true));
+        assert_eq!(json(), "[61]");
+        // Reported once, not on every poll
+        clock.advance(AFTER);
+        assert!(poll(false).is_empty());
+    }
+
+    #[test]
+    fn topics_never_heard_count_from_boot() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        clock.advance(AFTER);
+        let gone = poll(false);
+        assert_eq!(offline_ids(&gone), [61]);
+        assert_eq!(gone[0].measurement, 0);
+    }
+
+    #[test]
+    fn any_frame_brings_a_topic_back() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        clock.advance(AFTER);
+        assert_eq!(offline_ids(&poll(false)), [61]);
+
+        let back = heard(61, 7).expect("back online");
+        assert_eq!((back.topic_id, back.measurement), (61, 7));
+        assert_eq!(offline(), (vec![], false));
+        assert_eq!(heard(61, 8), None);
+        // The timeout counts from the frame that brought it back
+        clock.advance(AFTER - Duration::from_secs(1));
+        assert!(poll(false).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(offline_ids(&poll(false)), [61]);
+    }
+
+    #[test]
+    fn acknowledging_stops_the_chirp_until_the_next_time() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        clock.advance(AFTER);
+        poll(false);
+        acknowledge();
+        assert_eq!(offline(), (vec![61], false));
+
+        heard(61, 1);
+        clock.advance(AFTER);
+        poll(false);
+        assert_eq!(offline(), (vec![61], true));
+    }
+
+    #[test]
+    fn timeouts_start_over_once_the_radio_is_back() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        clock.advance(AFTER);
+        assert!(poll(true).is_empty());
+        assert_eq!(offline(), (vec![], false));
+
+        clock.advance(AFTER - Duration::from_secs(1));
+        assert!(poll(false).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(offline_ids(&poll(false)), [61]);
+    }
+
+    #[test]
+    fn topics_without_a_timeout_are_not_watched() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        clock.advance(AFTER * 100);
+        assert_eq!(offline_ids(&poll(false)), [61]);
+        assert_eq!(heard(62, 1), None);
+        assert!(summary().lines().any(|l| l.ends_with(": not watched")));
+    }
+
+    #[test]
+    fn new_timeouts_keep_counting_from_the_last_frame() {
+        let (_turn, clock) = clock::mock();
+        init(TIMEOUTS);
+        heard(61, 3);
+        clock.advance(AFTER / 2);
+        let shorter = AFTER / 2 + Duration::from_secs(1);
+        set_timeouts(&[
+            Timeout {
+                topic_id: 61,
+                after: shorter,
+            },
+            Timeout {
+                topic_id: 62,
+                after: AFTER,
+            },
+        ]);
+        assert!(poll(false).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(offline_ids(&poll(false)), [61]);
+
+        // Offline stays offline, and a topic dropped is no longer watched
+        set_timeouts(&[Timeout {
+            topic_id: 61,
+            after: AFTER,
+        }]);
+        assert_eq!(offline(), (vec![61], true));
+        clock.advance(AFTER * 2);
+        assert!(poll(false).is_empty());
+        assert_eq!(heard(62, 1), None);
+    }
+}
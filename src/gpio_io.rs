@@ -0,0 +1,73 @@
+//! Actuator outputs, apart from the pins behind them
+//!
+//! The annunciator, the output guard and the siren drive LEDs, the buzzer
+//! and the siren through [`Output`] only; the boards in board.rs implement
+//! it on pin and LEDC drivers. A [`VirtualOutput`] stands in for a real one
+//! on the headless board and in host tests.
+
+use log::info;
+
+use crate::platform::sys::EspError;
+
+/// An on/off actuator such as an alarm LED or the buzzer
+pub trait Output {
+    fn gpio(&self) -> i32;
+
+    fn set_level(&mut self, on: bool) -> Result<(), EspError>;
+
+    /// Finish configuring the output once boot is over
+    fn arm(&mut self) -> Result<(), EspError> {
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), EspError> {
+        self.set_level(false)
+    }
+
+    /// Sound `hz`, or go quiet with `None`; outputs that cannot make a tone
+    /// just switch on
+    fn set_tone(&mut self, hz: Option<u32>) -> Result<(), EspError> {
+        self.set_level(hz.is_some())
+    }
+
+    /// Play tones softly; outputs without volume control ignore this
+    fn set_quiet(&mut self, _quiet: bool) -> Result<(), EspError> {
+        Ok(())
+    }
+}
+
+/// An output that logs level changes instead of driving a pin
+pub struct VirtualOutput {
+    name: &'static str,
+    gpio: i32,
+    on: bool,
+}
+
+impl VirtualOutput {
+    pub fn new(name: &'static str, gpio: i32) -> Self {
+        Self {
+            name,
+            gpio,
+            on: false,
+        }
+    }
+}
+
+impl Output for VirtualOutput {
+    fn gpio(&self) -> i32 {
+        self.gpio
+    }
+
+    fn set_level(&mut self, on: bool) -> Result<(), EspError> {
+        if on != self.on {
+            info!(
+                "[virtual] {} (GPIO{}) {}",
+                self.name,
+                self.gpio,
+                if on { "on" } else { "off" }
+            );
+            self.on = on;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+//! What a processed reading means for the alarms, per topic
+//!
+//! After its pipeline, see the transforms module, a reading goes to its
+//! topic's [`TopicHandler`], which decides on an [`AlertAction`]; the main
+//! loop carries it out, raising or clearing the alarm or flagging the
+//! reading as unusual. The [`Dispatcher`] owns one handler per topic seen,
+//! built from the handler name the topic registry gives it, so a topic keeps
+//! its state, e.g. an anomaly window, from one reading to the next.
+//!
+//! - [`Rules`] alarm as the topic's threshold rule decides, and flag
+//!   unusual readings of topics without a rule if given a detector
+//! - [`Trigger`] alarms only on a rule too, but is never flagged unusual,
+//!   for on/off sensors whose readings are conditions of other rules
+
+use crate::alerts::Priority;
+use crate::anomaly::Detector;
+use crate::datalog::Sample;
+use crate::profiles;
+use crate::topics;
+
+const MAX_TOPICS: usize = 16;
+
+/// A reading at the end of its pipeline
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub sample: Sample,
+    /// The threshold stage's verdict, `None` if the topic has no rule
+    pub alarm: Option<Option<Priority>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertAction {
+    None,
+    Raise(Priority),
+    Clear,
+    /// The topic turned unusual, or usual again with `false`
+    Anomaly(bool),
+}
+
+pub trait TopicHandler: Send {
+    fn on_measurement(&mut self, m: Measurement) -> AlertAction;
+}
+
+/// The rule's verdict, cleared while the active profile mutes the topic
+fn rule_action(m: &Measurement) -> Option<AlertAction> {
+    match m.alarm? {
+        _ if !profiles::alarms_enabled(m.sample.topic_id) => Some(AlertAction::Clear),
+        Some(priority) => Some(AlertAction::Raise(priority)),
+        None => Some(AlertAction::Clear),
+    }
+}
+
+pub struct Rules {
+    anomalies: Option<Detector>,
+}
+
+impl Rules {
+    /// Flag unusual readings `z_score` standard deviations off, if given
+    pub fn new(z_score: Option<f32>) -> Self {
+        Self {
+            anomalies: z_score.map(Detector::new),
+        }
+    }
+}
+
+impl TopicHandler for Rules {
+    fn on_measurement(&mut self, m: Measurement) -> AlertAction {
+        if let Some(action) = rule_action(&m) {
+            return action;
+        }
+        let sample = m.sample;
+        self.anomalies
+            .as_mut()
+            .and_then(|d| d.observe(sample.topic_id, sample.measurement))
+            .map_or(AlertAction::None, AlertAction::Anomaly)
+    }
+}
+
+pub struct Trigger;
+
+impl TopicHandler for Trigger {
+    fn on_measurement(&mut self, m: Measurement) -> AlertAction {
+        rule_action(&m).unwrap_or(AlertAction::None)
+    }
+}
+
+/// Builds the handler for a handler name of the topic registry
+pub type Factory = Box<dyn Fn(&'static str) -> Box<dyn TopicHandler>>;
+
+/// Every topic's handler
+pub struct Dispatcher {
+    factory: Factory,
+    topics: Vec<(i32, &'static str, Box<dyn TopicHandler>)>,
+}
+
+impl Dispatcher {
+    pub fn new(factory: Factory) -> Self {
+        Self {
+            factory,
+            topics: Vec::new(),
+        }
+    }
+
+    /// Hand `m` to its topic's handler
+    pub fn dispatch(&mut self, m: Measurement) -> AlertAction {
+        let topic_id = m.sample.topic_id;
+        let name = topics::handler(topic_id);
+        let i = match self.topics.iter().position(|(t, _, _)| *t == topic_id) {
+            Some(i) => {
+                if self.topics[i].1 != name {
+                    // Registered with another handler since, start over
+                    self.topics[i] = (topic_id, name, (self.factory)(name));
+                }
+                i
+            }
+            // Past the limit the topic runs on a fresh handler each time
+            None if self.topics.len() >= MAX_TOPICS => {
+                return (self.factory)(name).on_measurement(m)
+            }
+            None => {
+                self.topics.push((topic_id, name, (self.factory)(name)));
+                self.topics.len() - 1
+            }
+        };
+        self.topics[i].2.on_measurement(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::clock;
+    use crate::platform::nvs::EspDefaultNvsPartition;
+    use crate::profiles::Profile;
+    use crate::topics::Topic;
+    use crate::transforms::{Pipeline, Stage, GENERIC};
+
+    // Topics of their own, as the registry and profiles are shared
+    const KETTLE: i32 = 51;
+    const SINK: i32 = 52;
+    const MUTED: i32 = 53;
+
+    static PROFILES: [Profile; 2] = [
+        Profile {
+            name: "loud",
+            muted: &[],
+            away: false,
+        },
+        Profile {
+            name: "quiet",
+            muted: &[MUTED],
+            away: false,
+        },
+    ];
+    static PIPELINES: [Pipeline; 2] = [
+        Pipeline {
+            handler: "thermometer",
+            valid: i32::MIN..=i32::MAX,
+            stages: &[Stage::Threshold],
+        },
+        Pipeline {
+            handler: "sound",
+            valid: 0..=1,
+            stages: &[Stage::Threshold],
+        },
+    ];
+    const TOPICS: [Topic; 2] = [
+        Topic {
+            name: "kettle/temp",
+            topic_id: KETTLE,
+            handler: "thermometer",
+        },
+        Topic {
+            name: "sink/temp",
+            topic_id: SINK,
+            handler: "thermometer",
+        },
+    ];
+
+    fn reading(topic_id: i32, measurement: i32, alarm: Option<Option<Priority>>) -> Measurement {
+        Measurement {
+            sample: Sample {
+                timestamp: 0,
+                topic_id,
+                measurement,
+            },
+            alarm,
+        }
+    }
+
+    /// The registry and profiles above, with `MUTED` muted
+    fn setup() -> std::sync::MutexGuard<'static, ()> {
+        let (turn, _) = clock::mock();
+        let nvs = EspDefaultNvsPartition::take().unwrap();
+        topics::init(Some(nvs.clone()), &TOPICS, &PIPELINES).unwrap();
+        profiles::init(Some(nvs), &PROFILES).unwrap();
+        profiles::select("quiet").unwrap();
+        turn
+    }
+
+    /// Raises on its third reading, so it tells whether a handler was kept
+    struct Counting {
+        seen: u32,
+    }
+
+    impl TopicHandler for Counting {
+        fn on_measurement(&mut self, _: Measurement) -> AlertAction {
+            self.seen += 1;
+            if self.seen == 3 {
+                AlertAction::Raise(Priority::Info)
+            } else {
+                AlertAction::None
+            }
+        }
+    }
+
+    fn counting() -> (Dispatcher, Rc<RefCell<Vec<&'static str>>>) {
+        let built = Rc::new(RefCell::new(Vec::new()));
+        let log = built.clone();
+        let dispatcher = Dispatcher::new(Box::new(move |name| -> Box<dyn TopicHandler> {
+            log.borrow_mut().push(name);
+            Box::new(Counting { seen: 0 })
+        }));
+        (dispatcher, built)
+    }
+
+    #[test]
+    fn rules_follow_the_threshold_verdict() {
+        let _turn = setup();
+        let mut rules = Rules::new(None);
+        let warning = Some(Some(Priority::Warning));
+        assert_eq!(
+            rules.on_measurement(reading(KETTLE, 80, warning)),
+            AlertAction::Raise(Priority::Warning)
+        );
+        assert_eq!(
+            rules.on_measurement(reading(KETTLE, 40, Some(None))),
+            AlertAction::Clear
+        );
+        assert_eq!(
+            rules.on_measurement(reading(SINK, 40, None)),
+            AlertAction::None
+        );
+    }
+
+    #[test]
+    fn muted_topics_clear_instead_of_raising() {
+        let _turn = setup();
+        let critical = Some(Some(Priority::Critical));
+        let mut handlers: [Box<dyn TopicHandler>; 2] =
+            [Box::new(Rules::new(None)), Box::new(Trigger)];
+        for handler in handlers.iter_mut() {
+            assert_eq!(
+                handler.on_measurement(reading(MUTED, 1, critical)),
+                AlertAction::Clear
+            );
+            assert_eq!(
+                handler.on_measurement(reading(KETTLE, 1, critical)),
+                AlertAction::Raise(Priority::Critical)
+            );
+        }
+    }
+
+    #[test]
+    fn rules_flag_unusual_readings_of_topics_without_a_rule() {
+        let _turn = setup();
+        let mut rules = Rules::new(Some(3.0));
+        for i in 0..20 {
+            let action = rules.on_measurement(reading(SINK, 20 + i % 3, None));
+            assert_eq!(action, AlertAction::None);
+        }
+        let outlier = reading(SINK, 100, None);
+        assert_eq!(rules.on_measurement(outlier), AlertAction::Anomaly(true));
+        assert_eq!(
+            rules.on_measurement(reading(SINK, 21, None)),
+            AlertAction::Anomaly(false)
+        );
+        // A rule's verdict goes first, however unusual the reading
+        assert_eq!(
+            rules.on_measurement(reading(SINK, 1000, Some(None))),
+            AlertAction::Clear
+        );
+    }
+
+    #[test]
+    fn triggers_alarm_on_their_rule_only() {
+        let _turn = setup();
+        let mut trigger = Trigger;
+        for value in [0, 1, 1000] {
+            assert_eq!(
+                trigger.on_measurement(reading(SINK, value, None)),
+                AlertAction::None
+            );
+        }
+        assert_eq!(
+            trigger.on_measurement(reading(SINK, 1, Some(Some(Priority::Warning)))),
+            AlertAction::Raise(Priority::Warning)
+        );
+    }
+
+    #[test]
+    fn each_topic_keeps_its_own_handler() {
+        let _turn = setup();
+        let (mut dispatcher, built) = counting();
+        let mut actions = Vec::new();
+        for topic_id in [KETTLE, SINK, KETTLE, SINK, KETTLE, SINK] {
+            actions.push(dispatcher.dispatch(reading(topic_id, 1, None)));
+        }
+        let raised = AlertAction::Raise(Priority::Info);
+        assert_eq!(&actions[4..], [raised, raised]);
+        assert_eq!(*built.borrow(), ["thermometer", "thermometer"]);
+    }
+
+    #[test]
+    fn registering_another_handler_starts_over() {
+        let _turn = setup();
+        let (mut dispatcher, built) = counting();
+        dispatcher.dispatch(reading(KETTLE, 1, None));
+        dispatcher.dispatch(reading(KETTLE, 1, None));
+        topics::set("kettle/temp", Some((KETTLE, "sound"))).unwrap();
+        let actions: Vec<_> = (0..3)
+            .map(|_| dispatcher.dispatch(reading(KETTLE, 1, None)))
+            .collect();
+        assert_eq!(actions[1], AlertAction::None);
+        assert_eq!(actions[2], AlertAction::Raise(Priority::Info));
+        assert_eq!(*built.borrow(), ["thermometer", "sound"]);
+    }
+
+    #[test]
+    fn topics_past_the_limit_get_a_fresh_handler_each_time() {
+        let _turn = setup();
+        let (mut dispatcher, built) = counting();
+        for topic_id in 0..MAX_TOPICS as i32 {
+            dispatcher.dispatch(reading(1000 + topic_id, 1, None));
+        }
+        let past = 1000 + MAX_TOPICS as i32;
+        for _ in 0..3 {
+            let action = dispatcher.dispatch(reading(past, 1, None));
+            assert_eq!(action, AlertAction::None);
+        }
+        assert_eq!(built.borrow().len(), MAX_TOPICS + 3);
+        assert!(built.borrow().iter().all(|&name| name == GENERIC));
+    }
+}
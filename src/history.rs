@@ -0,0 +1,186 @@
+//! Downsampled long-term history
+//!
+//! Raw samples in the data log rotate out after a few days. Alongside them,
+//! every sample is folded into per-topic 1-minute and 1-hour buckets
+//! (min/max/mean) which are written to their own compressed logs once the
+//! bucket period has passed, so older history survives at lower resolution.
+
+use std::io::{self, Write};
+
+use crate::clock;
+use crate::datalog::{BlockLog, LogStats, Record, Sample};
+
+const MINUTE_SECS: u32 = 60;
+const HOUR_SECS: u32 = 60 * 60;
+
+const MAX_MINUTE_LOG_SIZE: u64 = 64 * 1024;
+const MAX_HOUR_LOG_SIZE: u64 = 32 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket {
+    /// Start of the bucket period in RTC seconds
+    pub start: u32,
+    pub topic_id: i32,
+    pub min: i32,
+    pub max: i32,
+    pub mean: i32,
+    pub count: u32,
+}
+
+impl Record for Bucket {
+    const WORDS: usize = 6;
+    const CSV_HEADER: &'static str = "start,topic_id,min,max,mean,count";
+
+    fn to_words(&self, words: &mut [u32]) {
+        words[0] = self.start;
+        words[1] = self.topic_id as u32;
+        words[2] = self.min as u32;
+        words[3] = self.max as u32;
+        words[4] = self.mean as u32;
+        words[5] = self.count;
+    }
+
+    fn from_words(words: &[u32]) -> Self {
+        Self {
+            start: words[0],
+            topic_id: words[1] as i32,
+            min: words[2] as i32,
+            max: words[3] as i32,
+            mean: words[4] as i32,
+            count: words[5],
+        }
+    }
+
+    fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            self.start, self.topic_id, self.min, self.max, self.mean, self.count
+        )
+    }
+}
+
+struct Accumulator {
+    topic_id: i32,
+    start: u32,
+    min: i32,
+    max: i32,
+    sum: i64,
+    count: u32,
+}
+
+impl Accumulator {
+    fn new(start: u32, sample: &Sample) -> Self {
+        Self {
+            topic_id: sample.topic_id,
+            start,
+            min: sample.measurement,
+            max: sample.measurement,
+            sum: sample.measurement as i64,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, measurement: i32) {
+        self.min = self.min.min(measurement);
+        self.max = self.max.max(measurement);
+        self.sum = self.sum.saturating_add(measurement.into());
+        self.count += 1;
+    }
+
+    fn bucket(&self) -> Bucket {
+        Bucket {
+            start: self.start,
+            topic_id: self.topic_id,
+            min: self.min,
+            max: self.max,
+            mean: (self.sum / self.count as i64) as i32,
+            count: self.count,
+        }
+    }
+}
+
+/// One resolution level: the open bucket per topic and the log it rolls into
+struct Level {
+    period: u32,
+    open: Vec<Accumulator>,
+    log: BlockLog<Bucket>,
+}
+
+impl Level {
+    fn new(dir: &str, name: &'static str, period: u32, max_size: u64) -> Self {
+        Self {
+            period,
+            open: Vec::new(),
+            log: BlockLog::open(dir, name, max_size),
+        }
+    }
+
+    fn record(&mut self, sample: &Sample) -> io::Result<()> {
+        let start = sample.timestamp - sample.timestamp % self.period;
+
+        match self.open.iter().position(|a| a.topic_id == sample.topic_id) {
+            Some(i) if self.open[i].start == start => self.open[i].add(sample.measurement),
+            Some(i) => {
+                let closed = std::mem::replace(&mut self.open[i], Accumulator::new(start, sample));
+                self.log.append(closed.bucket())?;
+            }
+            None => self.open.push(Accumulator::new(start, sample)),
+        }
+
+        Ok(())
+    }
+
+    /// Close buckets whose period is over even if their topic went quiet
+    fn poll(&mut self, now: u32) -> io::Result<()> {
+        let period = self.period;
+        let mut i = 0;
+        while i < self.open.len() {
+            if now >= self.open[i].start + period {
+                let closed = self.open.swap_remove(i);
+                self.log.append(closed.bucket())?;
+            } else {
+                i += 1;
+            }
+        }
+
+        self.log.poll()
+    }
+}
+
+pub struct History {
+    minutes: Level,
+    hours: Level,
+}
+
+impl History {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            minutes: Level::new(dir, "min", MINUTE_SECS, MAX_MINUTE_LOG_SIZE),
+            hours: Level::new(dir, "hour", HOUR_SECS, MAX_HOUR_LOG_SIZE),
+        }
+    }
+
+    pub fn record(&mut self, sample: &Sample) -> io::Result<()> {
+        self.minutes.record(sample)?;
+        self.hours.record(sample)
+    }
+
+    pub fn poll(&mut self) -> io::Result<()> {
+        let now = clock::unix_secs();
+        self.minutes.poll(now)?;
+        self.hours.poll(now)
+    }
+
+    pub fn minutes(&self) -> &BlockLog<Bucket> {
+        &self.minutes.log
+    }
+
+    pub fn hours(&self) -> &BlockLog<Bucket> {
+        &self.hours.log
+    }
+
+    pub fn stats(&self) -> io::Result<(LogStats, LogStats)> {
+        Ok((self.minutes.log.stats()?, self.hours.log.stats()?))
+    }
+}
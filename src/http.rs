@@ -0,0 +1,85 @@
+//! The HTTP server shared by the upload and configuration endpoints
+//!
+//! There is one server on port 80; each feature registers its handlers on
+//! it. Bodies are small text documents, answered with `ok` or a 400 and the
+//! reason.
+//!
+//! Reading is open to the LAN, but every request that changes something
+//! must carry the API token, `Authorization: Bearer <32 hex digits>`, set
+//! with `key api` on the serial console, or gets a 401. Until a token is
+//! set nothing can be changed over HTTP.
+
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use esp_idf_svc::sys::EspError;
+
+use crate::keys;
+
+/// Start the server, it stops when the returned handle is dropped
+pub fn start() -> Result<EspHttpServer<'static>, EspError> {
+    EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })
+}
+
+pub fn respond(
+    req: Request<&mut EspHttpConnection>,
+    result: Result<(), &'static str>,
+) -> Result<(), EspIOError> {
+    match result {
+        Ok(()) => req.into_ok_response()?.write_all(b"ok\n"),
+        Err(e) => {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.as_bytes())?;
+            response.write_all(b"\n")
+        }
+    }
+}
+
+/// Check that `req` carries the API token
+pub fn authorize(req: &Request<&mut EspHttpConnection>) -> Result<(), &'static str> {
+    let token = keys::api_token().ok_or("no API token set, see `key api` on the serial console")?;
+    let given = req
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|value| keys::parse_key(value.trim()))
+        .ok_or("API token missing")?;
+    // In constant time, so the token cannot be guessed byte by byte
+    let diff = given
+        .iter()
+        .zip(&token)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if diff == 0 {
+        Ok(())
+    } else {
+        Err("wrong API token")
+    }
+}
+
+/// Answer a request that failed [`authorize`] with a 401 and the reason
+pub fn refuse(req: Request<&mut EspHttpConnection>, reason: &str) -> Result<(), EspIOError> {
+    let mut response = req.into_status_response(401)?;
+    response.write_all(reason.as_bytes())?;
+    response.write_all(b"\n")
+}
+
+/// Read a request body of at most `max` bytes as text
+pub fn read_body<R: Read>(
+    req: &mut R,
+    max: usize,
+) -> Result<Result<String, &'static str>, R::Error> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 128];
+    loop {
+        let n = req.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if body.len() + n > max {
+            return Ok(Err("body too large"));
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8(body).map_err(|_| "body is not text"))
+}
@@ -0,0 +1,164 @@
+//! I2C bus with hang recovery
+//!
+//! A device that resets or browns out halfway through a read can keep SDA
+//! low while it waits for clocks that never come, and every transfer after
+//! that times out. When a transfer times out the bus is recovered the usual
+//! way: the driver lets go of the pins, SCL is clocked by hand until the
+//! device releases SDA (at most 9 pulses), a STOP is sent and the driver is
+//! set up again.
+//!
+//! Recovery is attempted at most once per [`RECOVERY_BACKOFF`] and transfers
+//! fail straight away in between, so a dead display costs the main loop one
+//! timeout now and then, never the alarms.
+
+// No board wires up a display yet
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::{Ets, TickType};
+use esp_idf_svc::hal::gpio::{AnyIOPin, PinDriver};
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2C0};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::sys::{EspError, TickType_t, ESP_ERR_INVALID_STATE, ESP_ERR_TIMEOUT};
+use log::{info, warn};
+
+const BAUDRATE: Hertz = Hertz(100_000);
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(50);
+const RECOVERY_BACKOFF: Duration = Duration::from_secs(10);
+// A stuck device needs at most 8 data bits and the ACK clocked out
+const CLOCK_PULSES: u32 = 9;
+// Half period of the hand-made clock, 100 kHz
+const HALF_CLOCK_US: u32 = 5;
+
+pub struct I2cBus {
+    name: &'static str,
+    sda: i32,
+    scl: i32,
+    driver: Option<I2cDriver<'static>>,
+    /// Recoveries since boot
+    recoveries: u32,
+    last_recovery: Option<Instant>,
+}
+
+impl I2cBus {
+    /// Set up I2C0 on `sda` and `scl`
+    ///
+    /// # Safety
+    ///
+    /// Neither pin, nor I2C0, may be claimed anywhere else.
+    pub unsafe fn new(name: &'static str, sda: i32, scl: i32) -> Result<Self, EspError> {
+        Ok(Self {
+            name,
+            sda,
+            scl,
+            driver: Some(connect(sda, scl)?),
+            recoveries: 0,
+            last_recovery: None,
+        })
+    }
+
+    /// Run one transfer, `f` gets the driver and the timeout to pass to it
+    ///
+    /// A transfer that times out triggers a bus recovery; the error is still
+    /// returned so the caller can retry.
+    pub fn transact<T>(
+        &mut self,
+        f: impl FnOnce(&mut I2cDriver<'static>, TickType_t) -> Result<T, EspError>,
+    ) -> Result<T, EspError> {
+        if self.driver.is_none() {
+            self.recover()?;
+        }
+        let driver = self.driver.as_mut().unwrap();
+
+        let timeout = TickType::new_millis(TRANSFER_TIMEOUT.as_millis() as u64).ticks();
+        let result = f(driver, timeout);
+        if let Err(e) = &result {
+            if matches!(e.code(), ESP_ERR_TIMEOUT | ESP_ERR_INVALID_STATE) {
+                warn!("{}: I2C transfer timed out, bus looks hung", self.name);
+                self.recover().ok();
+            }
+        }
+        result
+    }
+
+    /// Clock the bus free and set the driver up again
+    pub fn recover(&mut self) -> Result<(), EspError> {
+        if self
+            .last_recovery
+            .is_some_and(|t| t.elapsed() < RECOVERY_BACKOFF)
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+        self.last_recovery = Some(Instant::now());
+        self.recoveries += 1;
+
+        // Dropping the driver hands the pins back
+        self.driver = None;
+        // Safe: the pins belong to this bus and the driver is gone
+        let released = unsafe { clock_out(self.sda, self.scl) }?;
+        if !released {
+            warn!(
+                "{}: SDA still held low after {} clocks",
+                self.name, CLOCK_PULSES
+            );
+        }
+        self.driver = Some(unsafe { connect(self.sda, self.scl) }?);
+
+        info!(
+            "{}: I2C bus reinitialised ({} recoveries since boot)",
+            self.name, self.recoveries
+        );
+        Ok(())
+    }
+}
+
+/// # Safety
+///
+/// Neither pin, nor I2C0, may be claimed anywhere else.
+unsafe fn connect(sda: i32, scl: i32) -> Result<I2cDriver<'static>, EspError> {
+    I2cDriver::new(
+        I2C0::new(),
+        AnyIOPin::new(sda),
+        AnyIOPin::new(scl),
+        &I2cConfig::new().baudrate(BAUDRATE),
+    )
+}
+
+/// Clock SCL until SDA is released, then send a STOP. Returns whether SDA
+/// came free.
+///
+/// # Safety
+///
+/// Neither pin may be claimed anywhere else.
+unsafe fn clock_out(sda: i32, scl: i32) -> Result<bool, EspError> {
+    // Open drain like the bus itself, high means released to the pull-ups
+    let mut sda = PinDriver::input_output_od(AnyIOPin::new(sda))?;
+    let mut scl = PinDriver::input_output_od(AnyIOPin::new(scl))?;
+    sda.set_high()?;
+    scl.set_high()?;
+    Ets::delay_us(HALF_CLOCK_US);
+
+    for _ in 0..CLOCK_PULSES {
+        if sda.is_high() {
+            break;
+        }
+        scl.set_low()?;
+        Ets::delay_us(HALF_CLOCK_US);
+        scl.set_high()?;
+        Ets::delay_us(HALF_CLOCK_US);
+    }
+    let released = sda.is_high();
+
+    // STOP: SDA rises while SCL is high
+    scl.set_low()?;
+    Ets::delay_us(HALF_CLOCK_US);
+    sda.set_low()?;
+    Ets::delay_us(HALF_CLOCK_US);
+    scl.set_high()?;
+    Ets::delay_us(HALF_CLOCK_US);
+    sda.set_high()?;
+    Ets::delay_us(HALF_CLOCK_US);
+
+    Ok(released)
+}
@@ -0,0 +1,209 @@
+//! A persistent device identity and the household that claimed it
+//!
+//! On first boot the hub generates a random UUID (version 4) and keeps it in
+//! NVS. Unlike the MAC it never changes with the radio setup, so a cloud
+//! bridge can tell receivers apart unambiguously. It goes out as `device` in
+//! every uplink event and in the discovery response.
+//!
+//! A bridge associates the hub with a household by claiming it. A claimed
+//! hub can only be unclaimed with the same household id, or from the
+//! console, which needs physical access:
+//!
+//! - `GET /identity` answers `{"device":"<uuid>","household":<id|null>,"firmware":"<version>"}`
+//! - `POST /claim` with the household id as the body
+//! - `POST /unclaim` with the household id as the body
+//!
+//! On the console, `identity` shows both, `claim <household>` and `unclaim`
+//! change the claim.
+
+use std::sync::Mutex;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+use log::{info, warn};
+
+#[cfg(target_os = "espidf")]
+use crate::http::{authorize, read_body, refuse, respond};
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::{esp_fill_random, EspError};
+
+const NAMESPACE: &str = "identity";
+const UUID: &str = "uuid";
+const HOUSEHOLD: &str = "household";
+const MAX_HOUSEHOLD: usize = 64;
+
+static IDENTITY: Mutex<Identity> = Mutex::new(Identity {
+    uuid: String::new(),
+    household: None,
+    nvs: None,
+});
+
+struct Identity {
+    uuid: String,
+    household: Option<String>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Identity {
+    fn set_household(&mut self, household: Option<String>) -> Result<(), &'static str> {
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable")?;
+        let result = match household.as_deref() {
+            Some(household) => nvs.set_str(HOUSEHOLD, household),
+            None => nvs.remove(HOUSEHOLD).map(|_| ()),
+        };
+        result.map_err(|e| {
+            warn!("Failed to save the claim: {}", e);
+            "write failed"
+        })?;
+        self.household = household;
+        Ok(())
+    }
+}
+
+/// Load the identity from NVS, generating it on first boot
+///
+/// Without NVS, `partition` being `None` or failing, a new UUID is made up
+/// for this boot only.
+pub fn init(partition: Option<EspDefaultNvsPartition>) -> Result<(), EspError> {
+    let mut identity = IDENTITY.lock().unwrap();
+    identity.uuid = format_uuid(&generate());
+    let Some(partition) = partition else {
+        info!("Device {}, for this boot only", identity.uuid);
+        return Ok(());
+    };
+
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = [0u8; 16];
+    match nvs.get_blob(UUID, &mut buf)? {
+        Some(uuid) if uuid.len() == 16 => identity.uuid = format_uuid(&buf),
+        _ => {
+            buf = generate();
+            nvs.set_blob(UUID, &buf)?;
+            identity.uuid = format_uuid(&buf);
+            info!("Identity: generated");
+        }
+    }
+    let mut buf = [0u8; MAX_HOUSEHOLD + 1];
+    identity.household = nvs.get_str(HOUSEHOLD, &mut buf)?.map(str::to_string);
+    identity.nvs = Some(nvs);
+
+    match identity.household.as_deref() {
+        Some(household) => info!("Device {}, claimed by {}", identity.uuid, household),
+        None => info!("Device {}, unclaimed", identity.uuid),
+    }
+    Ok(())
+}
+
+/// The device UUID, hyphenated
+pub fn uuid() -> String {
+    IDENTITY.lock().unwrap().uuid.clone()
+}
+
+/// The household that claimed the hub
+pub fn household() -> Option<String> {
+    IDENTITY.lock().unwrap().household.clone()
+}
+
+/// Claim the hub for `household`, unless another household has
+pub fn claim(household: &str) -> Result<(), &'static str> {
+    check_household(household)?;
+    let mut identity = IDENTITY.lock().unwrap();
+    match identity.household.as_deref() {
+        Some(owner) if owner == household => Ok(()),
+        Some(_) => Err("claimed by another household"),
+        None => {
+            identity.set_household(Some(household.to_string()))?;
+            info!("Claimed by {}", household);
+            Ok(())
+        }
+    }
+}
+
+/// Release the claim, only for the household that holds it unless `by` is
+/// `None`, meaning the console
+pub fn unclaim(by: Option<&str>) -> Result<(), &'static str> {
+    let mut identity = IDENTITY.lock().unwrap();
+    let owner = identity.household.as_deref().ok_or("not claimed")?;
+    if by.is_some_and(|by| by != owner) {
+        return Err("claimed by another household");
+    }
+    identity.set_household(None)?;
+    info!("Unclaimed");
+    Ok(())
+}
+
+/// The discovery response, see the module docs
+pub fn describe() -> String {
+    let identity = IDENTITY.lock().unwrap();
+    format!(
+        r#"{{"device":"{}","household":{},"firmware":"{}"}}"#,
+        identity.uuid,
+        identity
+            .household
+            .as_ref()
+            .map_or_else(|| "null".to_string(), |h| format!(r#""{}""#, h)),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Serve the discovery and claim endpoints on `server`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/identity", Method::Get, |req| {
+        req.into_ok_response()?.write_all(describe().as_bytes())
+    })?;
+
+    server.fn_handler("/claim", Method::Post, |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = read_body(&mut req, MAX_HOUSEHOLD + 2)?.and_then(|body| claim(body.trim()));
+        respond(req, result)
+    })?;
+
+    server.fn_handler("/unclaim", Method::Post, |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result =
+            read_body(&mut req, MAX_HOUSEHOLD + 2)?.and_then(|body| unclaim(Some(body.trim())));
+        respond(req, result)
+    })?;
+
+    info!("Identity served on /identity");
+    Ok(())
+}
+
+/// Household ids end up in JSON, so they stay plain
+fn check_household(household: &str) -> Result<(), &'static str> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if household.is_empty() || household.len() > MAX_HOUSEHOLD || !household.chars().all(valid) {
+        return Err("household ids are 1 to 64 of A-Z, a-z, 0-9, _ and -");
+    }
+    Ok(())
+}
+
+fn generate() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    unsafe { esp_fill_random(uuid.as_mut_ptr().cast(), uuid.len()) };
+    // Version 4, RFC 4122 variant
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
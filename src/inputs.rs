@@ -0,0 +1,259 @@
+//! Edge events from the runtime inputs
+//!
+//! Inputs such as the wake button raise a GPIO interrupt on every edge. The
+//! handler only reads the pin level and queues an [`Event`], which is safe
+//! in ISR context. The main loop drains the queue with [`take`] and, rather
+//! than sleeping a fixed tick, blocks in [`wait`] until the next event or
+//! the end of the tick, so a press is handled as soon as it happens. The
+//! ESP-NOW receive callback queues an [`Event::Frame`] when frames start to
+//! come in, so a critical one wakes the main loop just as soon.
+//!
+//! An event arriving while the queue is full is dropped; the main loop
+//! compares the level it last saw with the pin afterwards, so a lost edge
+//! costs latency but never leaves a button stuck.
+//!
+//! Next to the wake button there can be one button per [`Action`], each on
+//! a pin picked by configuration and queueing [`Event::Key`]s the same way.
+//! Contacts bounce, so the levels of every button pass a [`Debounce`]
+//! before anything acts on them. The [`Panel`] owns the buttons and turns
+//! their levels into [`Gesture`]s for the main loop to act on.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::{FreeRtos, TickType, NON_BLOCK};
+use esp_idf_svc::hal::task::queue::Queue;
+use log::{info, warn};
+
+use crate::board::{self, Button, WakeButton};
+use crate::clock;
+use crate::pins::PinError;
+
+/// Events the queue holds between two main loop iterations
+const QUEUE_LENGTH: usize = 8;
+/// Shortest time between two level changes of a button that count
+pub const DEBOUNCE: Duration = Duration::from_millis(30);
+
+static QUEUE: OnceLock<Queue<Event>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The wake button changed, `true` when pressed
+    Button(bool),
+    /// The action button on `gpio` changed
+    Key { gpio: i32, pressed: bool },
+    /// Received frames are waiting, only to wake the main loop; see the
+    /// fast_path module
+    Frame,
+}
+
+/// What a button press does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Acknowledge the raised alarms, as a wake button press does
+    Mute,
+    /// Keep the buzzer and siren off for a while, alarms stay unacknowledged
+    Snooze,
+    /// Call for someone upstream
+    Page,
+    /// Open a pairing window
+    Pair,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Mute => "mute",
+            Action::Snooze => "snooze",
+            Action::Page => "page",
+            Action::Pair => "pair",
+        }
+    }
+}
+
+/// Edge filter shared by the buttons, see [`DEBOUNCE`]
+///
+/// A change that comes too soon after the last one is dropped; the main
+/// loop reads every pin again after its events, so the level settles a few
+/// iterations later at worst.
+#[derive(Debug, Clone, Copy)]
+pub struct Debounce {
+    level: bool,
+    changed: Option<Instant>,
+}
+
+impl Debounce {
+    /// Starting from the pin's `level` now
+    pub fn new(level: bool) -> Self {
+        Self {
+            level,
+            changed: None,
+        }
+    }
+
+    /// `level` if it is a change that counts
+    pub fn level(&mut self, level: bool) -> Option<bool> {
+        // Real time, bounce is a hardware matter
+        let now = Instant::now();
+        if level == self.level || self.changed.is_some_and(|t| now - t < DEBOUNCE) {
+            return None;
+        }
+        self.level = level;
+        self.changed = Some(now);
+        Some(level)
+    }
+}
+
+/// What the buttons asked for, see [`Panel::poll`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// The wake button went down
+    Press,
+    /// The wake button came up after the profile hold
+    Hold,
+    /// The wake button is still down after the pairing hold
+    LongHold,
+    /// An action button went down
+    Action(Action),
+}
+
+/// The wake button and the action buttons, debounced
+pub struct Panel {
+    wake: WakeButton,
+    debounce: Debounce,
+    was_pressed: bool,
+    /// Set while the wake button is held, cleared once the hold has acted
+    held_since: Option<Instant>,
+    hold: Duration,
+    long_hold: Duration,
+    buttons: Vec<(Action, Button, Debounce)>,
+}
+
+impl Panel {
+    /// Listen to the wake button, after [`init`]; a release after `hold` is
+    /// a [`Gesture::Hold`], holding on for `long_hold` a
+    /// [`Gesture::LongHold`]
+    pub fn new(mut wake: WakeButton, hold: Duration, long_hold: Duration) -> Self {
+        if let Err(e) = wake.listen() {
+            warn!("Button interrupt unavailable, polling it: {}", e);
+        }
+        let was_pressed = wake.is_pressed();
+        Self {
+            wake,
+            debounce: Debounce::new(was_pressed),
+            was_pressed,
+            held_since: None,
+            hold,
+            long_hold,
+            buttons: Vec::new(),
+        }
+    }
+
+    /// Claim `gpio` for the button of `action`; `in_use` as for
+    /// [`board::claim_button`]
+    pub fn claim(&mut self, action: Action, gpio: i32, in_use: &[i32]) -> Result<(), PinError> {
+        let mut button = board::claim_button(gpio, in_use)?;
+        if let Err(e) = button.listen() {
+            warn!(
+                "{} button interrupt unavailable, polling it: {}",
+                action.name(),
+                e
+            );
+        }
+        let debounce = Debounce::new(button.is_pressed());
+        self.buttons.push((action, button, debounce));
+        Ok(())
+    }
+
+    /// The gestures since the last call: button edges from the queue, then
+    /// the pins themselves in case an edge was dropped, debounced. Presses
+    /// count on their edge, not while held
+    pub fn poll(&mut self) -> Vec<Gesture> {
+        let mut levels = Vec::new();
+        let mut keys = Vec::new();
+        for event in std::iter::from_fn(take) {
+            match event {
+                Event::Button(pressed) => levels.push(pressed),
+                Event::Key { gpio, pressed } => keys.push((gpio, pressed)),
+                Event::Frame => {}
+            }
+        }
+        let mut gestures = Vec::new();
+        self.wake.rearm().ok();
+        levels.push(self.wake.is_pressed());
+        let levels: Vec<bool> = levels
+            .into_iter()
+            .filter_map(|l| self.debounce.level(l))
+            .collect();
+        for pressed in levels {
+            if pressed && !self.was_pressed {
+                gestures.push(Gesture::Press);
+                self.held_since = Some(clock::now());
+            }
+            if !pressed {
+                if self
+                    .held_since
+                    .is_some_and(|t| clock::since(t) >= self.hold)
+                {
+                    gestures.push(Gesture::Hold);
+                }
+                self.held_since = None;
+            }
+            self.was_pressed = pressed;
+        }
+        if self
+            .held_since
+            .is_some_and(|t| clock::since(t) >= self.long_hold)
+        {
+            self.held_since = None;
+            gestures.push(Gesture::LongHold);
+        }
+
+        for (action, button, debounce) in self.buttons.iter_mut() {
+            button.rearm().ok();
+            let gpio = button.gpio();
+            let levels = keys
+                .iter()
+                .filter(|(g, _)| *g == gpio)
+                .map(|&(_, level)| level)
+                .chain([button.is_pressed()]);
+            // Every level goes through the debounce, even after a press
+            let presses = levels.filter(|&l| debounce.level(l) == Some(true));
+            if presses.count() > 0 {
+                info!("{} button pressed", action.name());
+                gestures.push(Gesture::Action(*action));
+            }
+        }
+        gestures
+    }
+}
+
+/// Create the event queue, before any input interrupt is enabled
+pub fn init() {
+    QUEUE.get_or_init(|| Queue::new(QUEUE_LENGTH));
+}
+
+/// Queue `event`, from an interrupt handler or the receive callback
+pub(crate) fn raise(event: Event) {
+    if let Some(queue) = QUEUE.get() {
+        // Full: dropped, see the module docs
+        queue.send_back(event, NON_BLOCK).ok();
+    }
+}
+
+/// The oldest queued event
+pub fn take() -> Option<Event> {
+    QUEUE.get()?.recv_front(NON_BLOCK).map(|(event, _)| event)
+}
+
+/// Block until an event is queued or `timeout` passes, leaving the event
+/// for [`take`]
+pub fn wait(timeout: Duration) {
+    let ticks = TickType::new_millis(timeout.as_millis() as u64).ticks();
+    match QUEUE.get() {
+        Some(queue) => {
+            queue.peek_front(ticks);
+        }
+        None => FreeRtos::delay_ms(timeout.as_millis() as u32),
+    }
+}
@@ -0,0 +1,210 @@
+//! Runtime state dump for bug reports
+//!
+//! `inspect` on the console logs what the hub is juggling right now: queue
+//! depths, the tasks and memory, pending timers, the peer table, the alarm
+//! state machines and the last frames received. The dump sits between
+//! `--- inspect ---` and `--- end ---` lines, one `[section]` after the
+//! other, so it can be pasted into a bug report as it is. [`report`]
+//! builds it from the modules owning the state and the main loop's state in
+//! a [`Context`]; support snapshots send the same rows.
+
+use esp_idf_svc::sys::{
+    esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_timer_get_time,
+    uxTaskGetNumberOfTasks, uxTaskGetStackHighWaterMark,
+};
+use log::info;
+
+use crate::control::Context;
+use crate::siren::Siren;
+use crate::uplink::Status;
+use crate::{
+    ack, addressing, battery, channel, commands, config, degraded, espnow, espnow_tx, fast_path,
+    forecast, fragment, freshness, keys, link, long_range, metrics, pairing, power, profiles,
+    quiet_hours, readiness, senders, sntp, support, timezone, transforms,
+};
+
+#[derive(Default)]
+pub struct Report {
+    lines: Vec<String>,
+}
+
+impl Report {
+    /// A report starting with the firmware version and uptime
+    pub fn new() -> Self {
+        let mut report = Self::default();
+        report.section("hub");
+        report.line(format!(
+            "Firmware {}, up {} s",
+            env!("CARGO_PKG_VERSION"),
+            unsafe { esp_timer_get_time() } / 1_000_000
+        ));
+        report
+    }
+
+    pub fn section(&mut self, title: &str) {
+        self.lines.push(format!("[{}]", title));
+    }
+
+    pub fn line(&mut self, line: impl AsRef<str>) {
+        self.lines.push(format!("  {}", line.as_ref()));
+    }
+
+    /// Add every line of `text`, `(none)` if there are none
+    pub fn lines(&mut self, text: &str) {
+        let before = self.lines.len();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            self.line(line);
+        }
+        if self.lines.len() == before {
+            self.line("(none)");
+        }
+    }
+
+    /// The report's lines, as logged
+    pub fn rows(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Log the report, one line at a time
+    pub fn log(&self) {
+        info!("--- inspect ---");
+        for line in &self.lines {
+            info!("{}", line);
+        }
+        info!("--- end ---");
+    }
+}
+
+/// Task count, the main task's unused stack and the free heap
+pub fn tasks() -> String {
+    let (tasks, stack) = unsafe {
+        (
+            uxTaskGetNumberOfTasks(),
+            uxTaskGetStackHighWaterMark(std::ptr::null_mut()),
+        )
+    };
+    format!(
+        "{} tasks, main stack {} bytes spare, heap {} bytes free ({} at least)",
+        tasks,
+        stack,
+        unsafe { esp_get_free_heap_size() },
+        unsafe { esp_get_minimum_free_heap_size() }
+    )
+}
+
+/// The status line, as published upstream and served on `/status`
+pub fn status() -> Status {
+    Status {
+        uptime_s: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+        free_heap: unsafe { esp_get_free_heap_size() },
+        awake: power::is_awake(),
+        profile: profiles::active(),
+        batteries_low: battery::low_count(),
+        rx_crc_errors: espnow::crc_errors(),
+        rx_legacy: espnow::legacy_frames(),
+        rx_legacy_refused: espnow::legacy_refused(),
+        rx_duplicates: espnow::duplicates(),
+        rx_unknown_senders: espnow::unknown_senders(),
+        rx_auth_failures: espnow::auth_failures(),
+        rx_outliers: transforms::outliers(),
+    }
+}
+
+/// The whole dump, for the console or a support snapshot
+pub fn report(context: &Context) -> Report {
+    let mut report = Report::new();
+    report.section("readiness");
+    report.line(format!(
+        "{}, faults {}, degraded {}",
+        readiness::state().name(),
+        readiness::faults_json(),
+        degraded::json()
+    ));
+    report.line(readiness::sla_summary());
+    report.line(fast_path::summary());
+    report.section("tasks");
+    report.line(tasks());
+    report.section("queues");
+    report.line(format!(
+        "Receive queue {}/{}, {} acks and {} commands waiting",
+        espnow::queued(),
+        espnow::RAW_QUEUE_LENGTH,
+        ack::pending(),
+        commands::queued()
+    ));
+    report.line(espnow_tx::summary());
+    report.line(fragment::summary());
+    report.section("timers");
+    let jobs = &context.jobs;
+    let running = [
+        ("Pairing window", jobs.pairing.is_some()),
+        ("Channel migration", context.migrating),
+        ("Channel scan", jobs.scan.is_some()),
+        ("Self-test", jobs.self_test.is_some()),
+        ("Bench", jobs.bench.is_some()),
+        ("Commissioning", jobs.commissioning.is_some()),
+        ("Storage mount", context.mounting),
+        ("AP join", context.joining),
+    ];
+    let mut timers: Vec<String> = running
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    if let Some(left) = context.radio_retry {
+        timers.push(format!("Radio retry in {} s", left.as_secs()));
+    }
+    if let Some(left) = config::revert_in() {
+        timers.push(format!("Config revert in {} s", left.as_secs()));
+    }
+    if let Some(confirmed) = config::deferred() {
+        timers.push(format!(
+            "Config import deferred to the maintenance window{}",
+            if confirmed { ", confirmed" } else { "" }
+        ));
+    }
+    if let Some(left) = support::remaining() {
+        timers.push(format!("Support mode ends in {} s", left.as_secs()));
+    }
+    if let Some(left) = context.snooze.remaining() {
+        timers.push(format!("Snooze ends in {} s", left.as_secs()));
+    }
+    report.lines(&timers.join("\n"));
+    report.section("clock");
+    report.line(timezone::summary());
+    report.line(sntp::summary());
+    report.section("channel");
+    report.line(channel::summary());
+    report.line(long_range::summary());
+    report.line(addressing::summary());
+    report.section("peers");
+    report.lines(&pairing::list());
+    report.section("keys");
+    report.lines(&keys::summary());
+    report.section("senders");
+    report.lines(&senders::list());
+    report.section("links");
+    report.lines(&link::summary());
+    report.section("freshness");
+    report.lines(&freshness::summary());
+    report.section("metrics");
+    report.line(metrics::summary());
+    report.section("uplinks");
+    report.lines(&context.uplinks.names().collect::<Vec<_>>().join("\n"));
+    report.section("forecast");
+    report.line(forecast::summary());
+    report.section("pipelines");
+    report.lines(&context.pipelines.describe());
+    report.section("alarms");
+    report.lines(&context.alerts.list());
+    report.line(quiet_hours::summary());
+    report.section("siren");
+    report.line(
+        context
+            .siren
+            .map_or_else(|| "none".to_string(), Siren::summary),
+    );
+    report.section("frames");
+    report.lines(&espnow::recent());
+    report
+}
@@ -0,0 +1,238 @@
+//! ESP-NOW encryption keys
+//!
+//! ESP-NOW encrypts unicast frames to and from a peer registered with a
+//! local master key (LMK), which is itself encrypted with the primary master
+//! key (PMK) set on the hub and on the sender. Keys are 16 bytes, kept in
+//! their own NVS namespace: the PMK under `pmk`, each sender's LMK under its
+//! MAC in hex. They are not part of the config backup, but a key change is
+//! staged like a config import, kept with the revert point and undone
+//! unless `config confirm` follows in time, so a wrong key cannot cut the
+//! hub off for good.
+//!
+//! On the console, written as 32 hex digits:
+//!
+//! - `keys` shows whether the PMK is set and which paired senders have an
+//!   LMK, never the keys themselves
+//! - `key pmk <key>` sets the PMK, `key pmk -` removes it
+//! - `key <MAC> <key>` sets a sender's LMK, `key <MAC> -` removes it;
+//!   pairing sets one too while a PMK is set, see the pairing module
+//! - `key hmac <key>` sets the secret data frames are authenticated with,
+//!   see the auth module, `key hmac -` removes it
+//! - `key api <key>` sets the token HTTP clients send to change anything,
+//!   see the http module, `key api -` removes it and with it every change
+//!   over HTTP
+//!
+//! Keys are only ever set on the serial console; `POST /console` refuses
+//! these commands, so nobody on the LAN can swap them.
+//!
+//! ESP-NOW keys take effect at the next boot, when the peers are registered.
+//! A sender without an LMK stays a plaintext peer, and broadcasts are never
+//! encrypted. ESP-IDF allows few encrypted peers, 7 by default. The HMAC
+//! secret applies right away, to every sender.
+
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::espnow_tx;
+use crate::pairing;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::{esp, esp_now_set_pmk, EspError};
+
+pub const KEY_LEN: usize = 16;
+
+const NAMESPACE: &str = "espnow_keys";
+const PMK: &str = "pmk";
+const HMAC: &str = "hmac";
+const API: &str = "api";
+
+static NVS: Mutex<Option<EspNvs<NvsDefault>>> = Mutex::new(None);
+// Read for every data frame, so kept out of NVS
+static HMAC_SECRET: Mutex<Option<[u8; KEY_LEN]>> = Mutex::new(None);
+
+/// Open the keys namespace in NVS
+pub fn load(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    *NVS.lock().unwrap() = Some(EspNvs::new(partition, NAMESPACE, true)?);
+    *HMAC_SECRET.lock().unwrap() = get(HMAC);
+    Ok(())
+}
+
+/// Set the stored PMK, call after `esp_now_init` and before peers are added
+pub fn apply_pmk() -> Result<(), EspError> {
+    let Some(pmk) = get(PMK) else {
+        return Ok(());
+    };
+    esp!(unsafe { esp_now_set_pmk(pmk.as_ptr()) })?;
+    info!("ESP-NOW PMK set, encrypting peers that have an LMK");
+    Ok(())
+}
+
+/// Whether a PMK is stored, without which no peer is encrypted
+pub fn pmk_set() -> bool {
+    get(PMK).is_some()
+}
+
+/// The LMK of `mac`, if it is to be an encrypted peer
+pub fn lmk(mac: &[u8; 6]) -> Option<[u8; KEY_LEN]> {
+    get(&lmk_name(mac))
+}
+
+/// The secret data frames are authenticated with, if set
+pub fn hmac_secret() -> Option<[u8; KEY_LEN]> {
+    *HMAC_SECRET.lock().unwrap()
+}
+
+/// Set or, with `None`, remove the HMAC secret, effective immediately
+pub fn set_hmac_secret(key: Option<[u8; KEY_LEN]>) -> Result<(), &'static str> {
+    set(HMAC, key)?;
+    *HMAC_SECRET.lock().unwrap() = key;
+    Ok(())
+}
+
+/// The token HTTP requests that change anything must carry, if set
+pub fn api_token() -> Option<[u8; KEY_LEN]> {
+    get(API)
+}
+
+/// Set or, with `None`, remove the HTTP API token
+pub fn set_api_token(key: Option<[u8; KEY_LEN]>) -> Result<(), &'static str> {
+    set(API, key)
+}
+
+/// Set or, with `None`, remove the PMK
+pub fn set_pmk(key: Option<[u8; KEY_LEN]>) -> Result<(), &'static str> {
+    set(PMK, key)
+}
+
+/// Set or, with `None`, remove the LMK of `mac`
+pub fn set_lmk(mac: [u8; 6], key: Option<[u8; KEY_LEN]>) -> Result<(), &'static str> {
+    if mac == espnow_tx::BROADCAST {
+        return Err("broadcasts cannot be encrypted");
+    }
+    set(&lmk_name(&mac), key)
+}
+
+/// Which keys are set, for the console
+pub fn summary() -> String {
+    let set_or_not = |key: Option<[u8; KEY_LEN]>| if key.is_some() { "set" } else { "not set" };
+    let mut summary = format!(
+        "PMK {}\nHMAC secret {}\nAPI token {}\n",
+        set_or_not(get(PMK)),
+        set_or_not(hmac_secret()),
+        set_or_not(api_token())
+    );
+    for mac in pairing::paired() {
+        summary.push_str(&format!(
+            "{:02X?} {}\n",
+            mac,
+            if lmk(&mac).is_some() {
+                "encrypted"
+            } else {
+                "plaintext"
+            }
+        ));
+    }
+    summary
+}
+
+/// Every key, `<pmk|hmac|api|MAC> <key>` one per line, for the revert
+/// point only; never exported nor shown
+pub fn list() -> String {
+    let mut table = String::new();
+    let mut push = |name: &str, key: Option<[u8; KEY_LEN]>| {
+        if let Some(key) = key {
+            table.push_str(&format!("{} {}\n", name, hex(&key)));
+        }
+    };
+    push(PMK, get(PMK));
+    push(HMAC, hmac_secret());
+    push(API, api_token());
+    for mac in pairing::paired() {
+        push(&lmk_name(&mac), lmk(&mac));
+    }
+    table
+}
+
+/// Check a table in the [`list`] format
+pub fn check(table: &str) -> Result<(), &'static str> {
+    parse_table(table).map(|_| ())
+}
+
+/// Replace the keys with a table in the [`list`] format; paired senders it
+/// leaves out lose their LMK
+pub fn replace(table: &str) -> Result<(), &'static str> {
+    let table = parse_table(table)?;
+    let find = |name: &str| table.iter().find(|(n, _)| n == name).map(|&(_, key)| key);
+    set(PMK, find(PMK))?;
+    set_hmac_secret(find(HMAC))?;
+    set_api_token(find(API))?;
+    for mac in pairing::paired() {
+        let name = lmk_name(&mac);
+        set(&name, find(&name))?;
+    }
+    Ok(())
+}
+
+fn parse_table(table: &str) -> Result<Vec<(String, [u8; KEY_LEN])>, &'static str> {
+    let mut keys = Vec::new();
+    for line in table.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let (Some(name), Some(key), None) = (words.next(), words.next(), words.next()) else {
+            return Err("expected <name> <key>");
+        };
+        let known = [PMK, HMAC, API].contains(&name)
+            || (name.len() == 12 && name.bytes().all(|b| b.is_ascii_hexdigit()));
+        if !known {
+            return Err("unknown key name");
+        }
+        let key = parse_key(key).ok_or("keys are 32 hex digits")?;
+        keys.push((name.to_ascii_lowercase(), key));
+    }
+    Ok(keys)
+}
+
+/// Parse a key written as 32 hex digits
+pub fn parse_key(text: &str) -> Option<[u8; KEY_LEN]> {
+    if text.len() != KEY_LEN * 2 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+fn lmk_name(mac: &[u8; 6]) -> String {
+    hex(mac)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn get(name: &str) -> Option<[u8; KEY_LEN]> {
+    let nvs = NVS.lock().unwrap();
+    let mut buf = [0u8; KEY_LEN];
+    match nvs.as_ref()?.get_blob(name, &mut buf) {
+        Ok(Some(key)) if key.len() == KEY_LEN => Some(buf),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Failed to read key {}: {}", name, e);
+            None
+        }
+    }
+}
+
+fn set(name: &str, key: Option<[u8; KEY_LEN]>) -> Result<(), &'static str> {
+    let mut nvs = NVS.lock().unwrap();
+    let nvs = nvs.as_mut().ok_or("NVS unavailable")?;
+    let result = match key {
+        Some(key) => nvs.set_blob(name, &key),
+        None => nvs.remove(name).map(|_| ()),
+    };
+    result.map_err(|e| {
+        warn!("Failed to save key {}: {}", name, e);
+        "write failed"
+    })
+}
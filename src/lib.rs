@@ -0,0 +1,106 @@
+//! ESP-NOW receiver hub
+//!
+//! Senders report measurements by topic over ESP-NOW; the hub raises
+//! alarms on its LEDs and buzzer, logs the readings and forwards them over
+//! the configured uplinks. The modules here hold the receiver logic; the
+//! binary in main.rs wires them to the board and runs the main loop.
+//!
+//! Built for the host the modules that drive the radio, pins, buses, flash
+//! or servers are left out, and the rest run on the stand-ins in
+//! [`platform`], so the receiver logic is unit-tested with `cargo +stable
+//! test --lib --target <host triple>`.
+
+pub mod ack;
+pub mod addressing;
+pub mod alerts;
+pub mod annunciator;
+pub mod anomaly;
+pub mod auth;
+pub mod auto_sleep;
+pub mod battery;
+pub mod bench;
+#[cfg(target_os = "espidf")]
+pub mod board;
+#[cfg(target_os = "espidf")]
+pub mod capture;
+#[cfg(target_os = "espidf")]
+pub mod channel;
+pub mod clock;
+pub mod commands;
+pub mod commissioning;
+pub mod config;
+#[cfg(target_os = "espidf")]
+pub mod console;
+#[cfg(target_os = "espidf")]
+pub mod control;
+pub mod datalog;
+pub mod dedup;
+pub mod defaults;
+pub mod degraded;
+pub mod dispatch;
+#[cfg(target_os = "espidf")]
+pub mod espnow;
+pub mod espnow_tx;
+pub mod fast_path;
+#[cfg(target_os = "espidf")]
+pub mod forecast;
+pub mod fragment;
+pub mod freshness;
+pub mod gpio_io;
+pub mod handlers;
+pub mod history;
+#[cfg(target_os = "espidf")]
+pub mod http;
+#[cfg(target_os = "espidf")]
+pub mod i2c_bus;
+pub mod identity;
+#[cfg(target_os = "espidf")]
+pub mod inputs;
+#[cfg(target_os = "espidf")]
+pub mod inspect;
+pub mod keys;
+pub mod link;
+pub mod log_governor;
+#[cfg(target_os = "espidf")]
+pub mod long_range;
+pub mod metrics;
+#[cfg(all(feature = "microphone", target_os = "espidf"))]
+pub mod microphone;
+pub mod names;
+pub mod output_guard;
+pub mod pairing;
+pub mod pins;
+pub mod platform;
+#[cfg(target_os = "espidf")]
+pub mod power;
+pub mod presence;
+pub mod profiles;
+pub mod protocol;
+pub mod quiet_hours;
+pub mod readiness;
+pub mod rtttl;
+pub mod schema;
+#[cfg(target_os = "espidf")]
+pub mod selftest;
+pub mod senders;
+pub mod settings;
+pub mod siren;
+pub mod snooze;
+pub mod sntp;
+pub mod sounds;
+pub mod startup;
+#[cfg(target_os = "espidf")]
+pub mod storage;
+pub mod strings;
+#[cfg(target_os = "espidf")]
+pub mod support;
+pub mod thresholds;
+pub mod timezone;
+pub mod topics;
+#[cfg(feature = "tracing")]
+pub mod trace;
+pub mod transforms;
+pub mod uplink;
+pub mod watchdog;
+pub mod wearable;
+pub mod whitelist;
@@ -0,0 +1,174 @@
+//! Signal strength of each sender's link to the hub
+//!
+//! Every data frame arrives with the RSSI the radio measured for it. The
+//! hub keeps a running average per sender, so a sender drifting out of
+//! range shows before its frames start to go missing: the status event
+//! lists every sender heard in the last [`STALE_AFTER`] with its average,
+//! and `inspect` shows them too.
+//!
+//! A sender whose average drops below the floor (`[link] weak_rssi`) has a
+//! weak link. It is warned about once, and with `weak_led` set the idle LEDs
+//! of its topics give a triple flash every 5 s until it recovers, a few dB
+//! above the floor, or goes silent. A sender without an LED only shows in
+//! the log and the status event.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::names;
+
+/// A sender not heard for this long is left out
+pub const STALE_AFTER: Duration = Duration::from_secs(300);
+// Recovering needs the average this many dB above the floor
+const HYSTERESIS_DB: i32 = 3;
+// New samples get 1/AVERAGE_WEIGHT of the running average
+const AVERAGE_WEIGHT: i32 = 4;
+const MAX_SENDERS: usize = 32;
+const MAX_TOPICS: usize = 4;
+
+static LINKS: Mutex<Links> = Mutex::new(Links {
+    floor: None,
+    senders: Vec::new(),
+});
+
+struct Links {
+    /// `None` until set, no link is weak then
+    floor: Option<i32>,
+    senders: Vec<Link>,
+}
+
+struct Link {
+    mac: [u8; 6],
+    /// dBm, averaged
+    average: i32,
+    last: i32,
+    heard: Instant,
+    /// Topics the sender reports, for their LEDs
+    topics: Vec<i32>,
+    weak: bool,
+}
+
+impl Link {
+    fn fresh(&self) -> bool {
+        clock::since(self.heard) < STALE_AFTER
+    }
+}
+
+/// Take links averaging below `floor` dBm as weak
+pub fn set_floor(floor: i32) {
+    LINKS.lock().unwrap().floor = Some(floor);
+}
+
+/// Note the RSSI of a frame from `mac` reporting `topic_id`
+pub fn record(mac: [u8; 6], topic_id: i32, rssi: i32) {
+    let mut links = LINKS.lock().unwrap();
+    let floor = links.floor;
+    let full = links.senders.len() >= MAX_SENDERS;
+    let link = match links.senders.iter().position(|l| l.mac == mac) {
+        Some(i) => &mut links.senders[i],
+        None if full => return,
+        None => {
+            links.senders.push(Link {
+                mac,
+                average: rssi,
+                last: rssi,
+                heard: clock::now(),
+                topics: Vec::new(),
+                weak: false,
+            });
+            links.senders.last_mut().unwrap()
+        }
+    };
+    if link.fresh() {
+        link.average += (rssi - link.average) / AVERAGE_WEIGHT;
+    } else {
+        // Back after a silence, start over
+        link.average = rssi;
+        link.weak = false;
+    }
+    link.last = rssi;
+    link.heard = clock::now();
+    if !link.topics.contains(&topic_id) && link.topics.len() < MAX_TOPICS {
+        link.topics.push(topic_id);
+    }
+    let Some(floor) = floor else {
+        return;
+    };
+    let weak = if link.weak {
+        link.average < floor + HYSTERESIS_DB
+    } else {
+        link.average < floor
+    };
+    if weak != link.weak {
+        link.weak = weak;
+        let label = names::label(topic_id);
+        if weak {
+            warn!(
+                "Weak link to {:02X?} ({}): {} dBm, below {} dBm; move the sender or the hub",
+                mac, label, link.average, floor
+            );
+        } else {
+            info!(
+                "Link to {:02X?} ({}) recovered: {} dBm",
+                mac, label, link.average
+            );
+        }
+    }
+}
+
+/// Topics of the senders heard recently over a weak link
+pub fn weak_topics() -> Vec<i32> {
+    let links = LINKS.lock().unwrap();
+    links
+        .senders
+        .iter()
+        .filter(|l| l.weak && l.fresh())
+        .flat_map(|l| l.topics.iter().copied())
+        .collect()
+}
+
+/// `[{"mac":..,"rssi":..,"last_rssi":..,"weak":..}]` of the senders heard
+/// recently, for the status event
+pub fn json() -> String {
+    let links = LINKS.lock().unwrap();
+    let entries: Vec<String> = links
+        .senders
+        .iter()
+        .filter(|l| l.fresh())
+        .map(|l| {
+            format!(
+                r#"{{"mac":"{}","rssi":{},"last_rssi":{},"weak":{}}}"#,
+                mac_text(&l.mac),
+                l.average,
+                l.last,
+                l.weak
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// Every sender heard recently, `<MAC> <average> dBm` lines, for inspect
+pub fn summary() -> String {
+    let links = LINKS.lock().unwrap();
+    let mut summary = String::new();
+    for link in links.senders.iter().filter(|l| l.fresh()) {
+        summary.push_str(&format!(
+            "{} {} dBm (last {}), {} s ago{}\n",
+            mac_text(&link.mac),
+            link.average,
+            link.last,
+            clock::since(link.heard).as_secs(),
+            if link.weak { ", weak" } else { "" }
+        ));
+    }
+    summary
+}
+
+fn mac_text(mac: &[u8; 6]) -> String {
+    let parts: Vec<String> = mac.iter().map(|b| format!("{:02X}", b)).collect();
+    parts.join(":")
+}
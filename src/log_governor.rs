@@ -0,0 +1,79 @@
+//! Rate limit for the per-frame log lines
+//!
+//! Every frame normally gets a log line as it arrives and another as it is
+//! processed. Under a flood that would bury the console, and a slow UART
+//! would hold up the receive path on top. So each topic may log
+//! [`BURST`] frames per [`WINDOW`]; further frames in the window are only
+//! counted, and when it ends a single summary line says how many came in.
+//! A quiet topic logs every frame as before.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::clock;
+use crate::names;
+
+/// Frames a topic logs individually per window
+const BURST: u32 = 5;
+const WINDOW: Duration = Duration::from_secs(10);
+// Topics tracked at once; frames of further topics are always logged
+const MAX_TOPICS: usize = 16;
+
+static TOPICS: Mutex<Vec<Topic>> = Mutex::new(Vec::new());
+
+struct Topic {
+    topic_id: i32,
+    window_start: Instant,
+    frames: u32,
+}
+
+/// Count a frame on `topic_id`, returns whether it may be logged on its own
+///
+/// Called for every reading received, so it only takes a short lock.
+pub fn frame(topic_id: i32) -> bool {
+    let mut topics = TOPICS.lock().unwrap();
+    let full = topics.len() >= MAX_TOPICS;
+    match topics.iter_mut().find(|t| t.topic_id == topic_id) {
+        Some(topic) => {
+            topic.frames += 1;
+            topic.frames <= BURST
+        }
+        None if !full => {
+            topics.push(Topic {
+                topic_id,
+                window_start: clock::now(),
+                frames: 1,
+            });
+            true
+        }
+        None => true,
+    }
+}
+
+/// Close the windows that are over, summarizing the topics that went past
+/// their burst
+pub fn poll() {
+    let mut summaries = Vec::new();
+    TOPICS.lock().unwrap().retain(|topic| {
+        let elapsed = clock::since(topic.window_start);
+        if elapsed < WINDOW {
+            return true;
+        }
+        if topic.frames > BURST {
+            summaries.push((topic.topic_id, topic.frames, elapsed));
+        }
+        false
+    });
+    // Logged outside the lock, names::label takes a lock of its own
+    for (topic_id, frames, elapsed) in summaries {
+        info!(
+            "Received {} frames for topic {} in the last {} s, {} logged",
+            frames,
+            names::label(topic_id),
+            elapsed.as_secs(),
+            BURST
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! 802.11 LR, the ESP32 long-range PHY, for ESP-NOW
+//!
+//! LR trades bit rate for reach, 250 or 500 kbit/s at several times the
+//! range, so a sensor can sit farther from the hub. It is an Espressif
+//! extension: only ESP32 radios with LR enabled hear LR frames, so senders
+//! must be switched along with the hub. The 11b/g/n modes stay enabled next
+//! to it, the hub can still join a normal AP.
+//!
+//! The `[radio] long_range` default is compiled in; `longrange on|off` on
+//! the console overrides it in NVS, staged like a config import so a mode
+//! the senders do not share reverts unless confirmed, and the main loop
+//! applies it right away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{
+    esp, esp_wifi_set_protocol, wifi_interface_t_WIFI_IF_STA, EspError, WIFI_PROTOCOL_11B,
+    WIFI_PROTOCOL_11G, WIFI_PROTOCOL_11N, WIFI_PROTOCOL_LR,
+};
+use log::{info, warn};
+
+const NAMESPACE: &str = "radio";
+const ENABLED: &str = "long_range";
+const STANDARD: u32 = WIFI_PROTOCOL_11B | WIFI_PROTOCOL_11G | WIFI_PROTOCOL_11N;
+
+static ON: AtomicBool = AtomicBool::new(false);
+// Set when the mode changes, for the main loop to apply it
+static CHANGED: AtomicBool = AtomicBool::new(false);
+static NVS: Mutex<Option<EspNvs<NvsDefault>>> = Mutex::new(None);
+
+/// Take `default` unless NVS holds an override
+pub fn init(partition: Option<EspDefaultNvsPartition>, default: bool) -> Result<(), EspError> {
+    ON.store(default, Ordering::Relaxed);
+    let Some(partition) = partition else {
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    if let Some(on) = nvs.get_u8(ENABLED)? {
+        ON.store(on != 0, Ordering::Relaxed);
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+/// Whether LR is on, for the console
+pub fn summary() -> String {
+    format!("Long range {}", if enabled() { "on" } else { "off" })
+}
+
+/// Switch LR on or off, stored for the next boot too, the radio follows
+/// once the main loop calls [`apply`]
+pub fn store(on: bool) -> Result<(), &'static str> {
+    ON.store(on, Ordering::Relaxed);
+    CHANGED.store(true, Ordering::Relaxed);
+    let mut nvs = NVS.lock().unwrap();
+    let nvs = nvs.as_mut().ok_or("NVS unavailable, not stored")?;
+    nvs.set_u8(ENABLED, on.into()).map_err(|e| {
+        warn!("Failed to store the PHY mode: {}", e);
+        "write failed"
+    })
+}
+
+/// Whether the mode changed since the last call
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::Relaxed)
+}
+
+/// `on` or `off`, for the config backup
+pub fn list() -> String {
+    if enabled() { "on" } else { "off" }.to_string()
+}
+
+/// Check a mode in the [`list`] format
+pub fn check(text: &str) -> Result<(), &'static str> {
+    parse(text).map(|_| ())
+}
+
+/// Store a mode in the [`list`] format
+pub fn replace(text: &str) -> Result<(), &'static str> {
+    store(parse(text)?)
+}
+
+fn parse(text: &str) -> Result<bool, &'static str> {
+    match text.trim() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("long range must be on or off"),
+    }
+}
+
+/// Put the radio in the configured mode, after WiFi started
+pub fn apply() -> Result<(), EspError> {
+    set_protocol(enabled())?;
+    if enabled() {
+        info!("ESP-NOW in 802.11 LR mode");
+    }
+    Ok(())
+}
+
+/// Enable or disable LR next to 11b/g/n on the station interface
+pub fn set_protocol(long_range: bool) -> Result<(), EspError> {
+    let protocol = if long_range {
+        STANDARD | WIFI_PROTOCOL_LR
+    } else {
+        STANDARD
+    };
+    esp!(unsafe { esp_wifi_set_protocol(wifi_interface_t_WIFI_IF_STA, protocol as u8) })
+}
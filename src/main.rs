@@ -1,86 +1,619 @@
-use esp_idf_svc::hal::delay::FreeRtos;
-use esp_idf_svc::hal::gpio::PinDriver;
 use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::sys::{
-    esp_deep_sleep_enable_gpio_wakeup, esp_deep_sleep_start, esp_now_init,
-    esp_now_register_recv_cb, esp_now_recv_info_t, esp_sleep_get_wakeup_cause,
-    esp_sleep_wakeup_cause_t_ESP_SLEEP_WAKEUP_GPIO, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-    gpio_wakeup_enable, ESP_OK,
-};
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+use esp_idf_svc::sys::{esp_timer_get_time, EspError};
+use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, EspWifi};
 use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
-use log::info;
-use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use esp_now_receiver::alerts::{Alerts, Cooldown, Escalation, Priority};
+use esp_now_receiver::annunciator::Annunciator;
+use esp_now_receiver::auto_sleep::{AutoSleep, SleepState};
+use esp_now_receiver::board::{Board, BoardIo, Switch};
+use esp_now_receiver::channel::{Migration, Scan};
+use esp_now_receiver::commissioning::Commissioning;
+use esp_now_receiver::console::{self, Console};
+use esp_now_receiver::control::{self, Jobs};
+use esp_now_receiver::datalog::DataLog;
+#[cfg(feature = "microphone")]
+use esp_now_receiver::datalog::Sample;
+use esp_now_receiver::defaults::DEFAULTS;
+use esp_now_receiver::degraded::Mode;
+use esp_now_receiver::handlers::{Dispatcher, Rules, TopicHandler, Trigger};
+use esp_now_receiver::history::History;
+use esp_now_receiver::i2c_bus::I2cBus;
+use esp_now_receiver::inputs::{Action, Panel};
+#[cfg(feature = "microphone")]
+use esp_now_receiver::microphone::{Band, Listener};
+use esp_now_receiver::output_guard::GuardedOutput;
+use esp_now_receiver::pairing::Pairing;
+use esp_now_receiver::presence::Presence;
+use esp_now_receiver::profiles::{Profile, Schedule};
+use esp_now_receiver::quiet_hours::{self, Mode as QuietMode, Window};
+use esp_now_receiver::readiness::Fault;
+use esp_now_receiver::settings::Setting;
+use esp_now_receiver::siren::Siren;
+use esp_now_receiver::snooze::Snooze;
+use esp_now_receiver::sounds::{Cue, Sounds};
+use esp_now_receiver::strings::Text;
+use esp_now_receiver::thresholds::{Limit, Rule};
+use esp_now_receiver::topics::{self, Topic};
+#[cfg(feature = "tracing")]
+use esp_now_receiver::trace;
+use esp_now_receiver::transforms::{Pipeline, Pipelines};
+use esp_now_receiver::uplink::{Event, Outbox, UplinkChain};
+use esp_now_receiver::watchdog::{Stage, Watchdog};
+use esp_now_receiver::wearable::Mirror;
+use esp_now_receiver::{
+    ack, addressing, board, capture, channel, clock, commands, config, degraded, dispatch, espnow,
+    espnow_tx, forecast, freshness, http, identity, inputs, inspect, keys, link, log_governor,
+    long_range, metrics, names, pairing, power, profiles, readiness, schema, settings, sntp,
+    sounds, startup, storage, strings, support, thresholds, timezone, transforms, uplink,
+    whitelist,
+};
+use log::{info, warn};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 // --- Topics ---
+// Wire ids of the built-in sensors, registered under TOPICS
 const TOPIC_ID_KETTLE_THERMO: i32 = 1;
 const TOPIC_ID_SINK_THERMO: i32 = 2;
-#[allow(dead_code)]
 const TOPIC_ID_KETTLE_SOUND: i32 = 3;
+// Tones heard by the hub's own microphone
+#[cfg(feature = "microphone")]
+const TOPIC_ID_MIC_WHISTLE: i32 = 4;
+#[cfg(feature = "microphone")]
+const TOPIC_ID_MIC_SMOKE: i32 = 5;
+// Pages from the page button, sent upstream as critical alarms
+const TOPIC_ID_PAGE: i32 = 6;
+
+// --- Alarm Priorities ---
+// When alarms overlap, the higher class gets the buzzer. A rule's bands
+// raise its priority further along, see THRESHOLDS
+const KETTLE_THERMO_PRIORITY: Priority = Priority::Info;
+const SINK_THERMO_PRIORITY: Priority = Priority::Warning;
+
+// --- Thresholds ---
+// Default alarm limits, from defaults.toml like the other tunables below;
+// `threshold` and `learn` on the console override them.
+// A rule with `requires` conditions alarms only once they hold as well, e.g.
+// the kettle sound sensor having gone off within the last 30 s
+// `bands` raise the priority past further limits: the kettle is info above
+// 50, warning above 70 and critical above 95 by default
+const THRESHOLDS: &[Rule] = &[
+    Rule {
+        topic_id: TOPIC_ID_KETTLE_THERMO,
+        alarm: Limit::Above(DEFAULTS.thresholds.kettle_above),
+        priority: KETTLE_THERMO_PRIORITY,
+        bands: &[
+            thresholds::Band {
+                limit: Limit::Above(DEFAULTS.thresholds.kettle_warning_above),
+                priority: Priority::Warning,
+            },
+            thresholds::Band {
+                limit: Limit::Above(DEFAULTS.thresholds.kettle_critical_above),
+                priority: Priority::Critical,
+            },
+        ],
+        requires: &[],
+    },
+    Rule {
+        topic_id: TOPIC_ID_SINK_THERMO,
+        alarm: Limit::Below(DEFAULTS.thresholds.sink_below),
+        priority: SINK_THERMO_PRIORITY,
+        bands: &[],
+        requires: &[],
+    },
+];
+
+// --- Alert Cooldowns ---
+// Rate-limited topics sound one burst per cooldown while their alarm
+// persists, see src/alerts.rs; the kettle sounds on by default
+const KETTLE_COOLDOWN: Duration = Duration::from_secs(DEFAULTS.alerts.kettle_cooldown_secs);
+const SINK_COOLDOWN: Duration = Duration::from_secs(DEFAULTS.alerts.sink_cooldown_secs);
+
+// --- Alert Escalation ---
+// Alarms build up from a short soft beep to their full pattern and then a
+// continuous tone while they persist, see src/alerts.rs
+const KETTLE_FULL_AFTER: Duration = Duration::from_secs(DEFAULTS.escalation.kettle_full_after_secs);
+const KETTLE_CONTINUOUS_AFTER: Duration =
+    Duration::from_secs(DEFAULTS.escalation.kettle_continuous_after_secs);
+const SINK_FULL_AFTER: Duration = Duration::from_secs(DEFAULTS.escalation.sink_full_after_secs);
+const SINK_CONTINUOUS_AFTER: Duration =
+    Duration::from_secs(DEFAULTS.escalation.sink_continuous_after_secs);
+
+// --- Pipelines ---
+// Processing per handler before a reading is stored or alarmed on, see
+// src/transforms.rs; topics of other handlers only get the threshold check.
+// Readings outside a handler's valid range are outliers: a disconnected
+// DS18B20 reads -127, which is dropped here instead of raising the frost
+// alarm
+const THERMOMETER: &str = "thermometer";
+// On/off sensors, their readings conditions of other rules; never flagged
+// unusual, see src/handlers.rs
+const SOUND: &str = "sound";
+const PIPELINES: &[Pipeline] = &[
+    Pipeline {
+        handler: THERMOMETER,
+        valid: -55..=125,
+        stages: &[transforms::Stage::Threshold],
+    },
+    Pipeline {
+        handler: SOUND,
+        valid: 0..=1,
+        stages: &[transforms::Stage::Threshold],
+    },
+];
+
+// --- Topic Registry ---
+// The built-in topics, see src/topics.rs; `topic` on the console registers
+// more at runtime
+const TOPICS: &[Topic] = &[
+    Topic {
+        name: "kettle/temp",
+        topic_id: TOPIC_ID_KETTLE_THERMO,
+        handler: THERMOMETER,
+    },
+    Topic {
+        name: "sink/temp",
+        topic_id: TOPIC_ID_SINK_THERMO,
+        handler: THERMOMETER,
+    },
+    Topic {
+        name: "kettle/sound",
+        topic_id: TOPIC_ID_KETTLE_SOUND,
+        handler: SOUND,
+    },
+];
+
+// --- Anomalies ---
+// Set ANOMALY_Z_SCORE (e.g. "4") at build time to flag readings that far
+// off, in standard deviations, on topics without a threshold alarm
+const ANOMALY_Z_SCORE: Option<&str> = option_env!("ANOMALY_Z_SCORE");
+
+// --- Microphone ---
+// Bands the hub listens for with the `microphone` feature
+#[cfg(feature = "microphone")]
+const MIC_BANDS: &[Band] = &[
+    Band {
+        name: "kettle whistle",
+        from_hz: 1500,
+        to_hz: 2500,
+        topic_id: TOPIC_ID_MIC_WHISTLE,
+        priority: Priority::Warning,
+    },
+    Band {
+        name: "smoke alarm",
+        from_hz: 2800,
+        to_hz: 3500,
+        topic_id: TOPIC_ID_MIC_SMOKE,
+        priority: Priority::Critical,
+    },
+];
+
+// --- Profiles ---
+// A/B alarm profiles, the first two are what toggling and the schedule flip
+// between. Holding the button for PROFILE_HOLD, and letting go before
+// PAIRING_HOLD, toggles them; set
+// PROFILE_A_MONTHS (e.g. "11-3") at build time to switch on a schedule.
+// `away` keeps only the frost alarm, for `away <days>` on the console.
+const PROFILES: &[Profile] = &[
+    Profile {
+        name: "winter",
+        muted: &[],
+        away: false,
+    },
+    Profile {
+        name: "summer",
+        muted: &[TOPIC_ID_SINK_THERMO],
+        away: false,
+    },
+    Profile {
+        name: "away",
+        muted: &[TOPIC_ID_KETTLE_THERMO],
+        away: true,
+    },
+];
+const PROFILE_HOLD: Duration = Duration::from_secs(DEFAULTS.profiles.hold_secs);
+const PROFILE_A_MONTHS: Option<&str> = option_env!("PROFILE_A_MONTHS");
+
+// --- Buttons ---
+// Action buttons next to the wake button, on the pins set in defaults.toml
+// and claimed at boot, see src/inputs.rs
+const BUTTONS: &[(Action, i32)] = &[
+    (Action::Mute, DEFAULTS.buttons.mute_gpio),
+    (Action::Snooze, DEFAULTS.buttons.snooze_gpio),
+    (Action::Page, DEFAULTS.buttons.page_gpio),
+    (Action::Pair, DEFAULTS.buttons.pair_gpio),
+];
+const SNOOZE: Duration = Duration::from_secs(DEFAULTS.buttons.snooze_mins * 60);
+
+// --- Board ---
+// Pin assignments live in the board impl, see src/board
+#[cfg(not(feature = "headless"))]
+type ActiveBoard = board::DevKit;
+#[cfg(feature = "headless")]
+type ActiveBoard = board::Headless;
+
+// Topic -> index into the board's alarm LEDs
+const TOPIC_LEDS: &[(i32, usize)] = &[(TOPIC_ID_KETTLE_THERMO, 0), (TOPIC_ID_SINK_THERMO, 1)];
+
+// --- Output Safety ---
+// Longest the buzzer may stay on continuously before it is forced off
+const BUZZER_MAX_ON: Duration = Duration::from_secs(DEFAULTS.buzzer.max_on_secs);
+
+// --- External Siren ---
+// Unacknowledged alarms of this class and up chirp the siren, which sounds
+// in full after SIREN_FULL_AFTER, see src/siren.rs; its own on-time limit
+// allows for that
+const SIREN_PRIORITY: Priority = Priority::Warning;
+const SIREN_FULL_AFTER: Duration = Duration::from_secs(DEFAULTS.siren.full_after_secs);
+const SIREN_MAX_ON: Duration = Duration::from_secs(DEFAULTS.siren.max_on_secs);
+
+// --- Quiet Hours ---
+// Nightly window with the buzzer turned down and the siren muted, see
+// src/quiet_hours.rs; `quiet` on the console overrides it
+const QUIET_HOURS: Window = Window {
+    from_hour: DEFAULTS.quiet_hours.from_hour,
+    to_hour: DEFAULTS.quiet_hours.to_hour,
+    mode: if DEFAULTS.quiet_hours.led_only {
+        QuietMode::LedOnly
+    } else {
+        QuietMode::Reduced
+    },
+};
+
+// --- Auto Sleep ---
+// Set AUTO_SLEEP_MINUTES at build time to deep sleep after that long without
+// frames, alarms or button presses
+const AUTO_SLEEP_MINUTES: Option<&str> = option_env!("AUTO_SLEEP_MINUTES");
+// Countdown before sleeping, a button press during it keeps the hub awake
+const SLEEP_WARNING: Duration = Duration::from_secs(DEFAULTS.sleep.warning_secs);
+// While away the hub stays awake this many times longer, deep sleep only
+// wakes on the button and nobody is home to press it
+const AWAY_SLEEP_FACTOR: u32 = DEFAULTS.sleep.away_factor;
+
+// --- Presence ---
+// Set BEACON_MAC at build time to a sender carried around as a presence
+// beacon; while its RSSI is at least BEACON_NEAR_RSSI dBm someone is taken to
+// be by the hub and the buzzer is kept down
+const BEACON_MAC: Option<&str> = option_env!("BEACON_MAC");
+const BEACON_NEAR_RSSI: Option<&str> = option_env!("BEACON_NEAR_RSSI");
+const DEFAULT_NEAR_RSSI: i32 = DEFAULTS.presence.near_rssi;
+
+// --- Link ---
+// Senders whose RSSI averages below this are warned about as weak links, and
+// with WEAK_LINK_LED their idle LEDs say so
+const WEAK_LINK_RSSI: i32 = DEFAULTS.link.weak_rssi;
+const WEAK_LINK_LED: bool = DEFAULTS.link.weak_led;
+
+// --- Freshness ---
+// Topics reported offline once silent for this long, see src/freshness.rs
+const KETTLE_OFFLINE_AFTER: Duration = Duration::from_secs(DEFAULTS.freshness.kettle_mins * 60);
+const SINK_OFFLINE_AFTER: Duration = Duration::from_secs(DEFAULTS.freshness.sink_mins * 60);
+
+// --- Pairing ---
+// How long `pair` on the console, or holding the button for PAIRING_HOLD,
+// accepts pairing requests
+const PAIRING_WINDOW: Duration = Duration::from_secs(DEFAULTS.pairing.window_secs);
+const PAIRING_HOLD: Duration = Duration::from_secs(DEFAULTS.pairing.hold_secs);
+
+// --- Channel Migration ---
+// When the AP is on another channel than ESP-NOW, the hub keeps listening on
+// the old channel this long, telling the senders, before joining it
+const CHANNEL_GRACE: Duration = Duration::from_secs(DEFAULTS.channel.grace_secs);
+// A channel scan listens this long on each channel
+const CHANNEL_SCAN_DWELL: Duration = Duration::from_secs(DEFAULTS.channel.scan_dwell_secs);
 
-// --- Pin Definitions ---
-// GPIO 34-39 are input only on ESP32
-// Using gpio32 for THERMO_1_LED (Kettle Thermostat)
-// Using gpio12 for THERMO_2_LED (Sink Thermostat)
-// Using gpio13 for BUZZER
-const WAKEUP_GPIO: i32 = 4;
-
-// --- Data structure matching the sender ---
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
-struct HubData {
-    topic_id: i32,
-    measurement: i32,
+// --- Clock ---
+// Timezone of schedules and log timestamps unless overridden in NVS, see
+// src/timezone.rs
+const TIMEZONE: &str = DEFAULTS.clock.timezone;
+// Sets the clock once the AP is joined, see src/sntp.rs
+const SNTP_SERVER: &str = DEFAULTS.clock.sntp_server;
+
+// --- Radio ---
+// 802.11 LR for ESP-NOW unless overridden in NVS, see src/long_range.rs
+const LONG_RANGE: bool = DEFAULTS.radio.long_range;
+
+// --- Addressing ---
+// Receiver id, 0 for none, and groups unless overridden in NVS, see
+// src/addressing.rs
+const RECEIVER_ID: u16 = DEFAULTS.addressing.receiver_id;
+const RECEIVER_GROUPS: &str = DEFAULTS.addressing.groups;
+
+// --- Config Import ---
+// An imported config reverts unless confirmed within this long
+const CONFIG_REVERT_TIMEOUT: Duration = Duration::from_secs(DEFAULTS.config.revert_timeout_secs);
+// Local hours imports are deferred to unless urgent, none with both the same
+const CONFIG_WINDOW: (u32, u32) = (
+    DEFAULTS.config.maintenance_from_hour,
+    DEFAULTS.config.maintenance_to_hour,
+);
+
+// --- Runtime Settings ---
+// Built-in values of the settings `set` on the console and config imports
+// can override, see src/settings.rs
+const SETTINGS: &[(Setting, Duration)] = &[
+    (Setting::StatusInterval, STATUS_INTERVAL),
+    (Setting::AwayStatusInterval, AWAY_STATUS_INTERVAL),
+    (Setting::PairingWindow, PAIRING_WINDOW),
+    (Setting::Snooze, SNOOZE),
+    (Setting::SirenFullAfter, SIREN_FULL_AFTER),
+    (Setting::BuzzerMaxOn, BUZZER_MAX_ON),
+    (Setting::LoopStall, LOOP_STALL_TIMEOUT),
+    (Setting::LoopRestart, LOOP_RESTART_TIMEOUT),
+    (
+        Setting::OfflineAfter(TOPIC_ID_KETTLE_THERMO),
+        KETTLE_OFFLINE_AFTER,
+    ),
+    (
+        Setting::OfflineAfter(TOPIC_ID_SINK_THERMO),
+        SINK_OFFLINE_AFTER,
+    ),
+    (Setting::Cooldown(TOPIC_ID_KETTLE_THERMO), KETTLE_COOLDOWN),
+    (Setting::Cooldown(TOPIC_ID_SINK_THERMO), SINK_COOLDOWN),
+    (
+        Setting::FullAfter(TOPIC_ID_KETTLE_THERMO),
+        KETTLE_FULL_AFTER,
+    ),
+    (Setting::FullAfter(TOPIC_ID_SINK_THERMO), SINK_FULL_AFTER),
+    (
+        Setting::ContinuousAfter(TOPIC_ID_KETTLE_THERMO),
+        KETTLE_CONTINUOUS_AFTER,
+    ),
+    (
+        Setting::ContinuousAfter(TOPIC_ID_SINK_THERMO),
+        SINK_CONTINUOUS_AFTER,
+    ),
+];
+
+// --- Support Mode ---
+// Verbose logs and diagnostics snapshots this long, see src/support.rs
+const SUPPORT_DURATION: Duration = Duration::from_secs(DEFAULTS.support.duration_mins * 60);
+const SUPPORT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(DEFAULTS.support.snapshot_secs);
+
+// --- Watchdog ---
+// The main loop is reset at the stuck step after LOOP_STALL_TIMEOUT without
+// completing an iteration, and the device restarts after LOOP_RESTART_TIMEOUT
+const LOOP_STALL_TIMEOUT: Duration = Duration::from_secs(DEFAULTS.watchdog.stall_secs);
+const LOOP_RESTART_TIMEOUT: Duration = Duration::from_secs(DEFAULTS.watchdog.restart_secs);
+// Longest the main loop waits for an input event between iterations
+const LOOP_TICK: Duration = Duration::from_millis(10);
+
+// --- Startup ---
+// Delay before the radio comes up, and how long the later stages may hold up
+// the boot before the main loop starts without them
+const STARTUP_DELAY: Duration = Duration::from_millis(DEFAULTS.startup.delay_ms);
+const RADIO_TIMEOUT: Duration = Duration::from_secs(DEFAULTS.startup.radio_timeout_secs);
+// How often ESP-NOW is retried after it failed to start
+const RADIO_RETRY: Duration = Duration::from_secs(DEFAULTS.startup.radio_retry_secs);
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(DEFAULTS.startup.storage_timeout_secs);
+const UPLINKS_TIMEOUT: Duration = Duration::from_secs(DEFAULTS.startup.uplinks_timeout_secs);
+const STORAGE_STACK_SIZE: usize = 4096;
+
+// --- WiFi (optional, set at build time) ---
+// Only needed for the IP uplinks; ESP-NOW works without joining an AP
+const WIFI_SSID: Option<&str> = option_env!("WIFI_SSID");
+const WIFI_PASS: Option<&str> = option_env!("WIFI_PASS");
+
+// --- Uplinks ---
+const STATUS_INTERVAL: Duration = Duration::from_secs(DEFAULTS.uplinks.status_interval_secs);
+// While away, status goes out more often for remote monitoring
+const AWAY_STATUS_INTERVAL: Duration =
+    Duration::from_secs(DEFAULTS.uplinks.away_status_interval_secs);
+
+// --- Forecast (optional, set at build time) ---
+// Returns tonight's low in sink units, see src/forecast.rs
+const FORECAST_URL: Option<&str> = option_env!("FORECAST_URL");
+const FORECAST_REFRESH: Duration = Duration::from_secs(DEFAULTS.forecast.refresh_mins * 60);
+
+// --- Stored Data ---
+// Upgrades of the data kept in NVS and on the storage partition, in order;
+// append one whenever a stored format changes, see src/schema.rs
+const MIGRATIONS: &[schema::Migration] = &[];
+
+// --- Data Logging ---
+// Set to dump the stored log as CSV over the console at boot
+const DATALOG_EXPORT_ON_BOOT: bool = false;
+
+fn connect_uplinks(storage_ok: bool) -> UplinkChain {
+    let mut uplinks = UplinkChain::configured();
+    if storage_ok {
+        uplinks.set_outbox(Outbox::open(storage::BASE_PATH));
+    }
+    uplinks
 }
 
-// FreeRTOS Implementation
-// Static variables for received data (used in callback)
-static RECEIVED_TOPIC: AtomicI32 = AtomicI32::new(-1);
-static RECEIVED_MEASUREMENT: AtomicI32 = AtomicI32::new(0);
-static DATA_READY: AtomicBool = AtomicBool::new(false);
-
-// RTC slow memory to persist across deep sleep
-// Note: In esp-idf-svc, we use a static with #[link_section] for RTC memory
-#[link_section = ".rtc.data"]
-static mut IS_AWAKE: bool = false;
-
-/// ESP-NOW receive callback
-unsafe extern "C" fn on_receive(
-    _info: *const esp_now_recv_info_t,
-    data: *const u8,
-    len: core::ffi::c_int,
-) {
-    if len as usize == std::mem::size_of::<HubData>() {
-        let hub_data: HubData = std::ptr::read_unaligned(data as *const HubData);
+/// Open the data log and history on the mounted storage partition
+fn open_logs() -> (DataLog, History) {
+    let log = DataLog::new(storage::BASE_PATH);
+    let history = History::new(storage::BASE_PATH);
+    if let Ok(stats) = log.stats() {
         info!(
-            "Received - Topic ID: {} | Measurement: {}",
-            hub_data.topic_id, hub_data.measurement
+            "Data log: {} samples in {} blocks, {} bytes on flash ({:.1}x compression)",
+            stats.records,
+            stats.blocks,
+            stats.stored_bytes,
+            stats.ratio()
         );
+    }
+    if DATALOG_EXPORT_ON_BOOT {
+        if let Err(e) = log.export(&mut std::io::stdout()) {
+            warn!("Data log export failed: {}", e);
+        }
+    }
 
-        RECEIVED_TOPIC.store(hub_data.topic_id, Ordering::SeqCst);
-        RECEIVED_MEASUREMENT.store(hub_data.measurement, Ordering::SeqCst);
-        DATA_READY.store(true, Ordering::SeqCst);
+    if let Ok((minutes, hours)) = history.stats() {
+        info!(
+            "History: {} minute buckets, {} hour buckets, {} bytes on flash",
+            minutes.records,
+            hours.records,
+            minutes.stored_bytes + hours.stored_bytes
+        );
+    }
+    if DATALOG_EXPORT_ON_BOOT {
+        let mut out = std::io::stdout();
+        if let Err(e) = history
+            .minutes()
+            .export(&mut out)
+            .and_then(|_| history.hours().export(&mut out))
+        {
+            warn!("History export failed: {}", e);
+        }
     }
+    (log, history)
 }
 
-fn go_to_sleep() {
-    info!("Going to sleep now");
-    unsafe {
-        IS_AWAKE = false;
-        esp_deep_sleep_start();
+/// Bring up ESP-NOW with the paired peers, and the receive callback while
+/// awake
+fn start_espnow() -> Result<(), EspError> {
+    if let Err(e) = long_range::apply() {
+        warn!("Failed to set the PHY mode: {}", e);
+    }
+    espnow::init()?;
+    if let Err(e) = keys::apply_pmk() {
+        warn!("ESP-NOW PMK not set: {}", e);
+    }
+    pairing::add_peers();
+    whitelist::init();
+    if power::is_awake() {
+        if let Err(e) = espnow::listen() {
+            warn!("ESP-NOW receive callback registration failed: {}", e);
+            readiness::set_fault(Fault::Receive, true);
+        }
     }
+    Ok(())
 }
 
-fn sound_buzzer(buzzer: &mut PinDriver<'_, impl esp_idf_svc::hal::gpio::OutputPin, esp_idf_svc::hal::gpio::Output>) {
-    buzzer.set_high().ok();
-    FreeRtos::delay_ms(500);
-    buzzer.set_low().ok();
+/// ESP-NOW came up after being down
+fn radio_back() {
+    info!("ESP-NOW up");
+    degraded::set(Mode::RadioDown, false);
+    readiness::radio_ready();
+    if power::is_awake() {
+        readiness::arm();
+    }
+}
+
+fn join_ap(wifi: &mut BlockingWifi<EspWifi<'static>>) -> bool {
+    let Some(ssid) = WIFI_SSID else {
+        return false;
+    };
+    match wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+        Ok(()) => {
+            info!("WiFi connected to {}", ssid);
+            true
+        }
+        Err(e) => {
+            warn!(
+                "WiFi connection to {} failed, IP uplinks offline: {}",
+                ssid, e
+            );
+            false
+        }
+    }
+}
+
+fn start_http(storage_ok: bool) -> Option<EspHttpServer<'static>> {
+    http::start()
+        .and_then(|mut server| {
+            server.fn_handler("/status", Method::Get, |req| {
+                let status = Event::Status(inspect::status()).to_json();
+                req.into_ok_response()?.write_all(status.as_bytes())
+            })?;
+            console::serve(&mut server)?;
+            identity::serve(&mut server)?;
+            metrics::serve(&mut server)?;
+            #[cfg(feature = "tracing")]
+            trace::serve(&mut server)?;
+            names::serve(&mut server)?;
+            topics::serve(&mut server)?;
+            config::serve(&mut server)?;
+            support::serve(&mut server)?;
+            if storage_ok {
+                sounds::serve(&mut server, storage::BASE_PATH)?;
+                capture::serve(&mut server)?;
+            }
+            Ok(server)
+        })
+        .map_err(|e| warn!("HTTP server failed to start: {}", e))
+        .ok()
+}
+
+/// Hand the timings stored as settings to what holds them, see
+/// src/settings.rs; the siren and the LEDs are updated on their own
+fn apply_settings(alerts: &mut Alerts, annunciator: &mut Annunciator, watchdog: &Watchdog) {
+    annunciator.set_buzzer_max_on(settings::get(Setting::BuzzerMaxOn));
+    watchdog.set_timeouts(
+        settings::get(Setting::LoopStall),
+        settings::get(Setting::LoopRestart),
+    );
+    let mut timeouts = Vec::new();
+    let mut cooldowns = Vec::new();
+    let mut escalations = Vec::new();
+    for setting in settings::per_topic() {
+        let value = settings::get(setting);
+        match setting {
+            Setting::OfflineAfter(topic_id) => timeouts.push(freshness::Timeout {
+                topic_id,
+                after: value,
+            }),
+            Setting::Cooldown(topic_id) => cooldowns.push(Cooldown {
+                topic_id,
+                every: value,
+            }),
+            Setting::FullAfter(topic_id) | Setting::ContinuousAfter(topic_id)
+                if !escalations
+                    .iter()
+                    .any(|e: &Escalation| e.topic_id == topic_id) =>
+            {
+                escalations.push(Escalation {
+                    topic_id,
+                    full_after: settings::get(Setting::FullAfter(topic_id)),
+                    continuous_after: settings::get(Setting::ContinuousAfter(topic_id)),
+                })
+            }
+            _ => {}
+        }
+    }
+    freshness::set_timeouts(&timeouts);
+    alerts.set_timings(&cooldowns, &escalations);
+}
+
+/// Move the alarm LEDs as stored, see src/settings.rs; `reserved` as for
+/// [`Annunciator::remap_led`]
+fn move_leds(annunciator: &mut Annunciator, reserved: &[i32]) {
+    for (topic_id, gpio) in settings::led_pins() {
+        if let Err(e) = annunciator.remap_led::<ActiveBoard>(topic_id, gpio, reserved) {
+            warn!("Stored LED pin of topic {} not applied: {}", topic_id, e);
+        }
+    }
 }
 
-fn flash_led(led: &mut PinDriver<'_, impl esp_idf_svc::hal::gpio::OutputPin, esp_idf_svc::hal::gpio::Output>) {
-    led.set_high().ok();
-    FreeRtos::delay_ms(1000);
-    led.set_low().ok();
+/// Raise and clear the alarms of tones the microphone hears
+#[cfg(feature = "microphone")]
+fn listen(microphone: &Listener, alerts: &mut Alerts, uplinks: &mut UplinkChain) {
+    for heard in microphone.poll() {
+        let band = heard.band;
+        let sample = Sample::now(band.topic_id, heard.level);
+        if heard.sounding {
+            thresholds::observe(band.topic_id, heard.level);
+        }
+        if !heard.sounding {
+            info!("Microphone: {} stopped", band.name);
+            alerts.clear(band.topic_id, uplinks);
+        } else if profiles::alarms_enabled(band.topic_id) {
+            warn!("Microphone: {} heard", band.name);
+            if alerts.raise(sample, band.priority) {
+                uplinks.publish(Event::Alarm {
+                    sample,
+                    priority: band.priority,
+                });
+            }
+        }
+    }
 }
 
 fn main() {
@@ -91,123 +624,715 @@ fn main() {
     esp_idf_svc::log::EspLogger::initialize_default();
 
     info!("ESP-NOW Receiver Starting...");
+    match strings::language() {
+        Ok(code) => info!("Language: {}", code),
+        Err(code) => warn!("Unsupported LANGUAGE {:?}, using English", code),
+    }
 
     // Get peripherals
     let peripherals = Peripherals::take().unwrap();
 
-    // Check wakeup cause
-    let wakeup_reason = unsafe { esp_sleep_get_wakeup_cause() };
+    // Claim the board's pins, the annunciator starts all outputs LOW
+    let BoardIo {
+        alarm_leds,
+        buzzer,
+        siren,
+        mute_switch,
+        wake_button,
+        mut display_bus,
+        #[cfg(feature = "microphone")]
+        microphone,
+        ..
+    } = board::init::<ActiveBoard>();
+    #[cfg(feature = "microphone")]
+    let microphone = microphone.and_then(|m| {
+        m.listen(MIC_BANDS)
+            .map_err(|e| warn!("Microphone unavailable: {}", e))
+            .ok()
+    });
 
-    unsafe {
-        if wakeup_reason == esp_sleep_wakeup_cause_t_ESP_SLEEP_WAKEUP_GPIO {
-            if IS_AWAKE {
-                info!("Sensor touched: Going to sleep");
-                go_to_sleep();
-            } else {
-                info!("Sensor touched: Waking up");
-                IS_AWAKE = true;
+    let mut alarm_leds: Vec<_> = alarm_leds.into_iter().map(Some).collect();
+    let topic_leds = TOPIC_LEDS
+        .iter()
+        .filter_map(|&(topic_id, i)| Some((topic_id, alarm_leds.get_mut(i)?.take()?)))
+        .collect();
+    let mut annunciator = Annunciator::new(
+        GuardedOutput::new("Buzzer", buzzer, BUZZER_MAX_ON),
+        topic_leds,
+    );
+    let mut siren = siren.map(|siren| {
+        Siren::new(
+            GuardedOutput::new("Siren", siren, SIREN_MAX_ON),
+            SIREN_PRIORITY,
+            settings::get(Setting::SirenFullAfter),
+        )
+    });
+
+    // Check wakeup cause
+    if power::woken_by_button() {
+        if power::is_awake() {
+            info!("Sensor touched: Going to sleep");
+            if annunciator.arm().is_ok() {
+                if storage::mount().is_ok() {
+                    annunciator.set_sounds(Sounds::load(storage::BASE_PATH));
+                }
+                annunciator.play_cue(Cue::Sleep);
             }
+            power::go_to_sleep();
         } else {
-            info!("Normal Boot");
-            IS_AWAKE = false;
+            info!("{}", strings::text(Text::WakingUp, &[]));
+            power::set_awake(true);
+            if let Some(slept) = power::slept_for() {
+                readiness::slept(slept);
+            }
         }
+    } else {
+        info!("Normal Boot");
+        power::set_awake(false);
     }
 
-    // Configure GPIO outputs
-    let mut thermo_1_led = PinDriver::output(peripherals.pins.gpio32).unwrap();
-    let mut thermo_2_led = PinDriver::output(peripherals.pins.gpio12).unwrap();
-    let mut buzzer = PinDriver::output(peripherals.pins.gpio13).unwrap();
-
-    // Ensure all outputs start LOW
-    thermo_1_led.set_low().ok();
-    thermo_2_led.set_low().ok();
-    buzzer.set_low().ok();
-
-    // Configure wakeup GPIO
-    let _wakeup_pin = PinDriver::input(peripherals.pins.gpio4).unwrap();
-
     // Initialize WiFi in STA mode (required for ESP-NOW)
     let sys_loop = EspSystemEventLoop::take().unwrap();
-    let nvs = EspDefaultNvsPartition::take().unwrap();
+    // Without NVS the hub runs on its built-in defaults, see src/degraded.rs
+    let nvs = EspDefaultNvsPartition::take()
+        .map_err(|e| warn!("NVS unavailable: {}", e))
+        .ok();
+    let mut nvs_ok = nvs.is_some();
+    if let Some(nvs) = nvs.as_ref() {
+        if let Err(e) = schema::run(nvs, MIGRATIONS) {
+            warn!(
+                "Stored data migration failed, will retry at the next boot: {}",
+                e
+            );
+        }
+    }
+    if let Err(e) = metrics::init(nvs.clone()) {
+        warn!("Metrics will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = identity::init(nvs.clone()) {
+        warn!("Device identity will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = timezone::init(nvs.clone(), TIMEZONE) {
+        warn!("Timezone changes will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = profiles::init(nvs.clone(), PROFILES) {
+        warn!("Profile switches will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = thresholds::init(nvs.clone(), THRESHOLDS) {
+        warn!("Threshold changes will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = topics::init(nvs.clone(), TOPICS, PIPELINES) {
+        warn!("Topic registry changes will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = long_range::init(nvs.clone(), LONG_RANGE) {
+        warn!("Long-range setting will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = quiet_hours::init(nvs.clone(), Some(QUIET_HOURS)) {
+        warn!("Quiet hours will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Err(e) = settings::init(nvs.clone(), SETTINGS) {
+        warn!("Setting changes will not persist: {}", e);
+        nvs_ok = false;
+    }
+    let groups = addressing::parse_groups(RECEIVER_GROUPS).unwrap_or_else(|e| {
+        warn!("[addressing] groups {:?} ignored: {}", RECEIVER_GROUPS, e);
+        Vec::new()
+    });
+    let receiver = (RECEIVER_ID != 0).then_some(RECEIVER_ID);
+    if let Err(e) = addressing::init(nvs.clone(), receiver, &groups) {
+        warn!("Address changes will not persist: {}", e);
+        nvs_ok = false;
+    }
+    if let Some(nvs) = nvs.as_ref() {
+        if let Err(e) = names::load(nvs.clone()) {
+            warn!("Sensor names unavailable: {}", e);
+            nvs_ok = false;
+        }
+        if let Err(e) = pairing::load(nvs.clone()) {
+            warn!("Paired senders unavailable: {}", e);
+            nvs_ok = false;
+        }
+        if let Err(e) = keys::load(nvs.clone()) {
+            warn!("ESP-NOW keys unavailable, peers are plaintext: {}", e);
+            nvs_ok = false;
+        }
+        if let Err(e) = channel::load(nvs.clone()) {
+            warn!("Stored ESP-NOW channel unavailable: {}", e);
+            nvs_ok = false;
+        }
+        // Last, a pending revert restores the modules above
+        let window = (CONFIG_WINDOW.0 != CONFIG_WINDOW.1).then_some(CONFIG_WINDOW);
+        if let Err(e) = config::init(nvs.clone(), CONFIG_REVERT_TIMEOUT, window) {
+            warn!("Config import unavailable: {}", e);
+            nvs_ok = false;
+        }
+    }
+    degraded::set(Mode::VolatileConfig, !nvs_ok);
+    support::init(SUPPORT_DURATION, SUPPORT_SNAPSHOT_INTERVAL);
+
+    if !STARTUP_DELAY.is_zero() {
+        info!("Startup delay of {} ms", STARTUP_DELAY.as_millis());
+        thread::sleep(STARTUP_DELAY);
+    }
 
+    // The radio comes up first, then storage and the uplinks, which may hold
+    // up the boot for their timeout at most, see src/startup.rs
+    let radio = startup::Stage::start("radio", RADIO_TIMEOUT);
     let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs)).unwrap(),
+        EspWifi::new(peripherals.modem, sys_loop.clone(), nvs).unwrap(),
         sys_loop,
     )
     .unwrap();
 
-    // Set WiFi to STA mode without connecting
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration::default(),
-    ))
-    .unwrap();
+    // Set WiFi to STA mode, joining an AP only if credentials were provided
+    let mut client_config = esp_idf_svc::wifi::ClientConfiguration::default();
+    if let Some(ssid) = WIFI_SSID {
+        client_config.ssid = ssid.try_into().unwrap();
+        match WIFI_PASS {
+            Some(pass) => client_config.password = pass.try_into().unwrap(),
+            None => client_config.auth_method = AuthMethod::None,
+        }
+    }
+    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(client_config))
+        .unwrap();
     wifi.start().unwrap();
 
     info!("WiFi started in STA mode");
+    if let Some(stored) = channel::stored() {
+        match channel::set(stored) {
+            Ok(()) => info!("ESP-NOW on stored channel {}", stored),
+            Err(e) => warn!("Failed to set the stored channel {}: {}", stored, e),
+        }
+    }
+
+    // Joining the AP waits for ESP-NOW, in case the senders must be told
+    // about a channel change first
+    let espnow_channel = channel::current()
+        .map_err(|e| warn!("Failed to read the WiFi channel: {}", e))
+        .ok();
+    let ap_channel = WIFI_SSID.and_then(|ssid| channel::ap_channel(&mut wifi, ssid));
+
+    // Initialize ESP-NOW, retried from the main loop while it is down
+    let radio_up = radio.finish(start_espnow());
+    let mut radio_retry = None;
+    if radio_up {
+        readiness::radio_ready();
+    } else {
+        degraded::set(Mode::RadioDown, true);
+        radio_retry = Some(clock::now());
+    }
+    // No display driver is supported yet, every board shows its status on
+    // the LEDs
+    degraded::set(Mode::LedCodes, true);
 
     // Enable GPIO wakeup
-    unsafe {
-        gpio_wakeup_enable(
-            WAKEUP_GPIO as u32,
-            gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-        );
-        esp_deep_sleep_enable_gpio_wakeup(
-            1u64 << WAKEUP_GPIO,
-            esp_idf_svc::sys::gpio_deepsleep_wakeup_level_t_ESP_GPIO_WAKEUP_GPIO_HIGH,
-        );
+    wake_button.enable_wakeup();
+
+    // Mount flash storage for the data logger, off the main task so a slow
+    // or broken partition cannot hold up the alarms
+    let storage_stage = startup::Stage::start("storage", STORAGE_TIMEOUT);
+    let (mounted_tx, mounted) = mpsc::channel();
+    let spawned = thread::Builder::new()
+        .name("storage".into())
+        .stack_size(STORAGE_STACK_SIZE)
+        .spawn(move || mounted_tx.send(storage::mount()).ok());
+    let mut pending_storage = None;
+    let mut storage_ok = match spawned {
+        Ok(_) => match storage_stage.wait(|| mounted.try_recv().ok()) {
+            Some(result) => storage_stage.finish(result),
+            None => {
+                pending_storage = Some((storage_stage, mounted));
+                false
+            }
+        },
+        Err(e) => storage_stage.finish(Err(e)),
+    };
+    let (mut datalog, mut history) = if storage_ok {
+        let (log, history) = open_logs();
+        (Some(log), Some(history))
+    } else {
+        (None, None)
+    };
+
+    // Custom alarm sounds, uploadable while on WiFi
+    if storage_ok {
+        annunciator.set_sounds(Sounds::load(storage::BASE_PATH));
     }
 
-    // Initialize ESP-NOW
-    unsafe {
-        if esp_now_init() != ESP_OK as i32 {
-            info!("ESP-NOW Init Failed");
-            return;
+    let mut migration = match (espnow_channel, ap_channel) {
+        (Some(from), Some(to)) if from != to => Some(Migration::start(from, to, CHANNEL_GRACE)),
+        _ => None,
+    };
+    // The AP is joined in the background, the main loop takes the uplinks
+    // into use once it is up
+    let mut pending_wifi = None;
+    let wifi_up = match WIFI_SSID {
+        Some(_) if migration.is_none() => {
+            let stage = startup::Stage::start("uplinks", UPLINKS_TIMEOUT);
+            match wifi.wifi_mut().connect() {
+                Ok(()) => match stage.wait(|| wifi.is_up().unwrap_or(false).then_some(())) {
+                    Some(()) => stage.finish(Ok::<_, EspError>(())),
+                    None => {
+                        pending_wifi = Some(stage);
+                        false
+                    }
+                },
+                Err(e) => stage.finish(Err(e)),
+            }
         }
-        info!("ESP-NOW Initialized");
+        _ => false,
+    };
+    if wifi_up {
+        sntp::start(SNTP_SERVER);
+    }
+    let mut _http_server = wifi_up.then(|| start_http(storage_ok)).flatten();
 
-        if IS_AWAKE {
-            esp_now_register_recv_cb(Some(on_receive));
-            info!("ESP-NOW receive callback registered");
+    let mut uplinks = connect_uplinks(storage_ok);
+    if !uplinks.is_empty() {
+        info!(
+            "Uplinks (by priority): {}",
+            uplinks.names().collect::<Vec<_>>().join(", ")
+        );
+    }
+    if let Some(url) = FORECAST_URL {
+        let config = forecast::Config {
+            url,
+            topic_id: TOPIC_ID_SINK_THERMO,
+            freezing: DEFAULTS.forecast.freezing,
+            tighten_by: DEFAULTS.forecast.tighten_by,
+            refresh: FORECAST_REFRESH,
+            night: (
+                DEFAULTS.forecast.night_from_hour,
+                DEFAULTS.forecast.night_to_hour,
+            ),
+        };
+        if let Err(e) = forecast::start(config) {
+            warn!("Forecast thread failed to start: {}", e);
         }
     }
+    let mut last_status: Option<Instant> = None;
+    let mut alerts = Alerts::default();
+    inputs::init();
+    let mut panel = Panel::new(wake_button, PROFILE_HOLD, PAIRING_HOLD);
+    let mut schedule = PROFILE_A_MONTHS.and_then(|months| {
+        let schedule = Schedule::parse(months);
+        if schedule.is_none() {
+            warn!("Invalid PROFILE_A_MONTHS {:?}, no profile schedule", months);
+        }
+        schedule
+    });
+
+    // Boot checks are done, strapping pins are safe to drive from here on
+    if let Err(e) = annunciator.arm() {
+        warn!("Failed to configure alarm outputs: {}", e);
+        readiness::set_fault(Fault::Outputs, true);
+    }
+    if let Some(Err(e)) = siren.as_mut().map(Siren::arm) {
+        warn!("Failed to configure the siren output: {}", e);
+    }
+    // An asleep hub does not receive, so it never arms
+    if power::is_awake() && radio_up {
+        readiness::arm();
+    }
+    annunciator.play_cue(Cue::Boot);
+
+    // Pins outside the annunciator that a remapped LED must not take
+    let mut reserved_pins: Vec<i32> = ActiveBoard::PIN_MAP
+        .iter()
+        .filter(|p| !p.output)
+        .map(|p| p.gpio)
+        .collect();
+    #[cfg(feature = "microphone")]
+    reserved_pins.extend(microphone.iter().flat_map(Listener::gpios));
+    reserved_pins.extend(siren.as_ref().map(Siren::gpio));
+    // Action buttons, kept off every pin in use and claimed in turn
+    for &(action, gpio) in BUTTONS.iter().filter(|(_, gpio)| *gpio >= 0) {
+        let in_use: Vec<i32> = ActiveBoard::PIN_MAP
+            .iter()
+            .map(|p| p.gpio)
+            .chain(reserved_pins.iter().copied())
+            .collect();
+        match panel.claim(action, gpio, &in_use) {
+            Ok(()) => reserved_pins.push(gpio),
+            Err(e) => warn!("{} button unavailable: {}", action.name(), e),
+        }
+    }
+    move_leds(&mut annunciator, &reserved_pins);
+    let mut snooze = Snooze::default();
+    let console = Console::start()
+        .map_err(|e| warn!("Console unavailable: {}", e))
+        .ok();
+    let mut jobs = Jobs::default();
+    let mut mirror = Mirror::default();
+    let watchdog = Watchdog::start(
+        settings::get(Setting::LoopStall),
+        settings::get(Setting::LoopRestart),
+    );
+    let mut sleep_idle = None;
+    let mut auto_sleep = AUTO_SLEEP_MINUTES.and_then(|minutes| match minutes.parse::<u64>() {
+        Ok(minutes) => {
+            let idle = Duration::from_secs(minutes * 60);
+            sleep_idle = Some(idle);
+            Some(AutoSleep::new(
+                if profiles::away() {
+                    idle * AWAY_SLEEP_FACTOR
+                } else {
+                    idle
+                },
+                SLEEP_WARNING,
+            ))
+        }
+        Err(_) => {
+            warn!(
+                "Invalid AUTO_SLEEP_MINUTES {:?}, auto-sleep disabled",
+                minutes
+            );
+            None
+        }
+    });
+    let mut pipelines = Pipelines::new(PIPELINES);
+    let z_score = ANOMALY_Z_SCORE.and_then(|z| match z.parse::<f32>() {
+        Ok(z) if z > 0.0 => Some(z),
+        _ => {
+            warn!("Invalid ANOMALY_Z_SCORE {:?}, anomalies not flagged", z);
+            None
+        }
+    });
+    let mut dispatcher = Dispatcher::new(Box::new(move |handler| -> Box<dyn TopicHandler> {
+        match handler {
+            SOUND => Box::new(Trigger),
+            _ => Box::new(Rules::new(z_score)),
+        }
+    }));
+    let mut presence = BEACON_MAC.and_then(|mac| {
+        let near_rssi = BEACON_NEAR_RSSI.map_or(Ok(DEFAULT_NEAR_RSSI), str::parse);
+        match (uplink::parse_mac(mac), near_rssi) {
+            (Some(beacon), Ok(near_rssi)) => {
+                info!("Presence beacon {}, near from {} dBm", mac, near_rssi);
+                Some(Presence::new(beacon, near_rssi))
+            }
+            _ => {
+                warn!("Invalid BEACON_MAC or BEACON_NEAR_RSSI, presence detection disabled");
+                None
+            }
+        }
+    });
+    link::set_floor(WEAK_LINK_RSSI);
+    apply_settings(&mut alerts, &mut annunciator, &watchdog);
+
+    // Arrival of the frames that raised an alarm this iteration, timed up to
+    // the output refresh
+    let mut actuating: Vec<i64> = Vec::new();
+    // Frame whose dispatch ended this iteration, traced through the outputs
+    #[cfg(feature = "tracing")]
+    let mut traced = None;
 
     // Main loop
     loop {
-        if DATA_READY.load(Ordering::SeqCst) {
-            let topic_id = RECEIVED_TOPIC.load(Ordering::SeqCst);
-            let measurement = RECEIVED_MEASUREMENT.load(Ordering::SeqCst);
-
-            info!("Processing - Topic ID: {} | Measurement: {}", topic_id, measurement);
-
-            match topic_id {
-                TOPIC_ID_KETTLE_THERMO => {
-                    if measurement > 50 {
-                        sound_buzzer(&mut buzzer);
-                        flash_led(&mut thermo_1_led);
-                    } else {
-                        thermo_1_led.set_low().ok();
-                        buzzer.set_low().ok();
-                    }
+        // Everything queued since the last iteration, in order, but no more
+        // than the queues hold so a flood cannot starve the rest of the loop;
+        // critical frames first and between the others
+        espnow::poll();
+        let frames = std::iter::from_fn(|| espnow::take_critical().or_else(espnow::take));
+        let mut context = dispatch::Context {
+            alerts: &mut alerts,
+            annunciator: &mut annunciator,
+            uplinks: &mut uplinks,
+            pipelines: &mut pipelines,
+            dispatcher: &mut dispatcher,
+            watchdog: &watchdog,
+            datalog: datalog.as_mut(),
+            history: history.as_mut(),
+            commissioning: jobs.commissioning.as_mut(),
+            bench: jobs.bench.as_mut(),
+            auto_sleep: auto_sleep.as_mut(),
+            actuating: &mut actuating,
+            #[cfg(feature = "tracing")]
+            traced: &mut traced,
+        };
+        for frame in frames.take(espnow::QUEUE_LENGTH + espnow::CRITICAL_QUEUE_LENGTH) {
+            // Self-test frames are consumed here and never reach the alarm logic
+            if jobs.self_test.as_mut().is_some_and(|t| t.receive(&frame)) {
+                continue;
+            }
+            context.handle(frame);
+        }
+        ack::flush();
+
+        #[cfg(feature = "microphone")]
+        if let Some(microphone) = microphone.as_ref() {
+            watchdog.enter(Stage::Uplinks);
+            listen(microphone, &mut alerts, &mut uplinks);
+        }
+
+        snooze.poll();
+        if let Some(schedule) = schedule.as_mut() {
+            schedule.poll();
+        }
+        profiles::poll();
+        forecast::poll();
+        if profiles::take_changed() {
+            if let (Some(auto_sleep), Some(idle)) = (auto_sleep.as_mut(), sleep_idle) {
+                auto_sleep.set_idle_after(if profiles::away() {
+                    idle * AWAY_SLEEP_FACTOR
+                } else {
+                    idle
+                });
+            }
+            for &(topic_id, _) in TOPIC_LEDS {
+                if !profiles::alarms_enabled(topic_id) {
+                    alerts.clear(topic_id, &mut uplinks);
                 }
-                TOPIC_ID_SINK_THERMO => {
-                    if measurement < 32 {
-                        sound_buzzer(&mut buzzer);
-                        flash_led(&mut thermo_2_led);
-                    } else {
-                        thermo_2_led.set_low().ok();
-                        buzzer.set_low().ok();
+            }
+        }
+        watchdog.enter(Stage::Uplinks);
+        alerts.poll(&mut uplinks);
+        mirror.poll(&alerts);
+        watchdog.enter(Stage::Outputs);
+        if settings::take_changed() {
+            apply_settings(&mut alerts, &mut annunciator, &watchdog);
+            move_leds(&mut annunciator, &reserved_pins);
+            if let Some(siren) = siren.as_mut() {
+                siren.set_full_after(settings::get(Setting::SirenFullAfter));
+            }
+        }
+        if let Some(auto_sleep) = auto_sleep.as_mut() {
+            if alerts.is_active() {
+                auto_sleep.activity();
+            }
+            match auto_sleep.poll() {
+                SleepState::Sleep => {
+                    annunciator.set_sleep_warning(false);
+                    annunciator.play_cue(Cue::Sleep);
+                    if let Some(log) = datalog.as_mut() {
+                        log.flush().ok();
                     }
+                    power::go_to_sleep();
                 }
-                _ => {
-                    thermo_1_led.set_low().ok();
-                    thermo_2_led.set_low().ok();
-                    buzzer.set_low().ok();
+                state => annunciator.set_sleep_warning(state == SleepState::Warning),
+            }
+        }
+        let near = presence.as_mut().is_some_and(Presence::poll);
+        let quiet = quiet_hours::poll();
+        annunciator.set_quiet(near || quiet == Some(QuietMode::Reduced));
+        let snoozed = snooze.active();
+        annunciator.set_silent(snoozed || quiet == Some(QuietMode::LedOnly));
+        annunciator.set_pin(jobs.pairing.as_ref().map(Pairing::pin));
+        readiness::set_fault(Fault::Buzzer, annunciator.buzzer_tripped());
+        annunciator.set_readiness(readiness::poll());
+        annunciator.set_radio_down(radio_retry.is_some());
+        if WEAK_LINK_LED {
+            annunciator.set_weak_links(link::weak_topics());
+        }
+        for sample in freshness::poll(radio_retry.is_some()) {
+            uplinks.publish(Event::Offline {
+                sample,
+                offline: true,
+            });
+        }
+        let (offline, chirp) = freshness::offline();
+        annunciator.set_offline(offline, chirp);
+        annunciator.set_guide(jobs.commissioning.as_ref().map(Commissioning::guide));
+        #[cfg(feature = "tracing")]
+        let actuate = traced.take().map(trace::Frame::start);
+        annunciator.poll(&alerts);
+        if let Some(siren) = siren.as_mut() {
+            let muted =
+                mute_switch.as_ref().is_some_and(Switch::is_closed) || quiet.is_some() || snoozed;
+            siren.poll(&alerts, muted);
+        }
+        let actuated_us = unsafe { esp_timer_get_time() };
+        for received_us in actuating.drain(..) {
+            let latency = actuated_us.saturating_sub(received_us).max(0) as u64;
+            readiness::actuated(Duration::from_micros(latency));
+        }
+        #[cfg(feature = "tracing")]
+        if let Some(frame) = actuate {
+            frame.end(trace::Stage::Actuate);
+        }
+
+        // The operator's buttons and commands, see src/control.rs
+        let mut control = control::Context {
+            alerts: &mut alerts,
+            annunciator: &mut annunciator,
+            uplinks: &mut uplinks,
+            snooze: &mut snooze,
+            jobs: &mut jobs,
+            datalog: datalog.as_mut(),
+            auto_sleep: auto_sleep.as_mut(),
+            pipelines: &pipelines,
+            siren: siren.as_ref(),
+            reserved_pins: &reserved_pins,
+            page_topic: TOPIC_ID_PAGE,
+            scan_dwell: CHANNEL_SCAN_DWELL,
+            storage_ok,
+            wifi_up: wifi.is_up().unwrap_or(false),
+            joining: pending_wifi.is_some(),
+            migrating: migration.is_some(),
+            mounting: pending_storage.is_some(),
+            radio_retry: radio_retry.map(|t| RADIO_RETRY.saturating_sub(clock::since(t))),
+        };
+        for gesture in panel.poll() {
+            control.gesture(gesture);
+        }
+        while let Some(command) = console.as_ref().and_then(Console::try_recv) {
+            control.run::<ActiveBoard>(command);
+        }
+        if support::poll() {
+            support::send(inspect::report(&control).rows());
+        }
+        // Commands from paired senders over ESP-NOW, see src/commands.rs
+        commands::dispatch(|command| control.remote(command));
+        config::poll(alerts.is_active());
+        metrics::poll();
+        log_governor::poll();
+        thresholds::poll();
+        jobs.poll(&mut annunciator);
+
+        watchdog.enter(Stage::Uplinks);
+        if pending_wifi.is_some() && wifi.is_up().unwrap_or(false) {
+            if let Some(stage) = pending_wifi.take() {
+                stage.finish(Ok::<_, EspError>(()));
+            }
+            sntp::start(SNTP_SERVER);
+            _http_server = start_http(storage_ok);
+            uplinks = connect_uplinks(storage_ok);
+        }
+        if jobs.scan.as_mut().and_then(Scan::poll).is_some() {
+            jobs.scan = None;
+        }
+        if migration.as_mut().is_some_and(Migration::poll) {
+            migration = None;
+            if join_ap(&mut wifi) {
+                sntp::start(SNTP_SERVER);
+                _http_server = start_http(storage_ok);
+                uplinks = connect_uplinks(storage_ok);
+            }
+        }
+        // Local-only while the AP is not joined, whatever the reason
+        degraded::set(Mode::LocalOnly, !wifi.is_up().unwrap_or(false));
+        let status_interval = settings::get(if profiles::away() {
+            Setting::AwayStatusInterval
+        } else {
+            Setting::StatusInterval
+        });
+        if !uplinks.is_empty() && last_status.map_or(true, |t| clock::since(t) >= status_interval) {
+            uplinks.publish(Event::Status(inspect::status()));
+            last_status = Some(clock::now());
+        }
+        uplinks.poll();
+        watchdog.enter(Stage::Radio);
+        if radio_retry.is_some_and(|t| clock::since(t) >= RADIO_RETRY) {
+            match start_espnow() {
+                Ok(()) => {
+                    radio_retry = None;
+                    radio_back();
                 }
+                Err(e) => {
+                    warn!("ESP-NOW still down: {}", e);
+                    radio_retry = Some(clock::now());
+                }
+            }
+        }
+        espnow_tx::poll();
+        // Console changes and their reverts alike
+        if long_range::take_changed() {
+            match long_range::apply() {
+                Ok(()) => info!("{}", long_range::summary()),
+                Err(e) => warn!("Failed to set the PHY mode: {}", e),
             }
+        }
+        if channel::take_changed() {
+            let on_ap =
+                wifi.is_up().unwrap_or(false) || pending_wifi.is_some() || migration.is_some();
+            match channel::stored() {
+                Some(channel) if !on_ap => match channel::set(channel) {
+                    Ok(()) => info!("ESP-NOW on channel {}", channel),
+                    Err(e) => warn!("Failed to set channel {}: {}", channel, e),
+                },
+                Some(channel) => info!("Channel {} stored, applies off the AP", channel),
+                None => info!("Stored channel forgotten, default from the next boot"),
+            }
+        }
 
-            DATA_READY.store(false, Ordering::SeqCst);
+        watchdog.enter(Stage::Storage);
+        if sounds::take_changed() {
+            annunciator.set_sounds(Sounds::load(storage::BASE_PATH));
+        }
+        let mounted = pending_storage
+            .as_ref()
+            .and_then(|(_, mounted)| mounted.try_recv().ok());
+        if let Some(result) = mounted {
+            // Mounted late, take it into use like at boot
+            let (stage, _) = pending_storage.take().unwrap();
+            storage_ok = stage.finish(result);
+            if storage_ok {
+                let (log, buckets) = open_logs();
+                datalog = Some(log);
+                history = Some(buckets);
+                annunciator.set_sounds(Sounds::load(storage::BASE_PATH));
+                uplinks.set_outbox(Outbox::open(storage::BASE_PATH));
+            }
+        }
+        if let Some(log) = datalog.as_mut() {
+            if let Err(e) = log.poll() {
+                warn!("Data log flush failed: {}", e);
+            }
+        }
+        if let Some(history) = history.as_mut() {
+            if let Err(e) = history.poll() {
+                warn!("History flush failed: {}", e);
+            }
+        }
+
+        // The loop got unstuck, reset whatever it was stuck on
+        let stalled = watchdog.feed();
+        if stalled.is_some() {
+            readiness::stalled();
+        }
+        match stalled {
+            Some(Stage::Storage) => {
+                warn!("Reopening storage, unflushed samples are lost");
+                datalog = storage_ok.then(|| DataLog::new(storage::BASE_PATH));
+                history = storage_ok.then(|| History::new(storage::BASE_PATH));
+            }
+            Some(Stage::Uplinks) => {
+                warn!("Reconnecting uplinks");
+                uplinks = connect_uplinks(storage_ok);
+            }
+            Some(Stage::Outputs) => {
+                warn!("Resetting the alarm outputs");
+                annunciator.reset();
+                if let Some(Err(e)) = display_bus.as_mut().map(I2cBus::recover) {
+                    warn!("Display bus not recovered: {}", e);
+                }
+            }
+            Some(Stage::Radio) => {
+                warn!("Restarting ESP-NOW");
+                espnow::deinit();
+                match start_espnow() {
+                    Ok(()) => {
+                        radio_retry = None;
+                        radio_back();
+                    }
+                    Err(e) => {
+                        warn!("ESP-NOW down, retrying: {}", e);
+                        radio_retry = Some(clock::now());
+                        degraded::set(Mode::RadioDown, true);
+                    }
+                }
+            }
+            None => {}
         }
 
-        FreeRtos::delay_ms(10);
+        inputs::wait(LOOP_TICK);
     }
 }
@@ -0,0 +1,234 @@
+//! Long-term counters kept across resets
+//!
+//! The receive counters and the like start from zero at every boot, which
+//! says little about a hub that lost power a few times. These few totals are
+//! kept in NVS instead: frames received, alarms raised, reboots and watchdog
+//! resets. A reboot is any boot but a wake-up from deep sleep; a watchdog
+//! reset is one by the hardware watchdogs, told by the reset reason, or by
+//! the main loop watchdog, counted just before it restarts.
+//!
+//! Counting every frame into flash would wear it out, so the totals are
+//! written at most every [`SAVE_INTERVAL`], at boot, and before a reboot on
+//! command or deep sleep. A power cut loses what was counted since the last
+//! write, frames mostly.
+//!
+//! `GET /metrics` serves the totals along with the alarm readiness figures
+//! of this boot, see the readiness module.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+use log::{info, warn};
+
+use crate::clock;
+#[cfg(target_os = "espidf")]
+use crate::espnow;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::{
+    esp_reset_reason, esp_reset_reason_t_ESP_RST_DEEPSLEEP, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT, EspError,
+};
+#[cfg(target_os = "espidf")]
+use crate::readiness;
+
+/// Shortest time between two writes of changed totals
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const NAMESPACE: &str = "metrics";
+const FRAMES: &str = "frames";
+const ALARMS: &str = "alarms";
+const REBOOTS: &str = "reboots";
+const WATCHDOG_RESETS: &str = "wdt_resets";
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    nvs: None,
+    frames_before: 0,
+    totals: Totals::ZERO,
+    saved: Totals::ZERO,
+    saved_at: None,
+});
+
+/// The totals, since the hub was first set up or its NVS erased
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    pub frames: u32,
+    pub alarms: u32,
+    pub reboots: u32,
+    pub watchdog_resets: u32,
+}
+
+impl Totals {
+    const ZERO: Totals = Totals {
+        frames: 0,
+        alarms: 0,
+        reboots: 0,
+        watchdog_resets: 0,
+    };
+}
+
+struct Metrics {
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// Frames received before this boot
+    frames_before: u32,
+    /// Frames as of the last refresh
+    totals: Totals,
+    /// As last written
+    saved: Totals,
+    saved_at: Option<Instant>,
+}
+
+impl Metrics {
+    fn refresh(&mut self) -> Totals {
+        self.totals.frames = self.frames_before.saturating_add(received());
+        self.totals
+    }
+
+    fn save(&mut self) {
+        let totals = self.refresh();
+        let Some(nvs) = self.nvs.as_mut() else {
+            return;
+        };
+        let saved = self.saved;
+        let result = [
+            (FRAMES, totals.frames, saved.frames),
+            (ALARMS, totals.alarms, saved.alarms),
+            (REBOOTS, totals.reboots, saved.reboots),
+            (
+                WATCHDOG_RESETS,
+                totals.watchdog_resets,
+                saved.watchdog_resets,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, total, saved)| total != saved)
+        .try_for_each(|(key, total, _)| nvs.set_u32(key, total));
+        match result {
+            Ok(()) => self.saved = totals,
+            Err(e) => warn!("Metrics not stored: {}", e),
+        }
+        self.saved_at = Some(clock::now());
+    }
+}
+
+/// Load the totals from NVS and count this boot, stored right away
+///
+/// Without a partition the totals only count from this boot.
+pub fn init(partition: Option<EspDefaultNvsPartition>) -> Result<(), EspError> {
+    let mut metrics = METRICS.lock().unwrap();
+    if let Some(partition) = partition {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        metrics.frames_before = nvs.get_u32(FRAMES)?.unwrap_or(0);
+        metrics.totals = Totals {
+            frames: metrics.frames_before,
+            alarms: nvs.get_u32(ALARMS)?.unwrap_or(0),
+            reboots: nvs.get_u32(REBOOTS)?.unwrap_or(0),
+            watchdog_resets: nvs.get_u32(WATCHDOG_RESETS)?.unwrap_or(0),
+        };
+        metrics.saved = metrics.totals;
+        metrics.nvs = Some(nvs);
+    }
+    let reason = unsafe { esp_reset_reason() };
+    if reason != esp_reset_reason_t_ESP_RST_DEEPSLEEP {
+        metrics.totals.reboots = metrics.totals.reboots.saturating_add(1);
+    }
+    let watchdogs = [
+        esp_reset_reason_t_ESP_RST_INT_WDT,
+        esp_reset_reason_t_ESP_RST_TASK_WDT,
+        esp_reset_reason_t_ESP_RST_WDT,
+    ];
+    if watchdogs.contains(&reason) {
+        metrics.totals.watchdog_resets = metrics.totals.watchdog_resets.saturating_add(1);
+    }
+    metrics.save();
+    info!("Metrics: {}", summary_of(&metrics.totals));
+    Ok(())
+}
+
+/// Frames received during this boot
+#[cfg(target_os = "espidf")]
+fn received() -> u32 {
+    espnow::counters().received
+}
+
+/// Frames received during this boot, none on the host
+#[cfg(not(target_os = "espidf"))]
+fn received() -> u32 {
+    0
+}
+
+/// Count a newly raised alarm
+pub fn alarm_raised() {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.totals.alarms = metrics.totals.alarms.saturating_add(1);
+}
+
+/// Count a restart by the main loop watchdog, stored before it happens
+///
+/// Skipped if the stuck loop holds the totals, the restart matters more.
+pub fn watchdog_reset() {
+    let Ok(mut metrics) = METRICS.try_lock() else {
+        return;
+    };
+    metrics.totals.watchdog_resets = metrics.totals.watchdog_resets.saturating_add(1);
+    metrics.save();
+}
+
+/// Store the totals if they changed and the last write is long enough ago,
+/// in the main loop
+pub fn poll() {
+    let mut metrics = METRICS.lock().unwrap();
+    let due = metrics
+        .saved_at
+        .map_or(true, |t| clock::since(t) >= SAVE_INTERVAL);
+    if due && metrics.refresh() != metrics.saved {
+        metrics.save();
+    }
+}
+
+/// Store the totals now, e.g. before deep sleep
+pub fn save() {
+    METRICS.lock().unwrap().save();
+}
+
+/// The totals as of now
+pub fn totals() -> Totals {
+    METRICS.lock().unwrap().refresh()
+}
+
+/// `{"frames":..,"alarms":..,"reboots":..,"watchdog_resets":..}`, for the
+/// status event
+pub fn json() -> String {
+    let totals = totals();
+    format!(
+        r#"{{"frames":{},"alarms":{},"reboots":{},"watchdog_resets":{}}}"#,
+        totals.frames, totals.alarms, totals.reboots, totals.watchdog_resets
+    )
+}
+
+/// Serve `{"totals":{..},"sla":{..}}` on `GET /metrics`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/metrics", Method::Get, |req| {
+        let metrics = format!(r#"{{"totals":{},"sla":{}}}"#, json(), readiness::sla_json());
+        req.into_ok_response()?.write_all(metrics.as_bytes())
+    })?;
+    info!("Metrics served on /metrics");
+    Ok(())
+}
+
+/// The totals in one line, for inspect
+pub fn summary() -> String {
+    summary_of(&totals())
+}
+
+fn summary_of(totals: &Totals) -> String {
+    format!(
+        "{} frames, {} alarms, {} reboots, {} watchdog resets",
+        totals.frames, totals.alarms, totals.reboots, totals.watchdog_resets
+    )
+}
@@ -0,0 +1,278 @@
+//! Alarm tones heard by a microphone at the hub
+//!
+//! With the `microphone` feature an I2S MEMS microphone (e.g. an INMP441,
+//! L/R pin to GND) lets the hub pick up a kettle whistle or a smoke alarm on
+//! its own, next to the remote sound sensor. Every 20 ms block of samples is
+//! checked for the share of its energy inside each watched [`Band`], summed
+//! from one Goertzel filter per 50 Hz bin. A band is sounding once it
+//! dominated 0.8 s of the last 2 s, which also catches the pulsed pattern of
+//! smoke alarms, and quiet again after 5 s without.
+//!
+//! Capture and analysis run in their own threads pinned to the second core,
+//! away from the WiFi and ESP-NOW tasks on the first, so audio never adds to
+//! the receive latency. Blocks pass between them through a bounded queue;
+//! when the analysis falls behind, blocks are dropped rather than queued up.
+
+use std::f32::consts::PI;
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::cpu::Core;
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2s::config::{DataBitWidth, StdConfig};
+use esp_idf_svc::hal::i2s::{I2sDriver, I2sRx, I2S0};
+use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+use esp_idf_svc::sys::{EspError, ESP_ERR_TIMEOUT};
+use log::{info, warn};
+
+use crate::alerts::Priority;
+use crate::clock;
+
+const SAMPLE_RATE: u32 = 16_000;
+// 20 ms, so the bins are 50 Hz apart
+const BLOCK: usize = 320;
+const BIN_HZ: u32 = SAMPLE_RATE / BLOCK as u32;
+// Blocks looked back on, at most 128
+const WINDOW: u32 = 100;
+const SOUNDING_BLOCKS: u32 = 40;
+const QUIET_AFTER: Duration = Duration::from_secs(5);
+// Share of a block's energy a band needs for the block to count
+const DOMINANCE: f32 = 0.5;
+// Blocks quieter than this RMS, of full scale, are background noise
+const MIN_RMS: f32 = 0.002;
+// Stereo frames of 32-bit slots, the microphone is on the left
+const FRAME_BYTES: usize = 8;
+// Blocks waiting for the analysis, 160 ms of audio
+const QUEUE_BLOCKS: usize = 8;
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const STACK_SIZE: usize = 4096;
+const PRIORITY: u8 = 5;
+
+/// A frequency range to listen for and the alarm it raises
+pub struct Band {
+    pub name: &'static str,
+    pub from_hz: u32,
+    pub to_hz: u32,
+    pub topic_id: i32,
+    pub priority: Priority,
+}
+
+/// A band starting or stopping to sound
+pub struct Heard {
+    pub band: &'static Band,
+    pub sounding: bool,
+    /// Share of the block energy in the band, in percent
+    pub level: i32,
+}
+
+struct Tracker {
+    band: &'static Band,
+    /// One bit per block, set when the band dominated it; newest lowest
+    history: u128,
+    last_hit: Option<Instant>,
+    sounding: bool,
+}
+
+pub struct Microphone {
+    driver: I2sDriver<'static, I2sRx>,
+    gpios: [i32; 3],
+}
+
+impl Microphone {
+    /// Set up I2S0 as a receiver on `bclk`, `ws` and `data`
+    ///
+    /// # Safety
+    ///
+    /// None of the pins, nor I2S0, may be claimed anywhere else.
+    pub unsafe fn new(bclk: i32, ws: i32, data: i32) -> Result<Self, EspError> {
+        let config = StdConfig::philips(SAMPLE_RATE, DataBitWidth::Bits32);
+        let mut driver = I2sDriver::new_std_rx(
+            I2S0::new(),
+            &config,
+            AnyIOPin::new(bclk),
+            AnyIOPin::new(data),
+            Option::<AnyIOPin>::None,
+            AnyIOPin::new(ws),
+        )?;
+        driver.rx_enable()?;
+        Ok(Self {
+            driver,
+            gpios: [bclk, ws, data],
+        })
+    }
+
+    /// Start listening for `bands` on the second core
+    pub fn listen(self, bands: &'static [Band]) -> io::Result<Listener> {
+        let gpios = self.gpios;
+        let (blocks_tx, blocks) = mpsc::sync_channel(QUEUE_BLOCKS);
+        let (heard_tx, heard) = mpsc::channel();
+        let mut analyser = Analyser::new(bands);
+
+        spawn_pinned(b"mic-capture\0", move || capture(self.driver, blocks_tx))?;
+        spawn_pinned(b"mic-analysis\0", move || {
+            for block in blocks {
+                for h in analyser.analyse(&block) {
+                    if heard_tx.send(h).is_err() {
+                        return;
+                    }
+                }
+            }
+        })?;
+        info!("Microphone listening on core 1");
+
+        Ok(Listener { heard, gpios })
+    }
+}
+
+/// The running capture and analysis, handing over what they heard
+pub struct Listener {
+    heard: Receiver<Heard>,
+    gpios: [i32; 3],
+}
+
+impl Listener {
+    /// The GPIOs the microphone is wired to
+    pub fn gpios(&self) -> [i32; 3] {
+        self.gpios
+    }
+
+    /// Bands that started or stopped sounding since the last call
+    pub fn poll(&self) -> Vec<Heard> {
+        self.heard.try_iter().collect()
+    }
+}
+
+/// Spawn `f` on the second core, leaving the spawn defaults as they were
+fn spawn_pinned(name: &'static [u8], f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    let pinned = ThreadSpawnConfiguration {
+        name: Some(name),
+        stack_size: STACK_SIZE,
+        priority: PRIORITY,
+        pin_to_core: Some(Core::Core1),
+        ..Default::default()
+    };
+    pinned.set().map_err(io::Error::other)?;
+    let spawned = thread::Builder::new().stack_size(STACK_SIZE).spawn(f);
+    ThreadSpawnConfiguration::default()
+        .set()
+        .map_err(io::Error::other)?;
+    spawned.map(|_| ())
+}
+
+/// Read the microphone into blocks, dropping them while the queue is full
+fn capture(mut driver: I2sDriver<'static, I2sRx>, blocks: SyncSender<Vec<f32>>) {
+    let mut raw = [0u8; BLOCK * FRAME_BYTES / 4];
+    let mut block = Vec::with_capacity(BLOCK);
+    let mut dropping = false;
+    let timeout = TickType::new_millis(READ_TIMEOUT.as_millis() as u64).ticks();
+
+    loop {
+        let n = match driver.read(&mut raw, timeout) {
+            Ok(n) => n,
+            Err(e) if e.code() == ESP_ERR_TIMEOUT => continue,
+            Err(e) => {
+                warn!("Microphone read failed: {}", e);
+                thread::sleep(READ_TIMEOUT);
+                continue;
+            }
+        };
+        for frame in raw[..n].chunks_exact(FRAME_BYTES) {
+            let left = i32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+            // 24-bit samples, left-justified in the slot
+            block.push((left >> 8) as f32 / (1 << 23) as f32);
+            if block.len() < BLOCK {
+                continue;
+            }
+            let full = std::mem::replace(&mut block, Vec::with_capacity(BLOCK));
+            match blocks.try_send(full) {
+                Ok(()) => dropping = false,
+                Err(TrySendError::Full(_)) => {
+                    if !dropping {
+                        warn!("Microphone analysis falling behind, dropping audio");
+                    }
+                    dropping = true;
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+    }
+}
+
+/// Per-band detection state, owned by the analysis thread
+struct Analyser {
+    trackers: Vec<Tracker>,
+}
+
+impl Analyser {
+    fn new(bands: &'static [Band]) -> Self {
+        let trackers = bands
+            .iter()
+            .map(|band| Tracker {
+                band,
+                history: 0,
+                last_hit: None,
+                sounding: false,
+            })
+            .collect();
+        Self { trackers }
+    }
+
+    /// Check one block, returns the bands that started or stopped sounding
+    fn analyse(&mut self, block: &[f32]) -> Vec<Heard> {
+        let now = clock::now();
+        let energy: f32 = block.iter().map(|x| x * x).sum();
+        let loud = (energy / BLOCK as f32).sqrt() >= MIN_RMS;
+
+        let mut heard = Vec::new();
+        for tracker in self.trackers.iter_mut() {
+            let band = tracker.band;
+            let share = if loud {
+                let power: f32 = (band.from_hz / BIN_HZ..=band.to_hz / BIN_HZ)
+                    .map(|bin| bin_power(block, bin))
+                    .sum();
+                // Positive and negative frequency halves, against Parseval
+                2.0 * power / (BLOCK as f32 * energy)
+            } else {
+                0.0
+            };
+
+            let hit = share >= DOMINANCE;
+            tracker.history = (tracker.history << 1 | hit as u128) & ((1 << WINDOW) - 1);
+            if hit {
+                tracker.last_hit = Some(now);
+            }
+
+            let sounding = if tracker.sounding {
+                tracker
+                    .last_hit
+                    .is_some_and(|t| now.duration_since(t) < QUIET_AFTER)
+            } else {
+                tracker.history.count_ones() >= SOUNDING_BLOCKS
+            };
+            if sounding != tracker.sounding {
+                tracker.sounding = sounding;
+                heard.push(Heard {
+                    band,
+                    sounding,
+                    level: (share * 100.0) as i32,
+                });
+            }
+        }
+        heard
+    }
+}
+
+/// Power of DFT bin `bin` of `samples`, by Goertzel
+fn bin_power(samples: &[f32], bin: u32) -> f32 {
+    let coeff = 2.0 * (2.0 * PI * bin as f32 / samples.len() as f32).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for &x in samples {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    s1 * s1 + s2 * s2 - coeff * s1 * s2
+}
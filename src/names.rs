@@ -0,0 +1,282 @@
+//! Friendly names for sensors
+//!
+//! A name is assigned to a topic id, or to one sender's frames on a topic
+//! when several senders share it (`<topic_id>@<MAC>`; the sender's own name
+//! wins). Names show up in the alarm messages, as the `name` field of the
+//! uplink JSON and in place of the topic id in MQTT topic paths. Topics
+//! without a name keep their numeric id there, and show with their name in
+//! the topic registry, if they have one, in messages.
+//!
+//! Names are set from the console (`name`, `names`) or over HTTP once the hub
+//! is on WiFi, and persisted in NVS so they survive reflashing the app:
+//!
+//! - `GET /names` lists the assigned names
+//! - `PUT /names/<key>` names a sensor, the body being the name
+//! - `DELETE /names/<key>` removes a name
+
+use core::fmt;
+use std::sync::Mutex;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+use log::{info, warn};
+
+#[cfg(target_os = "espidf")]
+use crate::http::{authorize, read_body, refuse, respond};
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+use crate::topics;
+use crate::uplink::parse_mac;
+
+const NAMESPACE: &str = "names";
+const TABLE: &str = "table";
+const MAX_NAMES: usize = 32;
+const MAX_NAME: usize = 24;
+// Longest table line is a MAC key and a full-length name
+const MAX_TABLE: usize = MAX_NAMES * (11 + 1 + 17 + 1 + MAX_NAME + 1);
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    names: Vec::new(),
+    senders: Vec::new(),
+    nvs: None,
+});
+
+/// What a name is assigned to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub topic_id: i32,
+    /// `None` names the topic whoever sends it
+    pub mac: Option<[u8; 6]>,
+}
+
+impl Key {
+    /// Parse `<topic_id>` or `<topic_id>@<MAC>`
+    pub fn parse(s: &str) -> Result<Self, &'static str> {
+        let (topic_id, mac) = match s.split_once('@') {
+            Some((topic_id, mac)) => (topic_id, Some(parse_mac(mac).ok_or("invalid MAC")?)),
+            None => (s, None),
+        };
+        let topic_id = topic_id.parse().map_err(|_| "invalid topic id")?;
+        Ok(Self { topic_id, mac })
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.topic_id)?;
+        if let Some(mac) = self.mac {
+            write!(
+                f,
+                "@{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+                mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Registry {
+    names: Vec<(Key, String)>,
+    /// Last sender seen on each topic, to resolve per-sender names
+    senders: Vec<(i32, [u8; 6])>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Registry {
+    fn name(&self, topic_id: i32) -> Option<&str> {
+        let sender = self
+            .senders
+            .iter()
+            .find(|(id, _)| *id == topic_id)
+            .map(|(_, mac)| *mac);
+        let find = |mac| {
+            self.names
+                .iter()
+                .find(|(key, _)| *key == Key { topic_id, mac })
+                .map(|(_, name)| name.as_str())
+        };
+        sender
+            .and_then(|mac| find(Some(mac)))
+            .or_else(|| find(None))
+    }
+
+    fn table(&self) -> String {
+        let mut table = String::new();
+        for (key, name) in &self.names {
+            table.push_str(&format!("{} {}\n", key, name));
+        }
+        table
+    }
+
+    fn save(&mut self) -> Result<(), &'static str> {
+        let table = self.table();
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable")?;
+        nvs.set_blob(TABLE, table.as_bytes()).map_err(|e| {
+            warn!("Failed to save names: {}", e);
+            "write failed"
+        })
+    }
+}
+
+/// Open the names namespace in NVS and load the stored names
+pub fn load(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_TABLE];
+    let table = nvs.get_blob(TABLE, &mut buf)?.unwrap_or_default();
+
+    let mut names = Vec::new();
+    for line in String::from_utf8_lossy(table).lines() {
+        match parse_line(line) {
+            Ok(entry) => names.push(entry),
+            Err(e) => warn!("Stored name {:?} dropped: {}", line, e),
+        }
+    }
+    info!("Names: {} assigned", names.len());
+
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.names = names;
+    registry.nvs = Some(nvs);
+    Ok(())
+}
+
+/// Note that `mac` sent the latest frame on `topic_id`
+pub fn seen(topic_id: i32, mac: [u8; 6]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    match registry.senders.iter_mut().find(|(id, _)| *id == topic_id) {
+        Some(sender) => sender.1 = mac,
+        None => registry.senders.push((topic_id, mac)),
+    }
+}
+
+/// The name of `topic_id`, taking its latest sender into account
+pub fn name(topic_id: i32) -> Option<String> {
+    REGISTRY.lock().unwrap().name(topic_id).map(str::to_string)
+}
+
+/// How to call `topic_id` in messages: its name, its registered topic name,
+/// or the id if it has neither
+pub fn label(topic_id: i32) -> Label {
+    match name(topic_id).or_else(|| topics::name(topic_id)) {
+        Some(name) => Label::Name(name),
+        None => Label::Id(topic_id),
+    }
+}
+
+pub enum Label {
+    Name(String),
+    Id(i32),
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Label::Name(name) => f.write_str(name),
+            Label::Id(topic_id) => write!(f, "{}", topic_id),
+        }
+    }
+}
+
+/// Assign `name` to `key`, or remove its name with `None`, and persist it
+pub fn set(key: Key, name: Option<&str>) -> Result<(), &'static str> {
+    if let Some(name) = name {
+        check_name(name)?;
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    let current = registry.names.iter().position(|(k, _)| *k == key);
+    match (current, name) {
+        (Some(i), Some(name)) => registry.names[i].1 = name.to_string(),
+        (None, Some(_)) if registry.names.len() >= MAX_NAMES => {
+            return Err("too many names, at most 32")
+        }
+        (None, Some(name)) => registry.names.push((key, name.to_string())),
+        (Some(i), None) => {
+            registry.names.remove(i);
+        }
+        (None, None) => return Err("no such name"),
+    }
+    registry.save()
+}
+
+/// Every assigned name as `<key> <name>` lines
+pub fn list() -> String {
+    REGISTRY.lock().unwrap().table()
+}
+
+/// Parse a table in the [`list`] format
+pub fn parse_table(table: &str) -> Result<Vec<(Key, String)>, &'static str> {
+    let names = table
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_line)
+        .collect::<Result<Vec<_>, _>>()?;
+    if names.len() > MAX_NAMES {
+        return Err("too many names, at most 32");
+    }
+    Ok(names)
+}
+
+/// Replace every name with those in `table`, in the [`list`] format
+pub fn replace(table: &str) -> Result<(), &'static str> {
+    let names = parse_table(table)?;
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.names = names;
+    registry.save()
+}
+
+/// Serve the naming endpoints on `server`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/names", Method::Get, |req| {
+        req.into_ok_response()?.write_all(list().as_bytes())
+    })?;
+
+    server.fn_handler("/names/*", Method::Put, |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = match key(req.uri()) {
+            Ok(key) => {
+                read_body(&mut req, MAX_NAME + 2)?.and_then(|body| set(key, Some(body.trim())))
+            }
+            Err(e) => Err(e),
+        };
+        respond(req, result)
+    })?;
+
+    server.fn_handler("/names/*", Method::Delete, |req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = key(req.uri()).and_then(|key| set(key, None));
+        respond(req, result)
+    })?;
+
+    info!("Sensor names served on /names");
+    Ok(())
+}
+
+/// The key at the end of `uri`
+#[cfg(target_os = "espidf")]
+fn key(uri: &str) -> Result<Key, &'static str> {
+    let path = uri.split('?').next().unwrap_or_default();
+    Key::parse(path.strip_prefix("/names/").unwrap_or_default())
+}
+
+/// Names end up in MQTT topics and JSON, so they stay plain
+fn check_name(name: &str) -> Result<(), &'static str> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(valid) {
+        return Err("names are 1 to 24 of A-Z, a-z, 0-9, _ and -");
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Result<(Key, String), &'static str> {
+    let (key, name) = line.split_once(' ').ok_or("expected <key> <name>")?;
+    check_name(name)?;
+    Ok((Key::parse(key)?, name.to_string()))
+}
@@ -0,0 +1,90 @@
+//! On-time limit for powered outputs
+//!
+//! Buzzers and relay coils are not rated for being driven indefinitely. A
+//! [`GuardedOutput`] forces its pin low once it has been on continuously for
+//! longer than its limit, whatever the alarm logic keeps asking for, and only
+//! re-arms after the logic has released the output at least once.
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::gpio_io::Output;
+use crate::platform::sys::EspError;
+use crate::sounds::BEEP_HZ;
+
+pub struct GuardedOutput {
+    name: &'static str,
+    pin: Box<dyn Output>,
+    max_on: Duration,
+    on_since: Option<Instant>,
+    tripped: bool,
+}
+
+impl GuardedOutput {
+    pub fn new(name: &'static str, mut pin: Box<dyn Output>, max_on: Duration) -> Self {
+        pin.set_low().ok();
+        Self {
+            name,
+            pin,
+            max_on,
+            on_since: None,
+            tripped: false,
+        }
+    }
+
+    pub fn arm(&mut self) -> Result<(), EspError> {
+        self.pin.arm()
+    }
+
+    /// Cut the output after `max_on` from now on
+    pub fn set_max_on(&mut self, max_on: Duration) {
+        self.max_on = max_on;
+    }
+
+    pub fn gpio(&self) -> i32 {
+        self.pin.gpio()
+    }
+
+    /// Whether the output is forced off until the logic releases it
+    pub fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.pin.set_quiet(quiet).ok();
+    }
+
+    /// Switch the output as the logic wants, for one that plays no tones;
+    /// call this on every pass of the loop
+    pub fn set_level(&mut self, on: bool) {
+        self.set_tone(on.then_some(BEEP_HZ));
+    }
+
+    /// Apply the tone the logic wants, `None` for off; call this on every
+    /// pass of the loop
+    pub fn set_tone(&mut self, tone: Option<u32>) {
+        if tone.is_none() {
+            self.on_since = None;
+            self.tripped = false;
+            self.pin.set_low().ok();
+            return;
+        }
+        if self.tripped {
+            return;
+        }
+
+        let since = *self.on_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= self.max_on {
+            warn!(
+                "{} on for over {} s, forcing it off",
+                self.name,
+                self.max_on.as_secs()
+            );
+            self.tripped = true;
+            self.pin.set_low().ok();
+            return;
+        }
+        self.pin.set_tone(tone).ok();
+    }
+}
@@ -0,0 +1,461 @@
+//! Pairing new senders, confirmed with a PIN
+//!
+//! `pair` on the console, or holding the button for 5 s, opens a pairing
+//! window. During it a sender asks to
+//! pair by broadcasting a request, and the hub answers with a challenge and
+//! shows a four-digit PIN, each digit 1 to 5: blinked on every idle alarm
+//! LED (a burst of blinks per digit) and logged on the console. The sender
+//! only becomes a peer once it echoes that PIN back, so a neighbour's device
+//! in range cannot pair itself without someone reading the hub. A sender gets
+//! three tries per window, and the window closes after one successful pairing.
+//!
+//! Frames, each starting with a 3-byte tag:
+//!
+//! - `PRQ` pair request, sender to hub; `PRQW` from a wearable
+//! - `PCH` challenge, hub to sender: enter the PIN shown on the hub
+//! - `PIN` and the four digits, sender to hub
+//! - `PAK` and 1 when paired, 0 for a wrong PIN, hub to sender; with a PMK
+//!   set, 1 is followed by a fresh 16-byte LMK for the sender
+//!
+//! Paired senders and their LMKs are kept in NVS and registered as ESP-NOW
+//! peers at boot, encrypted from then on. The LMK goes over the air once, in
+//! the clear, which is why only a sender that knows the PIN gets one.
+//! Wearables are marked as such, they get the active alarms mirrored.
+//!
+//! A peer marked `legacy` may send the 8 and 9-byte fixed frames of senders
+//! older than the CRC, which are taken from no one else. `legacy <MAC> on`
+//! marks one, pairing it first if need be, for senders too old to pair.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::espnow_tx::{self, Qos};
+use crate::keys::{self, KEY_LEN};
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::{esp_random, EspError};
+use crate::uplink::parse_mac;
+
+const REQUEST: &[u8; 3] = b"PRQ";
+const WEARABLE: u8 = b'W';
+const CHALLENGE: &[u8; 3] = b"PCH";
+const PIN: &[u8; 3] = b"PIN";
+const RESULT: &[u8; 3] = b"PAK";
+
+pub const PIN_DIGITS: usize = 4;
+const MAX_ATTEMPTS: u32 = 3;
+// Senders asking in one window, further requests are ignored
+const MAX_CANDIDATES: usize = 4;
+const MAX_INBOX: usize = 8;
+
+const NAMESPACE: &str = "peers";
+const TABLE: &str = "table";
+const MAX_PEERS: usize = 16;
+const MAX_TABLE: usize = MAX_PEERS * 34;
+
+// Set while a window is open, the receive path drops pairing frames
+// otherwise
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static INBOX: Mutex<VecDeque<([u8; 6], Message)>> = Mutex::new(VecDeque::new());
+static PEERS: Mutex<Peers> = Mutex::new(Peers {
+    peers: Vec::new(),
+    nvs: None,
+});
+
+#[derive(Debug, Clone, Copy)]
+enum Message {
+    Request { wearable: bool },
+    Pin([u8; PIN_DIGITS]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub mac: [u8; 6],
+    pub wearable: bool,
+    /// Frames without a CRC are taken from it
+    pub legacy: bool,
+}
+
+struct Peers {
+    peers: Vec<Peer>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Peers {
+    fn table(&self) -> String {
+        let mut table = String::new();
+        for Peer {
+            mac,
+            wearable,
+            legacy,
+        } in &self.peers
+        {
+            table.push_str(&format!(
+                "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}{}{}\n",
+                mac[0],
+                mac[1],
+                mac[2],
+                mac[3],
+                mac[4],
+                mac[5],
+                if *wearable { " wearable" } else { "" },
+                if *legacy { " legacy" } else { "" }
+            ));
+        }
+        table
+    }
+
+    fn save(&mut self) -> Result<(), &'static str> {
+        let table = self.table();
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable")?;
+        nvs.set_blob(TABLE, table.as_bytes()).map_err(|e| {
+            warn!("Failed to save peers: {}", e);
+            "write failed"
+        })
+    }
+}
+
+/// Open the peers namespace in NVS and load the paired senders
+pub fn load(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_TABLE];
+    let table = nvs.get_blob(TABLE, &mut buf)?.unwrap_or_default();
+
+    let mut paired = Vec::new();
+    for line in String::from_utf8_lossy(table).lines() {
+        match parse_peer(line) {
+            Ok(peer) => paired.push(peer),
+            Err(_) => warn!("Stored peer {:?} dropped", line),
+        }
+    }
+    info!("Peers: {} paired", paired.len());
+
+    let mut peers = PEERS.lock().unwrap();
+    peers.peers = paired;
+    peers.nvs = Some(nvs);
+    Ok(())
+}
+
+/// Register the paired senders with ESP-NOW, call after `esp_now_init`
+pub fn add_peers() {
+    for Peer { mac, .. } in PEERS.lock().unwrap().peers.iter() {
+        if let Err(e) = espnow_tx::add_peer(*mac) {
+            warn!("Peer {:02X?} not registered: {}", mac, e);
+        }
+    }
+}
+
+/// Every paired sender
+pub fn paired() -> Vec<[u8; 6]> {
+    PEERS.lock().unwrap().peers.iter().map(|p| p.mac).collect()
+}
+
+/// Whether `mac` is a paired sender
+pub fn is_paired(mac: &[u8; 6]) -> bool {
+    PEERS.lock().unwrap().peers.iter().any(|p| p.mac == *mac)
+}
+
+/// Every paired wearable
+pub fn wearables() -> Vec<[u8; 6]> {
+    let peers = PEERS.lock().unwrap();
+    peers
+        .peers
+        .iter()
+        .filter(|p| p.wearable)
+        .map(|p| p.mac)
+        .collect()
+}
+
+/// Whether `mac` is a peer allowed frames without a CRC
+pub fn takes_legacy(mac: &[u8; 6]) -> bool {
+    PEERS
+        .lock()
+        .unwrap()
+        .peers
+        .iter()
+        .any(|p| p.mac == *mac && p.legacy)
+}
+
+/// Every paired sender, one MAC per line, `wearable` after it for wearables
+/// and `legacy` for those allowed frames without a CRC
+pub fn list() -> String {
+    PEERS.lock().unwrap().table()
+}
+
+/// Parse a table in the [`list`] format
+pub fn parse_table(table: &str) -> Result<Vec<Peer>, &'static str> {
+    let peers = table
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_peer)
+        .collect::<Result<Vec<_>, _>>()?;
+    if peers.len() > MAX_PEERS {
+        return Err("too many peers, at most 16");
+    }
+    Ok(peers)
+}
+
+fn parse_peer(line: &str) -> Result<Peer, &'static str> {
+    let mut words = line.split_whitespace();
+    let mac = words.next().and_then(parse_mac).ok_or("invalid MAC")?;
+    let mut peer = Peer {
+        mac,
+        wearable: false,
+        legacy: false,
+    };
+    let mut flags = words.peekable();
+    if flags.next_if_eq(&"wearable").is_some() {
+        peer.wearable = true;
+    }
+    if flags.next_if_eq(&"legacy").is_some() {
+        peer.legacy = true;
+    }
+    if flags.next().is_some() {
+        return Err("expected <MAC> [wearable] [legacy]");
+    }
+    Ok(peer)
+}
+
+/// Replace every peer with those in `table`, in the [`list`] format
+///
+/// Takes effect for ESP-NOW at the next boot.
+pub fn replace(table: &str) -> Result<(), &'static str> {
+    let paired = parse_table(table)?;
+    let mut peers = PEERS.lock().unwrap();
+    peers.peers = paired;
+    peers.save()
+}
+
+/// Forget the paired sender `mac`
+pub fn unpair(mac: [u8; 6]) -> Result<(), &'static str> {
+    let mut peers = PEERS.lock().unwrap();
+    let i = peers
+        .peers
+        .iter()
+        .position(|p| p.mac == mac)
+        .ok_or("not paired")?;
+    peers.peers.remove(i);
+    peers.save()
+}
+
+/// Allow or stop frames without a CRC from `mac`, pairing it to allow them
+/// if it is not paired yet
+pub fn set_legacy(mac: [u8; 6], on: bool) -> Result<(), &'static str> {
+    let mut peers = PEERS.lock().unwrap();
+    let full = peers.peers.len() >= MAX_PEERS;
+    match peers.peers.iter_mut().find(|p| p.mac == mac) {
+        Some(peer) => peer.legacy = on,
+        None if !on => return Err("not paired"),
+        None if full => return Err("too many peers, at most 16"),
+        None => peers.peers.push(Peer {
+            mac,
+            wearable: false,
+            legacy: true,
+        }),
+    }
+    peers.save()
+}
+
+/// Take a pairing frame from the receive path, false for other frames
+pub fn receive(src: [u8; 6], frame: &[u8]) -> bool {
+    let message = if frame == REQUEST {
+        Message::Request { wearable: false }
+    } else if frame.strip_prefix(REQUEST) == Some(&[WEARABLE]) {
+        Message::Request { wearable: true }
+    } else if let Some(Ok(digits)) = frame.strip_prefix(PIN).map(<[u8; PIN_DIGITS]>::try_from) {
+        Message::Pin(digits)
+    } else {
+        return false;
+    };
+
+    if ACTIVE.load(Ordering::SeqCst) {
+        let mut inbox = INBOX.lock().unwrap();
+        if inbox.len() < MAX_INBOX {
+            inbox.push_back((src, message));
+        }
+    }
+    true
+}
+
+/// How a pairing window ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Closed {
+    Paired([u8; 6]),
+    Expired,
+}
+
+/// A sender that asked to pair in the current window
+struct Candidate {
+    mac: [u8; 6],
+    wearable: bool,
+    attempts: u32,
+}
+
+/// An open pairing window
+pub struct Pairing {
+    pin: [u8; PIN_DIGITS],
+    until: Instant,
+    candidates: Vec<Candidate>,
+}
+
+impl Pairing {
+    /// Open a window of `length` with a fresh PIN
+    pub fn start(length: Duration) -> Self {
+        let mut pin = [0u8; PIN_DIGITS];
+        for digit in pin.iter_mut() {
+            *digit = (unsafe { esp_random() } % 5 + 1) as u8;
+        }
+        INBOX.lock().unwrap().clear();
+        ACTIVE.store(true, Ordering::SeqCst);
+        info!(
+            "Pairing open for {} s, PIN {}{}{}{}",
+            length.as_secs(),
+            pin[0],
+            pin[1],
+            pin[2],
+            pin[3]
+        );
+
+        Self {
+            pin,
+            until: clock::now() + length,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// The PIN to show
+    pub fn pin(&self) -> [u8; PIN_DIGITS] {
+        self.pin
+    }
+
+    /// Handle the frames received so far, returns how the window ended once
+    /// it is over
+    pub fn poll(&mut self) -> Option<Closed> {
+        loop {
+            let Some((mac, message)) = INBOX.lock().unwrap().pop_front() else {
+                break;
+            };
+            match message {
+                Message::Request { wearable } => self.request(mac, wearable),
+                Message::Pin(digits) => {
+                    if self.check(mac, digits) {
+                        return Some(Closed::Paired(mac));
+                    }
+                }
+            }
+        }
+
+        if clock::now() >= self.until {
+            info!("Pairing window closed");
+            return Some(Closed::Expired);
+        }
+        None
+    }
+
+    fn request(&mut self, mac: [u8; 6], wearable: bool) {
+        if self.candidates.iter().any(|c| c.mac == mac) {
+            reply(mac, CHALLENGE);
+            return;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            warn!("Pairing request from {:02X?} ignored, too many", mac);
+            return;
+        }
+        info!("Pairing request from {:02X?}, waiting for the PIN", mac);
+        self.candidates.push(Candidate {
+            mac,
+            wearable,
+            attempts: 0,
+        });
+        reply(mac, CHALLENGE);
+    }
+
+    /// Check a PIN from `mac`, returns true once it paired
+    fn check(&mut self, mac: [u8; 6], digits: [u8; PIN_DIGITS]) -> bool {
+        let Some(candidate) = self.candidates.iter_mut().find(|c| c.mac == mac) else {
+            warn!("PIN from {:02X?} without a pairing request, ignored", mac);
+            return false;
+        };
+        if candidate.attempts >= MAX_ATTEMPTS {
+            return false;
+        }
+
+        if digits != self.pin {
+            candidate.attempts += 1;
+            warn!(
+                "Wrong PIN from {:02X?}, attempt {} of {}",
+                mac, candidate.attempts, MAX_ATTEMPTS
+            );
+            reply(mac, &[RESULT[0], RESULT[1], RESULT[2], 0]);
+            return false;
+        }
+
+        let peer = Peer {
+            mac,
+            wearable: candidate.wearable,
+            legacy: false,
+        };
+        let mut peers = PEERS.lock().unwrap();
+        if !peers
+            .peers
+            .iter()
+            .any(|p| p.mac == mac && p.wearable == peer.wearable)
+        {
+            let full = peers.peers.len() >= MAX_PEERS;
+            match peers.peers.iter_mut().find(|p| p.mac == mac) {
+                Some(known) => known.wearable = peer.wearable,
+                None if full => {
+                    warn!("Pairing {:02X?} failed: too many peers, at most 16", mac);
+                    return false;
+                }
+                None => peers.peers.push(peer),
+            }
+            if let Err(e) = peers.save() {
+                warn!("Pairing {:02X?} not persisted: {}", mac, e);
+            }
+        }
+        drop(peers);
+        if peer.wearable {
+            info!("Paired wearable {:02X?}", mac);
+        } else {
+            info!("Paired {:02X?}", mac);
+        }
+        let mut result = vec![RESULT[0], RESULT[1], RESULT[2], 1];
+        if keys::pmk_set() {
+            let lmk = fresh_lmk();
+            match keys::set_lmk(mac, Some(lmk)) {
+                Ok(()) => result.extend_from_slice(&lmk),
+                Err(e) => warn!("LMK for {:02X?} not stored, staying plaintext: {}", mac, e),
+            }
+        }
+        reply(mac, &result);
+        true
+    }
+}
+
+impl Drop for Pairing {
+    fn drop(&mut self) {
+        ACTIVE.store(false, Ordering::SeqCst);
+        INBOX.lock().unwrap().clear();
+    }
+}
+
+fn fresh_lmk() -> [u8; KEY_LEN] {
+    let mut lmk = [0u8; KEY_LEN];
+    for chunk in lmk.chunks_mut(4) {
+        chunk.copy_from_slice(&unsafe { esp_random() }.to_le_bytes());
+    }
+    lmk
+}
+
+fn reply(mac: [u8; 6], frame: &[u8]) {
+    if let Err(e) =
+        espnow_tx::add_peer(mac).and_then(|_| espnow_tx::send(mac, frame, Qos::Reliable))
+    {
+        warn!("Pairing reply to {:02X?} failed: {}", mac, e);
+    }
+}
@@ -0,0 +1,203 @@
+//! Boot-time validation of the GPIO pin map
+//!
+//! Pins are configured by number, so a typo would otherwise show up as a
+//! silent output, a GPIO driver error or a board that stops booting. The map
+//! is checked before any pin is touched; hard conflicts halt the firmware
+//! with a blink code, strapping pins only get a warning since whether they
+//! are safe depends on the external circuit.
+
+// Output pins go unused in headless builds
+#![cfg_attr(feature = "headless", allow(dead_code))]
+
+use core::fmt;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::delay::FreeRtos;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::gpio::{AnyOutputPin, PinDriver};
+#[cfg(target_os = "espidf")]
+use log::error;
+use log::warn;
+
+use crate::platform::sys::EspError;
+
+// GPIO numbers that exist on the ESP32
+const VALID: &[i32] = &[
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 21, 22, 23, 25, 26, 27,
+    32, 33, 34, 35, 36, 37, 38, 39,
+];
+// Wired to the SPI flash on most modules
+const FLASH: &[i32] = &[6, 7, 8, 9, 10, 11];
+const INPUT_ONLY: &[i32] = &[34, 35, 36, 37, 38, 39];
+// Sampled at reset, an external pull on these can change the boot mode
+const STRAPPING: &[i32] = &[0, 2, 5, 12, 15];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Assignment {
+    pub name: &'static str,
+    pub gpio: i32,
+    pub output: bool,
+}
+
+impl Assignment {
+    pub const fn output(name: &'static str, gpio: i32) -> Self {
+        Self {
+            name,
+            gpio,
+            output: true,
+        }
+    }
+
+    pub const fn input(name: &'static str, gpio: i32) -> Self {
+        Self {
+            name,
+            gpio,
+            output: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PinError {
+    NoSuchGpio(&'static str, i32),
+    FlashPin(&'static str, i32),
+    InputOnly(&'static str, i32),
+    Duplicate(&'static str, &'static str, i32),
+    /// Claimed at runtime while something else holds the pin
+    InUse(&'static str, i32),
+    Driver(EspError),
+}
+
+impl PinError {
+    /// Number of blinks in the error blink code
+    #[cfg(target_os = "espidf")]
+    fn blink_code(&self) -> u32 {
+        match self {
+            PinError::NoSuchGpio(..) | PinError::FlashPin(..) => 2,
+            PinError::InputOnly(..) => 3,
+            PinError::Duplicate(..) | PinError::InUse(..) => 4,
+            PinError::Driver(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinError::NoSuchGpio(name, gpio) => write!(f, "{}: GPIO{} does not exist", name, gpio),
+            PinError::FlashPin(name, gpio) => {
+                write!(f, "{}: GPIO{} is reserved for the SPI flash", name, gpio)
+            }
+            PinError::InputOnly(name, gpio) => {
+                write!(
+                    f,
+                    "{}: GPIO{} is input only, it cannot drive an output",
+                    name, gpio
+                )
+            }
+            PinError::Duplicate(a, b, gpio) => {
+                write!(f, "{} and {} are both assigned to GPIO{}", a, b, gpio)
+            }
+            PinError::InUse(name, gpio) => write!(f, "{}: GPIO{} is already in use", name, gpio),
+            PinError::Driver(e) => write!(f, "GPIO driver error: {}", e),
+        }
+    }
+}
+
+pub fn is_strapping(gpio: i32) -> bool {
+    STRAPPING.contains(&gpio)
+}
+
+/// GPIO number from a build-time environment variable, or `default` if unset
+///
+/// Fails the build if the variable is not a plain decimal number.
+pub const fn gpio_from_env(var: Option<&str>, default: i32) -> i32 {
+    let Some(var) = var else {
+        return default;
+    };
+    let bytes = var.as_bytes();
+    assert!(!bytes.is_empty(), "GPIO number must not be empty");
+
+    let mut gpio = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "GPIO number must be decimal");
+        gpio = gpio * 10 + (bytes[i] - b'0') as i32;
+        i += 1;
+    }
+    gpio
+}
+
+/// Check `map` for hard conflicts, warning about strapping pins on the way
+pub fn validate(map: &[Assignment]) -> Result<(), PinError> {
+    for (i, pin) in map.iter().enumerate() {
+        if pin.output {
+            check_output(pin.name, pin.gpio)?;
+        } else {
+            check_gpio(pin.name, pin.gpio)?;
+        }
+        if let Some(other) = map[..i].iter().find(|p| p.gpio == pin.gpio) {
+            return Err(PinError::Duplicate(other.name, pin.name, pin.gpio));
+        }
+        if STRAPPING.contains(&pin.gpio) {
+            warn!(
+                "{}: GPIO{} is a strapping pin, make sure nothing pulls it at reset",
+                pin.name, pin.gpio
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check that `gpio` exists and is free for general use
+fn check_gpio(name: &'static str, gpio: i32) -> Result<(), PinError> {
+    if !VALID.contains(&gpio) {
+        return Err(PinError::NoSuchGpio(name, gpio));
+    }
+    if FLASH.contains(&gpio) {
+        return Err(PinError::FlashPin(name, gpio));
+    }
+    Ok(())
+}
+
+/// Check that `gpio` can be read as an input
+pub fn check_input(name: &'static str, gpio: i32) -> Result<(), PinError> {
+    check_gpio(name, gpio)
+}
+
+/// Check that `gpio` can drive an output
+pub fn check_output(name: &'static str, gpio: i32) -> Result<(), PinError> {
+    check_gpio(name, gpio)?;
+    if INPUT_ONLY.contains(&gpio) {
+        return Err(PinError::InputOnly(name, gpio));
+    }
+    Ok(())
+}
+
+/// Refuse to start: log `err` and repeat its blink code forever on the
+/// first output in `map` that can still be driven safely
+#[cfg(target_os = "espidf")]
+pub fn halt(map: &[Assignment], err: &PinError) -> ! {
+    let blinker = map.iter().find(|p| {
+        p.output
+            && check_output(p.name, p.gpio).is_ok()
+            && !STRAPPING.contains(&p.gpio)
+            && map.iter().filter(|q| q.gpio == p.gpio).count() == 1
+    });
+    // Safe: nothing else has claimed any pin yet and this one is unique
+    let mut led =
+        blinker.and_then(|p| PinDriver::output(unsafe { AnyOutputPin::new(p.gpio) }).ok());
+
+    loop {
+        error!("Invalid pin map, refusing to start: {}", err);
+        for _ in 0..err.blink_code() {
+            if let Some(led) = led.as_mut() {
+                led.set_high().ok();
+                FreeRtos::delay_ms(200);
+                led.set_low().ok();
+            }
+            FreeRtos::delay_ms(300);
+        }
+        FreeRtos::delay_ms(2000);
+    }
+}
@@ -0,0 +1,415 @@
+//! The ESP-IDF surface the receiver logic builds on
+//!
+//! On the target [`nvs`], [`sys`] and [`delay`] are esp-idf-svc's own. Built
+//! for the host, for the unit tests, they are stand-ins with the same names
+//! and signatures: NVS is kept in memory, the radio never comes up so sends
+//! fail as before `esp_now_init`, there is no mbedTLS so nothing
+//! authenticates, and local time is UTC. Modules that drive pins, buses,
+//! the radio driver or servers are left out of host builds, see lib.rs.
+
+#[cfg(target_os = "espidf")]
+pub use esp_idf_svc::hal::delay;
+#[cfg(target_os = "espidf")]
+pub use esp_idf_svc::{nvs, sys};
+
+#[cfg(not(target_os = "espidf"))]
+pub mod sys {
+    #![allow(non_camel_case_types, non_upper_case_globals)]
+    #![allow(clippy::missing_safety_doc)]
+
+    use core::ffi::{c_char, c_int, c_void};
+    use core::fmt;
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    pub type esp_err_t = i32;
+
+    pub const ESP_OK: esp_err_t = 0;
+    pub const ESP_FAIL: esp_err_t = -1;
+    pub const ESP_ERR_NO_MEM: esp_err_t = 0x101;
+    pub const ESP_ERR_INVALID_ARG: esp_err_t = 0x102;
+    pub const ESP_ERR_INVALID_STATE: esp_err_t = 0x103;
+    pub const ESP_ERR_INVALID_SIZE: esp_err_t = 0x104;
+    pub const ESP_ERR_NOT_FOUND: esp_err_t = 0x105;
+    pub const ESP_ERR_NVS_INVALID_LENGTH: esp_err_t = 0x110c;
+    pub const ESP_ERR_WIFI_NOT_INIT: esp_err_t = 0x3001;
+    pub const ESP_ERR_ESPNOW_NOT_INIT: esp_err_t = 0x3065;
+    pub const ESP_ERR_ESPNOW_NO_MEM: esp_err_t = 0x3067;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EspError(esp_err_t);
+
+    impl EspError {
+        pub fn from(code: esp_err_t) -> Option<Self> {
+            (code != ESP_OK).then_some(Self(code))
+        }
+
+        pub const fn from_infallible<const E: esp_err_t>() -> Self {
+            Self(E)
+        }
+
+        pub fn convert(code: esp_err_t) -> Result<(), Self> {
+            Self::from(code).map_or(Ok(()), Err)
+        }
+
+        pub fn code(&self) -> esp_err_t {
+            self.0
+        }
+    }
+
+    impl fmt::Display for EspError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = match self.0 {
+                ESP_FAIL => "ESP_FAIL",
+                ESP_ERR_NO_MEM => "ESP_ERR_NO_MEM",
+                ESP_ERR_INVALID_ARG => "ESP_ERR_INVALID_ARG",
+                ESP_ERR_INVALID_STATE => "ESP_ERR_INVALID_STATE",
+                ESP_ERR_INVALID_SIZE => "ESP_ERR_INVALID_SIZE",
+                ESP_ERR_NOT_FOUND => "ESP_ERR_NOT_FOUND",
+                ESP_ERR_NVS_INVALID_LENGTH => "ESP_ERR_NVS_INVALID_LENGTH",
+                ESP_ERR_WIFI_NOT_INIT => "ESP_ERR_WIFI_NOT_INIT",
+                ESP_ERR_ESPNOW_NOT_INIT => "ESP_ERR_ESPNOW_NOT_INIT",
+                ESP_ERR_ESPNOW_NO_MEM => "ESP_ERR_ESPNOW_NO_MEM",
+                _ => "ESP error",
+            };
+            write!(f, "{} ({:#x})", name, self.0)
+        }
+    }
+
+    impl std::error::Error for EspError {}
+
+    /// `Ok(())` for `ESP_OK`, the error otherwise
+    macro_rules! esp {
+        ($err:expr) => {{
+            $crate::platform::sys::EspError::convert($err as $crate::platform::sys::esp_err_t)
+        }};
+    }
+    pub(crate) use esp;
+
+    /// Microseconds since the first call, for boot
+    pub unsafe fn esp_timer_get_time() -> i64 {
+        static BOOT: OnceLock<Instant> = OnceLock::new();
+        BOOT.get_or_init(Instant::now).elapsed().as_micros() as i64
+    }
+
+    pub unsafe fn esp_random() -> u32 {
+        RandomState::new().build_hasher().finish() as u32
+    }
+
+    pub unsafe fn esp_fill_random(buf: *mut c_void, len: usize) {
+        let buf = core::slice::from_raw_parts_mut(buf.cast::<u8>(), len);
+        for chunk in buf.chunks_mut(4) {
+            chunk.copy_from_slice(&esp_random().to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    pub unsafe fn esp_restart() -> ! {
+        panic!("esp_restart");
+    }
+
+    pub type esp_reset_reason_t = u32;
+    pub const esp_reset_reason_t_ESP_RST_POWERON: esp_reset_reason_t = 1;
+    pub const esp_reset_reason_t_ESP_RST_INT_WDT: esp_reset_reason_t = 5;
+    pub const esp_reset_reason_t_ESP_RST_TASK_WDT: esp_reset_reason_t = 6;
+    pub const esp_reset_reason_t_ESP_RST_WDT: esp_reset_reason_t = 7;
+    pub const esp_reset_reason_t_ESP_RST_DEEPSLEEP: esp_reset_reason_t = 8;
+
+    pub unsafe fn esp_reset_reason() -> esp_reset_reason_t {
+        esp_reset_reason_t_ESP_RST_POWERON
+    }
+
+    pub type time_t = i64;
+
+    #[repr(C)]
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct tm {
+        pub tm_sec: c_int,
+        pub tm_min: c_int,
+        pub tm_hour: c_int,
+        pub tm_mday: c_int,
+        pub tm_mon: c_int,
+        pub tm_year: c_int,
+        pub tm_wday: c_int,
+        pub tm_yday: c_int,
+        pub tm_isdst: c_int,
+    }
+
+    /// `*t` in UTC, whatever `TZ` says
+    pub unsafe fn localtime_r(t: *const time_t, out: *mut tm) -> *mut tm {
+        let secs = *t;
+        let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400) as c_int);
+        // Civil date from days since 1970-01-01, after Howard Hinnant
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        *out = tm {
+            tm_sec: secs % 60,
+            tm_min: secs / 60 % 60,
+            tm_hour: secs / 3600,
+            tm_mday: day as c_int,
+            tm_mon: month as c_int - 1,
+            tm_year: year as c_int - 1900,
+            tm_wday: (days + 4).rem_euclid(7) as c_int,
+            ..tm::default()
+        };
+        out
+    }
+
+    pub unsafe fn setenv(_name: *const c_char, _value: *const c_char, _overwrite: c_int) -> c_int {
+        0
+    }
+
+    pub unsafe fn tzset() {}
+
+    #[repr(C)]
+    pub struct mbedtls_md_info_t {
+        _private: [u8; 0],
+    }
+    pub type mbedtls_md_type_t = u32;
+    pub const mbedtls_md_type_t_MBEDTLS_MD_SHA256: mbedtls_md_type_t = 9;
+    const MBEDTLS_ERR_MD_BAD_INPUT_DATA: c_int = -0x5100;
+
+    pub unsafe fn mbedtls_md_info_from_type(
+        _md_type: mbedtls_md_type_t,
+    ) -> *const mbedtls_md_info_t {
+        core::ptr::null()
+    }
+
+    pub unsafe fn mbedtls_md_hmac(
+        _md_info: *const mbedtls_md_info_t,
+        _key: *const u8,
+        _keylen: usize,
+        _input: *const u8,
+        _ilen: usize,
+        _output: *mut u8,
+    ) -> c_int {
+        MBEDTLS_ERR_MD_BAD_INPUT_DATA
+    }
+
+    pub type wifi_interface_t = u32;
+    pub const wifi_interface_t_WIFI_IF_STA: wifi_interface_t = 0;
+
+    pub unsafe fn esp_wifi_get_mac(_ifx: wifi_interface_t, _mac: *mut u8) -> esp_err_t {
+        ESP_ERR_WIFI_NOT_INIT
+    }
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    pub struct esp_now_peer_info_t {
+        pub peer_addr: [u8; 6],
+        pub lmk: [u8; 16],
+        pub channel: u8,
+        pub ifidx: wifi_interface_t,
+        pub encrypt: bool,
+        pub priv_: *mut c_void,
+    }
+
+    impl Default for esp_now_peer_info_t {
+        fn default() -> Self {
+            Self {
+                peer_addr: [0; 6],
+                lmk: [0; 16],
+                channel: 0,
+                ifidx: wifi_interface_t_WIFI_IF_STA,
+                encrypt: false,
+                priv_: core::ptr::null_mut(),
+            }
+        }
+    }
+
+    pub unsafe fn esp_now_is_peer_exist(_peer_addr: *const u8) -> bool {
+        false
+    }
+
+    pub unsafe fn esp_now_add_peer(_peer: *const esp_now_peer_info_t) -> esp_err_t {
+        ESP_ERR_ESPNOW_NOT_INIT
+    }
+
+    pub unsafe fn esp_now_send(_peer_addr: *const u8, _data: *const u8, _len: usize) -> esp_err_t {
+        ESP_ERR_ESPNOW_NOT_INIT
+    }
+
+    pub unsafe fn esp_now_set_pmk(_pmk: *const u8) -> esp_err_t {
+        ESP_ERR_ESPNOW_NOT_INIT
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub mod nvs {
+    use std::collections::HashMap;
+    use std::marker::PhantomData;
+    use std::sync::{Arc, Mutex};
+
+    use super::sys::{EspError, ESP_ERR_NVS_INVALID_LENGTH};
+
+    type Entries = Arc<Mutex<HashMap<(String, String), Vec<u8>>>>;
+
+    #[derive(Debug)]
+    pub struct NvsDefault;
+
+    /// A partition held in memory, shared by its clones
+    #[derive(Debug)]
+    pub struct EspNvsPartition<T> {
+        entries: Entries,
+        _partition: PhantomData<T>,
+    }
+
+    impl<T> Clone for EspNvsPartition<T> {
+        fn clone(&self) -> Self {
+            Self {
+                entries: self.entries.clone(),
+                _partition: PhantomData,
+            }
+        }
+    }
+
+    pub type EspDefaultNvsPartition = EspNvsPartition<NvsDefault>;
+
+    impl EspDefaultNvsPartition {
+        /// A new, empty partition
+        pub fn take() -> Result<Self, EspError> {
+            Ok(Self {
+                entries: Entries::default(),
+                _partition: PhantomData,
+            })
+        }
+    }
+
+    pub struct EspNvs<T> {
+        partition: EspNvsPartition<T>,
+        namespace: String,
+    }
+
+    impl<T> EspNvs<T> {
+        pub fn new(
+            partition: EspNvsPartition<T>,
+            namespace: &str,
+            _read_write: bool,
+        ) -> Result<Self, EspError> {
+            Ok(Self {
+                partition,
+                namespace: namespace.to_string(),
+            })
+        }
+
+        pub fn contains(&self, name: &str) -> Result<bool, EspError> {
+            Ok(self.get(name).is_some())
+        }
+
+        pub fn remove(&mut self, name: &str) -> Result<bool, EspError> {
+            let key = (self.namespace.clone(), name.to_string());
+            Ok(self
+                .partition
+                .entries
+                .lock()
+                .unwrap()
+                .remove(&key)
+                .is_some())
+        }
+
+        pub fn get_blob<'a>(
+            &self,
+            name: &str,
+            buf: &'a mut [u8],
+        ) -> Result<Option<&'a [u8]>, EspError> {
+            let Some(value) = self.get(name) else {
+                return Ok(None);
+            };
+            let buf = buf
+                .get_mut(..value.len())
+                .ok_or(EspError::from_infallible::<ESP_ERR_NVS_INVALID_LENGTH>())?;
+            buf.copy_from_slice(&value);
+            Ok(Some(buf))
+        }
+
+        pub fn set_blob(&mut self, name: &str, buf: &[u8]) -> Result<(), EspError> {
+            self.set(name, buf.to_vec());
+            Ok(())
+        }
+
+        /// As on the target, `buf` must also hold the terminating NUL
+        pub fn get_str<'a>(
+            &self,
+            name: &str,
+            buf: &'a mut [u8],
+        ) -> Result<Option<&'a str>, EspError> {
+            let Some(value) = self.get(name) else {
+                return Ok(None);
+            };
+            if value.len() >= buf.len() {
+                return Err(EspError::from_infallible::<ESP_ERR_NVS_INVALID_LENGTH>());
+            }
+            buf[..value.len()].copy_from_slice(&value);
+            Ok(core::str::from_utf8(&buf[..value.len()]).ok())
+        }
+
+        pub fn set_str(&mut self, name: &str, val: &str) -> Result<(), EspError> {
+            self.set(name, val.as_bytes().to_vec());
+            Ok(())
+        }
+
+        pub fn get_u8(&self, name: &str) -> Result<Option<u8>, EspError> {
+            Ok(self.get_int(name).map(|v| v as u8))
+        }
+
+        pub fn set_u8(&self, name: &str, val: u8) -> Result<(), EspError> {
+            self.set(name, u64::from(val).to_le_bytes().to_vec());
+            Ok(())
+        }
+
+        pub fn get_u16(&self, name: &str) -> Result<Option<u16>, EspError> {
+            Ok(self.get_int(name).map(|v| v as u16))
+        }
+
+        pub fn set_u16(&self, name: &str, val: u16) -> Result<(), EspError> {
+            self.set(name, u64::from(val).to_le_bytes().to_vec());
+            Ok(())
+        }
+
+        pub fn get_u32(&self, name: &str) -> Result<Option<u32>, EspError> {
+            Ok(self.get_int(name).map(|v| v as u32))
+        }
+
+        pub fn set_u32(&self, name: &str, val: u32) -> Result<(), EspError> {
+            self.set(name, u64::from(val).to_le_bytes().to_vec());
+            Ok(())
+        }
+
+        fn get(&self, name: &str) -> Option<Vec<u8>> {
+            let key = (self.namespace.clone(), name.to_string());
+            self.partition.entries.lock().unwrap().get(&key).cloned()
+        }
+
+        fn get_int(&self, name: &str) -> Option<u64> {
+            let value = self.get(name)?;
+            Some(u64::from_le_bytes(value.try_into().ok()?))
+        }
+
+        fn set(&self, name: &str, value: Vec<u8>) {
+            let key = (self.namespace.clone(), name.to_string());
+            self.partition.entries.lock().unwrap().insert(key, value);
+        }
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub mod delay {
+    use std::thread;
+    use std::time::Duration;
+
+    pub struct FreeRtos;
+
+    impl FreeRtos {
+        pub fn delay_ms(ms: u32) {
+            thread::sleep(Duration::from_millis(ms.into()));
+        }
+    }
+}
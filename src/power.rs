@@ -0,0 +1,59 @@
+//! Deep sleep and the awake state kept across it
+//!
+//! The hub toggles between awake and asleep on the wake button: a button
+//! wake-up of a sleeping hub wakes it, one of an awake hub sends it back to
+//! sleep. Which of the two applies is kept in RTC slow memory, which
+//! survives deep sleep but not a power cycle, so a normal boot starts asleep.
+//! So is when the hub went to sleep, by the RTC, which keeps running.
+
+use esp_idf_svc::sys::{
+    esp_deep_sleep_start, esp_sleep_get_wakeup_cause,
+    esp_sleep_wakeup_cause_t_ESP_SLEEP_WAKEUP_GPIO,
+};
+use log::info;
+
+use std::time::Duration;
+
+use crate::clock;
+use crate::metrics;
+use crate::strings::{self, Text};
+
+// Note: In esp-idf-svc, we use a static with #[link_section] for RTC memory
+#[link_section = ".rtc.data"]
+static mut IS_AWAKE: bool = false;
+#[link_section = ".rtc.data"]
+static mut SLEPT_AT: Option<u32> = None;
+
+/// Whether this boot is a wake-up by the button, rather than a normal boot
+pub fn woken_by_button() -> bool {
+    unsafe { esp_sleep_get_wakeup_cause() == esp_sleep_wakeup_cause_t_ESP_SLEEP_WAKEUP_GPIO }
+}
+
+/// How long the hub slept before this wake-up by the button
+pub fn slept_for() -> Option<Duration> {
+    if !woken_by_button() {
+        return None;
+    }
+    let slept_at = unsafe { SLEPT_AT }?;
+    let slept = clock::unix_secs().checked_sub(slept_at)?;
+    Some(Duration::from_secs(slept.into()))
+}
+
+/// Whether the hub is awake, receiving and raising alarms
+pub fn is_awake() -> bool {
+    // Only written from the main task
+    unsafe { IS_AWAKE }
+}
+
+pub fn set_awake(awake: bool) {
+    unsafe { IS_AWAKE = awake };
+}
+
+/// Deep sleep until the wake button is pressed
+pub fn go_to_sleep() -> ! {
+    info!("{}", strings::text(Text::GoingToSleep, &[]));
+    set_awake(false);
+    metrics::save();
+    unsafe { SLEPT_AT = Some(clock::unix_secs()) };
+    unsafe { esp_deep_sleep_start() }
+}
@@ -0,0 +1,101 @@
+//! Rough presence detection from a carried beacon
+//!
+//! A beacon is any ESP-NOW sender kept on a key ring or in a pocket. The
+//! signal strength of its frames says roughly how far away it is: above the
+//! near threshold someone is taken to be standing by the hub, and the alarms
+//! can keep it down. RSSI swings by several dB from frame to frame and with
+//! how the beacon is held, so samples are averaged and leaving takes a
+//! margin below the threshold. A beacon that goes silent counts as far.
+
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::clock;
+
+// Leaving needs the average this many dB below the near threshold
+const HYSTERESIS_DB: i32 = 6;
+// Far once no beacon frame arrived for this long
+const BEACON_TIMEOUT: Duration = Duration::from_secs(30);
+// New samples get 1/AVERAGE_WEIGHT of the running average
+const AVERAGE_WEIGHT: i32 = 4;
+
+// Written by the ESP-NOW receive path; 0 means no beacon is set
+static BEACON: AtomicU64 = AtomicU64::new(0);
+static LAST_RSSI: AtomicI32 = AtomicI32::new(0);
+static SAMPLES: AtomicU32 = AtomicU32::new(0);
+
+fn mac_key(mac: &[u8; 6]) -> u64 {
+    let mut key = [0u8; 8];
+    key[..6].copy_from_slice(mac);
+    u64::from_le_bytes(key)
+}
+
+/// Note the RSSI of a frame from `src`, called for every received frame
+pub fn record(src: &[u8; 6], rssi: i32) {
+    let beacon = BEACON.load(Ordering::Relaxed);
+    if beacon != 0 && beacon == mac_key(src) {
+        LAST_RSSI.store(rssi, Ordering::Relaxed);
+        SAMPLES.fetch_add(1, Ordering::Release);
+    }
+}
+
+pub struct Presence {
+    near_rssi: i32,
+    samples: u32,
+    last_seen: Option<Instant>,
+    average: i32,
+    near: bool,
+}
+
+impl Presence {
+    /// Track `beacon`, near when its average RSSI reaches `near_rssi` dBm
+    pub fn new(beacon: [u8; 6], near_rssi: i32) -> Self {
+        BEACON.store(mac_key(&beacon), Ordering::Relaxed);
+        Self {
+            near_rssi,
+            samples: SAMPLES.load(Ordering::Acquire),
+            last_seen: None,
+            average: 0,
+            near: false,
+        }
+    }
+
+    /// Fold in new beacon frames, returns whether someone is near
+    pub fn poll(&mut self) -> bool {
+        let samples = SAMPLES.load(Ordering::Acquire);
+        if samples != self.samples {
+            let rssi = LAST_RSSI.load(Ordering::Relaxed);
+            self.average = match self.last_seen {
+                Some(_) => self.average + (rssi - self.average) / AVERAGE_WEIGHT,
+                None => rssi,
+            };
+            self.samples = samples;
+            self.last_seen = Some(clock::now());
+        } else if self
+            .last_seen
+            .is_some_and(|t| clock::since(t) >= BEACON_TIMEOUT)
+        {
+            self.last_seen = None;
+        }
+
+        let near = match self.last_seen {
+            None => false,
+            Some(_) if self.near => self.average >= self.near_rssi - HYSTERESIS_DB,
+            Some(_) => self.average >= self.near_rssi,
+        };
+        if near != self.near {
+            match self.last_seen {
+                Some(_) => info!(
+                    "Presence: {} ({} dBm)",
+                    if near { "near" } else { "far" },
+                    self.average
+                ),
+                None => info!("Presence: far (beacon silent)"),
+            }
+            self.near = near;
+        }
+        near
+    }
+}
@@ -0,0 +1,290 @@
+//! Seasonal alarm profiles
+//!
+//! A profile names the topics whose alarms are off while it is active, so
+//! the hub can e.g. watch the sink for frost in winter and leave it alone in
+//! summer. The active profile is switched by console command, by holding the
+//! button, by a config import or on a month schedule, persisted in NVS and
+//! reported in the status event.
+//!
+//! Profiles work as an A/B pair: toggling and the schedule flip between the
+//! first two.
+//!
+//! A profile marked `away` is for an empty house: besides its muted topics
+//! the main loop reports more often and stays awake longer before
+//! auto-sleep. `away <days>` switches to it and back to the previous profile
+//! once the days are over; the return is kept in NVS, and the month schedule
+//! moves the return target instead of switching while away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+use crate::timezone;
+
+const NAMESPACE: &str = "profile";
+const ACTIVE: &str = "active";
+const AWAY_UNTIL: &str = "away_until";
+const AWAY_BACK: &str = "away_back";
+
+static STATE: Mutex<State> = Mutex::new(State {
+    profiles: &[],
+    active: 0,
+    away: None,
+    nvs: None,
+});
+// Set on every switch, the main loop settles alarms of newly muted topics
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+pub struct Profile {
+    pub name: &'static str,
+    /// Topics whose alarms are off in this profile
+    pub muted: &'static [i32],
+    /// For an empty house, see the module docs
+    pub away: bool,
+}
+
+/// A timed stay in the away profile
+#[derive(Debug, Clone, Copy)]
+struct Away {
+    until: u32,
+    /// Profile to return to
+    back: usize,
+}
+
+struct State {
+    profiles: &'static [Profile],
+    active: usize,
+    away: Option<Away>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl State {
+    fn select(&mut self, index: usize) -> Result<(), &'static str> {
+        if index == self.active {
+            return Ok(());
+        }
+        self.active = index;
+        CHANGED.store(true, Ordering::SeqCst);
+        info!("Profile: {}", self.profiles[index].name);
+        match self.nvs.as_mut() {
+            Some(nvs) => nvs.set_u8(ACTIVE, index as u8).map_err(|e| {
+                warn!("Failed to save the profile: {}", e);
+                "write failed"
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn save_away(&mut self) -> Result<(), &'static str> {
+        let Some(nvs) = self.nvs.as_mut() else {
+            return Ok(());
+        };
+        let result = match self.away {
+            Some(away) => nvs
+                .set_u32(AWAY_UNTIL, away.until)
+                .and_then(|_| nvs.set_u8(AWAY_BACK, away.back as u8)),
+            None => nvs.remove(AWAY_UNTIL).map(|_| ()),
+        };
+        result.map_err(|e| {
+            warn!("Failed to save the away return: {}", e);
+            "write failed"
+        })
+    }
+}
+
+/// Use `profiles`, restoring the last active one from NVS
+///
+/// `profiles` must not be empty. Without NVS, `partition` being `None` or
+/// the namespace failing to open, the first profile is active and switches
+/// are not persisted.
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    profiles: &'static [Profile],
+) -> Result<(), EspError> {
+    let mut state = STATE.lock().unwrap();
+    state.profiles = profiles;
+
+    let Some(partition) = partition else {
+        info!("Profile: {}", profiles[state.active].name);
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    if let Some(index) = nvs.get_u8(ACTIVE)? {
+        state.active = (index as usize).min(profiles.len() - 1);
+    }
+    if let (Some(until), Some(back)) = (nvs.get_u32(AWAY_UNTIL)?, nvs.get_u8(AWAY_BACK)?) {
+        state.away = Some(Away {
+            until,
+            back: (back as usize).min(profiles.len() - 1),
+        });
+    }
+    state.nvs = Some(nvs);
+    info!("Profile: {}", profiles[state.active].name);
+    Ok(())
+}
+
+/// Name of the active profile
+pub fn active() -> &'static str {
+    let state = STATE.lock().unwrap();
+    state.profiles.get(state.active).map_or("", |p| p.name)
+}
+
+/// Whether out-of-range readings of `topic_id` raise alarms right now
+pub fn alarms_enabled(topic_id: i32) -> bool {
+    let state = STATE.lock().unwrap();
+    state
+        .profiles
+        .get(state.active)
+        .map_or(true, |p| !p.muted.contains(&topic_id))
+}
+
+/// Whether the active profile is for an empty house
+pub fn away() -> bool {
+    let state = STATE.lock().unwrap();
+    state.profiles.get(state.active).is_some_and(|p| p.away)
+}
+
+/// Switch to the profile called `name`, ending a timed stay away
+pub fn select(name: &str) -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    let index = state
+        .profiles
+        .iter()
+        .position(|p| p.name == name)
+        .ok_or("no such profile")?;
+    if state.away.take().is_some() {
+        state.save_away()?;
+    }
+    state.select(index)
+}
+
+/// Switch to the away profile for `days`, then back to the active one
+pub fn away_for(days: u32) -> Result<(), &'static str> {
+    let now = clock::unix_secs();
+    if now < clock::VALID_AFTER {
+        return Err("clock not set");
+    }
+    let mut state = STATE.lock().unwrap();
+    let index = state
+        .profiles
+        .iter()
+        .position(|p| p.away)
+        .ok_or("no away profile")?;
+    // Extending a stay keeps the original return target
+    let back = state.away.map_or(state.active, |a| a.back);
+    state.away = Some(Away {
+        until: now.saturating_add(days.saturating_mul(86_400)),
+        back,
+    });
+    state.save_away()?;
+    info!(
+        "Away for {} days, back to {} then",
+        days, state.profiles[back].name
+    );
+    state.select(index)
+}
+
+/// End a timed stay away early, returning to the profile before it
+pub fn come_back() -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    let away = state.away.take().ok_or("not away")?;
+    state.save_away()?;
+    state.select(away.back)
+}
+
+/// Return from a timed stay away once it is over
+pub fn poll() {
+    let mut state = STATE.lock().unwrap();
+    let Some(away) = state.away else {
+        return;
+    };
+    let now = clock::unix_secs();
+    if now >= clock::VALID_AFTER && now >= away.until {
+        state.away = None;
+        state.save_away().ok();
+        state.select(away.back).ok();
+    }
+}
+
+/// Whether `name` is a profile, for checking imports
+pub fn check(name: &str) -> Result<(), &'static str> {
+    let state = STATE.lock().unwrap();
+    if state.profiles.iter().any(|p| p.name == name) {
+        Ok(())
+    } else {
+        Err("no such profile")
+    }
+}
+
+/// Flip between the A and B profiles, ending a timed stay away
+pub fn toggle() -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    if state.away.take().is_some() {
+        state.save_away()?;
+    }
+    let next = match state.active {
+        0 if state.profiles.len() > 1 => 1,
+        _ => 0,
+    };
+    state.select(next)
+}
+
+/// True once after every profile switch
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// Months in which the A profile is active, the B profile the rest of the year
+pub struct Schedule {
+    from: u32,
+    to: u32,
+    /// Last profile the schedule asked for, it only switches on a change
+    last: Option<usize>,
+}
+
+impl Schedule {
+    /// Parse `<from>-<to>` months, 1 to 12, wrapping over the new year
+    pub fn parse(months: &str) -> Option<Self> {
+        let (from, to) = months.split_once('-')?;
+        let (from, to) = (from.trim().parse().ok()?, to.trim().parse().ok()?);
+        let valid = 1..=12;
+        (valid.contains(&from) && valid.contains(&to)).then_some(Self {
+            from,
+            to,
+            last: None,
+        })
+    }
+
+    /// Switch profiles when the local month enters or leaves the range; switches
+    /// made by hand in between stand until then
+    pub fn poll(&mut self) {
+        let now = clock::unix_secs();
+        if now < clock::VALID_AFTER {
+            return;
+        }
+        let month = timezone::local(now).month;
+        let in_range = if self.from <= self.to {
+            (self.from..=self.to).contains(&month)
+        } else {
+            month >= self.from || month <= self.to
+        };
+        let wanted = if in_range { 0 } else { 1 };
+        if self.last != Some(wanted) {
+            self.last = Some(wanted);
+            let mut state = STATE.lock().unwrap();
+            if wanted >= state.profiles.len() {
+                return;
+            }
+            if let Some(away) = state.away.as_mut() {
+                away.back = wanted;
+                state.save_away().ok();
+            } else {
+                state.select(wanted).ok();
+            }
+        }
+    }
+}
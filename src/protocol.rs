@@ -0,0 +1,550 @@
+//! Data frame payloads
+//!
+//! Senders encode their readings one of three ways, see [`Format`]:
+//!
+//! - the fixed layout: topic id and measurement as little-endian i32s,
+//!   optionally followed by the battery charge in percent (8 or 9 bytes),
+//!   or in 11 bytes with the battery byte always there (255 if unknown),
+//!   then a CRC-16 of the first 9
+//! - `b"MSG"` followed by a [`Message`] encoded with postcard and a CRC-16
+//!   of everything before it
+//! - `b"MSV"`, the frame version, then the message and the CRC as with
+//!   `b"MSG"`
+//!
+//! The CRC is CRC-16/CCITT-FALSE, little-endian. A frame failing it is
+//! rejected as [`Error::Crc`]. The 8 and 9-byte frames of older senders
+//! carry none, [`Format::Unchecked`], and are only taken as they are where
+//! the caller allows them, see [`decode`]; otherwise they are rejected as
+//! [`Error::Unchecked`].
+//!
+//! [`encode`] writes versioned frames, the hub reads all three, so old and
+//! new senders work side by side while a fleet is upgraded. The version only
+//! moves, to [`FRAME_VERSION`], when the message encoding changes in a way
+//! appending variants cannot cover; a frame of a version newer than the hub
+//! reads is rejected as [`Error::Version`], telling an outdated hub apart
+//! from a corrupted frame. [`encode_unversioned`] is for senders talking to
+//! hubs that read only `b"MSG"` frames.
+//!
+//! A new kind of sensor payload is a new `Message` variant, and senders and
+//! hub share this module rather than a byte layout. Postcard encodes the
+//! variant by its position, so variants are only ever appended. A variant the
+//! hub does not know yet, from a newer sender, fails to decode and the frame
+//! is dropped with a warning.
+//!
+//! A [`Message::Record`] carries several quantities of one sensor, e.g. a
+//! climate sensor's temperature and humidity, as whole numbers or floats.
+//! The hub handles each field as a topic of its own, [`field_topic`]: the
+//! primary field is the sender's topic, the others land [`FIELD_STEP`]
+//! times their quantity above it, so topic 7's humidity is topic 2007. The
+//! hub computes in whole units and rounds floats; a field that does not fit
+//! an `i32`, or is not a number, is dropped.
+//!
+//! A [`Message::Critical`] is a reading the sender already knows to be an
+//! emergency, e.g. a kettle boiling now; the hub takes it on a fast path that
+//! sounds the alarm before anything else is done with it, see the fast_path
+//! module. Senders still send their regular readings alongside.
+//!
+//! The same framing carries commands the other way: a controlling node sends
+//! a [`Message::Command`] and the hub answers with a [`Message::Reply`], see
+//! the commands module.
+//!
+//! Any message can be wrapped in a [`Message::Addressed`] for one receiver
+//! or a group of them by an [`Address`] inside the payload, on top of the
+//! MAC the frame goes to; a hub drops what is addressed to others, see the
+//! addressing module. An unwrapped message is for whoever receives it. The
+//! wrapping goes one deep: an addressed message inside another is rejected
+//! as [`Error::Nested`] before it is decoded, so a crafted frame cannot nest
+//! them until decoding runs out of stack.
+
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 3] = b"MSG";
+const VERSIONED: &[u8; 3] = b"MSV";
+/// Newest frame version this module reads and the one it writes
+pub const FRAME_VERSION: u8 = 1;
+const FIXED_LEN: usize = 8;
+// Fixed frame with battery byte and CRC
+const CHECKED_LEN: usize = FIXED_LEN + 1 + CRC_LEN;
+const CRC_LEN: usize = 2;
+const BATTERY_UNKNOWN: u8 = 255;
+// Postcard variant index of Message::Addressed
+const ADDRESSED: u8 = 7;
+/// Topic id distance between the fields of a record, see [`field_topic`]
+pub const FIELD_STEP: i32 = 1000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The CRC does not match, the frame was corrupted on the way
+    Crc,
+    /// The message does not decode, e.g. a variant this hub does not know
+    Malformed(postcard::Error),
+    /// A frame version this hub does not read, from a newer sender
+    Version(u8),
+    /// An addressed message wrapping another
+    Nested,
+    /// A frame without a CRC, not allowed from this sender
+    Unchecked,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Crc => f.write_str("CRC mismatch"),
+            Error::Malformed(e) => write!(f, "malformed message: {}", e),
+            Error::Version(version) => write!(
+                f,
+                "frame version {}, this receiver reads up to {}",
+                version, FRAME_VERSION
+            ),
+            Error::Nested => f.write_str("addressed message inside another"),
+            Error::Unchecked => f.write_str("frame without a CRC"),
+        }
+    }
+}
+
+/// How a data frame is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The 11-byte layout
+    Fixed,
+    /// The 8 or 9-byte layout of older senders, without a CRC
+    Unchecked,
+    /// A `b"MSG"` message, without a frame version
+    Unversioned,
+    /// A `b"MSV"` message of this frame version
+    Versioned(u8),
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Fixed => f.write_str("fixed frames"),
+            Format::Unchecked => f.write_str("fixed frames without a CRC"),
+            Format::Unversioned => f.write_str("unversioned messages"),
+            Format::Versioned(version) => write!(f, "version {} messages", version),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Message {
+    /// One reading
+    Measurement {
+        topic_id: i32,
+        measurement: i32,
+        battery: Option<u8>,
+    },
+    /// Readings taken together, e.g. by a node with several sensors
+    Measurements {
+        readings: Vec<Reading>,
+        battery: Option<u8>,
+    },
+    /// Readings with the sender's sequence number, which counts up per frame
+    /// from boot and repeats unchanged when the frame is retransmitted
+    Sequenced {
+        seq: u16,
+        readings: Vec<Reading>,
+        battery: Option<u8>,
+    },
+    /// A command for the hub, `id` chosen by the sender and echoed in the
+    /// reply; a repeated id is a retransmission
+    Command { id: u16, command: Command },
+    /// Whether command `id` succeeded, hub to sender
+    Reply { id: u16, ok: bool },
+    /// Several quantities of one sensor, with a sequence number as
+    /// `Sequenced` has
+    Record {
+        seq: u16,
+        topic_id: i32,
+        fields: Vec<Field>,
+        battery: Option<u8>,
+    },
+    /// One reading that calls for the alarm right away, with a sequence
+    /// number as `Sequenced` has
+    Critical {
+        seq: u16,
+        topic_id: i32,
+        measurement: i32,
+        battery: Option<u8>,
+    },
+    /// `message` for the receivers `to` only, never itself addressed
+    Addressed { to: Address, message: Box<Message> },
+}
+
+/// Who an [`Message::Addressed`] is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Address {
+    /// Every receiver
+    All,
+    /// The receiver with this id
+    Receiver(u16),
+    /// Every receiver in this group
+    Group(u8),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::All => f.write_str("all"),
+            Address::Receiver(id) => write!(f, "r{}", id),
+            Address::Group(group) => write!(f, "g{}", group),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Command {
+    /// Move the alarm limit of a topic, as `threshold` on the console
+    SetThreshold {
+        topic_id: i32,
+        value: i32,
+    },
+    /// Acknowledge the raised alarms, as the button does
+    Silence,
+    Reboot,
+    SelfTest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Reading {
+    pub topic_id: i32,
+    pub measurement: i32,
+}
+
+/// One quantity of a [`Message::Record`]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Field {
+    pub quantity: Quantity,
+    pub value: Value,
+}
+
+/// What a field measures; appended to only, like [`Message`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Quantity {
+    /// The sensor's main reading, handled on its own topic
+    Primary,
+    Temperature,
+    Humidity,
+    Pressure,
+    Illuminance,
+    Co2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Value {
+    Int(i32),
+    Float(f32),
+}
+
+impl Value {
+    /// The value in whole units, `None` if it does not fit an `i32`
+    pub fn measurement(self) -> Option<i32> {
+        match self {
+            Value::Int(value) => Some(value),
+            Value::Float(value) => {
+                let rounded = value.round();
+                // i32::MAX is not exact as f32, the bound above is 2^31
+                (rounded >= i32::MIN as f32 && rounded < 2_147_483_648.0).then_some(rounded as i32)
+            }
+        }
+    }
+}
+
+/// The topic a record's `quantity` field is handled on, `None` past the
+/// `i32` range
+pub fn field_topic(topic_id: i32, quantity: Quantity) -> Option<i32> {
+    FIELD_STEP
+        .checked_mul(quantity as i32)
+        .and_then(|offset| topic_id.checked_add(offset))
+}
+
+impl Message {
+    /// The readings the message carries
+    pub fn readings(&self) -> Vec<Reading> {
+        match self {
+            Message::Measurement {
+                topic_id,
+                measurement,
+                ..
+            }
+            | Message::Critical {
+                topic_id,
+                measurement,
+                ..
+            } => vec![Reading {
+                topic_id: *topic_id,
+                measurement: *measurement,
+            }],
+            Message::Measurements { readings, .. } | Message::Sequenced { readings, .. } => {
+                readings.clone()
+            }
+            Message::Record {
+                topic_id, fields, ..
+            } => fields
+                .iter()
+                .filter_map(|field| {
+                    Some(Reading {
+                        topic_id: field_topic(*topic_id, field.quantity)?,
+                        measurement: field.value.measurement()?,
+                    })
+                })
+                .collect(),
+            Message::Command { .. } | Message::Reply { .. } => Vec::new(),
+            Message::Addressed { message, .. } => message.readings(),
+        }
+    }
+
+    /// Fields of a record dropped from [`Message::readings`], not fitting
+    pub fn rejected(&self) -> usize {
+        match self {
+            Message::Record { fields, .. } => fields.len() - self.readings().len(),
+            Message::Addressed { message, .. } => message.rejected(),
+            _ => 0,
+        }
+    }
+
+    /// The topic a sequenced message is acked for: the record's, or else
+    /// its first reading's
+    pub fn ack_topic(&self) -> Option<i32> {
+        match self {
+            Message::Record { topic_id, .. } => Some(*topic_id),
+            Message::Addressed { message, .. } => message.ack_topic(),
+            _ => self.readings().first().map(|r| r.topic_id),
+        }
+    }
+
+    /// Battery charge in percent, if the sender reports a plausible one
+    pub fn battery(&self) -> Option<u8> {
+        match self {
+            Message::Measurement { battery, .. }
+            | Message::Measurements { battery, .. }
+            | Message::Sequenced { battery, .. }
+            | Message::Record { battery, .. }
+            | Message::Critical { battery, .. } => battery.filter(|&percent| percent <= 100),
+            Message::Command { .. } | Message::Reply { .. } => None,
+            Message::Addressed { message, .. } => message.battery(),
+        }
+    }
+
+    /// The sender's sequence number, for duplicate suppression
+    pub fn seq(&self) -> Option<u16> {
+        match self {
+            Message::Sequenced { seq, .. }
+            | Message::Record { seq, .. }
+            | Message::Critical { seq, .. } => Some(*seq),
+            Message::Addressed { message, .. } => message.seq(),
+            _ => None,
+        }
+    }
+
+    /// Whether the hub should take it on the fast path
+    pub fn is_critical(&self) -> bool {
+        match self {
+            Message::Addressed { message, .. } => message.is_critical(),
+            message => matches!(message, Message::Critical { .. }),
+        }
+    }
+}
+
+/// Decode a data frame, `None` for any other frame; 8 and 9-byte frames
+/// without a CRC only with `unchecked`
+pub fn decode(frame: &[u8], unchecked: bool) -> Option<Result<Message, Error>> {
+    if frame.starts_with(VERSIONED) {
+        return Some(
+            checked(frame).and_then(|body| match body[VERSIONED.len()..] {
+                [FRAME_VERSION, ref message @ ..] => from_bytes(message),
+                [version, ..] => Err(Error::Version(version)),
+                [] => Err(Error::Version(0)),
+            }),
+        );
+    }
+    if frame.starts_with(MAGIC) {
+        return Some(checked(frame).and_then(|body| from_bytes(&body[MAGIC.len()..])));
+    }
+    let body = match frame.len() {
+        CHECKED_LEN => match checked(frame) {
+            Ok(body) => body,
+            Err(e) => return Some(Err(e)),
+        },
+        len if len == FIXED_LEN || len == FIXED_LEN + 1 => {
+            if !unchecked {
+                return Some(Err(Error::Unchecked));
+            }
+            frame
+        }
+        _ => return None,
+    };
+    let field = |at: usize| i32::from_le_bytes(body[at..at + 4].try_into().unwrap());
+    Some(Ok(Message::Measurement {
+        topic_id: field(0),
+        measurement: field(4),
+        battery: body.get(FIXED_LEN).copied(),
+    }))
+}
+
+/// The layout of `frame` if it has the shape of a data frame, before it is
+/// decoded
+pub fn format(frame: &[u8]) -> Option<Format> {
+    if frame.starts_with(VERSIONED) {
+        frame.get(VERSIONED.len()).map(|&v| Format::Versioned(v))
+    } else if frame.starts_with(MAGIC) {
+        Some(Format::Unversioned)
+    } else {
+        match frame.len() {
+            CHECKED_LEN => Some(Format::Fixed),
+            len if len == FIXED_LEN || len == FIXED_LEN + 1 => Some(Format::Unchecked),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `frame` has the shape of a data frame, before it is decoded
+pub fn is_data(frame: &[u8]) -> bool {
+    format(frame).is_some()
+}
+
+/// Encode `message` as a versioned frame, for senders
+pub fn encode(message: &Message) -> Result<Vec<u8>, postcard::Error> {
+    let mut frame = VERSIONED.to_vec();
+    frame.push(FRAME_VERSION);
+    frame.extend(postcard::to_allocvec(message)?);
+    frame.extend(crc16(&frame).to_le_bytes());
+    Ok(frame)
+}
+
+/// Encode `message` as an unversioned `b"MSG"` frame, for senders talking to
+/// hubs that read only those
+pub fn encode_unversioned(message: &Message) -> Result<Vec<u8>, postcard::Error> {
+    let mut frame = MAGIC.to_vec();
+    frame.extend(postcard::to_allocvec(message)?);
+    frame.extend(crc16(&frame).to_le_bytes());
+    Ok(frame)
+}
+
+/// Encode a reading in the 11-byte fixed layout, for senders
+pub fn encode_fixed(reading: Reading, battery: Option<u8>) -> [u8; CHECKED_LEN] {
+    let mut frame = [0u8; CHECKED_LEN];
+    frame[..4].copy_from_slice(&reading.topic_id.to_le_bytes());
+    frame[4..8].copy_from_slice(&reading.measurement.to_le_bytes());
+    frame[8] = battery.unwrap_or(BATTERY_UNKNOWN);
+    let crc = crc16(&frame[..CHECKED_LEN - CRC_LEN]);
+    frame[CHECKED_LEN - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// Decode a postcard message, refusing an addressed one that wraps another
+/// before decoding any of it
+fn from_bytes(message: &[u8]) -> Result<Message, Error> {
+    // Postcard leads with the variant index, one byte while under 128
+    if let [ADDRESSED, rest @ ..] = message {
+        let (_, inner) = postcard::take_from_bytes::<Address>(rest).map_err(Error::Malformed)?;
+        if inner.first() == Some(&ADDRESSED) {
+            return Err(Error::Nested);
+        }
+    }
+    postcard::from_bytes(message).map_err(Error::Malformed)
+}
+
+/// `frame` without its trailing CRC, if that matches
+fn checked(frame: &[u8]) -> Result<&[u8], Error> {
+    let split = frame.len().checked_sub(CRC_LEN).ok_or(Error::Crc)?;
+    let (body, crc) = frame.split_at(split);
+    if crc16(body).to_le_bytes() != crc {
+        return Err(Error::Crc);
+    }
+    Ok(body)
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addressed(to: Address, message: Message) -> Message {
+        Message::Addressed {
+            to,
+            message: Box::new(message),
+        }
+    }
+
+    #[test]
+    fn addressed_message_round_trips() {
+        let message = addressed(
+            Address::Group(3),
+            Message::Critical {
+                seq: 1,
+                topic_id: 2,
+                measurement: 3,
+                battery: None,
+            },
+        );
+        let frame = encode(&message).unwrap();
+        assert_eq!(decode(&frame, false).unwrap().unwrap(), message);
+        let frame = encode_unversioned(&message).unwrap();
+        assert_eq!(decode(&frame, false).unwrap().unwrap(), message);
+    }
+
+    #[test]
+    fn frames_without_a_crc_only_where_allowed() {
+        let mut frame = [0u8; FIXED_LEN + 1];
+        frame[..4].copy_from_slice(&3i32.to_le_bytes());
+        frame[4..8].copy_from_slice(&(-40i32).to_le_bytes());
+        frame[8] = 80;
+        for frame in [&frame[..], &frame[..FIXED_LEN]] {
+            assert_eq!(format(frame), Some(Format::Unchecked));
+            assert!(matches!(decode(frame, false), Some(Err(Error::Unchecked))));
+            let Some(Ok(Message::Measurement {
+                topic_id,
+                measurement,
+                ..
+            })) = decode(frame, true)
+            else {
+                panic!("not taken");
+            };
+            assert_eq!((topic_id, measurement), (3, -40));
+        }
+
+        let checked = encode_fixed(
+            Reading {
+                topic_id: 3,
+                measurement: -40,
+            },
+            Some(80),
+        );
+        assert_eq!(format(&checked), Some(Format::Fixed));
+        assert!(matches!(
+            decode(&checked, false),
+            Some(Ok(Message::Measurement {
+                battery: Some(80),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn nested_envelopes_are_rejected() {
+        let mut message = Message::Reply { id: 1, ok: true };
+        for _ in 0..1000 {
+            message = addressed(Address::All, message);
+        }
+        let frame = encode(&message).unwrap();
+        assert!(matches!(decode(&frame, false), Some(Err(Error::Nested))));
+        let twice = addressed(Address::Receiver(300), addressed(Address::All, message));
+        let frame = encode_unversioned(&twice).unwrap();
+        assert!(matches!(decode(&frame, false), Some(Err(Error::Nested))));
+    }
+}
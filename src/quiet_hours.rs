@@ -0,0 +1,273 @@
+//! Quiet hours: a nightly window with the buzzer turned down
+//!
+//! Within the window, by local time, alarms go on as usual but the buzzer
+//! is [`Mode::Reduced`], as while someone is close by: critical alarms sound
+//! softly and the rest only on their LEDs; or [`Mode::LedOnly`], with the
+//! buzzer silent for every alarm. The external siren stays muted in either.
+//! Until the clock is set the local time is unknown and the window does not
+//! apply, so alarms are never quieter than they should be.
+//!
+//! The `[quiet_hours]` defaults are compiled in; `quiet <from>-<to>
+//! [reduced|led_only]` on the console overrides them in NVS, `quiet off`
+//! turns them off and `quiet default` goes back to the defaults.
+
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+use crate::timezone;
+
+const NAMESPACE: &str = "quiet_hours";
+const FROM: &str = "from";
+const TO: &str = "to";
+const MODE: &str = "mode";
+
+static STATE: Mutex<State> = Mutex::new(State {
+    default: None,
+    window: None,
+    nvs: None,
+    active: None,
+});
+
+/// How far the buzzer is turned down, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Reduced,
+    LedOnly,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Reduced => "reduced",
+            Mode::LedOnly => "led_only",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [Mode::Reduced, Mode::LedOnly]
+            .into_iter()
+            .find(|m| m.name() == name)
+    }
+}
+
+/// From `from_hour` up to `to_hour` local time, across midnight if it ends
+/// before it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub from_hour: u32,
+    pub to_hour: u32,
+    pub mode: Mode,
+}
+
+impl Window {
+    fn contains(&self, hour: u32) -> bool {
+        if self.from_hour <= self.to_hour {
+            (self.from_hour..self.to_hour).contains(&hour)
+        } else {
+            hour >= self.from_hour || hour < self.to_hour
+        }
+    }
+}
+
+struct State {
+    default: Option<Window>,
+    window: Option<Window>,
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// The mode last logged
+    active: Option<Mode>,
+}
+
+/// Take `default` unless NVS holds an override, `None` and a window of
+/// equal hours for no quiet hours
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    default: Option<Window>,
+) -> Result<(), EspError> {
+    let default = default.filter(|w| w.from_hour != w.to_hour);
+    let mut state = STATE.lock().unwrap();
+    state.default = default;
+    state.window = default;
+    let Some(partition) = partition else {
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    if let (Some(from_hour), Some(to_hour)) = (nvs.get_u8(FROM)?, nvs.get_u8(TO)?) {
+        let mode = nvs.get_u8(MODE)?.unwrap_or(0);
+        state.window = (from_hour != to_hour).then_some(Window {
+            from_hour: from_hour.into(),
+            to_hour: to_hour.into(),
+            mode: if mode == 0 {
+                Mode::Reduced
+            } else {
+                Mode::LedOnly
+            },
+        });
+    }
+    state.nvs = Some(nvs);
+    Ok(())
+}
+
+/// Set the window, `None` for no quiet hours, stored for the next boot too
+pub fn set(window: Option<Window>) -> Result<(), &'static str> {
+    if window.is_some_and(|w| w.from_hour == w.to_hour) {
+        return Err("the window must not start and end at the same hour");
+    }
+    let mut state = STATE.lock().unwrap();
+    state.window = window;
+    let nvs = state.nvs.as_mut().ok_or("NVS unavailable, not stored")?;
+    let (from_hour, to_hour, mode) = window.map_or((0, 0, 0), |w| {
+        (w.from_hour as u8, w.to_hour as u8, w.mode as u8)
+    });
+    [(FROM, from_hour), (TO, to_hour), (MODE, mode)]
+        .into_iter()
+        .try_for_each(|(key, value)| nvs.set_u8(key, value))
+        .map_err(|e| {
+            warn!("Failed to store the quiet hours: {}", e);
+            "write failed"
+        })
+}
+
+/// Go back to the compiled-in window
+pub fn reset() -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    state.window = state.default;
+    let Some(nvs) = state.nvs.as_mut() else {
+        return Ok(());
+    };
+    [FROM, TO, MODE]
+        .into_iter()
+        .try_for_each(|key| nvs.remove(key).map(|_| ()))
+        .map_err(|e| {
+            warn!("Failed to forget the quiet hours: {}", e);
+            "write failed"
+        })
+}
+
+/// The window in force, `<from>-<to> <mode>` or `off`, for the config
+/// backup
+pub fn list() -> String {
+    match STATE.lock().unwrap().window {
+        None => "off".to_string(),
+        Some(w) => format!("{}-{} {}", w.from_hour, w.to_hour, w.mode.name()),
+    }
+}
+
+/// Check a window in the [`list`] format
+pub fn check(text: &str) -> Result<(), &'static str> {
+    parse(text).map(|_| ())
+}
+
+/// Set the window to one in the [`list`] format
+pub fn replace(text: &str) -> Result<(), &'static str> {
+    set(parse(text)?)
+}
+
+fn parse(text: &str) -> Result<Option<Window>, &'static str> {
+    const MALFORMED: &str = "expected <from>-<to> <mode> or off";
+    let text = text.trim();
+    if text == "off" {
+        return Ok(None);
+    }
+    let (hours, mode) = text.split_once(' ').ok_or(MALFORMED)?;
+    let (from_hour, to_hour) = hours
+        .split_once('-')
+        .and_then(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)))
+        .ok_or(MALFORMED)?;
+    if from_hour > 23 || to_hour > 23 {
+        return Err("quiet hours are 0 to 23");
+    }
+    let mode = Mode::parse(mode.trim()).ok_or(MALFORMED)?;
+    Ok(Some(Window {
+        from_hour,
+        to_hour,
+        mode,
+    }))
+}
+
+/// The mode in force now, `None` outside quiet hours; logs entering and
+/// leaving them, call once per main loop iteration
+pub fn poll() -> Option<Mode> {
+    let mut state = STATE.lock().unwrap();
+    let now = clock::unix_secs();
+    let active = state
+        .window
+        .filter(|w| now >= clock::VALID_AFTER && w.contains(timezone::local(now).hour))
+        .map(|w| w.mode);
+    if active != state.active {
+        state.active = active;
+        match active {
+            Some(mode) => info!("Quiet hours, buzzer {}", mode.name()),
+            None => info!("Quiet hours over"),
+        }
+    }
+    active
+}
+
+/// The window and whether it is on, for the console and inspect
+pub fn summary() -> String {
+    let state = STATE.lock().unwrap();
+    match state.window {
+        None => "Quiet hours off".to_string(),
+        Some(w) => format!(
+            "Quiet hours {:02}:00-{:02}:00, buzzer {}{}",
+            w.from_hour,
+            w.to_hour,
+            w.mode.name(),
+            if state.active.is_some() { ", now" } else { "" }
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // 2024-01-11 00:00 UTC, the host's local time
+    const DAY: u32 = clock::VALID_AFTER + 10 * 86_400;
+
+    fn at(hour: u32, minute: u32) -> u32 {
+        DAY + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn window_across_midnight_follows_the_clock() {
+        let (_turn, clock) = clock::mock();
+        let window = Window {
+            from_hour: 22,
+            to_hour: 7,
+            mode: Mode::LedOnly,
+        };
+        init(None, Some(window)).unwrap();
+
+        clock.set_unix_secs(at(21, 59));
+        assert_eq!(poll(), None);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(poll(), Some(Mode::LedOnly));
+        clock.advance(Duration::from_secs(9 * 3600 - 60));
+        assert_eq!(poll(), Some(Mode::LedOnly));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(poll(), None);
+    }
+
+    #[test]
+    fn never_applies_before_the_clock_is_set() {
+        let (_turn, clock) = clock::mock();
+        let window = Window {
+            from_hour: 0,
+            to_hour: 23,
+            mode: Mode::Reduced,
+        };
+        init(None, Some(window)).unwrap();
+
+        clock.set_unix_secs(12 * 3600);
+        assert_eq!(poll(), None);
+        clock.set_unix_secs(at(12, 0));
+        assert_eq!(poll(), Some(Mode::Reduced));
+    }
+}
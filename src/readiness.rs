@@ -0,0 +1,300 @@
+//! Whether the hub can raise an alarm right now
+//!
+//! The hub moves through four states:
+//!
+//! - `booting` until ESP-NOW is up
+//! - `radio_ready` once it is, while the outputs are not armed yet; an
+//!   asleep hub stays here, it does not receive
+//! - `armed` receiving, with the buzzer and LEDs armed: alarms work
+//! - `degraded` armed, but a [`Fault`] keeps some alarm from getting through
+//!
+//! Faults come and go; the hub is armed again once the last one cleared. A
+//! main loop stall counts as a fault for [`STALL_HOLD`] after it got going
+//! again, so a brief hang still shows. The state is reported as `readiness`
+//! in the status uplink with the faults as `faults`, logged on every change
+//! and shown on the idle alarm LEDs, see the annunciator.
+//!
+//! Across the states the hub keeps its alarm readiness figures, since the
+//! main loop started: the share of time armed (degraded does not count),
+//! the longest blind window, and the mean time from a frame's arrival in the
+//! receive callback to the outputs showing the alarm it raised. The hub is
+//! blind while it cannot hear: booting, awake but not yet armed or asleep
+//! (it does not receive), with a receive fault, and in the deep sleep before
+//! a wake-up, counted as not armed too. They go out as `sla` in the status
+//! uplink and on `GET /metrics`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock;
+
+/// How long a main loop stall keeps the hub degraded
+pub const STALL_HOLD: Duration = Duration::from_secs(60);
+
+static READINESS: Mutex<Readiness> = Mutex::new(Readiness {
+    phase: State::Booting,
+    faults: Vec::new(),
+    stalled_at: None,
+    reported: State::Booting,
+    sla: Sla {
+        polled_at: None,
+        total: Duration::ZERO,
+        armed: Duration::ZERO,
+        blind_since: None,
+        longest_blind: Duration::ZERO,
+        actuations: 0,
+        actuation_total: Duration::ZERO,
+    },
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Booting,
+    RadioReady,
+    Armed,
+    Degraded,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Booting => "booting",
+            State::RadioReady => "radio_ready",
+            State::Armed => "armed",
+            State::Degraded => "degraded",
+        }
+    }
+}
+
+/// Something that keeps alarms from getting through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The ESP-NOW receive callback is not registered
+    Receive,
+    /// The alarm outputs could not be configured
+    Outputs,
+    /// The buzzer was forced off by its on-time limit while still wanted
+    Buzzer,
+    /// The main loop stalled within the last [`STALL_HOLD`]
+    Stalled,
+}
+
+impl Fault {
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::Receive => "receive",
+            Fault::Outputs => "outputs",
+            Fault::Buzzer => "buzzer",
+            Fault::Stalled => "stalled",
+        }
+    }
+}
+
+struct Readiness {
+    /// Booting, RadioReady or Armed; Degraded is derived from the faults
+    phase: State,
+    faults: Vec<Fault>,
+    stalled_at: Option<Instant>,
+    /// The state last logged
+    reported: State,
+    sla: Sla,
+}
+
+/// Time accounting for the readiness figures
+struct Sla {
+    polled_at: Option<Instant>,
+    total: Duration,
+    armed: Duration,
+    blind_since: Option<Instant>,
+    longest_blind: Duration,
+    actuations: u32,
+    actuation_total: Duration,
+}
+
+impl Sla {
+    fn longest_blind(&self) -> Duration {
+        let current = self.blind_since.map_or(Duration::ZERO, clock::since);
+        self.longest_blind.max(current)
+    }
+}
+
+impl Readiness {
+    fn state(&self) -> State {
+        match self.phase {
+            State::Armed if !self.faults.is_empty() => State::Degraded,
+            phase => phase,
+        }
+    }
+
+    fn blind(&self) -> bool {
+        matches!(self.phase, State::Booting | State::RadioReady)
+            || self.faults.contains(&Fault::Receive)
+    }
+
+    fn set_fault(&mut self, fault: Fault, on: bool) {
+        let known = self.faults.contains(&fault);
+        if on && !known {
+            warn!("Readiness fault: {}", fault.name());
+            self.faults.push(fault);
+        } else if !on && known {
+            info!("Readiness fault cleared: {}", fault.name());
+            self.faults.retain(|f| *f != fault);
+        }
+    }
+}
+
+/// ESP-NOW is up
+pub fn radio_ready() {
+    let mut readiness = READINESS.lock().unwrap();
+    if readiness.phase == State::Booting {
+        readiness.phase = State::RadioReady;
+    }
+}
+
+/// The hub is receiving and its outputs are armed
+pub fn arm() {
+    READINESS.lock().unwrap().phase = State::Armed;
+}
+
+/// Raise or clear `fault`
+pub fn set_fault(fault: Fault, on: bool) {
+    READINESS.lock().unwrap().set_fault(fault, on);
+}
+
+/// The main loop stalled and got going again
+pub fn stalled() {
+    let mut readiness = READINESS.lock().unwrap();
+    readiness.stalled_at = Some(clock::now());
+    readiness.set_fault(Fault::Stalled, true);
+}
+
+/// Expire a stall and log a change of state, returns the current state;
+/// call once per main loop iteration
+pub fn poll() -> State {
+    let mut readiness = READINESS.lock().unwrap();
+    if readiness
+        .stalled_at
+        .is_some_and(|at| clock::since(at) >= STALL_HOLD)
+    {
+        readiness.stalled_at = None;
+        readiness.set_fault(Fault::Stalled, false);
+    }
+
+    let state = readiness.state();
+    let now = clock::now();
+    let blind = readiness.blind();
+    let sla = &mut readiness.sla;
+    if let Some(polled_at) = sla.polled_at {
+        let step = now.saturating_duration_since(polled_at);
+        sla.total += step;
+        if state == State::Armed {
+            sla.armed += step;
+        }
+    }
+    sla.polled_at = Some(now);
+    match (blind, sla.blind_since) {
+        (true, None) => sla.blind_since = Some(now),
+        (false, Some(since)) => {
+            sla.longest_blind = sla.longest_blind.max(now.saturating_duration_since(since));
+            sla.blind_since = None;
+        }
+        _ => {}
+    }
+
+    if state != readiness.reported {
+        readiness.reported = state;
+        match state {
+            State::Degraded => warn!("Readiness: degraded"),
+            state => info!("Readiness: {}", state.name()),
+        }
+    }
+    state
+}
+
+pub fn state() -> State {
+    READINESS.lock().unwrap().state()
+}
+
+/// The active faults, in the order they were raised
+pub fn faults() -> Vec<Fault> {
+    READINESS.lock().unwrap().faults.clone()
+}
+
+/// The active faults as a JSON array, for the status uplink
+pub fn faults_json() -> String {
+    let faults: Vec<String> = faults()
+        .iter()
+        .map(|f| format!(r#""{}""#, f.name()))
+        .collect();
+    format!("[{}]", faults.join(","))
+}
+
+/// The hub slept for `slept` before this boot, a blind window
+pub fn slept(slept: Duration) {
+    let mut readiness = READINESS.lock().unwrap();
+    readiness.sla.total += slept;
+    readiness.sla.longest_blind = readiness.sla.longest_blind.max(slept);
+}
+
+/// The outputs show an alarm raised by a frame that arrived `latency` ago
+pub fn actuated(latency: Duration) {
+    let mut readiness = READINESS.lock().unwrap();
+    readiness.sla.actuations = readiness.sla.actuations.saturating_add(1);
+    readiness.sla.actuation_total += latency;
+}
+
+/// The readiness figures so far
+#[derive(Debug, Clone, Copy)]
+pub struct Figures {
+    /// Share of the time armed, `None` before any time has passed
+    pub armed_percent: Option<f32>,
+    pub longest_blind: Duration,
+    /// `None` until a frame raised an alarm
+    pub mean_actuation: Option<Duration>,
+}
+
+pub fn figures() -> Figures {
+    let readiness = READINESS.lock().unwrap();
+    let sla = &readiness.sla;
+    Figures {
+        armed_percent: (!sla.total.is_zero())
+            .then(|| 100.0 * sla.armed.as_secs_f32() / sla.total.as_secs_f32()),
+        longest_blind: sla.longest_blind(),
+        mean_actuation: (sla.actuations > 0).then(|| sla.actuation_total / sla.actuations),
+    }
+}
+
+/// `{"armed_pct":..,"longest_blind_s":..,"mean_actuation_ms":..}`, `null`
+/// for figures not known yet, for the status uplink and `/metrics`
+pub fn sla_json() -> String {
+    let figures = figures();
+    format!(
+        r#"{{"armed_pct":{},"longest_blind_s":{},"mean_actuation_ms":{}}}"#,
+        figures
+            .armed_percent
+            .map_or_else(|| "null".to_string(), |p| format!("{:.2}", p)),
+        figures.longest_blind.as_secs(),
+        figures.mean_actuation.map_or_else(
+            || "null".to_string(),
+            |d| format!("{:.1}", d.as_secs_f32() * 1000.0)
+        )
+    )
+}
+
+/// The readiness figures in one line, for inspect
+pub fn sla_summary() -> String {
+    let figures = figures();
+    format!(
+        "armed {}, longest blind window {} s, frame to outputs {}",
+        figures
+            .armed_percent
+            .map_or_else(|| "-".to_string(), |p| format!("{:.2}%", p)),
+        figures.longest_blind.as_secs(),
+        figures.mean_actuation.map_or_else(
+            || "-".to_string(),
+            |d| format!("{:.1} ms", d.as_secs_f32() * 1000.0)
+        )
+    )
+}
@@ -0,0 +1,145 @@
+//! RTTTL ringtone parser
+//!
+//! Ring Tone Text Transfer Language packs a melody into one line:
+//! `name:d=4,o=5,b=140:c,e,g,8p,2c6`. The middle section holds the default
+//! duration, octave and tempo, each note is `[duration]letter[#][.][octave]`
+//! with `p` for a pause and a `.` making it half as long again.
+
+use crate::sounds::Step;
+
+// Defaults from the RTTTL spec for a missing d=, o= or b=
+const DEFAULT_DURATION: u32 = 4;
+const DEFAULT_OCTAVE: u32 = 6;
+const DEFAULT_BPM: u32 = 63;
+const MAX_NOTES: usize = 64;
+// Each note is cut short by this many ms so repeated notes stay distinct
+const ARTICULATION_MS: u64 = 10;
+
+/// Parse `text` into buzzer steps, each note followed by a short gap
+pub fn parse(text: &str) -> Result<Vec<Step>, &'static str> {
+    let mut sections = text.trim().splitn(3, ':');
+    let (Some(_name), Some(defaults), Some(notes)) =
+        (sections.next(), sections.next(), sections.next())
+    else {
+        return Err("expected <name>:<defaults>:<notes>");
+    };
+
+    let mut duration = DEFAULT_DURATION;
+    let mut octave = DEFAULT_OCTAVE;
+    let mut bpm = DEFAULT_BPM;
+    for setting in defaults.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once('=').ok_or("bad default")?;
+        let value: u32 = value.trim().parse().map_err(|_| "bad default")?;
+        match key.trim() {
+            "d" => duration = check_duration(value)?,
+            "o" => octave = check_octave(value)?,
+            "b" if (1..=900).contains(&value) => bpm = value,
+            _ => return Err("bad default"),
+        }
+    }
+
+    // A whole note is four beats
+    let whole_ms = 4 * 60_000 / bpm as u64;
+    let mut steps = Vec::new();
+    for note in notes.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        if steps.len() >= 2 * MAX_NOTES {
+            return Err("more than 64 notes");
+        }
+        let (tone, ms) = parse_note(note, duration, octave, whole_ms)?;
+        match tone {
+            Some(hz) => {
+                let gap = ARTICULATION_MS.min(ms / 2);
+                steps.push(Step::tone(hz, ms - gap));
+                steps.push(Step::rest(gap));
+            }
+            None => steps.push(Step::rest(ms)),
+        }
+    }
+    if steps.is_empty() {
+        return Err("no notes");
+    }
+    Ok(steps)
+}
+
+/// One note as its frequency (`None` for a pause) and length in ms
+fn parse_note(
+    note: &str,
+    default_duration: u32,
+    default_octave: u32,
+    whole_ms: u64,
+) -> Result<(Option<u32>, u64), &'static str> {
+    let bytes = note.as_bytes();
+    let mut i = 0;
+
+    let digits = bytes.iter().take_while(|b| b.is_ascii_digit()).count();
+    let duration = match digits {
+        0 => default_duration,
+        _ => check_duration(note[..digits].parse().map_err(|_| "bad duration")?)?,
+    };
+    i += digits;
+
+    let semitone = match bytes.get(i).map(u8::to_ascii_lowercase) {
+        Some(b'c') => Some(0),
+        Some(b'd') => Some(2),
+        Some(b'e') => Some(4),
+        Some(b'f') => Some(5),
+        Some(b'g') => Some(7),
+        Some(b'a') => Some(9),
+        Some(b'b') | Some(b'h') => Some(11),
+        Some(b'p') => None,
+        _ => return Err("bad note"),
+    };
+    i += 1;
+
+    let sharp = bytes.get(i) == Some(&b'#');
+    if sharp {
+        i += 1;
+    }
+    // The dot may come before or after the octave, both are in the wild
+    let mut dotted = false;
+    if bytes.get(i) == Some(&b'.') {
+        dotted = true;
+        i += 1;
+    }
+    let octave = match bytes.get(i) {
+        Some(b) if b.is_ascii_digit() => {
+            i += 1;
+            check_octave((b - b'0') as u32)?
+        }
+        _ => default_octave,
+    };
+    if bytes.get(i) == Some(&b'.') {
+        dotted = true;
+        i += 1;
+    }
+    if i != bytes.len() {
+        return Err("bad note");
+    }
+
+    let mut ms = whole_ms / duration as u64;
+    if dotted {
+        ms += ms / 2;
+    }
+    let tone = semitone.map(|s| frequency(octave, s + sharp as u32));
+    Ok((tone, ms))
+}
+
+/// Equal-tempered frequency in Hz, A4 = 440 Hz
+fn frequency(octave: u32, semitone: u32) -> u32 {
+    let midi = (octave + 1) * 12 + semitone;
+    (440.0 * 2f32.powf((midi as f32 - 69.0) / 12.0)).round() as u32
+}
+
+fn check_duration(duration: u32) -> Result<u32, &'static str> {
+    match duration {
+        1 | 2 | 4 | 8 | 16 | 32 => Ok(duration),
+        _ => Err("duration must be 1, 2, 4, 8, 16 or 32"),
+    }
+}
+
+fn check_octave(octave: u32) -> Result<u32, &'static str> {
+    match octave {
+        3..=8 => Ok(octave),
+        _ => Err("octave must be 3 to 8"),
+    }
+}
@@ -0,0 +1,69 @@
+//! Upgrades of the stored data after a firmware update
+//!
+//! The stores in NVS and on the storage partition outlive the firmware: an
+//! OTA update only rewrites the app slots (see `partitions.csv`). The schema
+//! version the data was last written with is kept in NVS, and at boot,
+//! before any store loads, every migration above it runs in order. Each
+//! step's version is saved as soon as it succeeds, so a failed or
+//! interrupted step is retried at the next boot and never runs twice.
+//!
+//! A change to a stored format therefore comes with a [`Migration`] to the
+//! next version, appended to the list main.rs hands to [`run`]. Version 1 is
+//! the layout the stores had when versioning started. A new hub has no
+//! version yet either, so migrations must cope with a store being empty.
+
+use log::{info, warn};
+
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs};
+use crate::platform::sys::EspError;
+
+const NAMESPACE: &str = "schema";
+const VERSION: &str = "version";
+const FIRMWARE: &str = "firmware";
+const MAX_FIRMWARE: usize = 32;
+/// Data written before versioning is taken to be in this version
+const BASELINE: u32 = 1;
+
+/// One upgrade of the stored data
+pub struct Migration {
+    /// Schema version the data is in afterwards, one above the last
+    pub to: u32,
+    /// For the log
+    pub describe: &'static str,
+    pub run: fn(&EspDefaultNvsPartition) -> Result<(), EspError>,
+}
+
+/// Bring the stored data up to the last of `migrations`
+///
+/// Logs when the firmware changed since the last boot. Data from a newer
+/// schema than this firmware knows, after a downgrade, is left alone.
+pub fn run(partition: &EspDefaultNvsPartition, migrations: &[Migration]) -> Result<(), EspError> {
+    let mut nvs = EspNvs::new(partition.clone(), NAMESPACE, true)?;
+    let firmware = env!("CARGO_PKG_VERSION");
+    let mut buf = [0u8; MAX_FIRMWARE + 1];
+    if let Some(previous) = nvs.get_str(FIRMWARE, &mut buf)?.filter(|&p| p != firmware) {
+        info!("Firmware updated from {} to {}", previous, firmware);
+    }
+
+    let current = migrations.last().map_or(BASELINE, |m| m.to);
+    let stored = nvs.get_u32(VERSION)?.unwrap_or(BASELINE);
+    if stored > current {
+        warn!(
+            "Stored data is in schema {}, newer than this firmware's {}, leaving it as is",
+            stored, current
+        );
+    }
+    let mut version = stored;
+    for migration in migrations.iter().filter(|m| m.to > stored) {
+        info!(
+            "Migrating stored data to schema {}: {}",
+            migration.to, migration.describe
+        );
+        (migration.run)(partition)?;
+        version = migration.to;
+        nvs.set_u32(VERSION, version)?;
+    }
+    nvs.set_u32(VERSION, version)?;
+    nvs.set_str(FIRMWARE, firmware)?;
+    Ok(())
+}
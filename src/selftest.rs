@@ -0,0 +1,143 @@
+//! Field self-test of the ESP-NOW path
+//!
+//! The `selftest` console command broadcasts a frame carrying a random token
+//! and feeds the same frame to the ESP-NOW receive callback. The test passes
+//! once the send callback confirms the frame went out over the air and the
+//! main loop has picked the token up from the receive pipeline, both within
+//! [`DEADLINE`].
+//!
+//! It also feeds the receive callback a critical frame with the token
+//! inverted, which must reach the main loop's fast path within
+//! [`fast_path::DEADLINE`]. The self-test frame never raises an alarm, so
+//! this times the path up to the buzzer refresh, not the buzzer itself.
+//!
+//! A radio never hears its own transmissions, so the receive half starts at
+//! the callback rather than at the antenna.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::{esp_random, EspError};
+use log::{info, warn};
+
+use crate::clock;
+use crate::dispatch::Frame;
+use crate::espnow_tx::{self, Qos, Receipt};
+use crate::fast_path;
+use crate::protocol::{self, Message};
+
+const DEADLINE: Duration = Duration::from_secs(1);
+// Topic id of self-test frames, never used by a sender
+const TOPIC_ID_SELF_TEST: i32 = -2;
+
+pub struct SelfTest {
+    token: i32,
+    started: Instant,
+    receipt: Receipt,
+    sent: Option<Duration>,
+    received: Option<Duration>,
+    /// From the receive callback to the fast path
+    critical: Option<Duration>,
+}
+
+impl SelfTest {
+    /// Send the test frame and hand it and the critical one to `inject`,
+    /// which must push them through the receive callback
+    pub fn start(inject: impl Fn(&[u8])) -> Result<Self, EspError> {
+        let token = unsafe { esp_random() } as i32;
+
+        // Same layout as the sender's HubData: topic id, then measurement
+        let mut frame = [0u8; 8];
+        frame[..4].copy_from_slice(&TOPIC_ID_SELF_TEST.to_le_bytes());
+        frame[4..].copy_from_slice(&token.to_le_bytes());
+
+        espnow_tx::add_peer(espnow_tx::BROADCAST)?;
+        let receipt = espnow_tx::send_tracked(espnow_tx::BROADCAST, &frame, Qos::FireAndForget)?;
+        let started = clock::now();
+        inject(&frame);
+        let critical = Message::Critical {
+            seq: token as u16,
+            topic_id: TOPIC_ID_SELF_TEST,
+            measurement: !token,
+            battery: None,
+        };
+        if let Ok(critical) = protocol::encode(&critical) {
+            inject(&critical);
+        }
+        info!("Self-test started");
+
+        Ok(Self {
+            token,
+            started,
+            receipt,
+            sent: None,
+            received: None,
+            critical: None,
+        })
+    }
+
+    /// Swallow self-test frames, returns false for regular readings
+    pub fn receive(&mut self, frame: &Frame) -> bool {
+        if frame.topic_id != TOPIC_ID_SELF_TEST {
+            return false;
+        }
+        if frame.critical {
+            if frame.measurement == !self.token && self.critical.is_none() {
+                self.critical = Some(fast_path::since(frame.received_us));
+            }
+        } else if frame.measurement == self.token && self.received.is_none() {
+            self.received = Some(clock::since(self.started));
+        }
+        true
+    }
+
+    /// Check progress, logs the result and returns true once the test is over
+    pub fn poll(&mut self) -> bool {
+        let elapsed = clock::since(self.started);
+
+        if self.sent.is_none() {
+            match self.receipt.outcome() {
+                Some(true) => self.sent = Some(elapsed),
+                Some(false) => {
+                    warn!("Self-test FAILED: test frame was not transmitted");
+                    return true;
+                }
+                None => {}
+            }
+        }
+
+        match (self.sent, self.received, self.critical) {
+            (Some(_), Some(_), Some(critical)) if critical > fast_path::DEADLINE => {
+                warn!(
+                    "Self-test FAILED: critical frame reached the fast path after {} ms, over {} ms",
+                    critical.as_millis(),
+                    fast_path::DEADLINE.as_millis()
+                );
+                true
+            }
+            (Some(sent), Some(received), Some(critical)) => {
+                info!(
+                    "Self-test passed: transmitted after {} ms, received after {} ms, critical \
+                     frame on the fast path after {} ms",
+                    sent.as_millis(),
+                    received.as_millis(),
+                    critical.as_millis()
+                );
+                true
+            }
+            (sent, received, _) if elapsed >= DEADLINE => {
+                let missing = match (sent, received) {
+                    (None, _) => "no transmit confirmation",
+                    (Some(_), None) => "test frame never reached the receive pipeline",
+                    (Some(_), Some(_)) => "critical frame never reached the fast path",
+                };
+                warn!(
+                    "Self-test FAILED: {} within {} ms",
+                    missing,
+                    DEADLINE.as_millis()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Firmware versions reported by the senders
+//!
+//! Senders announce themselves at boot with a short frame carrying their
+//! firmware version and the revision of the frame protocol they speak:
+//!
+//! `b"ANN"`, protocol revision, firmware major, minor, patch (7 bytes)
+//!
+//! The latest announcement is kept per sender so the fleet can be checked
+//! with `senders` on the console before an upgrade, and a sender speaking an
+//! older revision than this receiver is warned about as it announces. So is
+//! the layout of its last data frame, see [`Format`], which also lists
+//! senders too old to announce themselves; a rollout is done once none send
+//! fixed frames or unversioned messages any more.
+
+use core::fmt;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::protocol::Format;
+
+/// Frame protocol revision this receiver speaks
+///
+/// 1 is the plain 8-byte frame, 2 adds the battery byte and announcements,
+/// 3 adds postcard messages, 4 the CRC-16 on message frames and the 11-byte
+/// fixed frame, 5 sequence numbers, 6 commands and replies, 7 fragmented
+/// payloads, 8 multi-field records, 9 versioned message frames; see the
+/// protocol and fragment modules.
+pub const PROTOCOL_REVISION: u8 = 9;
+
+const MAGIC: &[u8; 3] = b"ANN";
+// Senders kept, announced or not
+const MAX_SENDERS: usize = 32;
+
+static SENDERS: Mutex<Vec<Sender>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Announcement {
+    pub protocol: u8,
+    pub firmware: [u8; 3],
+}
+
+impl Announcement {
+    /// Parse an announcement frame, `None` for any other frame
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let rest = frame.strip_prefix(MAGIC)?;
+        let [protocol, major, minor, patch] = rest.try_into().ok()?;
+        Some(Self {
+            protocol,
+            firmware: [major, minor, patch],
+        })
+    }
+    /// The announcement frame, for senders
+    pub fn encode(&self) -> [u8; 7] {
+        let [major, minor, patch] = self.firmware;
+        [
+            MAGIC[0],
+            MAGIC[1],
+            MAGIC[2],
+            self.protocol,
+            major,
+            minor,
+            patch,
+        ]
+    }
+}
+
+impl fmt::Display for Announcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch] = self.firmware;
+        write!(
+            f,
+            "firmware {}.{}.{}, protocol {}",
+            major, minor, patch, self.protocol
+        )
+    }
+}
+
+struct Sender {
+    mac: [u8; 6],
+    /// `None` for a sender only heard sending data
+    announcement: Option<Announcement>,
+    /// Layout of its last data frame
+    format: Option<Format>,
+}
+
+/// Note the announcement `mac` sent, logging new senders and upgrades
+pub fn record(mac: [u8; 6], announcement: Announcement) {
+    let mut senders = SENDERS.lock().unwrap();
+    let full = senders.len() >= MAX_SENDERS;
+    match senders.iter_mut().find(|s| s.mac == mac) {
+        Some(sender) if sender.announcement == Some(announcement) => return,
+        Some(sender) => sender.announcement = Some(announcement),
+        None if full => return,
+        None => senders.push(Sender {
+            mac,
+            announcement: Some(announcement),
+            format: None,
+        }),
+    }
+    drop(senders);
+
+    info!("Sender {:02X?}: {}", mac, announcement);
+    if announcement.protocol < PROTOCOL_REVISION {
+        warn!(
+            "Sender {:02X?} speaks protocol {}, this receiver {}: update the sender",
+            mac, announcement.protocol, PROTOCOL_REVISION
+        );
+    } else if announcement.protocol > PROTOCOL_REVISION {
+        warn!(
+            "Sender {:02X?} speaks protocol {}, this receiver {}: update the receiver",
+            mac, announcement.protocol, PROTOCOL_REVISION
+        );
+    }
+}
+
+/// Note the layout of a data frame from `mac`
+pub fn heard(mac: [u8; 6], format: Format) {
+    let mut senders = SENDERS.lock().unwrap();
+    let full = senders.len() >= MAX_SENDERS;
+    match senders.iter_mut().find(|s| s.mac == mac) {
+        Some(sender) => sender.format = Some(format),
+        None if full => {}
+        None => senders.push(Sender {
+            mac,
+            announcement: None,
+            format: Some(format),
+        }),
+    }
+}
+
+/// Every sender heard as `<MAC> <version>, sends <format>` lines
+pub fn list() -> String {
+    let mut list = String::new();
+    for sender in SENDERS.lock().unwrap().iter() {
+        // Senders too old to announce are outdated too
+        let outdated = sender
+            .announcement
+            .map_or(true, |a| a.protocol < PROTOCOL_REVISION);
+        match sender.announcement {
+            Some(a) => list.push_str(&format!("{:02X?} {}", sender.mac, a)),
+            None => list.push_str(&format!("{:02X?} not announced", sender.mac)),
+        }
+        if let Some(format) = sender.format {
+            list.push_str(&format!(", sends {}", format));
+        }
+        list.push_str(if outdated { " (outdated)\n" } else { "\n" });
+    }
+    list
+}
@@ -0,0 +1,379 @@
+//! Runtime settings kept in NVS
+//!
+//! Alarm limits, the topic registry, names and the other tables have stores
+//! of their own. This one covers what was otherwise fixed at build time: the
+//! intervals and timeouts of [`Setting`], some per topic such as its
+//! cooldown (`cooldown.<topic_id>`), and the pins of the alarm LEDs.
+//! Each starts from its built-in value, from `defaults.toml` and the board's
+//! pin map; one set on the console (`set <name> <seconds>`, `map`) or by a
+//! config import is stored in NVS and read back at boot. Subsystems read a
+//! setting through [`get`] whenever they need it, so a change applies from
+//! its next use; what holds a value, such as the siren, the LEDs, the
+//! alarms or the watchdog, is updated by the main loop once
+//! [`take_changed`] says so. `set <name> default`
+//! forgets one.
+//!
+//! The store is one table, `<name> <seconds>` lines for the settings set and
+//! `led <topic_id> <gpio|off>` lines for the LEDs moved, which is also the
+//! `settings` section of the config backup.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+
+const NAMESPACE: &str = "settings";
+const TABLE: &str = "table";
+const MAX_TABLE: usize = 512;
+const LED: &str = "led";
+
+static STATE: Mutex<State> = Mutex::new(State {
+    defaults: &[],
+    values: Vec::new(),
+    leds: Vec::new(),
+    nvs: None,
+    changed: false,
+});
+
+/// A timing setting, in whole seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// Between two status events
+    StatusInterval,
+    /// Between two status events while away
+    AwayStatusInterval,
+    /// How long a pairing window stays open
+    PairingWindow,
+    /// How long the snooze button keeps the buzzer and siren off
+    Snooze,
+    /// Unacknowledged before the siren sounds in full
+    SirenFullAfter,
+    /// Longest the buzzer stays on unbroken before it is forced off
+    BuzzerMaxOn,
+    /// Main loop stuck before the watchdog resets the stuck step, shorter
+    /// than [`Setting::LoopRestart`]
+    LoopStall,
+    /// Main loop stuck before the watchdog restarts the hub
+    LoopRestart,
+    /// The topic silent before it is offline, 0 never
+    OfflineAfter(i32),
+    /// Between two bursts of the topic's alarm, 0 sounding on
+    Cooldown(i32),
+    /// The topic's alarm out of range before it sounds in full
+    FullAfter(i32),
+    /// The topic's alarm out of range before it goes continuous, 0 never
+    ContinuousAfter(i32),
+}
+
+/// The settings that are not per topic
+const ALL: [Setting; 8] = [
+    Setting::StatusInterval,
+    Setting::AwayStatusInterval,
+    Setting::PairingWindow,
+    Setting::Snooze,
+    Setting::SirenFullAfter,
+    Setting::BuzzerMaxOn,
+    Setting::LoopStall,
+    Setting::LoopRestart,
+];
+
+impl Setting {
+    /// The name, without the topic of a per-topic setting
+    pub fn name(self) -> &'static str {
+        match self {
+            Setting::StatusInterval => "status_interval",
+            Setting::AwayStatusInterval => "away_status_interval",
+            Setting::PairingWindow => "pairing_window",
+            Setting::Snooze => "snooze",
+            Setting::SirenFullAfter => "siren_full_after",
+            Setting::BuzzerMaxOn => "buzzer_max_on",
+            Setting::LoopStall => "loop_stall",
+            Setting::LoopRestart => "loop_restart",
+            Setting::OfflineAfter(_) => "offline_after",
+            Setting::Cooldown(_) => "cooldown",
+            Setting::FullAfter(_) => "full_after",
+            Setting::ContinuousAfter(_) => "continuous_after",
+        }
+    }
+
+    /// The topic of a per-topic setting
+    pub fn topic_id(self) -> Option<i32> {
+        match self {
+            Setting::OfflineAfter(topic_id)
+            | Setting::Cooldown(topic_id)
+            | Setting::FullAfter(topic_id)
+            | Setting::ContinuousAfter(topic_id) => Some(topic_id),
+            _ => None,
+        }
+    }
+
+    /// A name as [`Display`](fmt::Display) writes it, `<name>.<topic_id>`
+    /// for a per-topic setting
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.split_once('.') {
+            Some((name, topic_id)) => {
+                let setting: fn(i32) -> Self = match name {
+                    "offline_after" => Setting::OfflineAfter,
+                    "cooldown" => Setting::Cooldown,
+                    "full_after" => Setting::FullAfter,
+                    "continuous_after" => Setting::ContinuousAfter,
+                    _ => return None,
+                };
+                Some(setting(topic_id.parse().ok()?))
+            }
+            None => ALL.into_iter().find(|s| s.name() == name),
+        }
+    }
+
+    /// The seconds it may be set to
+    fn range(self) -> (u32, u32) {
+        match self {
+            Setting::StatusInterval | Setting::AwayStatusInterval => (5, 3600),
+            Setting::PairingWindow => (10, 600),
+            Setting::Snooze => (60, 2 * 3600),
+            Setting::SirenFullAfter | Setting::FullAfter(_) => (0, 3600),
+            Setting::BuzzerMaxOn => (1, 600),
+            Setting::LoopStall => (2, 300),
+            Setting::LoopRestart => (10, 3600),
+            Setting::OfflineAfter(_) => (0, 7 * 24 * 3600),
+            Setting::Cooldown(_) => (0, 24 * 3600),
+            Setting::ContinuousAfter(_) => (0, 4 * 3600),
+        }
+    }
+
+    fn check(self, secs: u32) -> Result<u32, &'static str> {
+        let (min, max) = self.range();
+        if (min..=max).contains(&secs) {
+            Ok(secs)
+        } else {
+            Err("out of range, see `settings`")
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.topic_id() {
+            Some(topic_id) => write!(f, "{}.{}", self.name(), topic_id),
+            None => f.write_str(self.name()),
+        }
+    }
+}
+
+/// A line of the table
+enum Entry {
+    Setting(Setting, u32),
+    Led(i32, Option<i32>),
+}
+
+struct State {
+    defaults: &'static [(Setting, Duration)],
+    /// The settings set, in seconds
+    values: Vec<(Setting, u32)>,
+    /// The LEDs moved, `None` detached
+    leds: Vec<(i32, Option<i32>)>,
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// Set by [`set`] and [`replace`], for [`take_changed`]
+    changed: bool,
+}
+
+impl State {
+    fn default(&self, setting: Setting) -> Duration {
+        self.defaults
+            .iter()
+            .find(|(s, _)| *s == setting)
+            .map_or(Duration::ZERO, |&(_, d)| d)
+    }
+
+    fn table(&self) -> String {
+        let mut table = String::new();
+        for (setting, secs) in &self.values {
+            table.push_str(&format!("{} {}\n", setting, secs));
+        }
+        for (topic_id, gpio) in &self.leds {
+            match gpio {
+                Some(gpio) => table.push_str(&format!("{} {} {}\n", LED, topic_id, gpio)),
+                None => table.push_str(&format!("{} {} off\n", LED, topic_id)),
+            }
+        }
+        table
+    }
+
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Setting(setting, secs) => {
+                self.values.retain(|(s, _)| *s != setting);
+                self.values.push((setting, secs));
+            }
+            Entry::Led(topic_id, gpio) => {
+                self.leds.retain(|(id, _)| *id != topic_id);
+                self.leds.push((topic_id, gpio));
+            }
+        }
+    }
+
+    fn save(&mut self) -> Result<(), &'static str> {
+        let table = self.table();
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable, not stored")?;
+        nvs.set_blob(TABLE, table.as_bytes()).map_err(|e| {
+            warn!("Failed to store the settings: {}", e);
+            "write failed"
+        })
+    }
+}
+
+/// Start from `defaults` and load what NVS holds over them
+///
+/// With `partition` `None` the defaults apply and changes are not kept.
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    defaults: &'static [(Setting, Duration)],
+) -> Result<(), EspError> {
+    let mut state = STATE.lock().unwrap();
+    state.defaults = defaults;
+    let Some(partition) = partition else {
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_TABLE];
+    let table = nvs.get_blob(TABLE, &mut buf)?.unwrap_or_default();
+    for line in String::from_utf8_lossy(table).lines() {
+        match parse_line(line) {
+            Ok(entry) => state.apply(entry),
+            Err(e) => warn!("Stored setting {:?} dropped: {}", line, e),
+        }
+    }
+    state.nvs = Some(nvs);
+    Ok(())
+}
+
+/// The value of `setting` in force
+pub fn get(setting: Setting) -> Duration {
+    let state = STATE.lock().unwrap();
+    match state.values.iter().find(|(s, _)| *s == setting) {
+        Some(&(_, secs)) => Duration::from_secs(secs.into()),
+        None => state.default(setting),
+    }
+}
+
+/// Set `setting` to `secs`, or go back to its default with `None`, stored
+/// for the next boot too
+pub fn set(setting: Setting, secs: Option<u32>) -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    match secs {
+        Some(secs) => state.apply(Entry::Setting(setting, setting.check(secs)?)),
+        None => state.values.retain(|(s, _)| *s != setting),
+    }
+    state.changed = true;
+    state.save()
+}
+
+/// The LEDs moved from the pin map, as `(topic_id, gpio)`, `None` detached
+pub fn led_pins() -> Vec<(i32, Option<i32>)> {
+    STATE.lock().unwrap().leds.clone()
+}
+
+/// Keep the LED of `topic_id` on `gpio`, or detached with `None`, once the
+/// annunciator has moved it
+pub fn set_led_pin(topic_id: i32, gpio: Option<i32>) -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    state.apply(Entry::Led(topic_id, gpio));
+    state.save()
+}
+
+/// The per-topic settings with a built-in value or set, by topic
+pub fn per_topic() -> Vec<Setting> {
+    let state = STATE.lock().unwrap();
+    let defaults = state.defaults.iter().map(|&(setting, _)| setting);
+    let set = state.values.iter().map(|&(setting, _)| setting);
+    let mut settings: Vec<Setting> = Vec::new();
+    for setting in defaults.chain(set) {
+        if setting.topic_id().is_some() && !settings.contains(&setting) {
+            settings.push(setting);
+        }
+    }
+    settings.sort_by_key(|s| (s.topic_id(), s.name()));
+    settings
+}
+
+/// Whether a setting was set or the table replaced since the last call
+pub fn take_changed() -> bool {
+    std::mem::take(&mut STATE.lock().unwrap().changed)
+}
+
+/// Every setting with its value and whether it is set, one per line, for
+/// the console
+pub fn summary() -> String {
+    let per_topic = per_topic();
+    let state = STATE.lock().unwrap();
+    let mut summary = String::new();
+    for setting in ALL.into_iter().chain(per_topic) {
+        let set = state.values.iter().find(|(s, _)| *s == setting);
+        let (min, max) = setting.range();
+        summary.push_str(&format!(
+            "{} {} s ({}, {} to {})\n",
+            setting,
+            set.map_or(state.default(setting).as_secs(), |&(_, secs)| secs.into()),
+            if set.is_some() { "set" } else { "default" },
+            min,
+            max
+        ));
+    }
+    for (topic_id, gpio) in &state.leds {
+        match gpio {
+            Some(gpio) => summary.push_str(&format!("LED of topic {} on GPIO{}\n", topic_id, gpio)),
+            None => summary.push_str(&format!("LED of topic {} detached\n", topic_id)),
+        }
+    }
+    summary
+}
+
+/// The settings set and LEDs moved, in the table format
+pub fn list() -> String {
+    STATE.lock().unwrap().table()
+}
+
+/// Check a table in the [`list`] format
+pub fn check(table: &str) -> Result<(), &'static str> {
+    table
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .try_for_each(|line| parse_line(line).map(|_| ()))
+}
+
+/// Replace the whole table with `table`, in the [`list`] format
+pub fn replace(table: &str) -> Result<(), &'static str> {
+    check(table)?;
+    let mut state = STATE.lock().unwrap();
+    state.values.clear();
+    state.leds.clear();
+    for line in table.lines().filter(|line| !line.trim().is_empty()) {
+        state.apply(parse_line(line)?);
+    }
+    state.changed = true;
+    state.save()
+}
+
+fn parse_line(line: &str) -> Result<Entry, &'static str> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some(LED), Some(topic_id), Some(gpio), None) => {
+            let topic_id = topic_id.parse().map_err(|_| "bad topic id")?;
+            let gpio = match gpio {
+                "off" => None,
+                gpio => Some(gpio.parse().map_err(|_| "bad GPIO")?),
+            };
+            Ok(Entry::Led(topic_id, gpio))
+        }
+        (Some(name), Some(secs), None, None) => {
+            let setting = Setting::parse(name).ok_or("unknown setting")?;
+            let secs = secs.parse().map_err(|_| "bad value")?;
+            Ok(Entry::Setting(setting, setting.check(secs)?))
+        }
+        _ => Err("expected `<name> <seconds>` or `led <topic_id> <gpio|off>`"),
+    }
+}
@@ -0,0 +1,129 @@
+//! External siren, a high-power actuator apart from the buzzer
+//!
+//! The siren is meant for alarms nobody near the hub has noticed, so it only
+//! follows unacknowledged alarms of its [`Priority`] or above, and in two
+//! stages: a short pre-alert chirp every few seconds first, then the full
+//! siren, on steady, once the oldest of them has gone unacknowledged for
+//! `full_after`. Acknowledging the alarms stops it.
+//!
+//! An interlock keeps it silent whatever the alarms: while the mute switch
+//! is closed or in quiet hours the siren stays off, and the stage it would
+//! be in is kept for when the interlock lets go. Like the buzzer it sits behind a
+//! [`GuardedOutput`], so a siren stuck on is cut after its on-time limit.
+
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::alerts::{Alerts, Priority};
+use crate::clock;
+use crate::output_guard::GuardedOutput;
+use crate::platform::sys::EspError;
+
+// Pre-alert: one short chirp every few seconds
+const CHIRP_PERIOD_MS: u128 = 5000;
+const CHIRP_ON_MS: u128 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Idle,
+    PreAlert,
+    Full,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Idle => "idle",
+            Stage::PreAlert => "pre_alert",
+            Stage::Full => "full",
+        }
+    }
+}
+
+pub struct Siren {
+    output: GuardedOutput,
+    priority: Priority,
+    full_after: Duration,
+    stage: Stage,
+    /// Phase reference of the pre-alert chirp
+    stage_started: Instant,
+    muted: bool,
+}
+
+impl Siren {
+    /// Sounding for unacknowledged alarms of at least `priority`, in full
+    /// after `full_after`
+    pub fn new(output: GuardedOutput, priority: Priority, full_after: Duration) -> Self {
+        Self {
+            output,
+            priority,
+            full_after,
+            stage: Stage::Idle,
+            stage_started: clock::now(),
+            muted: false,
+        }
+    }
+
+    /// Sound in full after `full_after` from now on
+    pub fn set_full_after(&mut self, full_after: Duration) {
+        self.full_after = full_after;
+    }
+
+    /// Configure the output once boot is over
+    pub fn arm(&mut self) -> Result<(), EspError> {
+        self.output.arm()
+    }
+
+    /// Follow the alarms, `muted` by the interlock; call on every pass of
+    /// the loop
+    pub fn poll(&mut self, alerts: &Alerts, muted: bool) {
+        let now = clock::now();
+        let stage = match alerts.unacknowledged_since(self.priority) {
+            None => Stage::Idle,
+            Some(since) if now.saturating_duration_since(since) >= self.full_after => Stage::Full,
+            Some(_) => Stage::PreAlert,
+        };
+        if stage != self.stage {
+            info!("Siren: {}", stage.name());
+            self.stage = stage;
+            self.stage_started = now;
+        }
+        if muted != self.muted {
+            info!("Siren {}", if muted { "muted" } else { "unmuted" });
+            self.muted = muted;
+        }
+
+        let chirp = now.duration_since(self.stage_started).as_millis() % CHIRP_PERIOD_MS;
+        let on = !muted
+            && match stage {
+                Stage::Idle => false,
+                Stage::PreAlert => chirp < CHIRP_ON_MS,
+                Stage::Full => true,
+            };
+        self.output.set_level(on);
+    }
+
+    pub fn gpio(&self) -> i32 {
+        self.output.gpio()
+    }
+
+    /// Whether the on-time limit forced the siren off
+    pub fn tripped(&self) -> bool {
+        self.output.tripped()
+    }
+
+    /// Stage and interlock in one line, for inspect
+    pub fn summary(&self) -> String {
+        format!(
+            "GPIO{}, {} alarms, {} after {} s unacknowledged, {}{}{}",
+            self.gpio(),
+            self.priority.name(),
+            Stage::Full.name(),
+            self.full_after.as_secs(),
+            self.stage.name(),
+            if self.muted { ", muted" } else { "" },
+            if self.tripped() { ", tripped" } else { "" }
+        )
+    }
+}
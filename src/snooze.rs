@@ -0,0 +1,81 @@
+//! Snooze: the buzzer and the siren silenced for a while
+//!
+//! The snooze button silences both for the `snooze` setting, the LEDs
+//! still showing every alarm; pressing it again starts the time over. The
+//! snooze runs on the application clock, so it ends by itself, also while
+//! alarms come and go.
+
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::clock;
+
+#[derive(Default)]
+pub struct Snooze {
+    until: Option<Instant>,
+}
+
+impl Snooze {
+    /// Snooze for `length` from now
+    pub fn start(&mut self, length: Duration) {
+        self.until = Some(clock::now() + length);
+        info!("Buzzer and siren snoozed for {} min", length.as_secs() / 60);
+    }
+
+    /// End the snooze once its time is up; call once per main loop iteration
+    pub fn poll(&mut self) {
+        if self.until.is_some_and(|t| clock::now() >= t) {
+            self.until = None;
+            info!("Snooze over");
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.until.is_some()
+    }
+
+    /// Time left, `None` when not snoozed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.until
+            .map(|until| until.saturating_duration_since(clock::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LENGTH: Duration = Duration::from_secs(15 * 60);
+
+    #[test]
+    fn ends_after_its_length() {
+        let (_turn, clock) = clock::mock();
+        let mut snooze = Snooze::default();
+        snooze.start(LENGTH);
+        clock.advance(LENGTH - Duration::from_secs(1));
+        snooze.poll();
+        assert!(snooze.active());
+        assert_eq!(snooze.remaining(), Some(Duration::from_secs(1)));
+
+        clock.advance(Duration::from_secs(1));
+        snooze.poll();
+        assert!(!snooze.active());
+        assert_eq!(snooze.remaining(), None);
+    }
+
+    #[test]
+    fn pressing_again_starts_over() {
+        let (_turn, clock) = clock::mock();
+        let mut snooze = Snooze::default();
+        snooze.start(LENGTH);
+        clock.advance(LENGTH / 2);
+        snooze.start(LENGTH);
+        clock.advance(LENGTH / 2 + Duration::from_secs(1));
+        snooze.poll();
+        assert!(snooze.active());
+        clock.advance(LENGTH / 2);
+        snooze.poll();
+        assert!(!snooze.active());
+    }
+}
@@ -0,0 +1,83 @@
+//! Wall-clock time over SNTP
+//!
+//! Nothing else sets the RTC, so after a power-up the hub only knows its
+//! uptime. Once the AP is joined [`start`] runs SNTP against the `[clock]
+//! sntp_server`: the first sync sets the clock, and from then on quiet
+//! hours, the profile and maintenance schedules, log timestamps and the data
+//! log and history follow wall-clock time, resynced every hour. Without an
+//! AP, or until the first sync, the hub stays on uptime only and everything
+//! by local time stays off, as it was before. The RTC keeps running through
+//! deep sleep, so a wake-up does not wait for another sync.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+#[cfg(target_os = "espidf")]
+use log::{info, warn};
+
+use crate::clock;
+
+static SNTP: Mutex<Option<Sntp>> = Mutex::new(None);
+/// Unix time of the last sync, 0 before the first
+static SYNCED_AT: AtomicU32 = AtomicU32::new(0);
+
+struct Sntp {
+    server: &'static str,
+    #[cfg(target_os = "espidf")]
+    _service: EspSntp<'static>,
+}
+
+/// Start syncing with `server`, once the AP is joined; later calls, e.g.
+/// after joining the AP again, leave the running service be
+#[cfg(target_os = "espidf")]
+pub fn start(server: &'static str) {
+    let mut sntp = SNTP.lock().unwrap();
+    if sntp.is_some() {
+        return;
+    }
+    let mut conf = SntpConf::default();
+    conf.servers[0] = server;
+    let service = EspSntp::new_with_callback(&conf, |since_epoch| {
+        let secs = since_epoch.as_secs() as u32;
+        if SYNCED_AT.swap(secs, Ordering::Relaxed) == 0 {
+            info!("Clock set over SNTP");
+        }
+    });
+    match service {
+        Ok(service) => {
+            info!("SNTP started with {}", server);
+            *sntp = Some(Sntp {
+                server,
+                _service: service,
+            });
+        }
+        Err(e) => warn!("SNTP failed to start, uptime only: {}", e),
+    }
+}
+
+/// Whether the clock was synced during this boot
+pub fn synced() -> bool {
+    SYNCED_AT.load(Ordering::Relaxed) != 0
+}
+
+/// Server and last sync in one line, for the console and inspect
+pub fn summary() -> String {
+    let sntp = SNTP.lock().unwrap();
+    let Some(sntp) = sntp.as_ref() else {
+        return if clock::unix_secs() >= clock::VALID_AFTER {
+            "SNTP off, clock kept through deep sleep".to_string()
+        } else {
+            "SNTP off, no AP, uptime only".to_string()
+        };
+    };
+    match SYNCED_AT.load(Ordering::Relaxed) {
+        0 => format!("SNTP {}, not synced yet, uptime only", sntp.server),
+        at => format!(
+            "SNTP {}, synced {} s ago",
+            sntp.server,
+            clock::unix_secs().saturating_sub(at)
+        ),
+    }
+}
@@ -0,0 +1,333 @@
+//! Alarm sounds stored on flash
+//!
+//! Sounds are small text files on the storage partition, assigned to topics
+//! or priority classes in `sounds.cfg`. Both can be uploaded over HTTP once
+//! the hub has joined WiFi, so alarm sounds change without a rebuild:
+//!
+//! - `GET /sounds` lists the stored sounds and the assignments
+//! - `PUT /sounds` replaces the assignments
+//! - `PUT /sounds/<name>` stores a sound
+//! - `DELETE /sounds/<name>` removes a sound
+//!
+//! A sound is either a buzzer pattern, on and off times in ms alternating and
+//! separated by spaces (`200 100 200 1500`), or an [`rtttl`] melody. Melodies
+//! need a passive buzzer driven by LEDC; on a plain on/off buzzer only their
+//! rhythm comes through. PCM is not supported.
+//!
+//! Assignments are `key = sound` lines, the key being a topic id or one of
+//! `critical`, `warning` and `info` and the sound a stored sound's name or an
+//! inline RTTTL string. A topic's own sound wins over its class's, and the
+//! built-in pattern is the fallback for both. The `boot`, `sleep`, `pairing`
+//! and `paired` keys set the [`Cue`]s played on wake-up, before deep sleep,
+//! when a pairing window opens and when a sender paired; `off` silences them.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+use log::{info, warn};
+
+use crate::alerts::Priority;
+#[cfg(target_os = "espidf")]
+use crate::http::{authorize, read_body, refuse, respond};
+#[cfg(target_os = "espidf")]
+use crate::platform::sys::EspError;
+use crate::rtttl;
+
+const CONFIG_FILE: &str = "sounds.cfg";
+// SPIFFS has no directories, sounds are stored as snd_<name>
+const SOUND_PREFIX: &str = "snd_";
+const MAX_NAME: usize = 16;
+const MAX_PATTERN_STEPS: usize = 32;
+const MAX_STEP_MS: u64 = 10_000;
+#[cfg(target_os = "espidf")]
+const MAX_BODY: usize = 512;
+
+/// Tone of the on steps of plain patterns, near the resonance of common
+/// piezo buzzers
+pub const BEEP_HZ: u32 = 2700;
+
+// Set when an upload changed the files, the main loop reloads on the next pass
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// One step of a sound: a tone in Hz, or silence, held for `ms`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub tone: Option<u32>,
+    pub ms: u64,
+}
+
+impl Step {
+    pub const fn tone(hz: u32, ms: u64) -> Self {
+        Self { tone: Some(hz), ms }
+    }
+
+    pub const fn rest(ms: u64) -> Self {
+        Self { tone: None, ms }
+    }
+}
+
+/// Steps of an on/off pattern in ms, starting with on
+pub const fn beeps<const N: usize>(ms: [u64; N]) -> [Step; N] {
+    let mut steps = [Step::rest(0); N];
+    let mut i = 0;
+    while i < N {
+        if i % 2 == 0 {
+            steps[i] = Step::tone(BEEP_HZ, ms[i]);
+        } else {
+            steps[i] = Step::rest(ms[i]);
+        }
+        i += 1;
+    }
+    steps
+}
+
+/// Short non-alarm feedback sounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cue {
+    Boot,
+    Sleep,
+    /// A pairing window opened from the button
+    Pairing,
+    Paired,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Topic(i32),
+    Class(Priority),
+    Cue(Cue),
+}
+
+/// Sounds assigned in `sounds.cfg`, loaded into memory
+#[derive(Default)]
+pub struct Sounds {
+    assigned: Vec<(Key, Vec<Step>)>,
+}
+
+impl Sounds {
+    /// Load the assignments in `dir`, skipping entries that do not resolve
+    pub fn load(dir: &str) -> Self {
+        let config = match fs::read_to_string(format!("{}/{}", dir, CONFIG_FILE)) {
+            Ok(config) => config,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Failed to read {}: {}", CONFIG_FILE, e);
+                return Self::default();
+            }
+        };
+
+        let mut assigned = Vec::new();
+        for (key, sound) in config.lines().filter_map(|line| parse_line(line).ok()?) {
+            let steps = match sound {
+                Source::Off => Ok(Vec::new()),
+                Source::Inline(text) => parse_sound(text).map_err(str::to_string),
+                Source::Stored(name) => fs::read_to_string(sound_path(dir, name))
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| parse_sound(&text).map_err(str::to_string)),
+            };
+            match steps {
+                Ok(steps) => assigned.push((key, steps)),
+                Err(e) => warn!("Sound for {:?} not used: {}", key, e),
+            }
+        }
+        info!("Sounds: {} assigned", assigned.len());
+        Self { assigned }
+    }
+
+    /// The custom sound for an alarm of `topic_id` in class `priority`
+    pub fn sound(&self, topic_id: i32, priority: Priority) -> Option<&[Step]> {
+        let find = |key| {
+            self.assigned
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, p)| p.as_slice())
+        };
+        find(Key::Topic(topic_id)).or_else(|| find(Key::Class(priority)))
+    }
+
+    /// The assigned sound for `cue`, empty if it is turned off
+    pub fn cue(&self, cue: Cue) -> Option<&[Step]> {
+        self.assigned
+            .iter()
+            .find(|(k, _)| *k == Key::Cue(cue))
+            .map(|(_, p)| p.as_slice())
+    }
+}
+
+/// True once after an upload changed the sounds
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// Serve the upload endpoints for the sounds in `dir` on `server`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>, dir: &'static str) -> Result<(), EspError> {
+    server.fn_handler("/sounds", Method::Get, move |req| {
+        let mut listing = String::new();
+        for name in stored_names(dir).unwrap_or_default() {
+            listing.push_str(&format!("sound {}\n", name));
+        }
+        if let Ok(config) = fs::read_to_string(format!("{}/{}", dir, CONFIG_FILE)) {
+            listing.push_str(&config);
+        }
+        req.into_ok_response()?.write_all(listing.as_bytes())
+    })?;
+
+    server.fn_handler("/sounds", Method::Put, move |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = read_body(&mut req, MAX_BODY)?.and_then(|body| {
+            for line in body.lines() {
+                parse_line(line)?;
+            }
+            store(&format!("{}/{}", dir, CONFIG_FILE), &body)
+        });
+        respond(req, result)
+    })?;
+
+    server.fn_handler("/sounds/*", Method::Put, move |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = match sound_name(req.uri()).map(str::to_string) {
+            Ok(name) => read_body(&mut req, MAX_BODY)?.and_then(|body| {
+                parse_sound(&body)?;
+                store(&sound_path(dir, &name), &body)
+            }),
+            Err(e) => Err(e),
+        };
+        respond(req, result)
+    })?;
+
+    server.fn_handler("/sounds/*", Method::Delete, move |req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = sound_name(req.uri()).and_then(|name| {
+            fs::remove_file(sound_path(dir, name)).map_err(|_| "no such sound")?;
+            CHANGED.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        respond(req, result)
+    })?;
+
+    info!("Sound uploads served on /sounds");
+    Ok(())
+}
+
+#[cfg(target_os = "espidf")]
+fn store(path: &str, contents: &str) -> Result<(), &'static str> {
+    fs::write(path, contents).map_err(|e| {
+        warn!("Failed to write {}: {}", path, e);
+        "write failed"
+    })?;
+    CHANGED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+#[cfg(target_os = "espidf")]
+fn stored_names(dir: &str) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file = entry?.file_name();
+        if let Some(name) = file.to_str().and_then(|f| f.strip_prefix(SOUND_PREFIX)) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+fn sound_path(dir: &str, name: &str) -> String {
+    format!("{}/{}{}", dir, SOUND_PREFIX, name)
+}
+
+/// The sound name at the end of `uri`, checked so it cannot leave the prefix
+#[cfg(target_os = "espidf")]
+fn sound_name(uri: &str) -> Result<&str, &'static str> {
+    let path = uri.split('?').next().unwrap_or_default();
+    let name = path.strip_prefix("/sounds/").unwrap_or_default();
+    check_name(name)?;
+    Ok(name)
+}
+
+fn check_name(name: &str) -> Result<(), &'static str> {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-';
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(valid) {
+        return Err("sound names are 1 to 16 of a-z, 0-9, _ and -");
+    }
+    Ok(())
+}
+
+/// Where an assigned sound comes from
+#[derive(Debug, Clone, Copy)]
+enum Source<'a> {
+    Stored(&'a str),
+    Inline(&'a str),
+    Off,
+}
+
+/// Parse one `key = sound` assignment, `None` for blank lines
+fn parse_line(line: &str) -> Result<Option<(Key, Source<'_>)>, &'static str> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let (key, sound) = line.split_once('=').ok_or("expected <key> = <sound>")?;
+    let key = match key.trim() {
+        "critical" => Key::Class(Priority::Critical),
+        "warning" => Key::Class(Priority::Warning),
+        "info" => Key::Class(Priority::Info),
+        "boot" => Key::Cue(Cue::Boot),
+        "sleep" => Key::Cue(Cue::Sleep),
+        "pairing" => Key::Cue(Cue::Pairing),
+        "paired" => Key::Cue(Cue::Paired),
+        topic_id => Key::Topic(topic_id.parse().map_err(|_| "invalid key")?),
+    };
+    let sound = sound.trim();
+    if sound == "off" {
+        return match key {
+            Key::Cue(_) => Ok(Some((key, Source::Off))),
+            _ => Err("only cues can be turned off"),
+        };
+    }
+    if sound.contains(':') {
+        rtttl::parse(sound)?;
+        return Ok(Some((key, Source::Inline(sound))));
+    }
+    check_name(sound)?;
+    Ok(Some((key, Source::Stored(sound))))
+}
+
+/// Parse a sound file, RTTTL if it has the `name:defaults:notes` shape
+fn parse_sound(text: &str) -> Result<Vec<Step>, &'static str> {
+    if text.contains(':') {
+        rtttl::parse(text)
+    } else {
+        parse_pattern(text)
+    }
+}
+
+fn parse_pattern(text: &str) -> Result<Vec<Step>, &'static str> {
+    let ms = text
+        .split_whitespace()
+        .map(|step| step.parse().map_err(|_| "steps must be whole ms"))
+        .collect::<Result<Vec<u64>, _>>()?;
+    if ms.is_empty() || ms.len() % 2 != 0 || ms.len() > MAX_PATTERN_STEPS {
+        return Err("a pattern is 1 to 16 on/off pairs");
+    }
+    if ms.iter().any(|&ms| ms == 0 || ms > MAX_STEP_MS) {
+        return Err("steps must be 1 to 10000 ms");
+    }
+    Ok(ms
+        .chunks(2)
+        .flat_map(|pair| [Step::tone(BEEP_HZ, pair[0]), Step::rest(pair[1])])
+        .collect())
+}
@@ -0,0 +1,139 @@
+//! Staggered startup
+//!
+//! The hub comes up in stages: the radio first, so frames are received and
+//! alarms raised as early as possible, then the peripherals such as the
+//! storage partition, and the uplinks last. Only the radio is needed for
+//! alarms, and one that fails is retried from the main loop. Each
+//! optional stage has a timeout: one that has not finished by then stops
+//! holding up the boot and is taken into use by the main loop whenever it
+//! does finish, and one that fails is left out. Either way the alarms work.
+//!
+//! Every stage's outcome is logged with the time it took and reported as
+//! `startup` in the status uplink, e.g.
+//! `{"radio":"ready","storage":"late","uplinks":"waiting"}`.
+
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+// How often `wait` checks whether a stage is done
+const POLL: Duration = Duration::from_millis(50);
+
+static STAGES: Mutex<Vec<(&'static str, State)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Starting,
+    Ready,
+    /// Still going after its timeout, the boot went on without it
+    Waiting,
+    /// Finished after its timeout
+    Late,
+    Failed,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Starting => "starting",
+            State::Ready => "ready",
+            State::Waiting => "waiting",
+            State::Late => "late",
+            State::Failed => "failed",
+        }
+    }
+}
+
+/// A startup stage being brought up
+#[derive(Debug)]
+pub struct Stage {
+    name: &'static str,
+    started: Instant,
+    timeout: Duration,
+}
+
+impl Stage {
+    pub fn start(name: &'static str, timeout: Duration) -> Self {
+        info!("Startup: {}", name);
+        set(name, State::Starting);
+        Self {
+            name,
+            started: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Poll `done` until it yields or the timeout passes, `None` leaves the
+    /// stage waiting for the main loop to finish it
+    pub fn wait<T>(&self, mut done: impl FnMut() -> Option<T>) -> Option<T> {
+        loop {
+            if let Some(result) = done() {
+                return Some(result);
+            }
+            if self.started.elapsed() >= self.timeout {
+                warn!(
+                    "Startup: {} not done after {} s, continuing without it",
+                    self.name,
+                    self.timeout.as_secs()
+                );
+                set(self.name, State::Waiting);
+                return None;
+            }
+            thread::sleep(POLL);
+        }
+    }
+
+    /// Record how the stage ended, returns whether it is ready
+    pub fn finish<E: Display>(self, result: Result<(), E>) -> bool {
+        let took = self.started.elapsed();
+        match result {
+            Ok(()) if took > self.timeout => {
+                info!(
+                    "Startup: {} ready late, after {} ms",
+                    self.name,
+                    took.as_millis()
+                );
+                set(self.name, State::Late);
+                true
+            }
+            Ok(()) => {
+                info!("Startup: {} ready in {} ms", self.name, took.as_millis());
+                set(self.name, State::Ready);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Startup: {} failed, continuing without it: {}",
+                    self.name, e
+                );
+                set(self.name, State::Failed);
+                false
+            }
+        }
+    }
+}
+
+/// The state of every stage so far, in boot order
+pub fn stages() -> Vec<(&'static str, State)> {
+    STAGES.lock().unwrap().clone()
+}
+
+/// The stages as a JSON object, for the status uplink
+pub fn json() -> String {
+    let fields: Vec<String> = stages()
+        .iter()
+        .map(|(name, state)| format!(r#""{}":"{}""#, name, state.name()))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn set(name: &'static str, state: State) {
+    let mut stages = STAGES.lock().unwrap();
+    match stages.iter_mut().find(|(n, _)| *n == name) {
+        Some(entry) => entry.1 = state,
+        None => stages.push((name, state)),
+    }
+}
@@ -0,0 +1,20 @@
+//! SPIFFS storage partition
+//!
+//! The `storage` partition from `partitions.csv` is mounted into the VFS so
+//! the rest of the firmware can use plain `std::fs` on it.
+
+use esp_idf_svc::sys::{esp, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register, EspError};
+
+pub const BASE_PATH: &str = "/spiffs";
+
+/// Mount the storage partition at `BASE_PATH`, formatting it on first boot
+pub fn mount() -> Result<(), EspError> {
+    let conf = esp_vfs_spiffs_conf_t {
+        base_path: c"/spiffs".as_ptr(),
+        partition_label: c"storage".as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+
+    esp!(unsafe { esp_vfs_spiffs_register(&conf) })
+}
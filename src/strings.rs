@@ -0,0 +1,190 @@
+//! User-facing message strings in several languages
+//!
+//! Messages meant for the household (alarms, batteries, unusual readings,
+//! silent sensors and sleep) are looked up here instead of being written
+//! inline, in the language picked with `LANGUAGE` at build time: `en` (the
+//! default), `de`, `es` or `fr`. Diagnostics stay in English. Each language
+//! is a plain array of `&'static str` in flash, indexed by [`Text`], so
+//! lookups cost nothing and the text is never copied to RAM.
+//!
+//! Parameters are written `{0}`, `{1}` and so on, letting a translation put
+//! them in whatever order its grammar wants.
+
+use core::fmt::{self, Display};
+
+const LANGUAGE: Option<&str> = option_env!("LANGUAGE");
+
+/// A user-facing message, the discriminant indexes the language tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Text {
+    /// Topic, measurement
+    AlarmRaised,
+    /// Topic
+    AlarmAcknowledged,
+    /// Topic
+    AlarmCleared,
+    /// Topic, minutes
+    EmergencyBroadcast,
+    /// Topic
+    EmergencyStoodDown,
+    /// Seconds
+    SleepWarning,
+    SleepCancelled,
+    GoingToSleep,
+    WakingUp,
+    /// Topic, percent
+    BatteryLow,
+    /// Topic, percent
+    BatteryOk,
+    /// Topic, measurement
+    Unusual,
+    /// Topic, measurement
+    UsualAgain,
+    /// Topic, measurement
+    AlarmBandUp,
+    /// Topic, measurement
+    AlarmBandDown,
+    /// Topic, minutes
+    SensorOffline,
+    /// Topic
+    SensorBack,
+}
+
+const COUNT: usize = Text::SensorBack as usize + 1;
+
+const EN: [&str; COUNT] = [
+    "Alarm on topic {0}: measurement {1}",
+    "Alarm on topic {0} acknowledged",
+    "Alarm on topic {0} cleared, back in range",
+    "Alarm on topic {0} unacknowledged for {1} min, broadcasting",
+    "Emergency on topic {0} stood down",
+    "Going to sleep in {0} s, press the button to stay awake",
+    "Sleep cancelled",
+    "Going to sleep now",
+    "Sensor touched: Waking up",
+    "Battery of sensor {0} low: {1}%",
+    "Battery of sensor {0} back at {1}%",
+    "Unusual reading on sensor {0}: {1}",
+    "Sensor {0} back to usual readings: {1}",
+    "Alarm on topic {0} more severe: measurement {1}",
+    "Alarm on topic {0} less severe: measurement {1}",
+    "Sensor {0} offline: nothing heard for {1} min",
+    "Sensor {0} back online",
+];
+
+const DE: [&str; COUNT] = [
+    "Alarm bei Sensor {0}: Messwert {1}",
+    "Alarm bei Sensor {0} quittiert",
+    "Alarm bei Sensor {0} beendet, Wert wieder im Bereich",
+    "Alarm bei Sensor {0} seit {1} min nicht quittiert, wird rundgesendet",
+    "Notfall bei Sensor {0} aufgehoben",
+    "Ruhezustand in {0} s, Taste drücken, um wach zu bleiben",
+    "Ruhezustand abgebrochen",
+    "Wechsel in den Ruhezustand",
+    "Sensor berührt: Aufwachen",
+    "Batterie von Sensor {0} schwach: {1}%",
+    "Batterie von Sensor {0} wieder bei {1}%",
+    "Ungewöhnlicher Messwert bei Sensor {0}: {1}",
+    "Sensor {0} wieder im üblichen Bereich: {1}",
+    "Alarm bei Sensor {0} verschärft: Messwert {1}",
+    "Alarm bei Sensor {0} abgeschwächt: Messwert {1}",
+    "Sensor {0} offline: seit {1} min nichts empfangen",
+    "Sensor {0} wieder online",
+];
+
+const ES: [&str; COUNT] = [
+    "Alarma en el sensor {0}: valor {1}",
+    "Alarma en el sensor {0} confirmada",
+    "Alarma en el sensor {0} finalizada, valor de nuevo en rango",
+    "Alarma en el sensor {0} sin confirmar durante {1} min, difundiendo",
+    "Emergencia en el sensor {0} desactivada",
+    "Suspensión en {0} s, pulse el botón para seguir activo",
+    "Suspensión cancelada",
+    "Entrando en suspensión",
+    "Sensor tocado: despertando",
+    "Batería del sensor {0} baja: {1}%",
+    "Batería del sensor {0} de nuevo al {1}%",
+    "Valor inusual en el sensor {0}: {1}",
+    "Sensor {0} de nuevo con valores habituales: {1}",
+    "Alarma en el sensor {0} más grave: valor {1}",
+    "Alarma en el sensor {0} menos grave: valor {1}",
+    "Sensor {0} desconectado: nada recibido en {1} min",
+    "Sensor {0} conectado de nuevo",
+];
+
+const FR: [&str; COUNT] = [
+    "Alarme sur le capteur {0} : valeur {1}",
+    "Alarme sur le capteur {0} acquittée",
+    "Alarme sur le capteur {0} terminée, valeur revenue dans la plage",
+    "Alarme sur le capteur {0} non acquittée depuis {1} min, diffusion en cours",
+    "Urgence sur le capteur {0} levée",
+    "Mise en veille dans {0} s, appuyez sur le bouton pour rester éveillé",
+    "Mise en veille annulée",
+    "Mise en veille",
+    "Capteur touché : réveil",
+    "Pile du capteur {0} faible : {1} %",
+    "Pile du capteur {0} revenue à {1} %",
+    "Valeur inhabituelle sur le capteur {0} : {1}",
+    "Capteur {0} revenu à des valeurs habituelles : {1}",
+    "Alarme sur le capteur {0} aggravée : valeur {1}",
+    "Alarme sur le capteur {0} atténuée : valeur {1}",
+    "Capteur {0} hors ligne : rien reçu depuis {1} min",
+    "Capteur {0} de nouveau en ligne",
+];
+
+fn table() -> &'static [&'static str; COUNT] {
+    match LANGUAGE {
+        Some("de") => &DE,
+        Some("es") => &ES,
+        Some("fr") => &FR,
+        _ => &EN,
+    }
+}
+
+/// The configured language code, `Err` with the code if it is not supported
+pub fn language() -> Result<&'static str, &'static str> {
+    match LANGUAGE {
+        None => Ok("en"),
+        Some(code @ ("en" | "de" | "es" | "fr")) => Ok(code),
+        Some(code) => Err(code),
+    }
+}
+
+/// `text` in the configured language with its parameters filled in
+pub fn text<'a>(message: Text, args: &'a [&'a dyn Display]) -> Localized<'a> {
+    Localized {
+        template: table()[message as usize],
+        args,
+    }
+}
+
+/// A message ready to be formatted, e.g. by `info!("{}", ..)`
+pub struct Localized<'a> {
+    template: &'static str,
+    args: &'a [&'a dyn Display],
+}
+
+impl Display for Localized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.template;
+        while let Some(start) = rest.find('{') {
+            f.write_str(&rest[..start])?;
+            let after = &rest[start + 1..];
+            let arg = after.find('}').and_then(|end| {
+                let index: usize = after[..end].parse().ok()?;
+                Some((self.args.get(index)?, end))
+            });
+            match arg {
+                Some((arg, end)) => {
+                    arg.fmt(f)?;
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    f.write_str("{")?;
+                    rest = after;
+                }
+            }
+        }
+        f.write_str(rest)
+    }
+}
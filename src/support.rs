@@ -0,0 +1,226 @@
+//! Remote assistance: verbose logs and diagnostics for a while
+//!
+//! For a hub installed where nobody can read its console, support mode
+//! turns the log level up to debug, including the ESP-IDF components, and
+//! streams the inspect report to `SUPPORT_ADDR` (`host:port`, set at build
+//! time) at a fixed interval, one UDP datagram per line, each snapshot
+//! opened by a `--- support <device> #<n> ---` line. It ends by itself after
+//! its time is up, or early with `support off`, and puts the log level back.
+//! Without `SUPPORT_ADDR` only the log level goes up.
+//!
+//! Started with `support on` on the console, or with `POST /support` while
+//! the hub is on WiFi; `DELETE /support` ends it and `GET /support` shows
+//! whether it runs. Debug lines above the `CONFIG_LOG_MAXIMUM_LEVEL` the
+//! firmware was built with are not compiled in and stay missing.
+//!
+//! `log level <level>` on the console sets the log level by hand, see
+//! [`set_log_level`]; during a session it is the level put back at the end.
+
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+use esp_idf_svc::sys::{
+    esp_log_level_set, esp_log_level_t, esp_log_level_t_ESP_LOG_DEBUG,
+    esp_log_level_t_ESP_LOG_ERROR, esp_log_level_t_ESP_LOG_INFO, esp_log_level_t_ESP_LOG_NONE,
+    esp_log_level_t_ESP_LOG_VERBOSE, esp_log_level_t_ESP_LOG_WARN, EspError,
+};
+use log::{info, warn, LevelFilter};
+
+use crate::clock;
+use crate::http::{authorize, refuse, respond};
+use crate::identity;
+
+const SUPPORT_ADDR: Option<&str> = option_env!("SUPPORT_ADDR");
+
+static SUPPORT: Mutex<Support> = Mutex::new(Support {
+    length: Duration::ZERO,
+    interval: Duration::ZERO,
+    session: None,
+});
+
+struct Support {
+    length: Duration,
+    interval: Duration,
+    session: Option<Session>,
+}
+
+struct Session {
+    until: Instant,
+    next_snapshot: Instant,
+    snapshots: u32,
+    socket: Option<UdpSocket>,
+    /// The log level to go back to
+    restore: LevelFilter,
+}
+
+/// Sessions last `length` and stream a snapshot every `interval`
+pub fn init(length: Duration, interval: Duration) {
+    let mut support = SUPPORT.lock().unwrap();
+    support.length = length;
+    support.interval = interval;
+}
+
+/// Start support mode, or restart its time if it runs
+pub fn start() -> Result<(), &'static str> {
+    let mut support = SUPPORT.lock().unwrap();
+    let until = clock::now() + support.length;
+    if let Some(session) = support.session.as_mut() {
+        session.until = until;
+        info!(
+            "Support mode extended, {} min",
+            support.length.as_secs() / 60
+        );
+        return Ok(());
+    }
+
+    let socket = match SUPPORT_ADDR {
+        Some(_) => Some(open_socket().map_err(|e| {
+            warn!("Support socket failed: {}", e);
+            "socket failed"
+        })?),
+        None => {
+            warn!("SUPPORT_ADDR not set at build time, support mode logs locally only");
+            None
+        }
+    };
+    let restore = log::max_level();
+    apply_log_level(LevelFilter::Debug);
+    support.session = Some(Session {
+        until,
+        next_snapshot: clock::now(),
+        snapshots: 0,
+        socket,
+        restore,
+    });
+    info!("Support mode on for {} min", support.length.as_secs() / 60);
+    Ok(())
+}
+
+/// End support mode early
+pub fn stop() -> Result<(), &'static str> {
+    let session = SUPPORT.lock().unwrap().session.take();
+    let session = session.ok_or("support mode is off")?;
+    end(session);
+    Ok(())
+}
+
+/// Time left in support mode, `None` while it is off
+pub fn remaining() -> Option<Duration> {
+    let support = SUPPORT.lock().unwrap();
+    let session = support.session.as_ref()?;
+    Some(session.until.saturating_duration_since(clock::now()))
+}
+
+pub fn summary() -> String {
+    match remaining() {
+        Some(left) => format!("Support mode on, {} s left", left.as_secs()),
+        None => "Support mode off".to_string(),
+    }
+}
+
+/// End an expired session, returns whether a snapshot is due; call once per
+/// main loop iteration
+pub fn poll() -> bool {
+    let mut support = SUPPORT.lock().unwrap();
+    let now = clock::now();
+    if support.session.as_ref().is_some_and(|s| now >= s.until) {
+        if let Some(session) = support.session.take() {
+            drop(support);
+            info!("Support mode time is up");
+            end(session);
+        }
+        return false;
+    }
+    let interval = support.interval;
+    match support.session.as_mut() {
+        Some(session) if now >= session.next_snapshot => {
+            session.next_snapshot = now + interval;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Stream a snapshot, the inspect report's lines
+pub fn send<'a>(lines: impl Iterator<Item = &'a str>) {
+    let mut support = SUPPORT.lock().unwrap();
+    let Some(session) = support.session.as_mut() else {
+        return;
+    };
+    let (Some(socket), Some(addr)) = (session.socket.as_ref(), SUPPORT_ADDR) else {
+        return;
+    };
+    session.snapshots += 1;
+    let header = format!(
+        "--- support {} #{} ---",
+        identity::uuid(),
+        session.snapshots
+    );
+    if let Err(e) = socket.send_to(header.as_bytes(), addr) {
+        warn!("Support snapshot not sent: {}", e);
+        return;
+    }
+    for line in lines {
+        if let Err(e) = socket.send_to(line.as_bytes(), addr) {
+            warn!("Support snapshot cut short: {}", e);
+            return;
+        }
+    }
+}
+
+/// Serve `POST`, `DELETE` and `GET /support` on `server`
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/support", Method::Post, |req| match authorize(&req) {
+        Ok(()) => respond(req, start()),
+        Err(e) => refuse(req, e),
+    })?;
+    server.fn_handler("/support", Method::Delete, |req| match authorize(&req) {
+        Ok(()) => respond(req, stop()),
+        Err(e) => refuse(req, e),
+    })?;
+    server.fn_handler("/support", Method::Get, |req| {
+        let mut summary = summary();
+        summary.push('\n');
+        req.into_ok_response()?.write_all(summary.as_bytes())
+    })?;
+    info!("Support mode served on /support");
+    Ok(())
+}
+
+fn open_socket() -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Set the log level, of the ESP-IDF components too; while support mode
+/// runs it takes effect once the session ends
+pub fn set_log_level(level: LevelFilter) {
+    let mut support = SUPPORT.lock().unwrap();
+    match support.session.as_mut() {
+        Some(session) => session.restore = level,
+        None => apply_log_level(level),
+    }
+}
+
+fn end(session: Session) {
+    apply_log_level(session.restore);
+    info!("Support mode off, {} snapshots streamed", session.snapshots);
+}
+
+fn apply_log_level(level: LevelFilter) {
+    log::set_max_level(level);
+    let esp_level: esp_log_level_t = match level {
+        LevelFilter::Off => esp_log_level_t_ESP_LOG_NONE,
+        LevelFilter::Error => esp_log_level_t_ESP_LOG_ERROR,
+        LevelFilter::Warn => esp_log_level_t_ESP_LOG_WARN,
+        LevelFilter::Info => esp_log_level_t_ESP_LOG_INFO,
+        LevelFilter::Debug => esp_log_level_t_ESP_LOG_DEBUG,
+        LevelFilter::Trace => esp_log_level_t_ESP_LOG_VERBOSE,
+    };
+    unsafe { esp_log_level_set(c"*".as_ptr(), esp_level) };
+}
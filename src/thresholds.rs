@@ -0,0 +1,884 @@
+//! Alarm thresholds and learning them from normal readings
+//!
+//! Every thermostat topic has a [`Rule`]: the side of the limit that raises
+//! its alarm and a default limit. A limit can be set by hand, or learned:
+//! `learn <topic_id> [hours]` watches the topic for a day or two (24 h by
+//! default, at most 48) and, once done, proposes a limit just past the range
+//! it saw, by a quarter of that range and at least [`MIN_MARGIN`]. The
+//! proposal only takes effect after `learn accept`, so a sensor that was off
+//! or misplaced while learning changes nothing.
+//!
+//! A rule may add further [`Band`]s past its limit, each with a priority of
+//! its own, e.g. the kettle at info above 50, warning above 70 and critical
+//! above 95. Past the limit, the highest band a reading is in decides the
+//! priority, and so the buzzer pattern and the severity the uplinks report;
+//! short of the limit the bands do not alarm, so a limit raised past a band
+//! silences it up to the limit. The bands are built in per rule and can be
+//! replaced on the console, `bands <topic> <n> <priority> ...`, `bands
+//! <topic> off` or `bands <topic> default`, stored next to the limits.
+//!
+//! A rule may also require other topics to agree before alarming, e.g. the
+//! kettle only alarms on temperature when its sound sensor went off within
+//! the last 30 s, so one noisy sensor alone cannot raise it. Each
+//! [`Condition`] is met while its topic read past its limit recently enough.
+//!
+//! A limit can also be tightened for a while without being stored, moved
+//! towards the normal side by some amount along with its bands, e.g. the
+//! sink frost alarm on a night forecast below freezing, see the forecast
+//! module.
+//!
+//! Limits, bands and a learning run in progress are kept in NVS, so they
+//! survive resets; learning resumes where it stopped.
+
+use core::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::alerts::Priority;
+use crate::clock;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+
+const NAMESPACE: &str = "thresholds";
+const TABLE: &str = "table";
+const LEARNING: &str = "learning";
+const MAX_TABLE: usize = 512;
+const MAX_LEARNING: usize = 64;
+/// Most bands a rule can have
+pub const MAX_BANDS: usize = 4;
+
+pub const DEFAULT_LEARN_HOURS: u32 = 24;
+pub const MAX_LEARN_HOURS: u32 = 48;
+/// Smallest distance between the learned range and the proposed limit
+const MIN_MARGIN: i32 = 2;
+// Readings needed before a proposal is made at all
+const MIN_READINGS: u32 = 10;
+// How often a learning run's progress is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static STATE: Mutex<State> = Mutex::new(State {
+    rules: &[],
+    limits: Vec::new(),
+    bands: Vec::new(),
+    met: Vec::new(),
+    learning: None,
+    proposal: None,
+    tightened: Vec::new(),
+    nvs: None,
+});
+
+/// Which side of the limit raises the alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Above(i32),
+    Below(i32),
+}
+
+impl Limit {
+    fn breached(self, measurement: i32) -> bool {
+        match self {
+            Limit::Above(limit) => measurement > limit,
+            Limit::Below(limit) => measurement < limit,
+        }
+    }
+
+    fn with(self, value: i32) -> Self {
+        match self {
+            Limit::Above(_) => Limit::Above(value),
+            Limit::Below(_) => Limit::Below(value),
+        }
+    }
+
+    /// The limit moved `by` towards the side that does not alarm
+    fn tightened(self, by: i32) -> Self {
+        match self {
+            Limit::Above(limit) => Limit::Above(limit.saturating_sub(by)),
+            Limit::Below(limit) => Limit::Below(limit.saturating_add(by)),
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Above(limit) => write!(f, "above {}", limit),
+            Limit::Below(limit) => write!(f, "below {}", limit),
+        }
+    }
+}
+
+/// A topic with a threshold alarm
+pub struct Rule {
+    pub topic_id: i32,
+    /// Default limit, until one is set or learned
+    pub alarm: Limit,
+    pub priority: Priority,
+    /// Further limits with their own priority
+    pub bands: &'static [Band],
+    /// Further conditions, all of which must hold as well
+    pub requires: &'static [Condition],
+}
+
+/// A limit past which a rule's alarm takes `priority`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Band {
+    pub limit: Limit,
+    pub priority: Priority,
+}
+
+/// Another topic that must have read past `limit` within the last `within`
+pub struct Condition {
+    pub topic_id: i32,
+    pub limit: Limit,
+    pub within: Duration,
+}
+
+struct Learning {
+    topic_id: i32,
+    length: Duration,
+    /// Learned before the last reset
+    carried: Duration,
+    since: Instant,
+    saved: Instant,
+    min: i32,
+    max: i32,
+    readings: u32,
+}
+
+impl Learning {
+    fn elapsed(&self) -> Duration {
+        self.carried + clock::since(self.since)
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "{} {} {} {} {} {}",
+            self.topic_id,
+            self.length.as_secs(),
+            self.elapsed().as_secs(),
+            self.min,
+            self.max,
+            self.readings
+        )
+    }
+
+    fn decode(text: &str) -> Option<Self> {
+        let mut fields = text.split_whitespace().map(str::parse::<i64>);
+        let mut next = || fields.next()?.ok();
+        let (topic_id, length, elapsed) = (next()?, next()?, next()?);
+        let (min, max, readings) = (next()?, next()?, next()?);
+        let now = clock::now();
+        Some(Self {
+            topic_id: topic_id as i32,
+            length: Duration::from_secs(length as u64),
+            carried: Duration::from_secs(elapsed as u64),
+            since: now,
+            saved: now,
+            min: min as i32,
+            max: max as i32,
+            readings: readings as u32,
+        })
+    }
+}
+
+struct State {
+    rules: &'static [Rule],
+    /// Limits set or learned, overriding the rule defaults
+    limits: Vec<(i32, Limit)>,
+    /// Bands set on the console, overriding the rule's
+    bands: Vec<(i32, Vec<Band>)>,
+    /// When each condition, by rule and condition index, was last met
+    met: Vec<((usize, usize), Instant)>,
+    learning: Option<Learning>,
+    proposal: Option<(i32, Limit)>,
+    /// Temporary tightening per topic, not stored
+    tightened: Vec<(i32, i32)>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl State {
+    fn rule(&self, topic_id: i32) -> Result<&'static Rule, &'static str> {
+        self.rules
+            .iter()
+            .find(|r| r.topic_id == topic_id)
+            .ok_or("no threshold alarm on this topic")
+    }
+
+    /// The limit in force, tightening included
+    fn limit(&self, rule: &Rule) -> Limit {
+        self.stored_limit(rule).tightened(self.tightening(rule))
+    }
+
+    /// The bands in force, tightened along with the limit
+    fn bands(&self, rule: &Rule) -> Vec<Band> {
+        let by = self.tightening(rule);
+        self.stored_bands(rule)
+            .iter()
+            .map(|band| Band {
+                limit: band.limit.tightened(by),
+                priority: band.priority,
+            })
+            .collect()
+    }
+
+    fn tightening(&self, rule: &Rule) -> i32 {
+        self.tightened
+            .iter()
+            .find(|(id, _)| *id == rule.topic_id)
+            .map_or(0, |&(_, by)| by)
+    }
+
+    fn stored_bands<'a>(&'a self, rule: &'a Rule) -> &'a [Band] {
+        self.bands
+            .iter()
+            .find(|(id, _)| *id == rule.topic_id)
+            .map_or(rule.bands, |(_, bands)| bands)
+    }
+
+    fn stored_limit(&self, rule: &Rule) -> Limit {
+        self.limits
+            .iter()
+            .find(|(id, _)| *id == rule.topic_id)
+            .map_or(rule.alarm, |(_, limit)| *limit)
+    }
+
+    fn requirements_met(&self, rule: usize) -> bool {
+        self.rules[rule]
+            .requires
+            .iter()
+            .enumerate()
+            .all(|(i, condition)| {
+                self.met
+                    .iter()
+                    .find(|(key, _)| *key == (rule, i))
+                    .is_some_and(|(_, at)| clock::since(*at) <= condition.within)
+            })
+    }
+
+    fn table(&self) -> String {
+        let mut table = String::new();
+        for (topic_id, limit) in &self.limits {
+            table.push_str(&format!("{} {}\n", topic_id, limit));
+        }
+        for (topic_id, bands) in &self.bands {
+            table.push_str(&format!("{} bands", topic_id));
+            if bands.is_empty() {
+                table.push_str(" off");
+            }
+            for band in bands {
+                let (Limit::Above(value) | Limit::Below(value)) = band.limit;
+                table.push_str(&format!(" {} {}", value, band.priority.name()));
+            }
+            table.push('\n');
+        }
+        table
+    }
+
+    fn add(&mut self, entry: Entry) {
+        match entry {
+            Entry::Limit(topic_id, limit) => self.limits.push((topic_id, limit)),
+            Entry::Bands(topic_id, bands) => self.bands.push((topic_id, bands)),
+        }
+    }
+
+    fn set(&mut self, topic_id: i32, limit: Limit) -> Result<(), &'static str> {
+        match self.limits.iter_mut().find(|(id, _)| *id == topic_id) {
+            Some(entry) => entry.1 = limit,
+            None => self.limits.push((topic_id, limit)),
+        }
+        self.save()
+    }
+
+    fn save(&mut self) -> Result<(), &'static str> {
+        let table = self.table();
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable")?;
+        nvs.set_blob(TABLE, table.as_bytes()).map_err(|e| {
+            warn!("Failed to save thresholds: {}", e);
+            "write failed"
+        })
+    }
+
+    fn save_learning(&mut self) {
+        let Some(nvs) = self.nvs.as_mut() else {
+            return;
+        };
+        let result = match self.learning.as_mut() {
+            Some(learning) => {
+                learning.saved = clock::now();
+                nvs.set_blob(LEARNING, learning.encode().as_bytes())
+            }
+            None => nvs.remove(LEARNING).map(|_| ()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to save the learning run: {}", e);
+        }
+    }
+}
+
+/// Use `rules`, loading the stored limits and any unfinished learning run
+///
+/// With `partition` `None` the built-in limits apply and changes are not kept.
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    rules: &'static [Rule],
+) -> Result<(), EspError> {
+    let mut state = STATE.lock().unwrap();
+    state.rules = rules;
+    state.limits.clear();
+    state.bands.clear();
+
+    let Some(partition) = partition else {
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_TABLE];
+    let table = nvs.get_blob(TABLE, &mut buf)?.unwrap_or_default();
+    for line in String::from_utf8_lossy(table).lines() {
+        match parse_line(&state, line) {
+            Ok(entry) => state.add(entry),
+            Err(e) => warn!("Stored threshold {:?} dropped: {}", line, e),
+        }
+    }
+    let mut buf = vec![0u8; MAX_LEARNING];
+    if let Some(learning) = nvs.get_blob(LEARNING, &mut buf)? {
+        state.learning = Learning::decode(&String::from_utf8_lossy(learning));
+        if let Some(learning) = state.learning.as_ref() {
+            info!(
+                "Learning topic {} resumed, {} of {} h done",
+                learning.topic_id,
+                learning.carried.as_secs() / 3600,
+                learning.length.as_secs() / 3600
+            );
+        }
+    }
+    state.nvs = Some(nvs);
+    Ok(())
+}
+
+/// The priority at which `measurement` raises the alarm of `topic_id`, past
+/// the limit from the highest band it is in, or `Some(None)` when in range;
+/// `None` for topics without a threshold alarm
+pub fn check(topic_id: i32, measurement: i32) -> Option<Option<Priority>> {
+    let state = STATE.lock().unwrap();
+    let i = state.rules.iter().position(|r| r.topic_id == topic_id)?;
+    let rule = &state.rules[i];
+    if !state.requirements_met(i) || !state.limit(rule).breached(measurement) {
+        return Some(None);
+    }
+    Some(Some(
+        state
+            .bands(rule)
+            .iter()
+            .filter(|band| band.limit.breached(measurement))
+            .map(|band| band.priority)
+            .fold(rule.priority, Priority::max),
+    ))
+}
+
+/// Whether `topic_id` has a threshold alarm
+pub fn has_rule(topic_id: i32) -> bool {
+    STATE
+        .lock()
+        .unwrap()
+        .rules
+        .iter()
+        .any(|r| r.topic_id == topic_id)
+}
+
+/// Set the limit of `topic_id`, keeping which side alarms
+pub fn set(topic_id: i32, value: i32) -> Result<Limit, &'static str> {
+    let mut state = STATE.lock().unwrap();
+    let limit = state.rule(topic_id)?.alarm.with(value);
+    state.set(topic_id, limit)?;
+    Ok(limit)
+}
+
+/// Replace the bands of `topic_id` with `bands`, each a limit on the side
+/// that alarms and its priority, or go back to the built-in ones with `None`
+pub fn set_bands(topic_id: i32, bands: Option<&[(i32, Priority)]>) -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    let rule = state.rule(topic_id)?;
+    state.bands.retain(|(id, _)| *id != topic_id);
+    if let Some(bands) = bands {
+        if bands.len() > MAX_BANDS {
+            return Err("at most 4 bands");
+        }
+        let bands = bands
+            .iter()
+            .map(|&(value, priority)| Band {
+                limit: rule.alarm.with(value),
+                priority,
+            })
+            .collect();
+        state.bands.push((topic_id, bands));
+    }
+    state.save()
+}
+
+/// Tighten the limit of `topic_id` by `by` until called again, `None`
+/// undoing it; returns the limit now in force
+pub fn tighten(topic_id: i32, by: Option<i32>) -> Result<Limit, &'static str> {
+    let mut state = STATE.lock().unwrap();
+    let rule = state.rule(topic_id)?;
+    state.tightened.retain(|(id, _)| *id != topic_id);
+    if let Some(by) = by {
+        state.tightened.push((topic_id, by));
+    }
+    Ok(state.limit(rule))
+}
+
+/// The limit in force on every threshold topic as `<topic_id> <limit>
+/// <priority>` lines, with its bands and the conditions it requires
+pub fn summary() -> String {
+    let state = STATE.lock().unwrap();
+    let mut summary = String::new();
+    for rule in state.rules {
+        summary.push_str(&format!(
+            "{} {} {}",
+            rule.topic_id,
+            state.limit(rule),
+            rule.priority.name()
+        ));
+        if let Some((_, by)) = state.tightened.iter().find(|(id, _)| *id == rule.topic_id) {
+            summary.push_str(&format!(" (tightened by {})", by));
+        }
+        for band in state.bands(rule) {
+            summary.push_str(&format!(", {} {}", band.limit, band.priority.name()));
+        }
+        for condition in rule.requires {
+            summary.push_str(&format!(
+                " and {} {} within {} s",
+                condition.topic_id,
+                condition.limit,
+                condition.within.as_secs()
+            ));
+        }
+        summary.push('\n');
+    }
+    summary
+}
+
+/// Every limit set or learned, in the `<topic_id> <above|below> <n>` format,
+/// then the bands set, as `<topic_id> bands <n> <priority> ...` or `off`
+pub fn list() -> String {
+    STATE.lock().unwrap().table()
+}
+
+/// A limit or the bands of a topic, a line of the [`list`] format
+pub enum Entry {
+    Limit(i32, Limit),
+    Bands(i32, Vec<Band>),
+}
+
+/// Parse a table in the [`list`] format
+pub fn parse_table(table: &str) -> Result<Vec<Entry>, &'static str> {
+    let state = STATE.lock().unwrap();
+    table
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| parse_line(&state, line))
+        .collect()
+}
+
+/// Replace every set limit and band with those in `table`, in the [`list`]
+/// format
+pub fn replace(table: &str) -> Result<(), &'static str> {
+    let entries = parse_table(table)?;
+    let mut state = STATE.lock().unwrap();
+    state.limits.clear();
+    state.bands.clear();
+    for entry in entries {
+        state.add(entry);
+    }
+    state.save()
+}
+
+/// Start learning the normal range of `topic_id` over `hours`
+///
+/// Replaces a run in progress and any proposal not yet accepted.
+pub fn learn(topic_id: i32, hours: u32) -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    state.rule(topic_id)?;
+    let now = clock::now();
+    state.learning = Some(Learning {
+        topic_id,
+        length: Duration::from_secs(u64::from(hours) * 3600),
+        carried: Duration::ZERO,
+        since: now,
+        saved: now,
+        min: i32::MAX,
+        max: i32::MIN,
+        readings: 0,
+    });
+    state.proposal = None;
+    state.save_learning();
+    info!("Learning topic {} for {} h", topic_id, hours);
+    Ok(())
+}
+
+/// Feed a reading to the rule conditions and the learning run
+pub fn observe(topic_id: i32, measurement: i32) {
+    let mut state = STATE.lock().unwrap();
+    let now = clock::now();
+    let rules = state.rules;
+    for (i, rule) in rules.iter().enumerate() {
+        for (j, condition) in rule.requires.iter().enumerate() {
+            if condition.topic_id != topic_id || !condition.limit.breached(measurement) {
+                continue;
+            }
+            match state.met.iter_mut().find(|(key, _)| *key == (i, j)) {
+                Some(entry) => entry.1 = now,
+                None => state.met.push(((i, j), now)),
+            }
+        }
+    }
+
+    let Some(learning) = state.learning.as_mut() else {
+        return;
+    };
+    if learning.topic_id == topic_id {
+        learning.min = learning.min.min(measurement);
+        learning.max = learning.max.max(measurement);
+        learning.readings += 1;
+    }
+}
+
+/// Finish the learning run once its time is up, proposing a limit
+pub fn poll() {
+    let mut state = STATE.lock().unwrap();
+    let Some(learning) = state.learning.as_ref() else {
+        return;
+    };
+    if learning.elapsed() < learning.length {
+        if clock::since(learning.saved) >= SAVE_INTERVAL {
+            state.save_learning();
+        }
+        return;
+    }
+
+    let learning = state.learning.take().unwrap();
+    state.save_learning();
+    if learning.readings < MIN_READINGS {
+        warn!(
+            "Learning topic {} failed: only {} readings",
+            learning.topic_id, learning.readings
+        );
+        return;
+    }
+    let Ok(rule) = state.rule(learning.topic_id) else {
+        return;
+    };
+    let spread = learning.max.abs_diff(learning.min) / 4;
+    let margin = i32::try_from(spread).unwrap_or(i32::MAX).max(MIN_MARGIN);
+    let proposal = match rule.alarm {
+        Limit::Above(_) => Limit::Above(learning.max.saturating_add(margin)),
+        Limit::Below(_) => Limit::Below(learning.min.saturating_sub(margin)),
+    };
+    info!(
+        "Learned topic {}: {} to {} over {} readings, proposing alarm {} (now {}); `learn accept` applies it",
+        learning.topic_id,
+        learning.min,
+        learning.max,
+        learning.readings,
+        proposal,
+        state.stored_limit(rule)
+    );
+    state.proposal = Some((learning.topic_id, proposal));
+}
+
+/// What is being learned or proposed, for the console
+pub fn status() -> String {
+    let state = STATE.lock().unwrap();
+    match (state.learning.as_ref(), state.proposal) {
+        (Some(l), _) if l.readings > 0 => format!(
+            "Learning topic {}: {} of {} h, {} to {} over {} readings",
+            l.topic_id,
+            l.elapsed().as_secs() / 3600,
+            l.length.as_secs() / 3600,
+            l.min,
+            l.max,
+            l.readings
+        ),
+        (Some(l), _) => format!(
+            "Learning topic {}: {} of {} h, no readings yet",
+            l.topic_id,
+            l.elapsed().as_secs() / 3600,
+            l.length.as_secs() / 3600
+        ),
+        (None, Some((topic_id, limit))) => {
+            format!("Proposed for topic {}: alarm {}", topic_id, limit)
+        }
+        (None, None) => "Not learning".to_string(),
+    }
+}
+
+/// Apply the proposed limit
+pub fn accept() -> Result<(i32, Limit), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    let (topic_id, limit) = state.proposal.take().ok_or("no proposal to accept")?;
+    state.set(topic_id, limit)?;
+    Ok((topic_id, limit))
+}
+
+/// Stop learning, or drop the proposal
+pub fn cancel() -> Result<(), &'static str> {
+    let mut state = STATE.lock().unwrap();
+    if state.learning.is_none() && state.proposal.is_none() {
+        return Err("not learning");
+    }
+    state.learning = None;
+    state.proposal = None;
+    state.save_learning();
+    Ok(())
+}
+
+fn parse_line(state: &State, line: &str) -> Result<Entry, &'static str> {
+    let mut words = line.split_whitespace();
+    let (Some(topic_id), Some(side), Some(value)) = (words.next(), words.next(), words.next())
+    else {
+        return Err("expected <topic_id> <above|below> <n>");
+    };
+    let topic_id = topic_id.parse().map_err(|_| "invalid topic id")?;
+    if side == "bands" {
+        return parse_bands(state, topic_id, value, words);
+    }
+    if words.next().is_some() {
+        return Err("expected <topic_id> <above|below> <n>");
+    }
+    let value = value.parse().map_err(|_| "invalid limit")?;
+    let limit = match side {
+        "above" => Limit::Above(value),
+        "below" => Limit::Below(value),
+        _ => return Err("expected above or below"),
+    };
+    if state.rule(topic_id)?.alarm.with(value) != limit {
+        return Err("an alarm cannot change sides");
+    }
+    Ok(Entry::Limit(topic_id, limit))
+}
+
+/// The rest of a `<topic_id> bands ...` line, from its first word on
+fn parse_bands<'a>(
+    state: &State,
+    topic_id: i32,
+    first: &'a str,
+    rest: impl Iterator<Item = &'a str>,
+) -> Result<Entry, &'static str> {
+    let side = state.rule(topic_id)?.alarm;
+    let words: Vec<&str> = std::iter::once(first).chain(rest).collect();
+    if words == ["off"] {
+        return Ok(Entry::Bands(topic_id, Vec::new()));
+    }
+    if words.len() % 2 != 0 || words.len() > MAX_BANDS * 2 {
+        return Err("expected up to 4 <n> <priority> pairs or off");
+    }
+    let bands = words
+        .chunks(2)
+        .map(|pair| {
+            Ok(Band {
+                limit: side.with(pair[0].parse().map_err(|_| "invalid limit")?),
+                priority: Priority::parse(pair[1]).ok_or("expected info, warning or critical")?,
+            })
+        })
+        .collect::<Result<_, &'static str>>()?;
+    Ok(Entry::Bands(topic_id, bands))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::MutexGuard;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    // One rule set for every test, each on topics of its own, as the state
+    // is shared
+    const KETTLE: i32 = 1;
+    const SINK: i32 = 2;
+    const TIGHTENED: i32 = 3;
+    const AGREED: i32 = 4;
+    const SOUND: i32 = 5;
+    const LEARNED: i32 = 6;
+    const RAISED: i32 = 7;
+
+    static BANDS: [Band; 2] = [
+        Band {
+            limit: Limit::Above(70),
+            priority: Priority::Warning,
+        },
+        Band {
+            limit: Limit::Above(95),
+            priority: Priority::Critical,
+        },
+    ];
+    static SOUND_WITHIN: Duration = Duration::from_secs(30);
+    static REQUIRES: [Condition; 1] = [Condition {
+        topic_id: SOUND,
+        limit: Limit::Above(0),
+        within: SOUND_WITHIN,
+    }];
+    static RULES: [Rule; 6] = [
+        rule(KETTLE, Limit::Above(50), &BANDS, &[]),
+        rule(SINK, Limit::Below(5), &[], &[]),
+        rule(TIGHTENED, Limit::Below(5), &[], &[]),
+        rule(AGREED, Limit::Above(50), &[], &REQUIRES),
+        rule(LEARNED, Limit::Above(50), &[], &[]),
+        rule(RAISED, Limit::Above(50), &BANDS, &[]),
+    ];
+
+    const fn rule(
+        topic_id: i32,
+        alarm: Limit,
+        bands: &'static [Band],
+        requires: &'static [Condition],
+    ) -> Rule {
+        Rule {
+            topic_id,
+            alarm,
+            priority: Priority::Info,
+            bands,
+            requires,
+        }
+    }
+
+    /// The rules above, on the mock clock; other tests set rules of their
+    /// own, so hold the turn for the whole case
+    fn rules() -> (MutexGuard<'static, ()>, &'static MockClock) {
+        let turn = clock::mock();
+        init(None, &RULES).unwrap();
+        turn
+    }
+
+    #[test]
+    fn raised_limits_silence_the_bands_short_of_them() {
+        let _turn = rules();
+        set(RAISED, 80).unwrap();
+        assert_eq!(check(RAISED, 75), Some(None));
+        assert_eq!(check(RAISED, 85), Some(Some(Priority::Warning)));
+        assert_eq!(check(RAISED, 96), Some(Some(Priority::Critical)));
+    }
+
+    #[test]
+    fn tightening_moves_the_bands_along() {
+        let _turn = rules();
+        tighten(RAISED, Some(10)).unwrap();
+        let tightened = (check(RAISED, 45), check(RAISED, 65), check(RAISED, 90));
+        tighten(RAISED, None).unwrap();
+        assert_eq!(
+            tightened,
+            (
+                Some(Some(Priority::Info)),
+                Some(Some(Priority::Warning)),
+                Some(Some(Priority::Critical))
+            )
+        );
+    }
+
+    #[test]
+    fn bands_are_stored_next_to_the_limits() {
+        let _turn = rules();
+        let nvs = EspDefaultNvsPartition::take().unwrap();
+        init(Some(nvs.clone()), &RULES).unwrap();
+        set(RAISED, 60).unwrap();
+        set_bands(RAISED, Some(&[(90, Priority::Critical)])).unwrap();
+        assert_eq!(list(), "7 above 60\n7 bands 90 critical\n");
+
+        init(Some(nvs), &RULES).unwrap();
+        assert_eq!(check(RAISED, 75), Some(Some(Priority::Info)));
+        assert_eq!(check(RAISED, 91), Some(Some(Priority::Critical)));
+        set_bands(RAISED, None).unwrap();
+        assert_eq!(check(RAISED, 75), Some(Some(Priority::Warning)));
+        assert!(set_bands(RAISED, Some(&[(60, Priority::Info); MAX_BANDS + 1])).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn bands_never_alarm_short_of_the_limit(
+            limit in -100..200,
+            measurement in -1000..1000,
+        ) {
+            let _turn = rules();
+            set(RAISED, limit).unwrap();
+            let alarmed = check(RAISED, measurement).flatten().is_some();
+            prop_assert_eq!(alarmed, measurement > limit);
+        }
+
+        #[test]
+        fn never_alarms_on_the_normal_side(measurement in -1000..=50) {
+            let _turn = rules();
+            prop_assert_eq!(check(KETTLE, measurement), Some(None));
+        }
+
+        #[test]
+        fn always_alarms_past_the_limit(measurement in 51..1000) {
+            let _turn = rules();
+            let priority = check(KETTLE, measurement).flatten();
+            prop_assert!(priority >= Some(Priority::Info));
+        }
+
+        #[test]
+        fn priority_rises_with_the_reading(a in -1000i32..1000, b in -1000i32..1000) {
+            let _turn = rules();
+            let (low, high) = (a.min(b), a.max(b));
+            prop_assert!(check(KETTLE, low).flatten() <= check(KETTLE, high).flatten());
+        }
+
+        #[test]
+        fn below_limits_alarm_only_below(measurement in any::<i32>()) {
+            let _turn = rules();
+            let alarmed = check(SINK, measurement).flatten().is_some();
+            prop_assert_eq!(alarmed, measurement < 5);
+        }
+
+        #[test]
+        fn tightening_never_lets_an_alarm_through(
+            measurement in -1000..1000,
+            by in 0..100,
+        ) {
+            let _turn = rules();
+            tighten(TIGHTENED, None).unwrap();
+            let before = check(TIGHTENED, measurement).flatten();
+            tighten(TIGHTENED, Some(by)).unwrap();
+            let after = check(TIGHTENED, measurement).flatten();
+            tighten(TIGHTENED, None).unwrap();
+            prop_assert!(before.is_none() || after.is_some());
+        }
+
+        #[test]
+        fn conditions_hold_for_their_window_only(
+            measurement in 51..1000,
+            secs in 0u64..120,
+        ) {
+            let (_turn, clock) = rules();
+            observe(SOUND, 1);
+            clock.advance(Duration::from_secs(secs));
+            let alarmed = check(AGREED, measurement).flatten().is_some();
+            prop_assert_eq!(alarmed, Duration::from_secs(secs) <= SOUND_WITHIN);
+            // Outlast the window, so the next case starts without it
+            clock.advance(SOUND_WITHIN * 2);
+        }
+
+        #[test]
+        fn learned_limits_clear_the_learned_range(
+            readings in proptest::collection::vec(-1000..1000, MIN_READINGS as usize..50),
+        ) {
+            let (_turn, clock) = rules();
+            learn(LEARNED, 1).unwrap();
+            for &measurement in &readings {
+                observe(LEARNED, measurement);
+            }
+            clock.advance(Duration::from_secs(3600));
+            poll();
+            let proposal = STATE.lock().unwrap().proposal.take();
+            let Some((LEARNED, Limit::Above(limit))) = proposal else {
+                return Err(TestCaseError::fail(format!("proposed {:?}", proposal)));
+            };
+            let max = *readings.iter().max().unwrap();
+            prop_assert!(limit >= max + MIN_MARGIN);
+        }
+    }
+}
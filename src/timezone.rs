@@ -0,0 +1,230 @@
+//! Local time, for schedules and log timestamps
+//!
+//! The RTC runs on UTC and every timestamp the hub stores or sends stays
+//! UTC. Anything that follows the calendar on the wall, the profile month
+//! schedule among them, goes through [`local`] instead, which applies the
+//! configured timezone including its daylight saving rules, so schedules
+//! move with the clocks. The ESP-IDF log prints its timestamps in local time
+//! too (`CONFIG_LOG_TIMESTAMP_SOURCE_SYSTEM`), once the clock is set.
+//!
+//! A zone is a POSIX TZ string, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`, or one of
+//! the IANA names in [`ZONES`], which stand for theirs; the firmware carries
+//! no zone database. The `[clock] timezone` default is compiled in;
+//! `timezone <zone>` on the console overrides it in NVS and applies it
+//! right away.
+
+use std::ffi::CString;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::clock;
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::{localtime_r, setenv, time_t, tm, tzset, EspError};
+
+const NAMESPACE: &str = "clock";
+const ZONE: &str = "tz";
+const MAX_ZONE: usize = 64;
+
+/// IANA names understood, with the POSIX rule each stands for
+pub const ZONES: &[(&str, &str)] = &[
+    ("UTC", "UTC0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Dublin", "IST-1GMT0,M10.5.0,M3.5.0/1"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Madrid", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Rome", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+];
+
+static TIMEZONE: Mutex<Timezone> = Mutex::new(Timezone {
+    default: "UTC",
+    zone: None,
+    nvs: None,
+});
+
+struct Timezone {
+    default: &'static str,
+    /// Set from the console, overriding the default
+    zone: Option<String>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Timezone {
+    fn zone(&self) -> &str {
+        self.zone.as_deref().unwrap_or(self.default)
+    }
+}
+
+/// A moment on the wall clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    /// 0 for Sunday to 6
+    pub weekday: u32,
+    /// Daylight saving time is in effect
+    pub dst: bool,
+}
+
+/// Take `default` unless NVS holds a zone set on the console, and apply it
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    default: &'static str,
+) -> Result<(), EspError> {
+    let mut timezone = TIMEZONE.lock().unwrap();
+    timezone.default = default;
+    if let Err(e) = apply(default) {
+        warn!(
+            "Default timezone {:?} not applied: {}, using UTC",
+            default, e
+        );
+        apply("UTC").ok();
+    }
+    let Some(partition) = partition else {
+        info!("Timezone {}", default);
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = [0u8; MAX_ZONE + 1];
+    if let Some(zone) = nvs.get_str(ZONE, &mut buf)? {
+        match apply(zone) {
+            Ok(()) => timezone.zone = Some(zone.to_string()),
+            Err(e) => warn!("Stored timezone {:?} dropped: {}", zone, e),
+        }
+    }
+    timezone.nvs = Some(nvs);
+    info!("Timezone {}", timezone.zone());
+    Ok(())
+}
+
+/// Switch to `zone`, or back to the default with `None`, stored for the
+/// next boot too
+pub fn set(zone: Option<&str>) -> Result<(), &'static str> {
+    let mut timezone = TIMEZONE.lock().unwrap();
+    apply(zone.unwrap_or(timezone.default))?;
+    timezone.zone = zone.map(str::to_string);
+    let nvs = timezone.nvs.as_mut().ok_or("NVS unavailable, not stored")?;
+    let stored = match zone {
+        Some(zone) => nvs.set_str(ZONE, zone),
+        None => nvs.remove(ZONE).map(|_| ()),
+    };
+    stored.map_err(|e| {
+        warn!("Failed to store the timezone: {}", e);
+        "write failed"
+    })
+}
+
+/// The zone set on the console, or `default`, for the config backup
+pub fn list() -> String {
+    let timezone = TIMEZONE.lock().unwrap();
+    timezone
+        .zone
+        .clone()
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// Check a zone in the [`list`] format
+pub fn check(text: &str) -> Result<(), &'static str> {
+    match text.trim() {
+        "default" => Ok(()),
+        zone => resolve(zone).map(|_| ()),
+    }
+}
+
+/// Switch to a zone in the [`list`] format
+pub fn replace(text: &str) -> Result<(), &'static str> {
+    check(text)?;
+    match text.trim() {
+        "default" => set(None),
+        zone => set(Some(zone)),
+    }
+}
+
+/// The zone in use and the local time, for the console
+pub fn summary() -> String {
+    let timezone = TIMEZONE.lock().unwrap();
+    let source = if timezone.zone.is_some() {
+        "set"
+    } else {
+        "default"
+    };
+    let now = local(clock::unix_secs());
+    format!(
+        "Timezone {} ({}), local time {}-{:02}-{:02} {:02}:{:02}{}",
+        timezone.zone(),
+        source,
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        if now.dst { " DST" } else { "" }
+    )
+}
+
+/// The listed zone names, for the console
+pub fn names() -> String {
+    let names: Vec<&str> = ZONES.iter().map(|(name, _)| *name).collect();
+    names.join(" ")
+}
+
+/// `unix_secs` on the wall clock of the configured timezone
+pub fn local(unix_secs: u32) -> LocalTime {
+    let secs = time_t::from(unix_secs);
+    let mut t = tm::default();
+    unsafe { localtime_r(&secs, &mut t) };
+    LocalTime {
+        year: t.tm_year + 1900,
+        month: (t.tm_mon + 1) as u32,
+        day: t.tm_mday as u32,
+        hour: t.tm_hour as u32,
+        minute: t.tm_min as u32,
+        weekday: t.tm_wday as u32,
+        dst: t.tm_isdst > 0,
+    }
+}
+
+/// The POSIX rule of `zone`, an IANA name from [`ZONES`] or a rule itself
+fn resolve(zone: &str) -> Result<&str, &'static str> {
+    if let Some(&(_, rule)) = ZONES.iter().find(|(name, _)| *name == zone) {
+        return Ok(rule);
+    }
+    // A name of letters or `<...>`, then an offset; zone names have none
+    let valid = !zone.is_empty()
+        && zone.len() <= MAX_ZONE
+        && zone.chars().all(|c| c.is_ascii_graphic())
+        && zone.starts_with(|c: char| c.is_ascii_alphabetic() || c == '<')
+        && zone.contains(|c: char| c.is_ascii_digit());
+    valid
+        .then_some(zone)
+        .ok_or("unknown zone, give a POSIX TZ string or a listed name")
+}
+
+fn apply(zone: &str) -> Result<(), &'static str> {
+    let rule = resolve(zone)?;
+    let rule = CString::new(rule).map_err(|_| "invalid POSIX TZ string")?;
+    unsafe {
+        setenv(c"TZ".as_ptr(), rule.as_ptr(), 1);
+        tzset();
+    }
+    Ok(())
+}
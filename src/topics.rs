@@ -0,0 +1,329 @@
+//! Stable topic names and the handler each topic's readings get
+//!
+//! Senders put a numeric topic id in every frame. The registry gives each id
+//! a stable name, e.g. `kettle/temp`, and a handler: the processing
+//! pipeline its readings run through, see the transforms module. Topics not
+//! in the registry keep their numeric id and get the [`GENERIC`] handler.
+//! Names label the topic in messages where it has no friendly name, see the
+//! names module.
+//!
+//! The built-in sensors are registered at build time (`TOPICS` in
+//! `src/main.rs`). A new sensor needs no rebuild: it is registered from the
+//! console (`topics`, `topic`) or over HTTP once the hub is on WiFi, and the
+//! table is persisted in NVS, replacing the built-in one from then on:
+//!
+//! - `GET /topics` lists the registered topics
+//! - `PUT /topics/<name>` registers a topic, the body being
+//!   `<topic_id> <handler>`
+//! - `DELETE /topics/<name>` removes a topic
+//!
+//! [`GENERIC`]: crate::transforms::GENERIC
+
+use std::sync::Mutex;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+use log::{info, warn};
+
+#[cfg(target_os = "espidf")]
+use crate::http::{authorize, read_body, refuse, respond};
+use crate::platform::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use crate::platform::sys::EspError;
+use crate::transforms::{Pipeline, GENERIC};
+
+const NAMESPACE: &str = "topics";
+const TABLE: &str = "table";
+const MAX_TOPICS: usize = 32;
+const MAX_NAME: usize = 32;
+// Longest table line is a full-length name, a topic id and a handler name
+const MAX_TABLE: usize = MAX_TOPICS * (MAX_NAME + 1 + 11 + 1 + 24 + 1);
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    topics: Vec::new(),
+    handlers: &[],
+    nvs: None,
+});
+
+/// A built-in topic
+pub struct Topic {
+    pub name: &'static str,
+    pub topic_id: i32,
+    pub handler: &'static str,
+}
+
+struct Entry {
+    name: String,
+    topic_id: i32,
+    handler: &'static str,
+}
+
+struct Registry {
+    topics: Vec<Entry>,
+    /// The configured pipelines, whose handler names are valid
+    handlers: &'static [Pipeline],
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Registry {
+    fn table(&self) -> String {
+        let mut table = String::new();
+        for entry in &self.topics {
+            table.push_str(&format!(
+                "{} {} {}\n",
+                entry.name, entry.topic_id, entry.handler
+            ));
+        }
+        table
+    }
+
+    fn save(&mut self) -> Result<(), &'static str> {
+        let table = self.table();
+        let nvs = self.nvs.as_mut().ok_or("NVS unavailable")?;
+        nvs.set_blob(TABLE, table.as_bytes()).map_err(|e| {
+            warn!("Failed to save topics: {}", e);
+            "write failed"
+        })
+    }
+
+    fn parse_table(&self, table: &str) -> Result<Vec<Entry>, &'static str> {
+        let mut topics: Vec<Entry> = Vec::new();
+        for line in table.lines().filter(|line| !line.trim().is_empty()) {
+            let entry = self.parse_line(line)?;
+            if topics
+                .iter()
+                .any(|t| t.name == entry.name || t.topic_id == entry.topic_id)
+            {
+                return Err("duplicate topic name or id");
+            }
+            topics.push(entry);
+        }
+        if topics.len() > MAX_TOPICS {
+            return Err("too many topics, at most 32");
+        }
+        Ok(topics)
+    }
+
+    fn parse_line(&self, line: &str) -> Result<Entry, &'static str> {
+        let mut words = line.split_whitespace();
+        let (Some(name), Some(topic_id), Some(handler), None) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            return Err("expected <name> <topic_id> <handler>");
+        };
+        check_name(name)?;
+        Ok(Entry {
+            name: name.to_string(),
+            topic_id: topic_id.parse().map_err(|_| "invalid topic id")?,
+            handler: self.handler(handler)?,
+        })
+    }
+
+    /// The static name of handler `name`, if one is configured
+    fn handler(&self, name: &str) -> Result<&'static str, &'static str> {
+        if name == GENERIC {
+            return Ok(GENERIC);
+        }
+        self.handlers
+            .iter()
+            .map(|p| p.handler)
+            .find(|&h| h == name)
+            .ok_or("no such handler")
+    }
+}
+
+/// Register the built-in `defaults` unless NVS holds a table, with the
+/// handlers of `pipelines`
+pub fn init(
+    partition: Option<EspDefaultNvsPartition>,
+    defaults: &[Topic],
+    pipelines: &'static [Pipeline],
+) -> Result<(), EspError> {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.handlers = pipelines;
+    registry.topics = defaults
+        .iter()
+        .map(|t| Entry {
+            name: t.name.to_string(),
+            topic_id: t.topic_id,
+            handler: t.handler,
+        })
+        .collect();
+    let Some(partition) = partition else {
+        return Ok(());
+    };
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_TABLE];
+    if let Some(table) = nvs.get_blob(TABLE, &mut buf)? {
+        let table = String::from_utf8_lossy(table);
+        let mut topics = Vec::new();
+        for line in table.lines() {
+            match registry.parse_line(line) {
+                Ok(entry) => topics.push(entry),
+                Err(e) => warn!("Stored topic {:?} dropped: {}", line, e),
+            }
+        }
+        registry.topics = topics;
+    }
+    info!("Topics: {} registered", registry.topics.len());
+    registry.nvs = Some(nvs);
+    Ok(())
+}
+
+/// The handler of `topic_id`, [`GENERIC`] if it is not registered
+pub fn handler(topic_id: i32) -> &'static str {
+    let registry = REGISTRY.lock().unwrap();
+    registry
+        .topics
+        .iter()
+        .find(|t| t.topic_id == topic_id)
+        .map_or(GENERIC, |t| t.handler)
+}
+
+/// The registered name of `topic_id`
+pub fn name(topic_id: i32) -> Option<String> {
+    let registry = REGISTRY.lock().unwrap();
+    registry
+        .topics
+        .iter()
+        .find(|t| t.topic_id == topic_id)
+        .map(|t| t.name.clone())
+}
+
+/// The topics registered as `name`, or else under it: `kettle` finds
+/// `kettle/temp` and `kettle/sound`
+pub fn find(name: &str) -> Vec<i32> {
+    let registry = REGISTRY.lock().unwrap();
+    if let Some(t) = registry.topics.iter().find(|t| t.name == name) {
+        return vec![t.topic_id];
+    }
+    registry
+        .topics
+        .iter()
+        .filter(|t| {
+            t.name
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .map(|t| t.topic_id)
+        .collect()
+}
+
+/// Register `name` as `topic_id` with `handler`, or remove it with `None`,
+/// and persist the table
+pub fn set(name: &str, topic: Option<(i32, &str)>) -> Result<(), &'static str> {
+    check_name(name)?;
+    let mut registry = REGISTRY.lock().unwrap();
+    let current = registry.topics.iter().position(|t| t.name == name);
+    match (current, topic) {
+        (_, Some((topic_id, _)))
+            if registry
+                .topics
+                .iter()
+                .any(|t| t.topic_id == topic_id && t.name != name) =>
+        {
+            return Err("topic id already registered")
+        }
+        (Some(i), Some((topic_id, handler))) => {
+            let handler = registry.handler(handler)?;
+            let entry = &mut registry.topics[i];
+            entry.topic_id = topic_id;
+            entry.handler = handler;
+        }
+        (None, Some(_)) if registry.topics.len() >= MAX_TOPICS => {
+            return Err("too many topics, at most 32")
+        }
+        (None, Some((topic_id, handler))) => {
+            let handler = registry.handler(handler)?;
+            registry.topics.push(Entry {
+                name: name.to_string(),
+                topic_id,
+                handler,
+            });
+        }
+        (Some(i), None) => {
+            registry.topics.remove(i);
+        }
+        (None, None) => return Err("no such topic"),
+    }
+    registry.save()
+}
+
+/// Every registered topic as `<name> <topic_id> <handler>` lines
+pub fn list() -> String {
+    REGISTRY.lock().unwrap().table()
+}
+
+/// Check a table in the [`list`] format
+pub fn check(table: &str) -> Result<(), &'static str> {
+    REGISTRY.lock().unwrap().parse_table(table).map(|_| ())
+}
+
+/// Replace every topic with those in `table`, in the [`list`] format
+pub fn replace(table: &str) -> Result<(), &'static str> {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.topics = registry.parse_table(table)?;
+    registry.save()
+}
+
+/// Serve the registry endpoints on `server`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/topics", Method::Get, |req| {
+        req.into_ok_response()?.write_all(list().as_bytes())
+    })?;
+
+    server.fn_handler("/topics/*", Method::Put, |mut req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let name = name_in(req.uri());
+        let result = read_body(&mut req, 40)?.and_then(|body| {
+            let mut words = body.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(topic_id), Some(handler), None) => {
+                    let topic_id = topic_id.parse().map_err(|_| "invalid topic id")?;
+                    set(&name, Some((topic_id, handler)))
+                }
+                _ => Err("expected <topic_id> <handler>"),
+            }
+        });
+        respond(req, result)
+    })?;
+
+    server.fn_handler("/topics/*", Method::Delete, |req| {
+        if let Err(e) = authorize(&req) {
+            return refuse(req, e);
+        }
+        let result = set(&name_in(req.uri()), None);
+        respond(req, result)
+    })?;
+
+    info!("Topic registry served on /topics");
+    Ok(())
+}
+
+/// The topic name at the end of `uri`
+#[cfg(target_os = "espidf")]
+fn name_in(uri: &str) -> String {
+    let path = uri.split('?').next().unwrap_or_default();
+    path.strip_prefix("/topics/")
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Names are paths of plain segments, e.g. `kettle/temp`
+fn check_name(name: &str) -> Result<(), &'static str> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '/';
+    if name.is_empty()
+        || name.len() > MAX_NAME
+        || !name.chars().all(valid)
+        || name.split('/').any(str::is_empty)
+    {
+        return Err("names are 1 to 32 of A-Z, a-z, 0-9, _, - and / between segments");
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+//! Latency spans along the packet path, for profiling
+//!
+//! With the `tracing` feature every data frame leaves up to four spans,
+//! tagged with a frame number counted from boot:
+//!
+//! - `callback`: the ESP-NOW receive callback copying it, in the WiFi task
+//! - `queue`: waiting in the receive queue and decoded, until the main loop
+//!   takes its reading
+//! - `dispatch`: the main loop handling it, storage, alarms and uplinks
+//! - `actuate`: the output refresh right after
+//!
+//! The last [`CAPACITY`] spans are kept in a ring and served as Chrome trace
+//! JSON on `GET /trace` once the hub is on WiFi; load it into
+//! `chrome://tracing` or Perfetto to compare builds. Timestamps are
+//! microseconds since boot from `esp_timer_get_time`.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::EspHttpServer;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+#[cfg(target_os = "espidf")]
+use log::info;
+
+use crate::platform::sys::esp_timer_get_time;
+#[cfg(target_os = "espidf")]
+use crate::platform::sys::EspError;
+
+/// Spans kept, older ones are dropped
+const CAPACITY: usize = 512;
+// Trace viewer rows: the callback runs in the WiFi task, the rest in main
+const TID_WIFI: u32 = 1;
+const TID_MAIN: u32 = 2;
+
+static SPANS: Mutex<VecDeque<Span>> = Mutex::new(VecDeque::new());
+// Frames numbered so far
+static FRAMES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Callback,
+    Queue,
+    Dispatch,
+    Actuate,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Callback => "callback",
+            Stage::Queue => "queue",
+            Stage::Dispatch => "dispatch",
+            Stage::Actuate => "actuate",
+        }
+    }
+
+    fn tid(self) -> u32 {
+        match self {
+            Stage::Callback => TID_WIFI,
+            _ => TID_MAIN,
+        }
+    }
+}
+
+struct Span {
+    stage: Stage,
+    frame: u32,
+    start_us: i64,
+    end_us: i64,
+}
+
+/// A frame on its way through the main loop, with the start of its current
+/// stage
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    id: u32,
+    start_us: i64,
+}
+
+impl Frame {
+    /// Start a stage of frame `id` now
+    pub fn start(id: u32) -> Self {
+        Self {
+            id,
+            start_us: now_us(),
+        }
+    }
+
+    /// End `stage` now, returns the frame id for the next stage
+    pub fn end(self, stage: Stage) -> u32 {
+        record(stage, self.id, self.start_us, now_us());
+        self.id
+    }
+}
+
+/// Microseconds since boot, for span boundaries
+pub fn now_us() -> i64 {
+    unsafe { esp_timer_get_time() }
+}
+
+/// The receive callback entered at `start_us` is copying a frame, returns
+/// it numbered and starting its queue span
+pub fn received(start_us: i64) -> Frame {
+    let id = FRAMES.fetch_add(1, Ordering::Relaxed) + 1;
+    let now = now_us();
+    record(Stage::Callback, id, start_us, now);
+    Frame { id, start_us: now }
+}
+
+/// The main loop took `frame` from the queue, ends its queue span and
+/// starts dispatch
+pub fn pickup(frame: Frame) -> Frame {
+    Frame::start(frame.end(Stage::Queue))
+}
+
+fn record(stage: Stage, frame: u32, start_us: i64, end_us: i64) {
+    let mut spans = SPANS.lock().unwrap();
+    if spans.len() == CAPACITY {
+        spans.pop_front();
+    }
+    spans.push_back(Span {
+        stage,
+        frame,
+        start_us,
+        end_us,
+    });
+}
+
+/// The recorded spans as a Chrome trace document
+pub fn chrome_json() -> String {
+    let spans = SPANS.lock().unwrap();
+    let mut json = String::from(r#"{"traceEvents":["#);
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(&format!(
+            r#"{{"name":"{}","cat":"frame","ph":"X","ts":{},"dur":{},"pid":1,"tid":{},"args":{{"frame":{}}}}}"#,
+            span.stage.name(),
+            span.start_us,
+            span.end_us - span.start_us,
+            span.stage.tid(),
+            span.frame
+        ));
+    }
+    json.push_str(r#"],"displayTimeUnit":"ms"}"#);
+    json
+}
+
+/// Serve the trace on `server`
+#[cfg(target_os = "espidf")]
+pub fn serve(server: &mut EspHttpServer<'static>) -> Result<(), EspError> {
+    server.fn_handler("/trace", Method::Get, |req| {
+        req.into_ok_response()?.write_all(chrome_json().as_bytes())
+    })?;
+    info!("Packet path trace served on /trace");
+    Ok(())
+}
@@ -0,0 +1,439 @@
+//! Per-topic measurement processing as a pipeline of transforms
+//!
+//! Every reading runs through its topic's pipeline before it is stored,
+//! alarmed on or sent upstream. A pipeline is a list of [`Stage`]s, applied
+//! in order, typically:
+//!
+//! - calibrate: correct a sensor's offset and gain
+//! - filter: drop implausible readings, smooth the rest
+//! - convert: change units, e.g. Celsius to Fahrenheit
+//! - threshold: check the alarm rule, see the thresholds module
+//!
+//! Each stage is built into a [`Transform`] trait object with state of its
+//! own, e.g. a filter's window, one per topic. A stage may drop the reading,
+//! the later stages and the rest of the main loop then never see it.
+//! Pipelines belong to handlers, which the topic registry assigns topics to;
+//! topics of the [`GENERIC`] handler, or of none, get [`DEFAULT`], the
+//! threshold check alone. Only stages after convert see converted values, so thresholds
+//! follow the units of the stage before them.
+//!
+//! Each pipeline also states the range a topic's raw readings can validly
+//! take. A reading outside it never enters the stages, and neither does a
+//! stage result out of `i32` range: the arithmetic is checked, and instead
+//! of wrapping or clamping the reading is dropped. Both count as outliers,
+//! per topic for `inspect` and in total as `rx_outliers` in the status
+//! uplink, as do readings a plausible stage drops.
+
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::alerts::Priority;
+use crate::thresholds;
+use crate::topics;
+
+/// Handler of topics without a pipeline of their own
+pub const GENERIC: &str = "generic";
+/// Stages of the generic handler
+pub const DEFAULT: &[Stage] = &[Stage::Threshold];
+/// Valid range of the generic handler, any reading
+pub const ANY: RangeInclusive<i32> = i32::MIN..=i32::MAX;
+const MAX_TOPICS: usize = 16;
+// Longest averaging window, which bounds what the sum of one can reach
+const MAX_WINDOW: usize = 256;
+
+static OUTLIERS: AtomicU32 = AtomicU32::new(0);
+
+/// One stage of a pipeline, as configured
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    /// `measurement * gain_permille / 1000 + offset`
+    Calibrate { offset: i32, gain_permille: i32 },
+    /// Drop readings outside `min..=max`, e.g. a sensor fault
+    Plausible { min: i32, max: i32 },
+    /// The mean of the last `window` readings, at most 256
+    Average { window: usize },
+    /// `measurement * mul / div + add`, `div` above 0; see the unit constants
+    Convert { mul: i32, div: i32, add: i32 },
+    /// The topic's alarm rule decides the alarm, and the reading counts
+    /// towards other rules' conditions
+    Threshold,
+}
+
+impl Stage {
+    pub const CELSIUS_TO_FAHRENHEIT: Stage = Stage::Convert {
+        mul: 9,
+        div: 5,
+        add: 32,
+    };
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Calibrate { .. } => "calibrate",
+            Stage::Plausible { .. } => "plausible",
+            Stage::Average { .. } => "average",
+            Stage::Convert { .. } => "convert",
+            Stage::Threshold => "threshold",
+        }
+    }
+
+    fn build(self) -> Box<dyn Transform> {
+        match self {
+            Stage::Calibrate {
+                offset,
+                gain_permille,
+            } => Box::new(Linear {
+                name: "calibrate",
+                mul: gain_permille,
+                div: 1000,
+                add: offset,
+            }),
+            Stage::Plausible { min, max } => Box::new(Plausible { min, max }),
+            Stage::Average { window } => Box::new(Average {
+                window: window.clamp(1, MAX_WINDOW),
+                readings: VecDeque::new(),
+            }),
+            Stage::Convert { mul, div, add } => Box::new(Linear {
+                name: "convert",
+                mul,
+                div: div.max(1),
+                add,
+            }),
+            Stage::Threshold => Box::new(Threshold),
+        }
+    }
+}
+
+/// A reading on its way through a pipeline
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub topic_id: i32,
+    pub measurement: i32,
+    /// Set by the threshold stage, as [`thresholds::check`] returns it
+    pub alarm: Option<Option<Priority>>,
+}
+
+pub trait Transform: Send {
+    fn name(&self) -> &'static str;
+
+    /// Process `reading` in place, `false` drops it
+    fn apply(&mut self, reading: &mut Reading) -> bool;
+}
+
+struct Linear {
+    name: &'static str,
+    mul: i32,
+    div: i32,
+    add: i32,
+}
+
+impl Transform for Linear {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn apply(&mut self, reading: &mut Reading) -> bool {
+        let result = i64::from(reading.measurement)
+            .checked_mul(self.mul.into())
+            .and_then(|scaled| scaled.checked_div(self.div.into()))
+            .and_then(|scaled| scaled.checked_add(self.add.into()))
+            .and_then(|result| i32::try_from(result).ok());
+        match result {
+            Some(measurement) => {
+                reading.measurement = measurement;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+struct Plausible {
+    min: i32,
+    max: i32,
+}
+
+impl Transform for Plausible {
+    fn name(&self) -> &'static str {
+        "plausible"
+    }
+
+    fn apply(&mut self, reading: &mut Reading) -> bool {
+        (self.min..=self.max).contains(&reading.measurement)
+    }
+}
+
+struct Average {
+    window: usize,
+    readings: VecDeque<i32>,
+}
+
+impl Transform for Average {
+    fn name(&self) -> &'static str {
+        "average"
+    }
+
+    fn apply(&mut self, reading: &mut Reading) -> bool {
+        if self.readings.len() >= self.window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading.measurement);
+        // At most MAX_WINDOW readings, the sum cannot overflow and the mean
+        // is within the readings' range
+        let sum: i64 = self.readings.iter().map(|&m| i64::from(m)).sum();
+        reading.measurement = (sum / self.readings.len() as i64) as i32;
+        true
+    }
+}
+
+struct Threshold;
+
+impl Transform for Threshold {
+    fn name(&self) -> &'static str {
+        "threshold"
+    }
+
+    fn apply(&mut self, reading: &mut Reading) -> bool {
+        thresholds::observe(reading.topic_id, reading.measurement);
+        reading.alarm = thresholds::check(reading.topic_id, reading.measurement);
+        true
+    }
+}
+
+/// The pipeline stages of one handler
+pub struct Pipeline {
+    /// Name topics are registered with, see the topics module
+    pub handler: &'static str,
+    /// Raw readings outside are outliers, e.g. beyond what the sensor reads
+    pub valid: RangeInclusive<i32>,
+    pub stages: &'static [Stage],
+}
+
+/// Every topic's pipeline with its state
+pub struct Pipelines {
+    config: &'static [Pipeline],
+    topics: Vec<Topic>,
+}
+
+struct Topic {
+    topic_id: i32,
+    handler: &'static str,
+    stages: Vec<Box<dyn Transform>>,
+    outliers: u32,
+}
+
+impl Pipelines {
+    pub fn new(config: &'static [Pipeline]) -> Self {
+        Self {
+            config,
+            topics: Vec::new(),
+        }
+    }
+
+    /// Run a reading through its topic's pipeline, the name of the stage
+    /// that dropped it if one did, `"range"` for a reading outside the
+    /// topic's valid range
+    pub fn process(&mut self, topic_id: i32, measurement: i32) -> Result<Reading, &'static str> {
+        let reading = Reading {
+            topic_id,
+            measurement,
+            alarm: None,
+        };
+        let handler = topics::handler(topic_id);
+        let pipeline = self.config.iter().find(|p| p.handler == handler);
+        let valid = pipeline.map_or(&ANY, |p| &p.valid);
+        let build = || -> Vec<Box<dyn Transform>> {
+            let stages = pipeline.map_or(DEFAULT, |p| p.stages);
+            stages.iter().map(|s| s.build()).collect()
+        };
+        let mut fresh;
+        let topic = match self.topics.iter().position(|t| t.topic_id == topic_id) {
+            Some(i) => {
+                let topic = &mut self.topics[i];
+                if topic.handler != handler {
+                    // Registered with another handler since, start over
+                    topic.handler = handler;
+                    topic.stages = build();
+                }
+                topic
+            }
+            None => {
+                fresh = Topic {
+                    topic_id,
+                    handler,
+                    stages: build(),
+                    outliers: 0,
+                };
+                if self.topics.len() < MAX_TOPICS {
+                    self.topics.push(fresh);
+                    self.topics.last_mut().unwrap()
+                } else {
+                    // Past the limit the topic runs on fresh state each time
+                    &mut fresh
+                }
+            }
+        };
+        let result = if valid.contains(&measurement) {
+            run(&mut topic.stages, reading)
+        } else {
+            Err("range")
+        };
+        if result.is_err() {
+            topic.outliers = topic.outliers.saturating_add(1);
+            OUTLIERS.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Each handler's valid range and stages by name, then the outliers of
+    /// each topic seen, for the console
+    pub fn describe(&self) -> String {
+        let mut list = String::new();
+        for pipeline in self.config {
+            let stages: Vec<&str> = pipeline.stages.iter().map(|s| s.name()).collect();
+            list.push_str(&format!(
+                "{}: {} to {}, {}\n",
+                pipeline.handler,
+                pipeline.valid.start(),
+                pipeline.valid.end(),
+                stages.join(" -> ")
+            ));
+        }
+        for topic in &self.topics {
+            list.push_str(&format!(
+                "topic {} ({}): {} outliers\n",
+                topic.topic_id, topic.handler, topic.outliers
+            ));
+        }
+        list
+    }
+}
+
+/// Readings dropped as out of range or implausible since boot
+pub fn outliers() -> u32 {
+    OUTLIERS.load(Ordering::Relaxed)
+}
+
+/// Count `count` readings dropped before their pipeline, e.g. record fields
+/// out of `i32` range
+pub fn count_outliers(count: u32) {
+    OUTLIERS.fetch_add(count, Ordering::Relaxed);
+}
+
+fn run(stages: &mut [Box<dyn Transform>], mut reading: Reading) -> Result<Reading, &'static str> {
+    for stage in stages {
+        if !stage.apply(&mut reading) {
+            return Err(stage.name());
+        }
+    }
+    Ok(reading)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // No threshold rule has these
+    const TOPIC: i32 = 100;
+    const OTHER: i32 = 101;
+
+    fn reading(measurement: i32) -> Reading {
+        Reading {
+            topic_id: TOPIC,
+            measurement,
+            alarm: None,
+        }
+    }
+
+    fn stages(stages: &[Stage]) -> Vec<Box<dyn Transform>> {
+        stages.iter().map(|s| s.build()).collect()
+    }
+
+    fn measurements(
+        stages: &mut [Box<dyn Transform>],
+        readings: &[i32],
+    ) -> Vec<Result<i32, &'static str>> {
+        readings
+            .iter()
+            .map(|&m| run(stages, reading(m)).map(|r| r.measurement))
+            .collect()
+    }
+
+    #[test]
+    fn calibrate_then_convert() {
+        let mut pipeline = stages(&[
+            Stage::Calibrate {
+                offset: -2,
+                gain_permille: 1100,
+            },
+            Stage::CELSIUS_TO_FAHRENHEIT,
+        ]);
+        assert_eq!(
+            measurements(&mut pipeline, &[0, 20, 100]),
+            [Ok(29), Ok(68), Ok(226)]
+        );
+    }
+
+    #[test]
+    fn plausible_drops_outside_its_range() {
+        let mut pipeline = stages(&[
+            Stage::Plausible { min: -10, max: 10 },
+            Stage::CELSIUS_TO_FAHRENHEIT,
+        ]);
+        assert_eq!(
+            measurements(&mut pipeline, &[-11, -10, 10, 11]),
+            [Err("plausible"), Ok(14), Ok(50), Err("plausible")]
+        );
+    }
+
+    #[test]
+    fn average_slides_over_its_window() {
+        let mut pipeline = stages(&[Stage::Average { window: 3 }]);
+        assert_eq!(
+            measurements(&mut pipeline, &[3, 6, 9, 30, -30]),
+            [Ok(3), Ok(4), Ok(6), Ok(15), Ok(3)]
+        );
+    }
+
+    #[test]
+    fn overflow_drops_the_reading() {
+        let mut pipeline = stages(&[
+            Stage::Convert {
+                mul: 1000,
+                div: 1,
+                add: 0,
+            },
+            Stage::Calibrate {
+                offset: i32::MAX,
+                gain_permille: 1000,
+            },
+        ]);
+        assert_eq!(
+            measurements(&mut pipeline, &[i32::MAX, 0, 1]),
+            [Err("convert"), Ok(i32::MAX), Err("calibrate")]
+        );
+    }
+
+    #[test]
+    fn pipelines_keep_state_per_topic_and_count_outliers() {
+        static CONFIG: [Pipeline; 1] = [Pipeline {
+            handler: GENERIC,
+            valid: -55..=125,
+            stages: &[Stage::Average { window: 2 }, Stage::Threshold],
+        }];
+        let mut pipelines = Pipelines::new(&CONFIG);
+        let mut process = |topic_id, measurement| {
+            pipelines
+                .process(topic_id, measurement)
+                .map(|r| (r.measurement, r.alarm))
+        };
+        assert_eq!(process(TOPIC, 10), Ok((10, None)));
+        assert_eq!(process(OTHER, 100), Ok((100, None)));
+        assert_eq!(process(TOPIC, 20), Ok((15, None)));
+        assert_eq!(process(TOPIC, 126), Err("range"));
+        assert_eq!(process(OTHER, 0), Ok((50, None)));
+        assert!(pipelines
+            .describe()
+            .contains(&format!("topic {} (generic): 1 outliers", TOPIC)));
+    }
+}
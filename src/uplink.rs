@@ -0,0 +1,466 @@
+//! Uplinks towards the hub / home automation side
+//!
+//! Every transport implements [`Uplink`]. Transports are collected in an
+//! [`UplinkChain`] in priority order: an event goes out over the first uplink
+//! that accepts it, and a failing uplink is skipped for a while before it is
+//! retried. Alarms ignore that backoff and walk the whole chain, so they still
+//! get out when the primary path is down.
+//!
+//! When every uplink is down, measurements and alarms are parked in the
+//! flash-backed [`Outbox`] and flushed in order, with their original
+//! timestamps, once a path comes back.
+//!
+//! Endpoints are configured at build time through environment variables:
+//! `UPLINK_MQTT_URL`, `UPLINK_WEBHOOK_URL`, `UPLINK_UDP_ADDR` and
+//! `UPLINK_RELAY_MAC` (in that priority order). Unset ones are left out, and
+//! so are MQTT and the webhook in host builds, which have no ESP-IDF
+//! clients.
+
+#[cfg(target_os = "espidf")]
+mod mqtt;
+mod outbox;
+mod relay;
+mod udp;
+#[cfg(target_os = "espidf")]
+mod webhook;
+
+use core::fmt;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::alerts::Priority;
+use crate::battery;
+use crate::clock;
+use crate::datalog::Sample;
+use crate::degraded;
+use crate::fast_path;
+use crate::freshness;
+use crate::identity;
+use crate::link;
+use crate::metrics;
+use crate::names;
+use crate::platform::sys::EspError;
+use crate::readiness;
+use crate::sntp;
+use crate::startup;
+
+#[cfg(target_os = "espidf")]
+pub use mqtt::MqttUplink;
+pub use outbox::Outbox;
+pub use relay::{parse_mac, RelayUplink};
+pub use udp::UdpUplink;
+#[cfg(target_os = "espidf")]
+pub use webhook::WebhookUplink;
+
+#[cfg(target_os = "espidf")]
+const UPLINK_MQTT_URL: Option<&str> = option_env!("UPLINK_MQTT_URL");
+#[cfg(target_os = "espidf")]
+const UPLINK_WEBHOOK_URL: Option<&str> = option_env!("UPLINK_WEBHOOK_URL");
+const UPLINK_UDP_ADDR: Option<&str> = option_env!("UPLINK_UDP_ADDR");
+const UPLINK_RELAY_MAC: Option<&str> = option_env!("UPLINK_RELAY_MAC");
+
+// How long a failed uplink is skipped for non-alarm traffic
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub uptime_s: u64,
+    pub free_heap: u32,
+    pub awake: bool,
+    /// Active alarm profile
+    pub profile: &'static str,
+    /// Senders reporting a low battery
+    pub batteries_low: u32,
+    /// Data frames rejected for a bad CRC since boot
+    pub rx_crc_errors: u32,
+    /// Data frames without a CRC taken from legacy peers since boot
+    pub rx_legacy: u32,
+    /// Data frames without a CRC refused from other senders since boot
+    pub rx_legacy_refused: u32,
+    /// Retransmitted data frames dropped since boot
+    pub rx_duplicates: u32,
+    /// Frames from untrusted senders dropped since boot
+    pub rx_unknown_senders: u32,
+    /// Data frames failing authentication since boot
+    pub rx_auth_failures: u32,
+    /// Readings dropped as out of range or implausible since boot
+    pub rx_outliers: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    Measurement(Sample),
+    /// A threshold alarm, `priority` from the band the reading is in
+    Alarm {
+        sample: Sample,
+        priority: Priority,
+    },
+    /// An alarm ended, with the last sample that raised it
+    AlarmCleared(Sample),
+    /// An alarm escalated to emergency mode, or (`active: false`) stood down
+    Emergency {
+        sample: Sample,
+        active: bool,
+    },
+    /// A sender's battery ran low, or (`low: false`) recovered; the sample's
+    /// measurement is the charge in percent
+    Battery {
+        sample: Sample,
+        low: bool,
+    },
+    /// A reading on a topic without a threshold alarm was unusual, or
+    /// (`unusual: false`) usual again
+    Anomaly {
+        sample: Sample,
+        unusual: bool,
+    },
+    /// A watched topic went silent, or (`offline: false`) was heard again;
+    /// the sample's measurement is the last reading
+    Offline {
+        sample: Sample,
+        offline: bool,
+    },
+    Status(Status),
+}
+
+impl Event {
+    pub fn is_alarm(&self) -> bool {
+        matches!(
+            self,
+            Event::Alarm { .. } | Event::AlarmCleared(_) | Event::Emergency { .. }
+        )
+    }
+
+    pub fn to_json(self) -> String {
+        match self {
+            Event::Measurement(s) => format!(
+                r#"{{"type":"measurement","device":"{}","timestamp":{},"topic_id":{}{},"measurement":{}{}}}"#,
+                identity::uuid(),
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement,
+                battery::level(s.topic_id)
+                    .map_or_else(String::new, |percent| format!(r#","battery":{}"#, percent))
+            ),
+            Event::Alarm {
+                sample: s,
+                priority,
+            } => format!(
+                r#"{{"type":"alarm","device":"{}","severity":"{}","timestamp":{},"topic_id":{}{},"measurement":{}}}"#,
+                identity::uuid(),
+                priority.name(),
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement
+            ),
+            Event::AlarmCleared(s) => format!(
+                r#"{{"type":"alarm","device":"{}","severity":"clear","timestamp":{},"topic_id":{}{},"measurement":{}}}"#,
+                identity::uuid(),
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement
+            ),
+            Event::Emergency { sample: s, active } => format!(
+                r#"{{"type":"emergency","device":"{}","active":{},"timestamp":{},"topic_id":{}{},"measurement":{}}}"#,
+                identity::uuid(),
+                active,
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement
+            ),
+            Event::Battery { sample: s, low } => format!(
+                r#"{{"type":"battery","device":"{}","low":{},"timestamp":{},"topic_id":{}{},"battery":{}}}"#,
+                identity::uuid(),
+                low,
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement
+            ),
+            Event::Anomaly { sample: s, unusual } => format!(
+                r#"{{"type":"anomaly","device":"{}","unusual":{},"timestamp":{},"topic_id":{}{},"measurement":{}}}"#,
+                identity::uuid(),
+                unusual,
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement
+            ),
+            Event::Offline { sample: s, offline } => format!(
+                r#"{{"type":"offline","device":"{}","offline":{},"timestamp":{},"topic_id":{}{},"measurement":{}}}"#,
+                identity::uuid(),
+                offline,
+                s.timestamp,
+                s.topic_id,
+                name_field(s.topic_id),
+                s.measurement
+            ),
+            Event::Status(status) => format!(
+                r#"{{"type":"status","device":"{}","uptime_s":{},"free_heap":{},"awake":{},"profile":"{}","batteries_low":{},"rx_crc_errors":{},"rx_legacy":{},"rx_legacy_refused":{},"rx_duplicates":{},"rx_unknown_senders":{},"rx_auth_failures":{},"rx_outliers":{},"startup":{},"readiness":"{}","faults":{},"sla":{},"fast_path":{},"degraded":{},"links":{},"offline":{},"metrics":{},"household":{},"clock_synced":{}}}"#,
+                identity::uuid(),
+                status.uptime_s,
+                status.free_heap,
+                status.awake,
+                status.profile,
+                status.batteries_low,
+                status.rx_crc_errors,
+                status.rx_legacy,
+                status.rx_legacy_refused,
+                status.rx_duplicates,
+                status.rx_unknown_senders,
+                status.rx_auth_failures,
+                status.rx_outliers,
+                startup::json(),
+                readiness::state().name(),
+                readiness::faults_json(),
+                readiness::sla_json(),
+                fast_path::json(),
+                degraded::json(),
+                link::json(),
+                freshness::json(),
+                metrics::json(),
+                identity::household().map_or_else(|| "null".to_string(), |h| format!(r#""{}""#, h)),
+                sntp::synced()
+            ),
+        }
+    }
+}
+
+/// `,"name":"<name>"` for a named topic, nothing otherwise
+fn name_field(topic_id: i32) -> String {
+    names::name(topic_id).map_or_else(String::new, |name| format!(r#","name":"{}""#, name))
+}
+
+#[derive(Debug)]
+pub enum UplinkError {
+    Esp(EspError),
+    Io(std::io::Error),
+    /// The remote end answered with a non-success HTTP status
+    Status(u16),
+    NotConnected,
+    /// No uplink in the chain accepted the event
+    NoRoute,
+}
+
+impl fmt::Display for UplinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UplinkError::Esp(e) => write!(f, "{}", e),
+            UplinkError::Io(e) => write!(f, "{}", e),
+            UplinkError::Status(code) => write!(f, "HTTP status {}", code),
+            UplinkError::NotConnected => write!(f, "not connected"),
+            UplinkError::NoRoute => write!(f, "no uplink available"),
+        }
+    }
+}
+
+impl From<EspError> for UplinkError {
+    fn from(e: EspError) -> Self {
+        UplinkError::Esp(e)
+    }
+}
+
+impl From<std::io::Error> for UplinkError {
+    fn from(e: std::io::Error) -> Self {
+        UplinkError::Io(e)
+    }
+}
+
+pub trait Uplink {
+    fn name(&self) -> &'static str;
+
+    fn send_measurement(&mut self, sample: &Sample) -> Result<(), UplinkError>;
+    fn send_alarm(&mut self, sample: &Sample, priority: Priority) -> Result<(), UplinkError>;
+    fn send_alarm_cleared(&mut self, sample: &Sample) -> Result<(), UplinkError>;
+    fn send_emergency(&mut self, sample: &Sample, active: bool) -> Result<(), UplinkError>;
+    fn send_battery(&mut self, sample: &Sample, low: bool) -> Result<(), UplinkError>;
+    fn send_anomaly(&mut self, sample: &Sample, unusual: bool) -> Result<(), UplinkError>;
+    fn send_offline(&mut self, sample: &Sample, offline: bool) -> Result<(), UplinkError>;
+    fn send_status(&mut self, status: &Status) -> Result<(), UplinkError>;
+
+    fn send(&mut self, event: &Event) -> Result<(), UplinkError> {
+        match event {
+            Event::Measurement(sample) => self.send_measurement(sample),
+            Event::Alarm { sample, priority } => self.send_alarm(sample, *priority),
+            Event::AlarmCleared(sample) => self.send_alarm_cleared(sample),
+            Event::Emergency { sample, active } => self.send_emergency(sample, *active),
+            Event::Battery { sample, low } => self.send_battery(sample, *low),
+            Event::Anomaly { sample, unusual } => self.send_anomaly(sample, *unusual),
+            Event::Offline { sample, offline } => self.send_offline(sample, *offline),
+            Event::Status(status) => self.send_status(status),
+        }
+    }
+}
+
+struct Entry {
+    uplink: Box<dyn Uplink>,
+    retry_at: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct UplinkChain {
+    entries: Vec<Entry>,
+    outbox: Option<Outbox>,
+    last_drain: Option<Instant>,
+}
+
+impl UplinkChain {
+    /// Build the chain from the endpoints configured at build time
+    ///
+    /// ESP-NOW must already be initialized for the relay uplink.
+    pub fn configured() -> Self {
+        let mut chain = Self::default();
+
+        #[cfg(target_os = "espidf")]
+        if let Some(url) = UPLINK_MQTT_URL {
+            match MqttUplink::new(url) {
+                Ok(uplink) => chain.push(Box::new(uplink)),
+                Err(e) => warn!("MQTT uplink unavailable: {}", e),
+            }
+        }
+        #[cfg(target_os = "espidf")]
+        if let Some(url) = UPLINK_WEBHOOK_URL {
+            chain.push(Box::new(WebhookUplink::new(url)));
+        }
+        if let Some(addr) = UPLINK_UDP_ADDR {
+            match UdpUplink::new(addr) {
+                Ok(uplink) => chain.push(Box::new(uplink)),
+                Err(e) => warn!("UDP uplink unavailable: {}", e),
+            }
+        }
+        if let Some(mac) = UPLINK_RELAY_MAC {
+            match relay::parse_mac(mac).map(RelayUplink::new) {
+                Some(Ok(uplink)) => chain.push(Box::new(uplink)),
+                Some(Err(e)) => warn!("ESP-NOW relay uplink unavailable: {}", e),
+                None => warn!("Invalid UPLINK_RELAY_MAC: {}", mac),
+            }
+        }
+
+        chain
+    }
+
+    /// Append an uplink with lower priority than the ones already present
+    pub fn push(&mut self, uplink: Box<dyn Uplink>) {
+        self.entries.push(Entry {
+            uplink,
+            retry_at: None,
+        });
+    }
+
+    /// Park undeliverable events in `outbox` instead of dropping them
+    pub fn set_outbox(&mut self, outbox: Outbox) {
+        if !outbox.is_empty() {
+            info!("Outbox holds {} undelivered event(s)", outbox.len());
+        }
+        self.outbox = Some(outbox);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.entries.iter().map(|e| e.uplink.name())
+    }
+
+    /// [`send`](Self::send) `event`, logging a failed delivery
+    pub fn publish(&mut self, event: Event) {
+        if let Err(e) = self.send(&event) {
+            warn!("Uplink delivery failed: {}", e);
+        }
+    }
+
+    /// Deliver `event`, queueing it in the outbox if nothing accepts it
+    ///
+    /// Backlogged events go first so the far end sees them in order. Alarms
+    /// are the exception: they are attempted live even behind a backlog.
+    pub fn send(&mut self, event: &Event) -> Result<(), UplinkError> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+
+        let backlogged = self.drain_outbox();
+        let result = if backlogged && !event.is_alarm() {
+            Err(UplinkError::NoRoute)
+        } else {
+            self.deliver(event)
+        };
+
+        match (result, self.outbox.as_mut()) {
+            (Err(_), Some(outbox)) if !matches!(event, Event::Status(_)) => {
+                outbox.push(*event)?;
+                Ok(())
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Retry the backlog periodically even when no new events show up
+    pub fn poll(&mut self) {
+        let due = self
+            .last_drain
+            .map_or(true, |t| clock::since(t) >= RETRY_BACKOFF);
+        if due && !self.entries.is_empty() {
+            self.drain_outbox();
+        }
+    }
+
+    /// Flush queued events in order, returns true if a backlog remains
+    fn drain_outbox(&mut self) -> bool {
+        let Some(mut outbox) = self.outbox.take() else {
+            return false;
+        };
+        self.last_drain = Some(clock::now());
+
+        let mut delivered = 0;
+        while let Some(event) = outbox.get(delivered) {
+            if self.deliver(&event).is_err() {
+                break;
+            }
+            delivered += 1;
+        }
+
+        if delivered > 0 {
+            info!("Flushed {} queued event(s) from the outbox", delivered);
+            if let Err(e) = outbox.pop_front(delivered) {
+                warn!("Outbox rewrite failed: {}", e);
+            }
+        }
+
+        let backlogged = !outbox.is_empty();
+        self.outbox = Some(outbox);
+        backlogged
+    }
+
+    fn deliver(&mut self, event: &Event) -> Result<(), UplinkError> {
+        let now = clock::now();
+        let mut last_err = UplinkError::NoRoute;
+
+        for entry in self.entries.iter_mut() {
+            let backing_off = entry.retry_at.is_some_and(|at| now < at);
+            if backing_off && !event.is_alarm() {
+                continue;
+            }
+
+            match entry.uplink.send(event) {
+                Ok(()) => {
+                    debug!("Event delivered via {}", entry.uplink.name());
+                    entry.retry_at = None;
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Uplink {} failed: {}", entry.uplink.name(), e);
+                    entry.retry_at = Some(now + RETRY_BACKOFF);
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+}
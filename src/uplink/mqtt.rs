@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload, MqttClientConfiguration, QoS};
+use esp_idf_svc::sys::EspError;
+
+use super::{Event, Status, Uplink, UplinkError};
+use crate::alerts::Priority;
+use crate::datalog::Sample;
+use crate::names;
+
+const CLIENT_ID: &str = "esp-now-receiver";
+
+/// Publishes JSON events under `hub/<topic>/...` and `hub/status`, `<topic>`
+/// being the topic's name if it has one and its id otherwise
+///
+/// Threshold alarms also keep a retained `hub/<topic>/state` holding just
+/// the severity (`info`, `warning`, `critical`) or `clear`, so a dashboard
+/// always shows the current one. It is published when it changes.
+pub struct MqttUplink {
+    client: EspMqttClient<'static>,
+    connected: Arc<AtomicBool>,
+    /// The `state` last published per topic
+    states: Vec<(i32, &'static str)>,
+}
+
+impl MqttUplink {
+    pub fn new(url: &str) -> Result<Self, EspError> {
+        let connected = Arc::new(AtomicBool::new(false));
+        let flag = connected.clone();
+
+        let conf = MqttClientConfiguration {
+            client_id: Some(CLIENT_ID),
+            ..Default::default()
+        };
+        let client = EspMqttClient::new_cb(url, &conf, move |event| match event.payload() {
+            EventPayload::Connected(_) => flag.store(true, Ordering::SeqCst),
+            EventPayload::Disconnected => flag.store(false, Ordering::SeqCst),
+            _ => {}
+        })?;
+
+        Ok(Self {
+            client,
+            connected,
+            states: Vec::new(),
+        })
+    }
+
+    fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        event: &Event,
+    ) -> Result<(), UplinkError> {
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(UplinkError::NotConnected);
+        }
+        self.client
+            .enqueue(topic, qos, retain, event.to_json().as_bytes())?;
+        Ok(())
+    }
+
+    /// Publish the retained `state` of `topic_id`, unless it already holds
+    /// `state`
+    fn publish_state(&mut self, topic_id: i32, state: &'static str) -> Result<(), UplinkError> {
+        if self.states.contains(&(topic_id, state)) {
+            return Ok(());
+        }
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(UplinkError::NotConnected);
+        }
+        self.client.enqueue(
+            &format!("hub/{}/state", names::label(topic_id)),
+            QoS::AtLeastOnce,
+            true,
+            state.as_bytes(),
+        )?;
+        self.states.retain(|(id, _)| *id != topic_id);
+        self.states.push((topic_id, state));
+        Ok(())
+    }
+}
+
+impl Uplink for MqttUplink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn send_measurement(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/measurement", names::label(sample.topic_id)),
+            QoS::AtMostOnce,
+            false,
+            &Event::Measurement(*sample),
+        )
+    }
+
+    fn send_alarm(&mut self, sample: &Sample, priority: Priority) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/alarm", names::label(sample.topic_id)),
+            QoS::AtLeastOnce,
+            false,
+            &Event::Alarm {
+                sample: *sample,
+                priority,
+            },
+        )?;
+        self.publish_state(sample.topic_id, priority.name())
+    }
+
+    fn send_alarm_cleared(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/alarm", names::label(sample.topic_id)),
+            QoS::AtLeastOnce,
+            false,
+            &Event::AlarmCleared(*sample),
+        )?;
+        self.publish_state(sample.topic_id, "clear")
+    }
+
+    /// Retained, so clients connecting mid-emergency see it straight away;
+    /// the stand-down message replaces the retained copy
+    fn send_emergency(&mut self, sample: &Sample, active: bool) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/emergency", names::label(sample.topic_id)),
+            QoS::ExactlyOnce,
+            true,
+            &Event::Emergency {
+                sample: *sample,
+                active,
+            },
+        )
+    }
+
+    /// Retained like emergencies, so the battery state is always there to see
+    fn send_battery(&mut self, sample: &Sample, low: bool) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/battery", names::label(sample.topic_id)),
+            QoS::AtLeastOnce,
+            true,
+            &Event::Battery {
+                sample: *sample,
+                low,
+            },
+        )
+    }
+
+    fn send_anomaly(&mut self, sample: &Sample, unusual: bool) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/anomaly", names::label(sample.topic_id)),
+            QoS::AtLeastOnce,
+            false,
+            &Event::Anomaly {
+                sample: *sample,
+                unusual,
+            },
+        )
+    }
+
+    /// Retained like the battery state
+    fn send_offline(&mut self, sample: &Sample, offline: bool) -> Result<(), UplinkError> {
+        self.publish(
+            &format!("hub/{}/offline", names::label(sample.topic_id)),
+            QoS::AtLeastOnce,
+            true,
+            &Event::Offline {
+                sample: *sample,
+                offline,
+            },
+        )
+    }
+
+    fn send_status(&mut self, status: &Status) -> Result<(), UplinkError> {
+        self.publish(
+            "hub/status",
+            QoS::AtMostOnce,
+            false,
+            &Event::Status(*status),
+        )
+    }
+}
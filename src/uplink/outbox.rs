@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+
+use super::Event;
+use crate::alerts::Priority;
+use crate::datalog::Sample;
+
+const OUTBOX_FILE: &str = "outbox.bin";
+const MAX_QUEUED: usize = 256;
+
+const KIND_MEASUREMENT: u32 = 0;
+// Alarm queued before alarms had a severity, replayed as critical
+const KIND_ALARM: u32 = 1;
+const KIND_EMERGENCY: u32 = 2;
+const KIND_STAND_DOWN: u32 = 3;
+const KIND_BATTERY_LOW: u32 = 4;
+const KIND_BATTERY_OK: u32 = 5;
+const KIND_UNUSUAL: u32 = 6;
+const KIND_USUAL: u32 = 7;
+const KIND_ALARM_INFO: u32 = 8;
+const KIND_ALARM_WARNING: u32 = 9;
+const KIND_ALARM_CRITICAL: u32 = 10;
+const KIND_ALARM_CLEARED: u32 = 11;
+const KIND_OFFLINE: u32 = 12;
+const KIND_ONLINE: u32 = 13;
+const RECORD_SIZE: usize = 16;
+
+/// Bounded, flash-backed FIFO of events waiting for an uplink
+///
+/// New events are appended to the file as fixed 16-byte records, so a push
+/// costs one small write. The file is rewritten only when events leave the
+/// queue. When full, the oldest measurement is dropped first; alarms are
+/// only dropped once nothing else is left.
+pub struct Outbox {
+    path: String,
+    queue: VecDeque<Event>,
+}
+
+impl Outbox {
+    /// Open the outbox in `dir`, picking up whatever survived the last reset
+    pub fn open(dir: &str) -> Self {
+        let path = format!("{}/{}", dir, OUTBOX_FILE);
+        let queue = load(&path).unwrap_or_default();
+        Self { path, queue }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Event> {
+        self.queue.get(index).copied()
+    }
+
+    /// Queue any event but a status report, those are not worth keeping
+    pub fn push(&mut self, event: Event) -> io::Result<()> {
+        let Some(record) = encode(&event) else {
+            return Ok(());
+        };
+
+        if self.queue.len() >= MAX_QUEUED {
+            let victim = self.queue.iter().position(|e| !e.is_alarm()).unwrap_or(0);
+            self.queue.remove(victim);
+            self.queue.push_back(event);
+            return self.rewrite();
+        }
+
+        self.queue.push_back(event);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&record)
+    }
+
+    /// Remove the first `count` events after they have been delivered
+    pub fn pop_front(&mut self, count: usize) -> io::Result<()> {
+        self.queue.drain(..count.min(self.queue.len()));
+        self.rewrite()
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        if self.queue.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        let mut data = Vec::with_capacity(self.queue.len() * RECORD_SIZE);
+        for record in self.queue.iter().filter_map(encode) {
+            data.extend_from_slice(&record);
+        }
+        File::create(&self.path)?.write_all(&data)
+    }
+}
+
+fn load(path: &str) -> io::Result<VecDeque<Event>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    // A partially written trailing record is ignored
+    Ok(data.chunks_exact(RECORD_SIZE).filter_map(decode).collect())
+}
+
+fn encode(event: &Event) -> Option<[u8; RECORD_SIZE]> {
+    let (kind, sample) = match event {
+        Event::Measurement(sample) => (KIND_MEASUREMENT, sample),
+        Event::Alarm { sample, priority } => match priority {
+            Priority::Info => (KIND_ALARM_INFO, sample),
+            Priority::Warning => (KIND_ALARM_WARNING, sample),
+            Priority::Critical => (KIND_ALARM_CRITICAL, sample),
+        },
+        Event::AlarmCleared(sample) => (KIND_ALARM_CLEARED, sample),
+        Event::Emergency { sample, active } if *active => (KIND_EMERGENCY, sample),
+        Event::Emergency { sample, .. } => (KIND_STAND_DOWN, sample),
+        Event::Battery { sample, low } if *low => (KIND_BATTERY_LOW, sample),
+        Event::Battery { sample, .. } => (KIND_BATTERY_OK, sample),
+        Event::Anomaly { sample, unusual } if *unusual => (KIND_UNUSUAL, sample),
+        Event::Anomaly { sample, .. } => (KIND_USUAL, sample),
+        Event::Offline { sample, offline } if *offline => (KIND_OFFLINE, sample),
+        Event::Offline { sample, .. } => (KIND_ONLINE, sample),
+        Event::Status(_) => return None,
+    };
+
+    let mut record = [0u8; RECORD_SIZE];
+    record[0..4].copy_from_slice(&kind.to_le_bytes());
+    record[4..8].copy_from_slice(&sample.timestamp.to_le_bytes());
+    record[8..12].copy_from_slice(&sample.topic_id.to_le_bytes());
+    record[12..16].copy_from_slice(&sample.measurement.to_le_bytes());
+    Some(record)
+}
+
+fn decode(record: &[u8]) -> Option<Event> {
+    let word = |i: usize| [record[i], record[i + 1], record[i + 2], record[i + 3]];
+    let sample = Sample {
+        timestamp: u32::from_le_bytes(word(4)),
+        topic_id: i32::from_le_bytes(word(8)),
+        measurement: i32::from_le_bytes(word(12)),
+    };
+
+    match u32::from_le_bytes(word(0)) {
+        KIND_MEASUREMENT => Some(Event::Measurement(sample)),
+        KIND_ALARM | KIND_ALARM_CRITICAL => Some(Event::Alarm {
+            sample,
+            priority: Priority::Critical,
+        }),
+        KIND_ALARM_INFO => Some(Event::Alarm {
+            sample,
+            priority: Priority::Info,
+        }),
+        KIND_ALARM_WARNING => Some(Event::Alarm {
+            sample,
+            priority: Priority::Warning,
+        }),
+        KIND_ALARM_CLEARED => Some(Event::AlarmCleared(sample)),
+        KIND_EMERGENCY => Some(Event::Emergency {
+            sample,
+            active: true,
+        }),
+        KIND_STAND_DOWN => Some(Event::Emergency {
+            sample,
+            active: false,
+        }),
+        KIND_BATTERY_LOW => Some(Event::Battery { sample, low: true }),
+        KIND_BATTERY_OK => Some(Event::Battery { sample, low: false }),
+        KIND_UNUSUAL => Some(Event::Anomaly {
+            sample,
+            unusual: true,
+        }),
+        KIND_USUAL => Some(Event::Anomaly {
+            sample,
+            unusual: false,
+        }),
+        KIND_OFFLINE => Some(Event::Offline {
+            sample,
+            offline: true,
+        }),
+        KIND_ONLINE => Some(Event::Offline {
+            sample,
+            offline: false,
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,93 @@
+use super::{Event, Status, Uplink, UplinkError};
+use crate::alerts::Priority;
+use crate::datalog::Sample;
+use crate::espnow_tx::{self, Qos};
+use crate::platform::sys::EspError;
+
+/// Forwards JSON events over ESP-NOW to a relay node (typically the hub)
+///
+/// Works without any WiFi AP, which makes it the path of last resort. Alarms
+/// are sent reliably, measurements and status reports fire-and-forget.
+pub struct RelayUplink {
+    peer: [u8; 6],
+}
+
+impl RelayUplink {
+    pub fn new(peer: [u8; 6]) -> Result<Self, EspError> {
+        espnow_tx::add_peer(peer)?;
+        Ok(Self { peer })
+    }
+
+    fn post(&mut self, event: &Event) -> Result<(), UplinkError> {
+        let qos = if event.is_alarm() {
+            Qos::Reliable
+        } else {
+            Qos::FireAndForget
+        };
+        espnow_tx::send(self.peer, event.to_json().as_bytes(), qos)?;
+        Ok(())
+    }
+}
+
+impl Uplink for RelayUplink {
+    fn name(&self) -> &'static str {
+        "espnow-relay"
+    }
+
+    fn send_measurement(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.post(&Event::Measurement(*sample))
+    }
+
+    fn send_alarm(&mut self, sample: &Sample, priority: Priority) -> Result<(), UplinkError> {
+        self.post(&Event::Alarm {
+            sample: *sample,
+            priority,
+        })
+    }
+
+    fn send_alarm_cleared(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.post(&Event::AlarmCleared(*sample))
+    }
+
+    fn send_emergency(&mut self, sample: &Sample, active: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Emergency {
+            sample: *sample,
+            active,
+        })
+    }
+
+    fn send_battery(&mut self, sample: &Sample, low: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Battery {
+            sample: *sample,
+            low,
+        })
+    }
+
+    fn send_anomaly(&mut self, sample: &Sample, unusual: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Anomaly {
+            sample: *sample,
+            unusual,
+        })
+    }
+
+    fn send_offline(&mut self, sample: &Sample, offline: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Offline {
+            sample: *sample,
+            offline,
+        })
+    }
+
+    fn send_status(&mut self, status: &Status) -> Result<(), UplinkError> {
+        self.post(&Event::Status(*status))
+    }
+}
+
+/// Parse a MAC address written as `AA:BB:CC:DD:EE:FF`
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
@@ -0,0 +1,82 @@
+use std::net::UdpSocket;
+
+use super::{Event, Status, Uplink, UplinkError};
+use crate::alerts::Priority;
+use crate::datalog::Sample;
+
+/// Sends each event as one JSON datagram
+pub struct UdpUplink {
+    socket: UdpSocket,
+    addr: String,
+}
+
+impl UdpUplink {
+    pub fn new(addr: &str) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            addr: addr.to_string(),
+        })
+    }
+
+    fn post(&mut self, event: &Event) -> Result<(), UplinkError> {
+        self.socket
+            .send_to(event.to_json().as_bytes(), self.addr.as_str())?;
+        Ok(())
+    }
+}
+
+impl Uplink for UdpUplink {
+    fn name(&self) -> &'static str {
+        "udp"
+    }
+
+    fn send_measurement(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.post(&Event::Measurement(*sample))
+    }
+
+    fn send_alarm(&mut self, sample: &Sample, priority: Priority) -> Result<(), UplinkError> {
+        self.post(&Event::Alarm {
+            sample: *sample,
+            priority,
+        })
+    }
+
+    fn send_alarm_cleared(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.post(&Event::AlarmCleared(*sample))
+    }
+
+    fn send_emergency(&mut self, sample: &Sample, active: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Emergency {
+            sample: *sample,
+            active,
+        })
+    }
+
+    fn send_battery(&mut self, sample: &Sample, low: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Battery {
+            sample: *sample,
+            low,
+        })
+    }
+
+    fn send_anomaly(&mut self, sample: &Sample, unusual: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Anomaly {
+            sample: *sample,
+            unusual,
+        })
+    }
+
+    fn send_offline(&mut self, sample: &Sample, offline: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Offline {
+            sample: *sample,
+            offline,
+        })
+    }
+
+    fn send_status(&mut self, status: &Status) -> Result<(), UplinkError> {
+        self.post(&Event::Status(*status))
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+
+use super::{Event, Status, Uplink, UplinkError};
+use crate::alerts::Priority;
+use crate::datalog::Sample;
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// POSTs each event as JSON to a fixed URL
+pub struct WebhookUplink {
+    url: String,
+}
+
+impl WebhookUplink {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+        }
+    }
+
+    fn post(&mut self, event: &Event) -> Result<(), UplinkError> {
+        let body = event.to_json();
+        let content_length = body.len().to_string();
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("Content-Length", content_length.as_str()),
+        ];
+
+        let mut conn = EspHttpConnection::new(&Configuration {
+            timeout: Some(TIMEOUT),
+            ..Default::default()
+        })?;
+        conn.initiate_request(Method::Post, &self.url, &headers)?;
+        conn.write_all(body.as_bytes())?;
+        conn.initiate_response()?;
+
+        match conn.status() {
+            200..=299 => Ok(()),
+            status => Err(UplinkError::Status(status)),
+        }
+    }
+}
+
+impl Uplink for WebhookUplink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send_measurement(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.post(&Event::Measurement(*sample))
+    }
+
+    fn send_alarm(&mut self, sample: &Sample, priority: Priority) -> Result<(), UplinkError> {
+        self.post(&Event::Alarm {
+            sample: *sample,
+            priority,
+        })
+    }
+
+    fn send_alarm_cleared(&mut self, sample: &Sample) -> Result<(), UplinkError> {
+        self.post(&Event::AlarmCleared(*sample))
+    }
+
+    fn send_emergency(&mut self, sample: &Sample, active: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Emergency {
+            sample: *sample,
+            active,
+        })
+    }
+
+    fn send_battery(&mut self, sample: &Sample, low: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Battery {
+            sample: *sample,
+            low,
+        })
+    }
+
+    fn send_anomaly(&mut self, sample: &Sample, unusual: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Anomaly {
+            sample: *sample,
+            unusual,
+        })
+    }
+
+    fn send_offline(&mut self, sample: &Sample, offline: bool) -> Result<(), UplinkError> {
+        self.post(&Event::Offline {
+            sample: *sample,
+            offline,
+        })
+    }
+
+    fn send_status(&mut self, status: &Status) -> Result<(), UplinkError> {
+        self.post(&Event::Status(*status))
+    }
+}
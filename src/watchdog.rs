@@ -0,0 +1,150 @@
+//! Liveness check on the main loop
+//!
+//! The hardware task watchdog only fires when a task hogs the CPU, so a loop
+//! blocked waiting on a hung peripheral goes unnoticed. A monitor thread
+//! watches the loop's iteration count instead; when it stops moving for the
+//! stall timeout the monitor logs the [`Stage`] the loop is stuck in. Once the
+//! loop gets going again [`Watchdog::feed`] hands that stage back so the loop
+//! can reset whatever hung. A loop that stays stuck for the restart timeout
+//! reboots the device as a last resort.
+
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+
+use crate::metrics;
+use crate::platform::sys::esp_restart;
+
+const STACK_SIZE: usize = 3072;
+// Sentinel for no stage, stages are stored as their discriminant
+const NONE: u8 = u8::MAX;
+
+/// What the main loop is busy with, set before each potentially blocking step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Stage {
+    Storage,
+    Uplinks,
+    Outputs,
+    Radio,
+}
+
+impl Stage {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Stage::Storage),
+            1 => Some(Stage::Uplinks),
+            2 => Some(Stage::Outputs),
+            3 => Some(Stage::Radio),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Storage => "storage",
+            Stage::Uplinks => "uplinks",
+            Stage::Outputs => "outputs",
+            Stage::Radio => "radio",
+        }
+    }
+}
+
+struct Shared {
+    iterations: AtomicU32,
+    stage: AtomicU8,
+    stalled: AtomicU8,
+    /// The timeouts in milliseconds, see [`Watchdog::set_timeouts`]
+    stall_ms: AtomicU32,
+    restart_ms: AtomicU32,
+}
+
+pub struct Watchdog {
+    shared: Arc<Shared>,
+}
+
+impl Watchdog {
+    /// Start the monitor, `stall` must be shorter than `restart`
+    ///
+    /// If the monitor thread cannot be spawned the loop runs unwatched.
+    pub fn start(stall: Duration, restart: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            iterations: AtomicU32::new(0),
+            stage: AtomicU8::new(NONE),
+            stalled: AtomicU8::new(NONE),
+            stall_ms: AtomicU32::new(0),
+            restart_ms: AtomicU32::new(0),
+        });
+        let watchdog = Self { shared };
+        watchdog.set_timeouts(stall, restart);
+        let monitor = watchdog.shared.clone();
+        let spawned = thread::Builder::new()
+            .name("watchdog".into())
+            .stack_size(STACK_SIZE)
+            .spawn(move || watch(&monitor));
+        if let Err(e) = spawned {
+            warn!("Watchdog unavailable, main loop unwatched: {}", e);
+        }
+        watchdog
+    }
+
+    /// Reset a step stuck for `stall` and restart after `restart` from the
+    /// monitor's next check on, `stall` shorter as for [`start`](Self::start)
+    pub fn set_timeouts(&self, stall: Duration, restart: Duration) {
+        let ms = |d: Duration| d.as_millis().min(u32::MAX.into()) as u32;
+        self.shared.stall_ms.store(ms(stall), Ordering::SeqCst);
+        self.shared.restart_ms.store(ms(restart), Ordering::SeqCst);
+    }
+
+    pub fn enter(&self, stage: Stage) {
+        self.shared.stage.store(stage as u8, Ordering::SeqCst);
+    }
+
+    /// Mark one loop iteration done, returns the stage that stalled since
+    /// the last call, if any
+    pub fn feed(&self) -> Option<Stage> {
+        self.shared.iterations.fetch_add(1, Ordering::SeqCst);
+        Stage::from_u8(self.shared.stalled.swap(NONE, Ordering::SeqCst))
+    }
+}
+
+fn watch(shared: &Shared) {
+    let mut last = shared.iterations.load(Ordering::SeqCst);
+    let mut since = Instant::now();
+    let mut reported = false;
+
+    loop {
+        let timeout = |ms: &AtomicU32| Duration::from_millis(ms.load(Ordering::SeqCst).into());
+        let (stall, restart) = (timeout(&shared.stall_ms), timeout(&shared.restart_ms));
+        thread::sleep(stall / 4);
+
+        let iterations = shared.iterations.load(Ordering::SeqCst);
+        if iterations != last {
+            last = iterations;
+            since = Instant::now();
+            reported = false;
+            continue;
+        }
+
+        let stuck = since.elapsed();
+        let stage = shared.stage.load(Ordering::SeqCst);
+        let name = Stage::from_u8(stage).map_or("startup", Stage::name);
+        if stuck >= restart {
+            error!(
+                "Main loop stuck in {} for {} s, restarting",
+                name,
+                stuck.as_secs()
+            );
+            metrics::watchdog_reset();
+            unsafe { esp_restart() };
+        }
+        if stuck >= stall && !reported {
+            warn!("Main loop stuck in {} for over {} s", name, stall.as_secs());
+            shared.stalled.store(stage, Ordering::SeqCst);
+            reported = true;
+        }
+    }
+}
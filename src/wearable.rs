@@ -0,0 +1,76 @@
+//! Mirroring the active alarms to paired wearables
+//!
+//! A wearable is a small ESP32 keyfob with a vibration motor and an LED,
+//! paired like any sender but with a `PRQW` request. Whenever the set of
+//! raised alarms changes, every paired wearable is sent all of them at once:
+//!
+//! `b"WAL"`, count, then per alarm the topic id (i32 LE), the priority
+//! (0 info, 1 warning, 2 critical) and 1 once acknowledged (3 + 6n bytes)
+//!
+//! An empty list means all clear. Changes go out reliably through the
+//! transmit queue, retried until the wearable acks them; the list is also
+//! repeated every minute so a wearable that was out of range or restarted
+//! catches up.
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::alerts::{Alerts, Priority};
+use crate::clock;
+use crate::espnow_tx::{self, Qos};
+use crate::pairing;
+
+const MAGIC: &[u8; 3] = b"WAL";
+// Keeps the frame well under the ESP-NOW payload limit
+const MAX_ALARMS: usize = 16;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct Mirror {
+    last: Vec<(i32, Priority, bool)>,
+    last_sent: Option<Instant>,
+}
+
+impl Mirror {
+    /// Send the alarms to the wearables if they changed, or are due again
+    pub fn poll(&mut self, alerts: &Alerts) {
+        let mut raised: Vec<_> = alerts.raised().take(MAX_ALARMS).collect();
+        raised.sort_by_key(|&(topic_id, _, _)| topic_id);
+
+        let qos = if raised != self.last {
+            Qos::Reliable
+        } else if self
+            .last_sent
+            .map_or(true, |t| clock::since(t) >= REFRESH_INTERVAL)
+        {
+            Qos::FireAndForget
+        } else {
+            return;
+        };
+        self.last_sent = Some(clock::now());
+
+        let wearables = pairing::wearables();
+        if !wearables.is_empty() {
+            let frame = encode(&raised);
+            for mac in wearables {
+                if let Err(e) = espnow_tx::send(mac, &frame, qos) {
+                    warn!("Alarm mirror to wearable {:02X?} failed: {}", mac, e);
+                }
+            }
+        }
+        self.last = raised;
+    }
+}
+
+fn encode(raised: &[(i32, Priority, bool)]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(MAGIC.len() + 1 + 6 * raised.len());
+    frame.extend_from_slice(MAGIC);
+    frame.push(raised.len() as u8);
+    for &(topic_id, priority, acknowledged) in raised {
+        frame.extend_from_slice(&topic_id.to_le_bytes());
+        frame.push(priority as u8);
+        frame.push(acknowledged as u8);
+    }
+    frame
+}
@@ -0,0 +1,61 @@
+//! Trusted senders
+//!
+//! Without a filter, anything in range sending a well-formed frame can ring
+//! the buzzer. Building with `SENDER_WHITELIST` set to a comma-separated
+//! list of MACs (`AA:BB:CC:DD:EE:FF,...`) turns the filter on: data frames
+//! and announcements are only taken from those MACs and from paired senders,
+//! others are dropped and counted. An empty list trusts paired senders only.
+//! Pairing requests always get through, or nothing new could pair.
+
+use std::sync::OnceLock;
+
+use log::{info, warn};
+
+use crate::pairing;
+use crate::uplink::parse_mac;
+
+const SENDER_WHITELIST: Option<&str> = option_env!("SENDER_WHITELIST");
+
+// `None` when the filter is off
+static TRUSTED: OnceLock<Option<Vec<[u8; 6]>>> = OnceLock::new();
+
+/// Parse the build-time whitelist, before frames are received
+pub fn init() {
+    TRUSTED.get_or_init(|| {
+        let list = SENDER_WHITELIST?;
+        let trusted: Vec<[u8; 6]> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let mac = parse_mac(entry);
+                if mac.is_none() {
+                    warn!("Invalid SENDER_WHITELIST entry {:?} ignored", entry);
+                }
+                mac
+            })
+            .collect();
+        info!(
+            "Sender whitelist: {} MACs and the paired senders",
+            trusted.len()
+        );
+        Some(trusted)
+    });
+}
+
+/// Whether frames from `mac` are taken
+pub fn allows(mac: &[u8; 6]) -> bool {
+    match TRUSTED.get() {
+        Some(Some(trusted)) => trusted.contains(mac) || pairing::is_paired(mac),
+        _ => true,
+    }
+}
+
+/// Whether `mac` is paired or on the whitelist, whether or not the filter
+/// is on
+pub fn known(mac: &[u8; 6]) -> bool {
+    match TRUSTED.get() {
+        Some(Some(trusted)) => trusted.contains(mac) || pairing::is_paired(mac),
+        _ => pairing::is_paired(mac),
+    }
+}